@@ -1,8 +1,121 @@
-use pcd_acm::mock_alert_mgr;
-fn main() {
-    let alert_mgr_handle = std::thread::spawn(|| mock_alert_mgr::run_mock_alert_mgr());
-    
-    if let Err(err) = alert_mgr_handle.join() {
-        eprintln!("Error joining mock_alert_mgr thread: {:?}", err);
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use pcd_acm::ack_policy::{AckPolicy, AckRule, Delivery};
+use pcd_acm::conformance::ConformanceConfig;
+use pcd_acm::logging;
+use pcd_acm::mock_alert_mgr::{self, AlertMgrConfig};
+use pcd_acm::shutdown::Shutdown;
+use pcd_acm::tls::{TlsConfig, TlsTransport};
+use pcd_acm::transport::DefaultTransport;
+
+fn usage() -> ! {
+    eprintln!("Usage: alert_manager [--listen ADDR] [--conformance] [--report-dir DIR]");
+    eprintln!("                     [--ack-policy FILE]");
+    eprintln!(
+        "                     [--for-code MDC_CODE|CWE] [--ack-code AA|AE|AR] [--ack-delay MS]"
+    );
+    eprintln!("                     [--drop-ack] [--duplicate-ack N] [--wrong-msa2]");
+    eprintln!("                     [--close-mid-frame]");
+    eprintln!("                     [--tls-cert PEM --tls-key PEM --tls-ca PEM...]");
+    eprintln!("                     [--audit FILE|udp://HOST:PORT|tcp://HOST:PORT]...");
+    eprintln!("                     [--max-connections N] [--max-frame-bytes N]");
+    eprintln!("                     [--api 127.0.0.1:PORT|unix:PATH] [--metrics 127.0.0.1:PORT]");
+    eprintln!("                     [--log FILTER] [--log-format human|json]");
+    std::process::exit(2);
+}
+
+fn number<T: std::str::FromStr>(value: Option<String>) -> T {
+    value
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+fn parse_args(args: Vec<String>) -> AlertMgrConfig {
+    let mut config = AlertMgrConfig::default();
+    let mut conformance = false;
+    let mut report_dir = PathBuf::from("conformance-reports");
+    // Flags describe a single rule that is tried after any rules from --ack-policy.
+    let mut rule = AckRule::default();
+    let mut tls: Option<TlsConfig> = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => config.listen_address = args.next().unwrap_or_else(|| usage()),
+            "--conformance" => conformance = true,
+            "--ack-policy" => {
+                let path = args.next().map(PathBuf::from).unwrap_or_else(|| usage());
+                config.ack_policy = AckPolicy::from_file(&path).unwrap_or_else(|e| {
+                    eprintln!("Error reading {}: {}", path.display(), e);
+                    std::process::exit(2);
+                });
+            }
+            "--for-code" => rule.alert_code = Some(args.next().unwrap_or_else(|| usage())),
+            "--ack-code" => rule.ack_code = Some(args.next().unwrap_or_else(|| usage())),
+            "--ack-delay" => rule.delay_ms = number(args.next()),
+            "--drop-ack" => rule.delivery = Delivery::Drop,
+            "--duplicate-ack" => {
+                rule.delivery = Delivery::Duplicate {
+                    copies: number(args.next()),
+                }
+            }
+            "--wrong-msa2" => rule.wrong_control_id = true,
+            "--close-mid-frame" => rule.delivery = Delivery::CloseMidFrame,
+            "--report-dir" => {
+                report_dir = args.next().map(PathBuf::from).unwrap_or_else(|| usage())
+            }
+            "--tls-cert" | "--tls-key" | "--tls-ca" => {
+                let value = PathBuf::from(args.next().unwrap_or_else(|| usage()));
+                let tls = tls.get_or_insert_with(TlsConfig::default);
+                match arg.as_str() {
+                    "--tls-cert" => tls.cert = value,
+                    "--tls-key" => tls.key = value,
+                    _ => tls.ca.push(value),
+                }
+            }
+            "--audit" => match args.next().unwrap_or_else(|| usage()).parse() {
+                Ok(sink) => config.audit.push(sink),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    usage();
+                }
+            },
+            "--max-connections" => config.max_connections = number(args.next()),
+            "--max-frame-bytes" => config.max_frame_len = number(args.next()),
+            "--api" => config.api_address = Some(args.next().unwrap_or_else(|| usage())),
+            "--metrics" => config.metrics_address = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+    if let Some(tls) = tls {
+        let transport = TlsTransport::new(&tls, Arc::new(DefaultTransport));
+        config.transport = Arc::new(transport.unwrap_or_else(|e| {
+            eprintln!("Error setting up TLS: {}", e);
+            std::process::exit(2);
+        }));
+    }
+    if rule != AckRule::default() {
+        config.ack_policy.rules.push(rule);
+    }
+    if conformance {
+        config.conformance = Some(ConformanceConfig { report_dir });
+    }
+    config
+}
+
+#[tokio::main]
+async fn main() {
+    let args = logging::init_from_args(std::env::args().skip(1).collect()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let config = parse_args(args);
+    let shutdown = Shutdown::new();
+    shutdown.on_ctrl_c();
+
+    if let Err(e) = mock_alert_mgr::run_mock_alert_mgr_with(config, shutdown).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
 }
@@ -1,9 +1,594 @@
-use pcd_acm::mock_alert_rpt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-fn main() {
-    let alert_rpt_handle = std::thread::spawn(|| mock_alert_rpt::run_mock_alert_rpt());
+use serde::Serialize;
+use tracing::info;
+use uuid::Uuid;
 
-    if let Err(err) = alert_rpt_handle.join() {
-        eprintln!("Error joining mock_alert_rpt thread: {:?}", err);
+use pcd_acm::device::{ActiveAlert, AlertSpec, DeviceConfig, ObservationSpec};
+use pcd_acm::load::{self, LoadConfig, LoadMix};
+use pcd_acm::logging;
+use pcd_acm::mock_alert_rpt::{self, AlertRptConfig};
+use pcd_acm::pcd04_msg::PCD04Message;
+use pcd_acm::profiles::{DeviceClass, Metric, PATIENT_MONITOR};
+use pcd_acm::scenario::{self, DeviceSender, Scenario, StepOutcome};
+use pcd_acm::shutdown::Shutdown;
+use pcd_acm::tls::{TlsConfig, TlsTransport};
+use pcd_acm::transport::{DefaultTransport, Transport};
+use pcd_acm::validate::{self, Finding};
+use pcd_acm::vitals::{self, VitalsConfig};
+
+// Exit codes of the one-shot subcommands.
+const EXIT_OK: i32 = 0;
+/// A message was not answered with AA or CA, a scenario step could not be played, or a file did not
+/// validate.
+const EXIT_REJECTED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+/// The manager could not be reached or did not acknowledge in time.
+const EXIT_UNREACHABLE: i32 = 3;
+
+fn usage() -> ! {
+    eprintln!("Usage: alert_reporter [--manager ADDR]... [--failback-ms MS] [--max-missed-acks N]");
+    eprintln!("                      [--queue-file FILE] [--queue-size N] [--reconnect-max-ms MS]");
+    eprintln!("                      [--tls-cert PEM --tls-key PEM --tls-ca PEM...]");
+    eprintln!("                      [--tls-server-name NAME]");
+    eprintln!("                      [--audit FILE|udp://HOST:PORT|tcp://HOST:PORT]...");
+    eprintln!(
+        "                      [--control 127.0.0.1:PORT|unix:PATH] [--metrics 127.0.0.1:PORT]"
+    );
+    eprintln!("                      [--profile PROFILE]");
+    eprintln!(
+        "       alert_reporter send-alert --code CODE [--text TEXT] [--priority PN|PL|PM|PH]"
+    );
+    eprintln!("                                 [--kind SP|ST|SA] [--phase PHASE]");
+    eprintln!("                                 [[--metric CODE] --value V [--unit UNIT]]");
+    eprintln!("                                 [--profile PROFILE] [CONNECTION] [--json]");
+    eprintln!("       alert_reporter send-alert --profile PROFILE [--metric CODE] --value V");
+    eprintln!("                                 [CONNECTION] [--json]");
+    eprintln!("       alert_reporter heartbeat [--period 5s] [--count N] [--profile PROFILE]");
+    eprintln!("                                [CONNECTION] [--json]");
+    eprintln!("       alert_reporter run-scenario SCENARIO.yaml|SCENARIO.json [--json]");
+    eprintln!("       alert_reporter validate FILE.hl7 [--json]");
+    eprintln!("       alert_reporter load [--config FILE] [--manager ADDR] [--devices N]");
+    eprintln!("                           [--duration SECS] [--heartbeat-ms MS] [--alarm-ms MS]");
+    eprintln!("                           [--tls-cert PEM --tls-key PEM --tls-ca PEM...]");
+    eprintln!("                           [--tls-server-name NAME] [--json]");
+    eprintln!("       alert_reporter vitals [--config FILE] [--manager ADDR] [--duration SECS]");
+    eprintln!("                             [--time-scale X] [--seed N]");
+    eprintln!("                             [--tls-cert PEM --tls-key PEM --tls-ca PEM...]");
+    eprintln!("                             [--tls-server-name NAME]");
+    eprintln!("       Every form also takes [--log FILTER] [--log-format human|json].");
+    eprintln!("PROFILE: patient_monitor, infusion_pump or ventilator");
+    eprintln!("CONNECTION: [--manager ADDR] [--ack-timeout-ms MS]");
+    eprintln!(
+        "            [--tls-cert PEM --tls-key PEM --tls-ca PEM...] [--tls-server-name NAME]"
+    );
+    std::process::exit(EXIT_USAGE);
+}
+
+fn number<T: std::str::FromStr>(value: Option<String>) -> T {
+    value
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+/// Parses `5s`, `500ms`, `2m` or a bare number of seconds.
+fn duration(value: Option<String>) -> Duration {
+    let value = value.unwrap_or_else(|| usage());
+    let (number, scale) = if let Some(ms) = value.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(s) = value.strip_suffix('s') {
+        (s, 1.0)
+    } else if let Some(m) = value.strip_suffix('m') {
+        (m, 60.0)
+    } else {
+        (value.as_str(), 1.0)
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n >= 0.0 => Duration::from_secs_f64(n * scale),
+        _ => usage(),
+    }
+}
+
+fn profile(value: Option<String>) -> DeviceClass {
+    value
+        .unwrap_or_else(|| usage())
+        .parse()
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            usage();
+        })
+}
+
+fn tls_flag(tls: &mut Option<TlsConfig>, flag: &str, value: String) {
+    let tls = tls.get_or_insert_with(TlsConfig::default);
+    match flag {
+        "--tls-cert" => tls.cert = value.into(),
+        "--tls-key" => tls.key = value.into(),
+        "--tls-ca" => tls.ca.push(value.into()),
+        _ => tls.server_name = Some(value),
+    }
+}
+
+fn tls_transport(tls: &TlsConfig) -> Arc<dyn Transport> {
+    let transport = TlsTransport::new(tls, Arc::new(DefaultTransport));
+    Arc::new(transport.unwrap_or_else(|e| {
+        eprintln!("Error setting up TLS: {}", e);
+        std::process::exit(EXIT_USAGE);
+    }))
+}
+
+/// The manager the one-shot subcommands talk to.
+struct Target {
+    manager: String,
+    ack_timeout: Duration,
+    tls: Option<TlsConfig>,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target {
+            manager: "127.0.0.1:8888".to_string(),
+            ack_timeout: Duration::from_secs(5),
+            tls: None,
+        }
+    }
+}
+
+impl Target {
+    /// Takes `flag` and its value if it is a connection flag.
+    fn flag(&mut self, flag: &str, args: &mut impl Iterator<Item = String>) -> bool {
+        match flag {
+            "--manager" => self.manager = args.next().unwrap_or_else(|| usage()),
+            "--ack-timeout-ms" => self.ack_timeout = Duration::from_millis(number(args.next())),
+            "--tls-cert" | "--tls-key" | "--tls-ca" | "--tls-server-name" => {
+                tls_flag(&mut self.tls, flag, args.next().unwrap_or_else(|| usage()))
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn transport(&self) -> Arc<dyn Transport> {
+        match &self.tls {
+            Some(tls) => tls_transport(tls),
+            None => Arc::new(DefaultTransport),
+        }
+    }
+}
+
+/// Sends messages one at a time and collects what became of each.
+struct Sender {
+    device: DeviceSender,
+    outcomes: Vec<StepOutcome>,
+}
+
+impl Sender {
+    fn new(target: Target, device: DeviceConfig) -> Self {
+        Sender {
+            device: DeviceSender::new(
+                device,
+                target.transport(),
+                &target.manager,
+                target.ack_timeout,
+            ),
+            outcomes: Vec::new(),
+        }
+    }
+
+    async fn send(&mut self, msg: PCD04Message) {
+        let step = self.outcomes.len() + 1;
+        let outcome = self
+            .device
+            .send(step, Uuid::new_v4().to_string(), msg)
+            .await;
+        self.outcomes.push(outcome);
+    }
+
+    async fn finish(mut self) -> Vec<StepOutcome> {
+        self.device.disconnect().await;
+        self.outcomes
+    }
+}
+
+#[derive(Serialize)]
+struct SendReport {
+    sent: usize,
+    acknowledged: usize,
+    failed: usize,
+    outcomes: Vec<StepOutcome>,
+}
+
+/// Prints the outcome of a batch of messages and exits with the code it deserves.
+fn report(outcomes: Vec<StepOutcome>, json: bool) -> ! {
+    let acknowledged = outcomes.iter().filter(|o| o.is_ok()).count();
+    let report = SendReport {
+        sent: outcomes.len(),
+        acknowledged,
+        failed: outcomes.len() - acknowledged,
+        outcomes,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        println!("{} messages sent, {} failed", report.sent, report.failed);
+    }
+    let code = if report.outcomes.iter().any(StepOutcome::is_unreachable) {
+        EXIT_UNREACHABLE
+    } else if report.failed > 0 {
+        EXIT_REJECTED
+    } else {
+        EXIT_OK
+    };
+    std::process::exit(code);
+}
+
+/// The metric `--value` measures when `--metric` is not given: the one on the device's channel
+/// (SpO2 by default), or else the first of its profile.
+fn default_metric(device: &DeviceConfig) -> &'static Metric {
+    let profile = device
+        .profile
+        .map_or(&PATIENT_MONITOR, DeviceClass::profile);
+    let channels = || profile.vmds.iter().flat_map(|vmd| vmd.channels);
+    channels()
+        .find(|channel| channel.code == device.chan_type)
+        .or_else(|| channels().next())
+        .and_then(|channel| channel.metrics.first())
+        .expect("every profile has a metric")
+}
+
+async fn send_alert(mut args: impl Iterator<Item = String>) {
+    let mut target = Target::default();
+    let mut spec = AlertSpec {
+        code: String::new(),
+        text: String::new(),
+        priority: "PM".to_string(),
+        kind: "SP".to_string(),
+        observation: None,
+    };
+    let mut phase = "start".to_string();
+    let (mut metric, mut value, mut unit) = (None, None, String::new());
+    let mut device = DeviceConfig::default();
+    let mut json = false;
+
+    while let Some(arg) = args.next() {
+        if target.flag(&arg, &mut args) {
+            continue;
+        }
+        let mut value_of = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--code" => spec.code = value_of(),
+            "--text" => spec.text = value_of(),
+            "--priority" => spec.priority = value_of(),
+            "--kind" => spec.kind = value_of(),
+            "--phase" => phase = value_of(),
+            "--metric" => metric = Some(value_of()),
+            "--value" => value = Some(value_of()),
+            "--unit" => unit = value_of(),
+            "--profile" => device.profile = Some(profile(Some(value_of()))),
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+    match (metric, value) {
+        (Some(code), Some(value)) => {
+            spec.observation = Some(ObservationSpec {
+                code,
+                value,
+                unit,
+                value_type: "NM".to_string(),
+            })
+        }
+        (None, Some(value)) => {
+            let metric = default_metric(&device);
+            spec.observation = Some(ObservationSpec {
+                code: metric.code.to_string(),
+                value,
+                unit: if unit.is_empty() {
+                    metric.unit.to_string()
+                } else {
+                    unit
+                },
+                value_type: "NM".to_string(),
+            })
+        }
+        (None, None) => {}
+        (Some(_), None) => {
+            eprintln!("--metric needs a --value");
+            usage();
+        }
+    }
+    // Without --code the alert is the one the profile's default limits raise for the value.
+    if spec.code.is_empty() {
+        let (Some(class), Some(observation)) = (device.profile, &spec.observation) else {
+            usage();
+        };
+        let value = observation.value.parse().unwrap_or_else(|_| usage());
+        spec = class
+            .profile()
+            .check_limits(&observation.code, value)
+            .unwrap_or_else(|| {
+                eprintln!(
+                    "{} is within the limits of a {} for {}",
+                    value,
+                    class.profile(),
+                    observation.code
+                );
+                std::process::exit(EXIT_USAGE);
+            });
+    }
+
+    let mut sender = Sender::new(target, device);
+    let alert = ActiveAlert {
+        id: Uuid::new_v4().to_string(),
+        spec,
+        update: 0,
+    };
+    let state = if phase == "end" { "inactive" } else { "active" };
+    let msg = sender.device.config.alert_message(&alert, &phase, state);
+    sender.send(msg).await;
+    report(sender.finish().await, json);
+}
+
+async fn heartbeat(mut args: impl Iterator<Item = String>) {
+    let mut target = Target::default();
+    let mut period = Duration::from_secs(5);
+    let mut count: u32 = 1;
+    let mut device = DeviceConfig::default();
+    let mut json = false;
+
+    while let Some(arg) = args.next() {
+        if target.flag(&arg, &mut args) {
+            continue;
+        }
+        match arg.as_str() {
+            "--period" => period = duration(args.next()),
+            "--count" => count = number(args.next()),
+            "--profile" => device.profile = Some(profile(args.next())),
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+
+    let mut sender = Sender::new(target, device);
+    let mut ticks = tokio::time::interval(period);
+    for _ in 0..count {
+        ticks.tick().await;
+        let msg = sender.device.config.heartbeat();
+        sender.send(msg).await;
+    }
+    report(sender.finish().await, json);
+}
+
+async fn run(mut args: impl Iterator<Item = String>) {
+    let path = args.next().map(PathBuf::from).unwrap_or_else(|| usage());
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+    let scenario = Scenario::from_file(&path).unwrap_or_else(|e| {
+        eprintln!("Error reading {}: {}", path.display(), e);
+        std::process::exit(EXIT_USAGE);
+    });
+
+    let outcomes = scenario::run_scenario(&scenario).await.unwrap_or_else(|e| {
+        eprintln!("Error connecting to {}: {}", scenario.manager, e);
+        std::process::exit(EXIT_USAGE);
+    });
+    report(outcomes, json);
+}
+
+#[derive(Serialize)]
+struct ValidationReport {
+    file: PathBuf,
+    valid: bool,
+    findings: Vec<Finding>,
+}
+
+fn validate(mut args: impl Iterator<Item = String>) {
+    let path = args.next().map(PathBuf::from).unwrap_or_else(|| usage());
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+    let text = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        eprintln!("Error reading {}: {}", path.display(), e);
+        std::process::exit(EXIT_USAGE);
+    });
+    let findings = validate::validate_pcd04(&text);
+    let valid = !validate::has_errors(&findings);
+    if json {
+        let report = ValidationReport {
+            file: path,
+            valid,
+            findings,
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        for finding in &findings {
+            println!("{}", finding);
+        }
+        println!(
+            "{}: {}",
+            path.display(),
+            if valid { "valid" } else { "invalid" }
+        );
+    }
+    std::process::exit(if valid { EXIT_OK } else { EXIT_REJECTED });
+}
+
+async fn load(mut args: impl Iterator<Item = String>) {
+    let mut config = LoadConfig::default();
+    // --heartbeat-ms and --alarm-ms replace the mix with a single device class.
+    let mut mix: Option<LoadMix> = None;
+    let mut json = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                let path = args.next().map(PathBuf::from).unwrap_or_else(|| usage());
+                config = LoadConfig::from_file(&path).unwrap_or_else(|e| {
+                    eprintln!("Error reading {}: {}", path.display(), e);
+                    std::process::exit(EXIT_USAGE);
+                });
+            }
+            "--manager" => config.manager = args.next().unwrap_or_else(|| usage()),
+            "--devices" => config.devices = number(args.next()),
+            "--duration" => config.duration_secs = number(args.next()),
+            "--heartbeat-ms" => {
+                mix.get_or_insert_with(LoadMix::default)
+                    .heartbeat_interval_ms = number(args.next())
+            }
+            "--alarm-ms" => {
+                mix.get_or_insert_with(LoadMix::default).alarm_interval_ms = number(args.next())
+            }
+            "--tls-cert" | "--tls-key" | "--tls-ca" | "--tls-server-name" => tls_flag(
+                &mut config.tls,
+                &arg,
+                args.next().unwrap_or_else(|| usage()),
+            ),
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+    if let Some(mix) = mix {
+        config.mix = vec![mix];
+    }
+
+    info!(
+        devices = config.devices,
+        manager = %config.manager,
+        duration_secs = config.duration_secs,
+        "Simulating load"
+    );
+    let report = load::run_load(&config).await.unwrap_or_else(|e| {
+        eprintln!(
+            "Error setting up the connection to {}: {}",
+            config.manager, e
+        );
+        std::process::exit(EXIT_USAGE);
+    });
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        println!("{}", report);
+    }
+}
+
+async fn simulate_vitals(mut args: impl Iterator<Item = String>) {
+    let mut config = VitalsConfig::default();
+    let mut overrides = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                let path = args.next().map(PathBuf::from).unwrap_or_else(|| usage());
+                config = VitalsConfig::from_file(&path).unwrap_or_else(|e| {
+                    eprintln!("Error reading {}: {}", path.display(), e);
+                    std::process::exit(EXIT_USAGE);
+                });
+            }
+            "--manager" | "--duration" | "--time-scale" | "--seed" | "--tls-cert" | "--tls-key"
+            | "--tls-ca" | "--tls-server-name" => {
+                overrides.push((arg, args.next().unwrap_or_else(|| usage())))
+            }
+            _ => usage(),
+        }
+    }
+    // Flags win over the config file wherever they appear.
+    for (flag, value) in overrides {
+        match flag.as_str() {
+            "--manager" => config.manager = value,
+            "--duration" => config.duration_secs = number(Some(value)),
+            "--time-scale" => config.time_scale = number(Some(value)),
+            "--seed" => config.seed = number(Some(value)),
+            _ => tls_flag(&mut config.tls, &flag, value),
+        }
+    }
+
+    let outcomes = vitals::run_vitals(&config).await.unwrap_or_else(|e| {
+        eprintln!(
+            "Error setting up the connection to {}: {}",
+            config.manager, e
+        );
+        std::process::exit(EXIT_USAGE);
+    });
+    let failed = outcomes.iter().filter(|o| !o.is_ok()).count();
+    println!("{} messages sent, {} failed", outcomes.len(), failed);
+    if failed > 0 {
+        std::process::exit(EXIT_REJECTED);
+    }
+}
+
+fn interactive_config(mut args: impl Iterator<Item = String>) -> AlertRptConfig {
+    let mut config = AlertRptConfig::default();
+    let mut managers = Vec::new();
+    let mut tls: Option<TlsConfig> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Repeat --manager to list secondaries after the primary.
+            "--manager" => managers.push(args.next().unwrap_or_else(|| usage())),
+            "--failback-ms" => {
+                config.failback_interval = Some(Duration::from_millis(number(args.next())))
+            }
+            "--max-missed-acks" => config.max_missed_acks = number(args.next()),
+            "--queue-file" => config.queue_file = args.next().map(PathBuf::from),
+            "--queue-size" => config.queue_capacity = number(args.next()),
+            "--reconnect-max-ms" => config.reconnect.max_ms = number(args.next()),
+            "--control" => config.control_address = args.next(),
+            "--metrics" => config.metrics_address = args.next(),
+            "--profile" => config.device.profile = Some(profile(args.next())),
+            "--tls-cert" | "--tls-key" | "--tls-ca" | "--tls-server-name" => {
+                tls_flag(&mut tls, &arg, args.next().unwrap_or_else(|| usage()))
+            }
+            "--audit" => match args.next().unwrap_or_else(|| usage()).parse() {
+                Ok(sink) => config.audit.push(sink),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    usage();
+                }
+            },
+            _ => usage(),
+        }
+    }
+    if !managers.is_empty() {
+        config.managers = managers;
+    }
+    if let Some(tls) = tls {
+        config.transport = tls_transport(&tls);
+    }
+    config
+}
+
+#[tokio::main]
+async fn main() {
+    let args = logging::init_from_args(std::env::args().skip(1).collect()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(EXIT_USAGE);
+    });
+    let mut args = args.into_iter().peekable();
+    match args.peek().map(String::as_str) {
+        Some("run") | Some("run-scenario") => return run(args.skip(1)).await,
+        Some("send-alert") => return send_alert(args.skip(1)).await,
+        Some("heartbeat") => return heartbeat(args.skip(1)).await,
+        Some("validate") => return validate(args.skip(1)),
+        Some("load") => return load(args.skip(1)).await,
+        Some("vitals") => return simulate_vitals(args.skip(1)).await,
+        _ => {}
+    }
+
+    let config = interactive_config(args);
+    let shutdown = Shutdown::new();
+    shutdown.on_ctrl_c();
+    if let Err(e) = mock_alert_rpt::run_mock_alert_rpt_with(config, shutdown).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,153 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::segments::component;
+
+/// How the acknowledgment is put on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    #[default]
+    Normal,
+    /// Never answer the message.
+    Drop,
+    /// Send the same ACK `copies` times.
+    Duplicate { copies: u32 },
+    /// Send the start of the ACK frame, then close the connection.
+    CloseMidFrame,
+}
+
+/// One rule of an acknowledgment policy. The first rule that matches a message applies.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AckRule {
+    /// MDC code of the alert identification OBX, bare (`196670`) or as the full OBX-3 value
+    /// (`196670^MDC_EVT_LO^MDC`); any message when unset.
+    pub alert_code: Option<String>,
+    /// Overrides MSA-1 (`AA`, `AE` or `AR`).
+    pub ack_code: Option<String>,
+    pub delay_ms: u64,
+    pub delivery: Delivery,
+    /// Puts a control ID that doesn't match the message in MSA-2.
+    pub wrong_control_id: bool,
+    /// Only apply the rule to this many messages.
+    pub times: Option<u32>,
+}
+
+/// A scenario of acknowledgment behaviour for negative testing, e.g.
+///
+/// ```json
+/// { "rules": [
+///     { "alert_code": "196670", "ack_code": "AE" },
+///     { "delay_ms": 2000, "delivery": { "duplicate": { "copies": 2 } }, "times": 3 }
+/// ] }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AckPolicy {
+    pub rules: Vec<AckRule>,
+}
+
+impl AckPolicy {
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl AckRule {
+    fn matches(&self, alert_code: Option<&str>) -> bool {
+        match &self.alert_code {
+            Some(code) => alert_code == Some(component(code, 1)),
+            None => true,
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay_ms)
+    }
+}
+
+/// Applies an [`AckPolicy`] across connections, keeping track of how often each rule fired.
+#[derive(Debug, Default)]
+pub struct AckPolicyEngine {
+    policy: AckPolicy,
+    used: Mutex<Vec<u32>>,
+}
+
+impl AckPolicyEngine {
+    pub fn new(policy: AckPolicy) -> Self {
+        let used = Mutex::new(vec![0; policy.rules.len()]);
+        AckPolicyEngine { policy, used }
+    }
+
+    /// The rule to apply to a message carrying `alert_code`, if any.
+    pub fn decide(&self, alert_code: Option<&str>) -> Option<AckRule> {
+        let mut used = self.used.lock().unwrap();
+        let index = self.policy.rules.iter().enumerate().position(|(i, rule)| {
+            rule.matches(alert_code) && rule.times.is_none_or(|times| used[i] < times)
+        })?;
+        used[index] += 1;
+        Some(self.policy.rules[index].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"{ "rules": [
+        { "alert_code": "196670^MDC_EVT_LO^MDC", "ack_code": "AE", "wrong_control_id": true },
+        { "delay_ms": 2000, "delivery": { "duplicate": { "copies": 2 } }, "times": 2 },
+        { "alert_code": "196648", "delivery": "close_mid_frame" },
+        { "delivery": "drop" }
+    ] }"#;
+
+    #[test]
+    fn policy_file_format() {
+        let policy: AckPolicy = serde_json::from_str(POLICY).unwrap();
+        assert_eq!(policy.rules.len(), 4);
+        assert_eq!(policy.rules[0].delivery, Delivery::Normal);
+        assert_eq!(policy.rules[1].delivery, Delivery::Duplicate { copies: 2 });
+        assert_eq!(policy.rules[1].delay(), Duration::from_secs(2));
+        assert_eq!(policy.rules[2].delivery, Delivery::CloseMidFrame);
+        assert_eq!(policy.rules[3].delivery, Delivery::Drop);
+        assert!(AckPolicy::default().is_empty());
+    }
+
+    #[test]
+    fn first_matching_rule_applies_until_used_up() {
+        let engine = AckPolicyEngine::new(serde_json::from_str(POLICY).unwrap());
+
+        let rule = engine.decide(Some("196670")).unwrap();
+        assert_eq!(rule.ack_code.as_deref(), Some("AE"));
+        assert!(rule.wrong_control_id);
+        // Rules without a count never run out.
+        assert_eq!(engine.decide(Some("196670")), Some(rule));
+
+        // The duplicate rule matches any message, but only twice.
+        for _ in 0..2 {
+            let rule = engine.decide(Some("196648")).unwrap();
+            assert_eq!(rule.delivery, Delivery::Duplicate { copies: 2 });
+        }
+        let rule = engine.decide(Some("196648")).unwrap();
+        assert_eq!(rule.delivery, Delivery::CloseMidFrame);
+        let rule = engine.decide(None).unwrap();
+        assert_eq!(rule.delivery, Delivery::Drop);
+    }
+
+    #[test]
+    fn empty_policy_leaves_messages_alone() {
+        let engine = AckPolicyEngine::new(AckPolicy::default());
+        assert_eq!(engine.decide(Some("196670")), None);
+    }
+}
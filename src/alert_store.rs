@@ -0,0 +1,591 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::handlers::{MessageContext, MessageKind};
+use crate::segments::component;
+
+/// Messages kept in the log of one alert; older ones are dropped.
+const MAX_ALERT_MESSAGES: usize = 100;
+
+/// Ended alerts kept for queries; older ones are dropped.
+const MAX_ENDED_ALERTS: usize = 1000;
+
+/// Heartbeat timeout of a source whose heartbeats don't state one.
+const DEFAULT_HEARTBEAT_TIMEOUT_SECS: u64 = 10;
+
+/// Lifecycle events kept for subscribers that replay from a sequence number.
+const MAX_EVENTS: usize = 10_000;
+
+/// Events a live subscriber may fall behind by before it is cut off.
+const EVENT_CAPACITY: usize = 1024;
+
+const PRIORITIES: [&str; 4] = ["PN", "PL", "PM", "PH"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeartbeatStatus {
+    /// The last heartbeat is within its timeout.
+    Ok,
+    Lost,
+    /// The source has not sent a heartbeat yet.
+    Never,
+}
+
+/// A device that has sent messages to the manager, keyed by the EUI-64 in MSH-3.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SourceRecord {
+    pub source: String,
+    /// Address of the connection its last message arrived on.
+    pub peer: String,
+    pub connected: bool,
+    pub location: String,
+    pub patient_id: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub messages: u64,
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    /// MDC_ATTR_CONFIRM_TIMEOUT of its heartbeats.
+    pub heartbeat_timeout_secs: u64,
+    /// Time between its last two heartbeats.
+    pub heartbeat_interval_ms: Option<u64>,
+    /// How much that interval differed from the one before.
+    pub heartbeat_jitter_ms: Option<u64>,
+    /// A [`AlertChange::SourceLost`] was published and nothing has arrived since.
+    #[serde(skip)]
+    lost: bool,
+}
+
+impl SourceRecord {
+    pub fn heartbeat_status(&self, now: DateTime<Utc>) -> HeartbeatStatus {
+        match self.last_heartbeat {
+            None => HeartbeatStatus::Never,
+            Some(last) if now - last > timeout(self.heartbeat_timeout_secs) => {
+                HeartbeatStatus::Lost
+            }
+            Some(_) => HeartbeatStatus::Ok,
+        }
+    }
+}
+
+fn timeout(secs: u64) -> Duration {
+    Duration::seconds(secs.try_into().unwrap_or(i64::MAX))
+}
+
+/// One message about an alert, as it arrived.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AlertMessage {
+    pub received: DateTime<Utc>,
+    pub control_id: String,
+    pub phase: String,
+    pub state: String,
+    pub priority: String,
+    /// Value and unit of the triggering measurement, e.g. `85 %`.
+    pub value: Option<String>,
+    /// MSA-1 the manager answered with.
+    pub ack: String,
+}
+
+/// An alert from its start message until its end message, with the messages about it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AlertRecord {
+    /// OBR-3 alert identifier.
+    pub id: String,
+    pub source: String,
+    /// MDC event code, e.g. `196670`.
+    pub code: String,
+    pub text: String,
+    /// SP, ST or SA.
+    pub kind: String,
+    pub priority: String,
+    /// Event phase of the latest message.
+    pub phase: String,
+    pub location: String,
+    pub patient_id: String,
+    pub started: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub ended: Option<DateTime<Utc>>,
+    /// Messages received about the alert, including those dropped from `messages`.
+    pub message_count: u64,
+    /// The latest messages, oldest first.
+    #[serde(skip)]
+    pub messages: VecDeque<AlertMessage>,
+}
+
+/// What happened to an alert or source. Alert changes carry the alert (without its message log)
+/// and the message that caused them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AlertChange {
+    New {
+        alert: AlertRecord,
+        message: AlertMessage,
+    },
+    Updated {
+        alert: AlertRecord,
+        message: AlertMessage,
+    },
+    /// The priority went up from `from`.
+    Escalated {
+        from: String,
+        alert: AlertRecord,
+        message: AlertMessage,
+    },
+    Deescalated {
+        from: String,
+        alert: AlertRecord,
+        message: AlertMessage,
+    },
+    Ended {
+        alert: AlertRecord,
+        message: AlertMessage,
+    },
+    /// The source's connection closed or its heartbeat is overdue.
+    SourceLost { source: String, reason: String },
+    /// A lost source sent a message again.
+    SourceRestored { source: String },
+}
+
+/// One change, numbered in the order the store saw them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AlertEvent {
+    /// Starts at 1 and increases by one per event.
+    pub seq: u64,
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub change: AlertChange,
+}
+
+fn rank(priority: &str) -> Option<usize> {
+    PRIORITIES.iter().position(|p| *p == priority)
+}
+
+/// Which sources and alerts a query asks for. Empty fields match everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AlertFilter {
+    /// A PV1-3 location or a leading part of one, e.g. `ICU` or `ICU^Room1`.
+    pub location: Option<String>,
+    /// The ID number of PID-3.
+    pub patient: Option<String>,
+    pub priorities: Vec<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl AlertFilter {
+    fn place(&self, location: &str, patient_id: &str) -> bool {
+        let at = |filter: &String| {
+            location == filter
+                || location
+                    .strip_prefix(filter.as_str())
+                    .is_some_and(|rest| rest.starts_with('^'))
+        };
+        self.location.as_ref().is_none_or(at)
+            && self.patient.as_ref().is_none_or(|p| p == patient_id)
+    }
+
+    /// Whether `time` falls in the time range.
+    pub fn at(&self, time: DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| time >= since) && self.until.is_none_or(|until| time <= until)
+    }
+
+    pub fn source(&self, source: &SourceRecord) -> bool {
+        self.place(&source.location, &source.patient_id)
+            && self.since.is_none_or(|since| source.last_seen >= since)
+            && self.until.is_none_or(|until| source.first_seen <= until)
+    }
+
+    /// An alert matches the time range if it was active at any time in it.
+    pub fn alert(&self, alert: &AlertRecord) -> bool {
+        self.place(&alert.location, &alert.patient_id)
+            && (self.priorities.is_empty() || self.priorities.contains(&alert.priority))
+            && self
+                .since
+                .is_none_or(|since| alert.ended.is_none_or(|end| end >= since))
+            && self.until.is_none_or(|until| alert.started <= until)
+    }
+}
+
+/// The sources and alerts a manager has seen, built from the messages its handlers processed,
+/// and the lifecycle events they went through.
+#[derive(Debug)]
+pub struct AlertStore {
+    sources: BTreeMap<String, SourceRecord>,
+    /// Keyed by source and alert ID.
+    active: BTreeMap<(String, String), AlertRecord>,
+    ended: VecDeque<AlertRecord>,
+    events: VecDeque<AlertEvent>,
+    next_seq: u64,
+    live: broadcast::Sender<AlertEvent>,
+}
+
+impl Default for AlertStore {
+    fn default() -> Self {
+        AlertStore::new()
+    }
+}
+
+impl AlertStore {
+    pub fn new() -> Self {
+        AlertStore {
+            sources: BTreeMap::new(),
+            active: BTreeMap::new(),
+            ended: VecDeque::new(),
+            events: VecDeque::new(),
+            next_seq: 1,
+            live: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    fn publish(&mut self, now: DateTime<Utc>, change: AlertChange) {
+        let event = AlertEvent {
+            seq: self.next_seq,
+            time: now,
+            change,
+        };
+        self.next_seq += 1;
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event.clone());
+        // Nobody listening is fine.
+        let _ = self.live.send(event);
+    }
+
+    /// Takes in a processed message and the ACK decided for it.
+    pub fn record(&mut self, ctx: &MessageContext, now: DateTime<Utc>) {
+        let source = self
+            .sources
+            .entry(ctx.source.clone())
+            .or_insert_with(|| SourceRecord {
+                source: ctx.source.clone(),
+                peer: String::new(),
+                connected: true,
+                location: String::new(),
+                patient_id: String::new(),
+                first_seen: now,
+                last_seen: now,
+                messages: 0,
+                last_heartbeat: None,
+                heartbeat_timeout_secs: DEFAULT_HEARTBEAT_TIMEOUT_SECS,
+                heartbeat_interval_ms: None,
+                heartbeat_jitter_ms: None,
+                lost: false,
+            });
+        source.peer = ctx.peer.clone();
+        source.connected = true;
+        source.location = ctx.location().to_string();
+        source.patient_id = ctx.patient_id().to_string();
+        source.last_seen = now;
+        source.messages += 1;
+        if ctx.kind == MessageKind::Heartbeat {
+            if let Some(last) = source.last_heartbeat {
+                let interval = (now - last).num_milliseconds().max(0) as u64;
+                source.heartbeat_jitter_ms = source
+                    .heartbeat_interval_ms
+                    .map(|previous| interval.abs_diff(previous));
+                source.heartbeat_interval_ms = Some(interval);
+            }
+            source.last_heartbeat = Some(now);
+            if let Some(secs) = ctx.confirm_timeout() {
+                source.heartbeat_timeout_secs = secs;
+            }
+        }
+        if std::mem::take(&mut source.lost) {
+            let source = source.source.clone();
+            self.publish(now, AlertChange::SourceRestored { source });
+        }
+
+        match ctx.kind {
+            MessageKind::Heartbeat => {}
+            MessageKind::Unknown => {}
+            _ => self.record_alert(ctx, now),
+        }
+    }
+
+    fn record_alert(&mut self, ctx: &MessageContext, now: DateTime<Utc>) {
+        let id = match ctx.alert_id() {
+            "" => ctx.alert_code().unwrap_or_default(),
+            id => id,
+        };
+        let key = (ctx.source.clone(), id.to_string());
+        let new = !self.active.contains_key(&key);
+        let alert = self
+            .active
+            .entry(key.clone())
+            .or_insert_with(|| AlertRecord {
+                id: id.to_string(),
+                source: ctx.source.clone(),
+                code: String::new(),
+                text: String::new(),
+                kind: String::new(),
+                priority: String::new(),
+                phase: String::new(),
+                location: String::new(),
+                patient_id: String::new(),
+                started: now,
+                updated: now,
+                ended: None,
+                message_count: 0,
+                messages: VecDeque::new(),
+            });
+        let from = std::mem::take(&mut alert.priority);
+        alert.code = ctx.alert_code().unwrap_or_default().to_string();
+        alert.text = ctx.unescape(ctx.alert_text());
+        alert.kind = ctx.alert_type().to_string();
+        alert.priority = ctx.priority().to_string();
+        alert.phase = ctx.event_phase().to_string();
+        alert.location = ctx.unescape(ctx.location());
+        alert.patient_id = ctx.unescape(ctx.patient_id());
+        alert.updated = now;
+        alert.message_count += 1;
+        if alert.messages.len() == MAX_ALERT_MESSAGES {
+            alert.messages.pop_front();
+        }
+        let message = AlertMessage {
+            received: now,
+            control_id: ctx.control_id().to_string(),
+            phase: alert.phase.clone(),
+            state: ctx.alarm_state().to_string(),
+            priority: alert.priority.clone(),
+            value: ctx.observation().map(|(value, unit)| {
+                format!("{} {}", ctx.unescape(value), component(unit, 1))
+                    .trim_end()
+                    .to_string()
+            }),
+            ack: ctx.ack.msa.msa_1_acknowledgment_code.clone(),
+        };
+        let messages = std::mem::take(&mut alert.messages);
+        let summary = alert.clone();
+        alert.messages = messages;
+        alert.messages.push_back(message.clone());
+
+        let change = if new {
+            AlertChange::New {
+                alert: summary.clone(),
+                message: message.clone(),
+            }
+        } else if rank(&alert.priority) > rank(&from) {
+            AlertChange::Escalated {
+                from,
+                alert: summary.clone(),
+                message: message.clone(),
+            }
+        } else if rank(&alert.priority) < rank(&from) {
+            AlertChange::Deescalated {
+                from,
+                alert: summary.clone(),
+                message: message.clone(),
+            }
+        } else {
+            AlertChange::Updated {
+                alert: summary.clone(),
+                message: message.clone(),
+            }
+        };
+        let ended = matches!(alert.phase.as_str(), "end" | "start_only");
+        // An end message is reported as the end only, unless it is the first news of the alert.
+        if new || !ended {
+            self.publish(now, change);
+        }
+
+        // A start_only alert has no end message; it is over as soon as it is reported.
+        if ended {
+            let mut alert = self.active.remove(&key).expect("alert was just recorded");
+            alert.ended = Some(now);
+            let summary = AlertRecord {
+                ended: alert.ended,
+                ..summary
+            };
+            self.publish(
+                now,
+                AlertChange::Ended {
+                    alert: summary,
+                    message,
+                },
+            );
+            if self.ended.len() == MAX_ENDED_ALERTS {
+                self.ended.pop_front();
+            }
+            self.ended.push_back(alert);
+        }
+    }
+
+    /// Marks the sources last heard from over `peer` as disconnected.
+    pub fn disconnected(&mut self, peer: &str, now: DateTime<Utc>) {
+        let mut lost = Vec::new();
+        for source in self.sources.values_mut().filter(|s| s.peer == peer) {
+            source.connected = false;
+            if !std::mem::replace(&mut source.lost, true) {
+                lost.push(source.source.clone());
+            }
+        }
+        for source in lost {
+            let reason = "disconnected".to_string();
+            self.publish(now, AlertChange::SourceLost { source, reason });
+        }
+    }
+
+    /// Reports sources whose heartbeat became overdue since the last check as lost.
+    pub fn check_heartbeats(&mut self, now: DateTime<Utc>) {
+        let mut lost = Vec::new();
+        for source in self.sources.values_mut() {
+            if !source.lost && source.heartbeat_status(now) == HeartbeatStatus::Lost {
+                source.lost = true;
+                lost.push(source.source.clone());
+            }
+        }
+        for source in lost {
+            let reason = "heartbeat overdue".to_string();
+            self.publish(now, AlertChange::SourceLost { source, reason });
+        }
+    }
+
+    /// The kept events after sequence number `seq`, and a receiver for the ones that follow
+    /// them. Events older than the last [`MAX_EVENTS`] are gone; the first one's `seq` shows the
+    /// gap.
+    pub fn events_after(&self, seq: u64) -> (Vec<AlertEvent>, broadcast::Receiver<AlertEvent>) {
+        let kept = self
+            .events
+            .iter()
+            .filter(|e| e.seq > seq)
+            .cloned()
+            .collect();
+        (kept, self.live.subscribe())
+    }
+
+    /// Sequence number of the latest event, 0 before the first.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    pub fn sources(&self) -> impl Iterator<Item = &SourceRecord> {
+        self.sources.values()
+    }
+
+    /// A source by its EUI-64.
+    pub fn source(&self, source: &str) -> Option<&SourceRecord> {
+        self.sources.get(source)
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = &AlertRecord> {
+        self.active.values()
+    }
+
+    /// The most recently ended alerts, oldest first.
+    pub fn ended(&self) -> impl Iterator<Item = &AlertRecord> {
+        self.ended.iter()
+    }
+
+    /// An active or ended alert by its ID.
+    pub fn alert(&self, id: &str) -> Option<&AlertRecord> {
+        self.active()
+            .chain(self.ended.iter().rev())
+            .find(|alert| alert.id == id)
+    }
+
+    /// Active alerts of each priority.
+    pub fn active_by_priority(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for alert in self.active.values() {
+            *counts.entry(alert.priority.clone()).or_default() += 1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{ActiveAlert, AlertSpec, DeviceConfig};
+    use crate::messages::{Ack, Message};
+
+    fn context(phase: &str, state: &str, priority: &str) -> MessageContext {
+        let alert = ActiveAlert {
+            id: "alert-1".to_string(),
+            spec: AlertSpec {
+                code: "196670^MDC_EVT_LO^MDC".to_string(),
+                text: "SpO2 low".to_string(),
+                priority: priority.to_string(),
+                kind: "SP".to_string(),
+                observation: None,
+            },
+            update: 0,
+        };
+        let msg = DeviceConfig::default().alert_message(&alert, phase, state);
+        let Ok(Message::Oru(oru)) = msg.encode().parse::<Message>() else {
+            panic!("not an ORU");
+        };
+        MessageContext {
+            peer: "mem:test#1".to_string(),
+            source: "0000000000000001".to_string(),
+            kind: MessageKind::of(&oru),
+            oru,
+            ack: Ack::default(),
+            routes: Vec::new(),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    /// A store that has seen an alert start, escalate and end.
+    fn store() -> AlertStore {
+        let mut store = AlertStore::new();
+        let now = Utc::now();
+        store.record(&context("start", "active", "PM"), now);
+        store.record(&context("update", "active", "PH"), now);
+        store.record(&context("end", "inactive", "PH"), now);
+        store
+    }
+
+    fn seqs(events: &[AlertEvent]) -> Vec<u64> {
+        events.iter().map(|e| e.seq).collect()
+    }
+
+    #[test]
+    fn events_are_numbered_in_order() {
+        let store = store();
+        let (events, _) = store.events_after(0);
+        assert_eq!(seqs(&events), vec![1, 2, 3]);
+        assert!(matches!(events[0].change, AlertChange::New { .. }));
+        assert!(matches!(
+            &events[1].change,
+            AlertChange::Escalated { from, .. } if from == "PM"
+        ));
+        assert!(matches!(events[2].change, AlertChange::Ended { .. }));
+        assert_eq!(store.last_seq(), 3);
+    }
+
+    #[test]
+    fn replay_resumes_after_the_last_seen_event() {
+        let mut store = store();
+        let (events, _) = store.events_after(1);
+        assert_eq!(seqs(&events), vec![2, 3]);
+
+        let (events, mut live) = store.events_after(store.last_seq());
+        assert!(events.is_empty());
+        store.disconnected("mem:test#1", Utc::now());
+        let event = live.try_recv().unwrap();
+        assert_eq!(event.seq, 4);
+        assert!(matches!(event.change, AlertChange::SourceLost { .. }));
+    }
+
+    #[test]
+    fn replay_starts_at_the_oldest_kept_event() {
+        let mut store = AlertStore::new();
+        let now = Utc::now();
+        let (start, end) = (
+            context("start", "active", "PM"),
+            context("end", "inactive", "PM"),
+        );
+        for _ in 0..MAX_EVENTS / 2 + 1 {
+            store.record(&start, now);
+            store.record(&end, now);
+        }
+        let (events, _) = store.events_after(0);
+        assert_eq!(events.len(), MAX_EVENTS);
+        // The first two events were dropped; the gap shows in the first sequence number.
+        assert_eq!(events[0].seq, 3);
+    }
+}
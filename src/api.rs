@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+
+use crate::alert_store::{
+    AlertEvent, AlertFilter, AlertMessage, AlertRecord, HeartbeatStatus, SourceRecord,
+};
+use crate::http::{Handler, Request, Response};
+use crate::mock_alert_mgr::{ManagerHandle, ManagerStats};
+
+/// What `GET /` lists.
+const ENDPOINTS: [&str; 6] = [
+    "/sources",
+    "/alerts?state=active|ended|all",
+    "/alerts/{id}",
+    "/alerts/{id}/messages",
+    "/stats",
+    "/events?after=SEQ",
+];
+
+/// How often an idle event stream sends a comment, which also notices clients that went away.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Serialize)]
+struct SourceView<'a> {
+    #[serde(flatten)]
+    source: &'a SourceRecord,
+    heartbeat: HeartbeatStatus,
+    active_alerts: usize,
+}
+
+#[derive(Serialize)]
+struct AlertDetail<'a> {
+    #[serde(flatten)]
+    alert: &'a AlertRecord,
+    messages: Vec<&'a AlertMessage>,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    manager: ManagerStats,
+    sources: usize,
+    connected_sources: usize,
+    heartbeats_lost: usize,
+    active_alerts: usize,
+    active_by_priority: BTreeMap<String, usize>,
+    ended_alerts: usize,
+}
+
+fn time(request: &Request, name: &str) -> Result<Option<DateTime<Utc>>, Response> {
+    request
+        .param(name)
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|e| Response::error(400, format!("{}: {} ({})", name, e, value)))
+        })
+        .transpose()
+}
+
+/// Reads `location`, `patient`, `priority` (comma-separated) and the RFC 3339 `since` and
+/// `until` parameters.
+fn filter(request: &Request) -> Result<AlertFilter, Response> {
+    Ok(AlertFilter {
+        location: request.param("location").map(str::to_string),
+        patient: request.param("patient").map(str::to_string),
+        priorities: request
+            .param("priority")
+            .map(|p| p.split(',').map(str::to_string).collect())
+            .unwrap_or_default(),
+        since: time(request, "since")?,
+        until: time(request, "until")?,
+    })
+}
+
+fn sources(manager: &ManagerHandle, filter: &AlertFilter) -> Response {
+    let now = Utc::now();
+    manager.alerts(|store| {
+        let sources: Vec<_> = store
+            .sources()
+            .filter(|source| filter.source(source))
+            .map(|source| SourceView {
+                source,
+                heartbeat: source.heartbeat_status(now),
+                active_alerts: store
+                    .active()
+                    .filter(|alert| alert.source == source.source)
+                    .count(),
+            })
+            .collect();
+        Response::json(&sources)
+    })
+}
+
+fn alerts(manager: &ManagerHandle, filter: &AlertFilter, state: &str) -> Response {
+    manager.alerts(|store| {
+        let alerts: Vec<&AlertRecord> = match state {
+            "active" => store.active().collect(),
+            "ended" => store.ended().collect(),
+            "all" => store.active().chain(store.ended()).collect(),
+            _ => return Response::error(400, format!("unknown state {}", state)),
+        };
+        let alerts: Vec<_> = alerts.into_iter().filter(|a| filter.alert(a)).collect();
+        Response::json(&alerts)
+    })
+}
+
+fn alert(manager: &ManagerHandle, id: &str, filter: &AlertFilter, log_only: bool) -> Response {
+    manager.alerts(|store| {
+        let Some(alert) = store.alert(id) else {
+            return Response::error(404, format!("no alert {}", id));
+        };
+        let messages: Vec<_> = alert
+            .messages
+            .iter()
+            .filter(|m| filter.at(m.received))
+            .collect();
+        if log_only {
+            Response::json(&messages)
+        } else {
+            Response::json(&AlertDetail { alert, messages })
+        }
+    })
+}
+
+fn stats(manager: &ManagerHandle) -> Response {
+    let now = Utc::now();
+    let stats = manager.alerts(|store| Stats {
+        manager: manager.stats(),
+        sources: store.sources().count(),
+        connected_sources: store.sources().filter(|s| s.connected).count(),
+        heartbeats_lost: store
+            .sources()
+            .filter(|s| s.heartbeat_status(now) == HeartbeatStatus::Lost)
+            .count(),
+        active_alerts: store.active().count(),
+        active_by_priority: store.active_by_priority(),
+        ended_alerts: store.ended().count(),
+    });
+    Response::json(&stats)
+}
+
+/// One Server-Sent Event: the sequence number as its ID and the event as JSON data.
+fn sse(event: &AlertEvent) -> Vec<u8> {
+    let data = serde_json::to_string(event).expect("events serialize");
+    format!("id: {}\ndata: {}\n\n", event.seq, data).into_bytes()
+}
+
+/// Lifecycle events as Server-Sent Events. `after=SEQ` (or the `Last-Event-ID` header an
+/// EventSource sends when it reconnects) first replays the kept events after SEQ; without it
+/// the stream starts with the next event. A client that falls too far behind is disconnected and
+/// can resume from the last ID it saw.
+fn events(manager: &ManagerHandle, request: &Request) -> Result<Response, Response> {
+    let after = request
+        .param("after")
+        .or(request.header("last-event-id"))
+        .map(|seq| {
+            seq.parse::<u64>().map_err(|_| {
+                Response::error(400, format!("after: not a sequence number ({})", seq))
+            })
+        })
+        .transpose()?;
+    let (backlog, live) = manager.alert_events(after);
+    let keepalive = tokio::time::interval_at(Instant::now() + KEEPALIVE, KEEPALIVE);
+    let replay = stream::iter(backlog).map(|event| sse(&event));
+    let live = stream::unfold((live, keepalive), |(mut live, mut keepalive)| async move {
+        let chunk = tokio::select! {
+            event = live.recv() => match event {
+                Ok(event) => sse(&event),
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => return None,
+            },
+            _ = keepalive.tick() => b":\n\n".to_vec(),
+        };
+        Some((chunk, (live, keepalive)))
+    });
+    Ok(Response::stream(
+        "text/event-stream",
+        replay.chain(live).boxed(),
+    ))
+}
+
+fn route(manager: &ManagerHandle, request: &Request) -> Result<Response, Response> {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    Ok(match segments[..] {
+        [""] => Response::json(&ENDPOINTS),
+        ["sources"] => sources(manager, &filter(request)?),
+        ["alerts"] => alerts(
+            manager,
+            &filter(request)?,
+            request.param("state").unwrap_or("active"),
+        ),
+        ["alerts", id] => alert(manager, id, &filter(request)?, false),
+        ["alerts", id, "messages"] => alert(manager, id, &filter(request)?, true),
+        ["stats"] => stats(manager),
+        ["events"] => events(manager, request)?,
+        _ => Response::not_found(),
+    })
+}
+
+/// The manager's query API: connected sources with their heartbeat status, active and ended
+/// alerts with their message logs, and statistics, as JSON. The lists take `location`,
+/// `patient`, `priority` and `since`/`until` filters. `/events` pushes alert lifecycle events.
+pub fn routes(manager: ManagerHandle) -> Handler {
+    Arc::new(move |request| route(&manager, request).unwrap_or_else(|error| error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use crate::connection::ManagerConnection;
+    use crate::device::{ActiveAlert, AlertSpec, DeviceConfig};
+    use crate::mock_alert_mgr::{AlertManager, AlertMgrConfig};
+
+    /// Reads the event stream until `until` shows up.
+    async fn read_events(stream: &mut TcpStream, until: &str) -> String {
+        let mut text = String::new();
+        let mut buf = [0; 4096];
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !text.contains(until) {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "stream closed: {}", text);
+                text.push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+        })
+        .await
+        .unwrap();
+        text
+    }
+
+    #[tokio::test]
+    async fn event_stream_resumes_from_last_event_id() {
+        let mut manager = AlertManager::new(AlertMgrConfig {
+            listen_address: "mem:sse-test".to_string(),
+            api_address: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        });
+        manager.start().await.unwrap();
+
+        let device = DeviceConfig::default();
+        let mut alert = ActiveAlert {
+            id: "alert-1".to_string(),
+            spec: AlertSpec {
+                code: "196670^MDC_EVT_LO^MDC".to_string(),
+                text: "SpO2 low".to_string(),
+                priority: "PM".to_string(),
+                kind: "SP".to_string(),
+                observation: None,
+            },
+            update: 0,
+        };
+        let mut connection = ManagerConnection::connect("mem:sse-test", Duration::from_secs(5))
+            .await
+            .unwrap();
+        for phase in ["start", "update", "end"] {
+            let state = if phase == "end" { "inactive" } else { "active" };
+            let msg = device.alert_message(&alert, phase, state);
+            connection.exchange(&msg).await.unwrap();
+            alert.update += 1;
+        }
+
+        let mut stream = TcpStream::connect(manager.api_address().unwrap())
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nHost: test\r\nLast-Event-ID: 1\r\n\r\n")
+            .await
+            .unwrap();
+        let text = read_events(&mut stream, "id: 3\n").await;
+        assert!(text.contains("text/event-stream"));
+        assert!(!text.contains("id: 1\n"));
+        assert!(text.find("id: 2\n") < text.find("id: 3\n"));
+        assert!(text.contains(r#""event":"ended""#));
+
+        // Events after the replay arrive live on the same stream.
+        connection.close().await;
+        let text = read_events(&mut stream, "id: 4\n").await;
+        assert!(text.contains(r#""event":"source_lost""#));
+
+        manager.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,748 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::error;
+
+use crate::connection::{Backoff, ReconnectPolicy};
+
+/// MSGID of RFC 5424 syslog messages carrying a DICOM audit message (IHE ITI TF-2a 3.20).
+const SYSLOG_MSGID: &str = "IHE+RFC-3881";
+/// Facility 10 (security/authorization), as ATNA recommends.
+const SYSLOG_FACILITY: u8 = 10;
+/// Events waiting for the audit thread; beyond this new ones are dropped.
+const MAX_BACKLOG: usize = 10_000;
+/// Events kept for a TCP collector while it is unreachable.
+const MAX_PENDING: usize = 10_000;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often undelivered events are retried when nothing new is recorded.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The message exchange events an Audit Record Repository is told about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    MessageSent,
+    MessageReceived,
+    MessageAcknowledged,
+    MessageRejected,
+    NodeAuthenticationFailure,
+}
+
+impl AuditEventKind {
+    /// DICOM EventID (PS3.15 A.5.3) as code, display name and event action code.
+    fn event_id(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            AuditEventKind::MessageSent | AuditEventKind::MessageAcknowledged => {
+                ("110106", "Export", "R")
+            }
+            AuditEventKind::MessageReceived | AuditEventKind::MessageRejected => {
+                ("110107", "Import", "C")
+            }
+            AuditEventKind::NodeAuthenticationFailure => ("110113", "Security Alert", "E"),
+        }
+    }
+
+    /// EventOutcomeIndicator: 0 success, 4 minor failure, 8 serious failure.
+    pub fn outcome(self) -> u8 {
+        match self {
+            AuditEventKind::MessageRejected => 4,
+            AuditEventKind::NodeAuthenticationFailure => 8,
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for AuditEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AuditEventKind::MessageSent => "message_sent",
+            AuditEventKind::MessageReceived => "message_received",
+            AuditEventKind::MessageAcknowledged => "message_acknowledged",
+            AuditEventKind::MessageRejected => "message_rejected",
+            AuditEventKind::NodeAuthenticationFailure => "node_authentication_failure",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One audited event between two nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub time: String,
+    pub event: AuditEventKind,
+    pub outcome: u8,
+    /// The actor that recorded the event, `alert_reporter` or `alert_manager`.
+    pub actor: String,
+    /// The node that sent the message.
+    pub source: String,
+    /// The node the message was sent to.
+    pub destination: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ack_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(event: AuditEventKind, source: &str, destination: &str) -> Self {
+        AuditEvent {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            event,
+            outcome: event.outcome(),
+            actor: String::new(),
+            source: source.to_string(),
+            destination: destination.to_string(),
+            control_id: None,
+            message_type: None,
+            ack_code: None,
+            detail: None,
+        }
+    }
+
+    pub fn message(mut self, control_id: &str, message_type: &str) -> Self {
+        self.control_id = Some(control_id.to_string());
+        self.message_type = Some(message_type.to_string());
+        self
+    }
+
+    pub fn ack_code(mut self, code: &str) -> Self {
+        self.ack_code = Some(code.to_string());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// The event as a DICOM AuditMessage (PS3.15 A.5), the payload ATNA expects over syslog.
+    pub fn to_xml(&self) -> String {
+        let (code, name, action) = self.event.event_id();
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><AuditMessage>\
+             <EventIdentification EventActionCode=\"{}\" EventDateTime=\"{}\" \
+             EventOutcomeIndicator=\"{}\"><EventID csd-code=\"{}\" codeSystemName=\"DCM\" \
+             originalText=\"{}\"/>",
+            action, self.time, self.outcome, code, name
+        );
+        if self.event == AuditEventKind::NodeAuthenticationFailure {
+            xml.push_str(
+                "<EventTypeCode csd-code=\"110126\" codeSystemName=\"DCM\" \
+                 originalText=\"Node Authentication\"/>",
+            );
+        }
+        if let Some(detail) = &self.detail {
+            xml.push_str(&format!(
+                "<EventOutcomeDescription>{}</EventOutcomeDescription>",
+                escape(detail)
+            ));
+        }
+        xml.push_str("</EventIdentification>");
+
+        for (node, role, role_name, requestor) in [
+            (&self.source, "110153", "Source Role ID", true),
+            (&self.destination, "110152", "Destination Role ID", false),
+        ] {
+            let address = node_address(node);
+            // 2 is an IP address, 1 a machine name.
+            let address_type = if address.parse::<IpAddr>().is_ok() {
+                2
+            } else {
+                1
+            };
+            xml.push_str(&format!(
+                "<ActiveParticipant UserID=\"{}\" UserIsRequestor=\"{}\" \
+                 NetworkAccessPointID=\"{}\" NetworkAccessPointTypeCode=\"{}\">\
+                 <RoleIDCode csd-code=\"{}\" codeSystemName=\"DCM\" originalText=\"{}\"/>\
+                 </ActiveParticipant>",
+                escape(node),
+                requestor,
+                escape(address),
+                address_type,
+                role,
+                role_name
+            ));
+        }
+        xml.push_str(&format!(
+            "<AuditSourceIdentification AuditSourceID=\"{}\"/>",
+            escape(&self.actor)
+        ));
+
+        if let Some(control_id) = &self.control_id {
+            xml.push_str(&format!(
+                "<ParticipantObjectIdentification ParticipantObjectID=\"{}\" \
+                 ParticipantObjectTypeCode=\"2\" ParticipantObjectTypeCodeRole=\"24\">\
+                 <ParticipantObjectIDTypeCode csd-code=\"MSH-10\" codeSystemName=\"HL7v2\" \
+                 originalText=\"Message Control ID\"/>",
+                escape(control_id)
+            ));
+            if let Some(message_type) = &self.message_type {
+                xml.push_str(&format!(
+                    "<ParticipantObjectDetail type=\"MSH-9\" value=\"{}\"/>",
+                    base64(message_type.as_bytes())
+                ));
+            }
+            if let Some(ack_code) = &self.ack_code {
+                xml.push_str(&format!(
+                    "<ParticipantObjectDetail type=\"MSA-1\" value=\"{}\"/>",
+                    base64(ack_code.as_bytes())
+                ));
+            }
+            xml.push_str("</ParticipantObjectIdentification>");
+        }
+        xml.push_str("</AuditMessage>");
+        xml
+    }
+}
+
+/// The host part of `host:port`, or the whole node description.
+fn node_address(node: &str) -> &str {
+    node.rsplit_once(':')
+        .map_or(node, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+}
+
+/// Standard base64 with padding, as `ParticipantObjectDetail@value` (xs:base64Binary) needs.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | ((b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyslogProtocol {
+    Udp,
+    Tcp,
+}
+
+/// Where audit events go: `udp://host:port` or `tcp://host:port` for a syslog collector, anything
+/// else is a file that gets one JSON event per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditSink {
+    File(PathBuf),
+    Syslog {
+        protocol: SyslogProtocol,
+        address: String,
+    },
+}
+
+impl FromStr for AuditSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let syslog = |protocol, address: &str| {
+            if address.is_empty() {
+                return Err(format!("missing collector address in {:?}", s));
+            }
+            Ok(AuditSink::Syslog {
+                protocol,
+                address: address.to_string(),
+            })
+        };
+        if let Some(address) = s.strip_prefix("udp://") {
+            syslog(SyslogProtocol::Udp, address)
+        } else if let Some(address) = s.strip_prefix("tcp://") {
+            syslog(SyslogProtocol::Tcp, address)
+        } else if s.is_empty() {
+            Err("empty audit destination".to_string())
+        } else {
+            Ok(AuditSink::File(PathBuf::from(s)))
+        }
+    }
+}
+
+/// Syslog over TCP. Events wait while the collector is unreachable and go out once a reconnect,
+/// spaced by a backoff, succeeds.
+struct TcpWriter {
+    address: String,
+    stream: Option<TcpStream>,
+    /// Octet-counted frames not yet written.
+    pending: VecDeque<String>,
+    backoff: Backoff,
+    retry_at: Instant,
+}
+
+impl TcpWriter {
+    fn new(address: &str) -> Self {
+        TcpWriter {
+            address: address.to_string(),
+            stream: None,
+            pending: VecDeque::new(),
+            backoff: Backoff::new(ReconnectPolicy::default()),
+            retry_at: Instant::now(),
+        }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "collector address did not resolve")
+        }))
+    }
+
+    fn queue(&mut self, message: &str) {
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+            error!(collector = %self.address, "Audit collector unreachable, dropping the oldest event");
+        }
+        // RFC 6587 octet counting.
+        self.pending.push_back(octet_counted(message));
+    }
+
+    /// Writes the pending frames, reconnecting first if the backoff allows.
+    fn send_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if self.stream.is_none() {
+            if Instant::now() < self.retry_at {
+                return Ok(());
+            }
+            match self.connect() {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.backoff.reset();
+                }
+                Err(e) => return Err(self.failed(e)),
+            }
+        }
+        while let Some(frame) = self.pending.front() {
+            let written = self.stream.as_mut().unwrap().write_all(frame.as_bytes());
+            if let Err(e) = written {
+                return Err(self.failed(e));
+            }
+            self.pending.pop_front();
+        }
+        Ok(())
+    }
+
+    fn failed(&mut self, error: io::Error) -> io::Error {
+        self.stream = None;
+        self.retry_at = Instant::now() + self.backoff.next_delay();
+        error
+    }
+}
+
+enum Writer {
+    File(File),
+    Udp(UdpSocket, String),
+    Tcp(TcpWriter),
+}
+
+impl Writer {
+    fn open(sink: &AuditSink) -> io::Result<Self> {
+        Ok(match sink {
+            AuditSink::File(path) => {
+                Writer::File(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+            AuditSink::Syslog {
+                protocol: SyslogProtocol::Udp,
+                address,
+            } => Writer::Udp(UdpSocket::bind("0.0.0.0:0")?, address.clone()),
+            AuditSink::Syslog {
+                protocol: SyslogProtocol::Tcp,
+                address,
+            } => Writer::Tcp(TcpWriter::new(address)),
+        })
+    }
+
+    fn write(&mut self, event: &AuditEvent, hostname: &str) -> io::Result<()> {
+        match self {
+            Writer::File(file) => {
+                let mut line = serde_json::to_string(event).map_err(io::Error::other)?;
+                line.push('\n');
+                file.write_all(line.as_bytes())
+            }
+            Writer::Udp(socket, address) => {
+                socket.send_to(syslog_message(event, hostname).as_bytes(), address.as_str())?;
+                Ok(())
+            }
+            Writer::Tcp(tcp) => {
+                tcp.queue(&syslog_message(event, hostname));
+                tcp.send_pending()
+            }
+        }
+    }
+
+    /// Retries whatever could not be delivered earlier.
+    fn retry(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Tcp(tcp) => tcp.send_pending(),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::File(file) => file.sync_data(),
+            Writer::Udp(..) => Ok(()),
+            Writer::Tcp(tcp) => {
+                tcp.send_pending()?;
+                if !tcp.pending.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        format!(
+                            "{} events not delivered to {}",
+                            tcp.pending.len(),
+                            tcp.address
+                        ),
+                    ));
+                }
+                tcp.stream.as_mut().map_or(Ok(()), |s| s.flush())
+            }
+        }
+    }
+}
+
+/// An RFC 5424 message with the DICOM audit XML as its payload.
+fn syslog_message(event: &AuditEvent, hostname: &str) -> String {
+    // Notice for successful exchanges, warning for failures.
+    let severity = if event.outcome == 0 { 5 } else { 4 };
+    format!(
+        "<{}>1 {} {} {} {} {} - \u{feff}{}",
+        SYSLOG_FACILITY * 8 + severity,
+        event.time,
+        hostname,
+        event.actor,
+        std::process::id(),
+        SYSLOG_MSGID,
+        event.to_xml()
+    )
+}
+
+/// Frames a syslog message for TCP with its length in octets (RFC 6587 3.4.1).
+fn octet_counted(message: &str) -> String {
+    format!("{} {}", message.len(), message)
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+enum Command {
+    Record(Box<AuditEvent>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Runs on the audit thread: writes events as they arrive and retries undelivered ones.
+fn write_events(mut writers: Vec<Writer>, hostname: String, commands: Receiver<Command>) {
+    loop {
+        match commands.recv_timeout(RETRY_INTERVAL) {
+            Ok(Command::Record(event)) => {
+                for writer in writers.iter_mut() {
+                    if let Err(e) = writer.write(&event, &hostname) {
+                        error!(event = %event.event, "Error writing audit event: {}", e);
+                    }
+                }
+            }
+            Ok(Command::Flush(done)) => {
+                for writer in writers.iter_mut() {
+                    if let Err(e) = writer.flush() {
+                        error!("Error flushing audit trail: {}", e);
+                    }
+                }
+                let _ = done.send(());
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                for writer in writers.iter_mut() {
+                    if let Err(e) = writer.retry() {
+                        error!("Error writing audit event: {}", e);
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                for writer in writers.iter_mut() {
+                    if let Err(e) = writer.flush() {
+                        error!("Error flushing audit trail: {}", e);
+                    }
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// The audit trail of one actor. With no sinks it records nothing. Events are written on a
+/// thread of their own, so a slow file system or an unreachable collector never holds up the
+/// exchange being audited.
+pub struct AuditLog {
+    actor: String,
+    sinks: usize,
+    commands: Option<SyncSender<Command>>,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("actor", &self.actor)
+            .field("sinks", &self.sinks)
+            .finish()
+    }
+}
+
+impl AuditLog {
+    /// Opens the files and sockets of `sinks`; TCP collectors are connected on first use.
+    pub fn open(actor: &str, sinks: &[AuditSink]) -> io::Result<Self> {
+        if sinks.is_empty() {
+            return Ok(AuditLog::disabled());
+        }
+        let writers = sinks.iter().map(Writer::open).collect::<io::Result<_>>()?;
+        let (commands, received) = mpsc::sync_channel(MAX_BACKLOG);
+        let hostname = hostname();
+        thread::Builder::new()
+            .name("audit".to_string())
+            .spawn(move || write_events(writers, hostname, received))?;
+        Ok(AuditLog {
+            actor: actor.to_string(),
+            sinks: sinks.len(),
+            commands: Some(commands),
+        })
+    }
+
+    pub fn disabled() -> Self {
+        AuditLog {
+            actor: String::new(),
+            sinks: 0,
+            commands: None,
+        }
+    }
+
+    pub fn record(&self, mut event: AuditEvent) {
+        let Some(commands) = &self.commands else {
+            return;
+        };
+        event.actor = self.actor.clone();
+        if let Err(TrySendError::Full(Command::Record(event))) =
+            commands.try_send(Command::Record(Box::new(event)))
+        {
+            error!(event = %event.event, "Audit trail is backed up, dropping event");
+        }
+    }
+
+    /// Waits until every recorded event has reached its destination, e.g. before the actor exits.
+    pub async fn flush(&self) {
+        let Some(commands) = &self.commands else {
+            return;
+        };
+        let (done, flushed) = oneshot::channel();
+        if commands.try_send(Command::Flush(done)).is_err() {
+            error!("Audit trail is backed up, not flushing");
+            return;
+        }
+        if tokio::time::timeout(FLUSH_TIMEOUT, flushed).await.is_err() {
+            error!("Audit trail not flushed within {:?}", FLUSH_TIMEOUT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn rejected() -> AuditEvent {
+        let mut event = AuditEvent::new(
+            AuditEventKind::MessageRejected,
+            "10.0.0.7:40112",
+            "manager.local:8888",
+        )
+        .message("ctl<1>", "ORU^R40")
+        .ack_code("AE")
+        .detail("priority \"PX\" & more");
+        event.actor = "alert_manager".to_string();
+        event
+    }
+
+    /// Splits RFC 6587 octet-counted frames.
+    fn frames(mut data: &str) -> Vec<&str> {
+        let mut frames = Vec::new();
+        while let Some((len, rest)) = data.split_once(' ') {
+            let len: usize = len.parse().unwrap();
+            frames.push(&rest[..len]);
+            data = &rest[len..];
+        }
+        frames
+    }
+
+    #[test]
+    fn base64_encoding() {
+        let cases = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v")];
+        for (text, encoded) in cases {
+            assert_eq!(base64(text.as_bytes()), encoded);
+        }
+        assert_eq!(base64(b"ORU^R40"), "T1JVXlI0MA==");
+    }
+
+    #[test]
+    fn audit_message_xml() {
+        let xml = rejected().to_xml();
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?><AuditMessage>"));
+        assert!(xml.contains("EventActionCode=\"C\""));
+        assert!(xml.contains("EventOutcomeIndicator=\"4\""));
+        assert!(xml.contains("<EventID csd-code=\"110107\""));
+        assert!(xml.contains(
+            "<EventOutcomeDescription>priority &quot;PX&quot; &amp; more</EventOutcomeDescription>"
+        ));
+        assert!(xml.contains(
+            "UserID=\"10.0.0.7:40112\" UserIsRequestor=\"true\" \
+             NetworkAccessPointID=\"10.0.0.7\" NetworkAccessPointTypeCode=\"2\""
+        ));
+        assert!(
+            xml.contains("NetworkAccessPointID=\"manager.local\" NetworkAccessPointTypeCode=\"1\"")
+        );
+        assert!(xml.contains("<AuditSourceIdentification AuditSourceID=\"alert_manager\"/>"));
+        assert!(xml.contains("ParticipantObjectID=\"ctl&lt;1&gt;\""));
+        assert!(xml.contains("<ParticipantObjectDetail type=\"MSH-9\" value=\"T1JVXlI0MA==\"/>"));
+        assert!(xml.contains("<ParticipantObjectDetail type=\"MSA-1\" value=\"QUU=\"/>"));
+        assert!(xml.ends_with("</ParticipantObjectIdentification></AuditMessage>"));
+
+        let failure = AuditEvent::new(
+            AuditEventKind::NodeAuthenticationFailure,
+            "10.0.0.7:40112",
+            "manager.local:8888",
+        );
+        let xml = failure.to_xml();
+        assert!(xml.contains("EventOutcomeIndicator=\"8\""));
+        assert!(xml.contains("<EventTypeCode csd-code=\"110126\""));
+        assert!(!xml.contains("ParticipantObjectIdentification"));
+    }
+
+    #[test]
+    fn syslog_framing() {
+        let event = rejected();
+        let message = syslog_message(&event, "ward-3");
+        let header = format!(
+            "<84>1 {} ward-3 alert_manager {} IHE+RFC-3881 - \u{feff}<?xml",
+            event.time,
+            std::process::id()
+        );
+        assert!(message.starts_with(&header), "{}", message);
+
+        let mut accepted = rejected();
+        accepted.outcome = 0;
+        assert!(syslog_message(&accepted, "ward-3").starts_with("<85>1 "));
+
+        // The count is in octets: the byte order mark alone is three.
+        let framed = octet_counted(&message);
+        assert_eq!(framed, format!("{} {}", message.len(), message));
+        assert_eq!(frames(&framed), [message.as_str()]);
+    }
+
+    #[test]
+    fn sink_parsing() {
+        assert_eq!(
+            "udp://collector:514".parse(),
+            Ok(AuditSink::Syslog {
+                protocol: SyslogProtocol::Udp,
+                address: "collector:514".to_string()
+            })
+        );
+        assert_eq!(
+            "tcp://10.1.1.1:6514".parse(),
+            Ok(AuditSink::Syslog {
+                protocol: SyslogProtocol::Tcp,
+                address: "10.1.1.1:6514".to_string()
+            })
+        );
+        assert_eq!(
+            "audit.jsonl".parse(),
+            Ok(AuditSink::File(PathBuf::from("audit.jsonl")))
+        );
+        assert!("tcp://".parse::<AuditSink>().is_err());
+        assert!("".parse::<AuditSink>().is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_collector_gets_events_after_it_comes_up() {
+        // Find a free port, then leave it closed for the first event.
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let audit = AuditLog::open(
+            "alert_reporter",
+            &[AuditSink::Syslog {
+                protocol: SyslogProtocol::Tcp,
+                address: address.clone(),
+            }],
+        )
+        .unwrap();
+
+        let started = Instant::now();
+        audit.record(rejected().ack_code("AR"));
+        assert!(started.elapsed() < Duration::from_millis(100));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let listener = TcpListener::bind(&address).unwrap();
+        audit.record(rejected());
+        // Past the first backoff delay the retry delivers both, in order.
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        audit.flush().await;
+        drop(audit);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut data = String::new();
+        stream.read_to_string(&mut data).unwrap();
+        let frames = frames(&data);
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].contains("value=\"QVI=\""));
+        assert!(frames[1].contains("value=\"QUU=\""));
+        assert!(frames[1].contains("AuditSourceID=\"alert_reporter\""));
+    }
+}
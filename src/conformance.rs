@@ -0,0 +1,284 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::encoding::Delimiters;
+use crate::segments::ERR;
+use crate::validate::{Finding, Severity};
+
+/// How many failing messages are listed individually in a source's report.
+const MAX_LISTED_FAILURES: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct ConformanceConfig {
+    pub report_dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+struct RuleSummary {
+    severity: Severity,
+    occurrences: usize,
+    first_location: String,
+    example: String,
+}
+
+#[derive(Debug, Clone)]
+struct FailedMessage {
+    control_id: String,
+    received: DateTime<Utc>,
+    errors: usize,
+    warnings: usize,
+}
+
+#[derive(Debug, Clone)]
+struct SourceSummary {
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    messages: usize,
+    rejected: usize,
+    rules: BTreeMap<&'static str, RuleSummary>,
+    failures: Vec<FailedMessage>,
+}
+
+/// Collects validation results per message source over one manager session.
+#[derive(Debug, Default)]
+pub struct ConformanceTracker {
+    sources: HashMap<String, SourceSummary>,
+    /// Sources with messages recorded since their report was last written.
+    changed: BTreeSet<String>,
+}
+
+impl ConformanceTracker {
+    pub fn record(&mut self, source: &str, control_id: &str, findings: &[Finding]) {
+        let now = Utc::now();
+        let summary = self
+            .sources
+            .entry(source.to_string())
+            .or_insert_with(|| SourceSummary {
+                first_seen: now,
+                last_seen: now,
+                messages: 0,
+                rejected: 0,
+                rules: BTreeMap::new(),
+                failures: Vec::new(),
+            });
+
+        summary.last_seen = now;
+        summary.messages += 1;
+        self.changed.insert(source.to_string());
+        if findings.is_empty() {
+            return;
+        }
+
+        let errors = count(findings, Severity::Error);
+        if errors > 0 {
+            summary.rejected += 1;
+        }
+        for finding in findings {
+            let rule = summary
+                .rules
+                .entry(finding.rule)
+                .or_insert_with(|| RuleSummary {
+                    severity: finding.severity,
+                    occurrences: 0,
+                    first_location: finding.location.to_string(),
+                    example: finding.message.clone(),
+                });
+            rule.severity = rule.severity.min(finding.severity);
+            rule.occurrences += 1;
+        }
+        if summary.failures.len() < MAX_LISTED_FAILURES {
+            summary.failures.push(FailedMessage {
+                control_id: control_id.to_string(),
+                received: now,
+                errors,
+                warnings: count(findings, Severity::Warning),
+            });
+        }
+    }
+
+    pub fn sources(&self) -> impl Iterator<Item = &str> {
+        self.sources.keys().map(|s| s.as_str())
+    }
+
+    pub fn render_markdown(&self, source: &str) -> Option<String> {
+        let summary = self.sources.get(source)?;
+        let mut out = String::new();
+
+        out.push_str(&format!("# PCD-04 conformance report: {}\n\n", source));
+        out.push_str(&format!(
+            "- Session: {} to {}\n",
+            summary.first_seen.to_rfc3339(),
+            summary.last_seen.to_rfc3339()
+        ));
+        out.push_str(&format!("- Messages received: {}\n", summary.messages));
+        out.push_str(&format!("- Messages rejected (AE): {}\n", summary.rejected));
+        out.push_str(&format!(
+            "- Messages with warnings only: {}\n\n",
+            summary.failures.iter().filter(|f| f.errors == 0).count()
+        ));
+
+        if summary.rules.is_empty() {
+            out.push_str("All messages conformed to IHE PCD TF-2 PCD-04.\n");
+            return Some(out);
+        }
+
+        out.push_str("## Rules violated\n\n");
+        out.push_str("| Rule | Severity | Occurrences | First location | Example |\n");
+        out.push_str("|------|----------|-------------|----------------|---------|\n");
+        for (rule, s) in &summary.rules {
+            out.push_str(&format!(
+                "| {} | {} | {} | {} | {} |\n",
+                rule,
+                s.severity,
+                s.occurrences,
+                s.first_location,
+                s.example.replace('|', "\\|")
+            ));
+        }
+
+        out.push_str("\n## Non-conforming messages\n\n");
+        out.push_str("| Received | MSH-10 | Errors | Warnings |\n");
+        out.push_str("|----------|--------|--------|----------|\n");
+        for f in &summary.failures {
+            out.push_str(&format!(
+                "| {} | {} | {} | {} |\n",
+                f.received.to_rfc3339(),
+                f.control_id.replace('|', "\\|"),
+                f.errors,
+                f.warnings
+            ));
+        }
+        if summary.failures.len() == MAX_LISTED_FAILURES {
+            out.push_str(&format!(
+                "\nOnly the first {} non-conforming messages are listed.\n",
+                MAX_LISTED_FAILURES
+            ));
+        }
+        Some(out)
+    }
+
+    /// Rewrites the report file of `source` in `dir`, returning its path.
+    pub fn write_report(&self, dir: &Path, source: &str) -> io::Result<PathBuf> {
+        let report = self
+            .render_markdown(source)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown source"))?;
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("conformance-{}.md", file_safe(source)));
+        fs::write(&path, report)?;
+        Ok(path)
+    }
+
+    /// Rewrites the reports of the sources that received messages since the last call, returning
+    /// their paths. Every source is tried; those whose report could not be written are tried
+    /// again next time, and the last error is returned.
+    pub fn write_changed_reports(&mut self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut written = Vec::new();
+        let mut error = None;
+        for source in self.changed.clone() {
+            match self.write_report(dir, &source) {
+                Ok(path) => {
+                    self.changed.remove(&source);
+                    written.push(path);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(written),
+        }
+    }
+}
+
+fn count(findings: &[Finding], severity: Severity) -> usize {
+    findings.iter().filter(|f| f.severity == severity).count()
+}
+
+fn file_safe(source: &str) -> String {
+    source
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// HL7 table 0357 error code for a validation rule.
+fn hl7_error_code(rule: &str) -> &'static str {
+    match rule {
+        r if r.starts_with("ACM-STRUCT-") => "100^Segment sequence error^HL70357",
+        "ACM-MSH-002" | "ACM-PID-001" | "ACM-PV1-001" | "ACM-OBX-003" | "ACM-OBX-007" => {
+            "101^Required field missing^HL70357"
+        }
+        r if r.starts_with("ACM-VS-") => "103^Table value not found^HL70357",
+        "ACM-MSH-001" | "ACM-MSH-003" | "ACM-MSH-005" | "ACM-MSH-006" | "ACM-CS-001" => {
+            "103^Table value not found^HL70357"
+        }
+        _ => "102^Data type error^HL70357",
+    }
+}
+
+/// Converts a finding into an ERR segment for the acknowledgment.
+pub fn to_err(finding: &Finding) -> ERR {
+    let delimiters = Delimiters::default();
+    let location = &finding.location;
+    let error_location = format!(
+        "{}^{}^{}^^{}",
+        location.segment,
+        location.sequence,
+        location.field.map_or(String::new(), |f| f.to_string()),
+        location.component.map_or(String::new(), |c| c.to_string())
+    )
+    .trim_end_matches('^')
+    .to_string();
+
+    ERR {
+        err_2_error_location: vec![error_location],
+        err_3_hl7_error_code: hl7_error_code(finding.rule).to_string(),
+        err_4_severity: match finding.severity {
+            Severity::Error => "E",
+            Severity::Warning => "W",
+            Severity::Info => "I",
+        }
+        .to_string(),
+        err_7_diagnostic_information: finding.rule.to_string(),
+        err_8_user_message: delimiters.escape(&finding.message),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwritten_reports_are_retried() {
+        let dir = std::env::temp_dir().join(format!("conformance-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // A directory where the first report should go makes only that write fail.
+        let blocked = dir.join("conformance-a.md");
+        fs::create_dir_all(&blocked).unwrap();
+
+        let mut tracker = ConformanceTracker::default();
+        for source in ["a", "b", "c"] {
+            tracker.record(source, "1", &[]);
+        }
+        assert!(tracker.write_changed_reports(&dir).is_err());
+        assert!(dir.join("conformance-b.md").is_file());
+        assert!(dir.join("conformance-c.md").is_file());
+
+        fs::remove_dir(&blocked).unwrap();
+        let written = tracker.write_changed_reports(&dir).unwrap();
+        assert_eq!(written, [blocked]);
+        assert!(tracker.write_changed_reports(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+use std::io;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::messages::{Ack, Message};
+use crate::pcd04_msg::PCD04Message;
+use crate::transport::{DefaultTransport, FrameStream, Transport};
+
+/// An MLLP connection from a reporter to an alert manager that sends one message at a time and
+/// waits for its acknowledgment.
+pub struct ManagerConnection {
+    stream: FrameStream,
+    ack_timeout: Duration,
+}
+
+impl ManagerConnection {
+    /// Connects over the transport the address names (see [`DefaultTransport`]).
+    pub async fn connect(address: &str, ack_timeout: Duration) -> io::Result<Self> {
+        ManagerConnection::connect_with(&DefaultTransport, address, ack_timeout).await
+    }
+
+    /// Connects over `transport`, giving up after the ACK timeout.
+    pub async fn connect_with(
+        transport: &dyn Transport,
+        address: &str,
+        ack_timeout: Duration,
+    ) -> io::Result<Self> {
+        let socket = tokio::time::timeout(ack_timeout, transport.connect(address))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no connection within {:?}", ack_timeout),
+                )
+            })??;
+        Ok(ManagerConnection {
+            stream: FrameStream::new(socket),
+            ack_timeout,
+        })
+    }
+
+    /// Sends `msg` and returns the acknowledgment the manager answered with.
+    pub async fn exchange(&mut self, msg: &PCD04Message) -> io::Result<Ack> {
+        self.exchange_frame(msg.get_control_id(), &msg.to_bytes())
+            .await
+    }
+
+    /// Sends an already encoded message with MSH-10 `control_id` and returns its acknowledgment.
+    /// ACKs for other messages, e.g. late duplicates of an earlier one, are skipped; if none
+    /// for `control_id` arrives within the ACK timeout the exchange times out.
+    pub async fn exchange_frame(&mut self, control_id: &str, bytes: &[u8]) -> io::Result<Ack> {
+        self.stream.send_frame(bytes).await?;
+
+        let deadline = Instant::now() + self.ack_timeout;
+        loop {
+            let frame = tokio::time::timeout_at(deadline, self.stream.receive_frame())
+                .await
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no ACK for {} within {:?}", control_id, self.ack_timeout),
+                    )
+                })??
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before ACK")
+                })?;
+            let ack = match Message::from_bytes(&frame) {
+                Ok(Message::Ack(ack)) => ack,
+                Ok(other) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("expected ACK, got {}", other.msh().msh_9_message_type),
+                    ))
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            if ack.msa.msa_2_message_control_id == control_id {
+                return Ok(ack);
+            }
+            warn!(
+                "Ignoring {} for {} while waiting for {}",
+                ack.msa.msa_1_acknowledgment_code, ack.msa.msa_2_message_control_id, control_id
+            );
+        }
+    }
+
+    pub async fn close(mut self) {
+        self.stream.shutdown().await;
+    }
+}
+
+/// Whether `error` means the acknowledgment did not arrive within the read timeout.
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Exponential backoff between reconnection attempts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectPolicy {
+    pub initial_ms: u64,
+    pub max_ms: u64,
+    pub multiplier: f64,
+    /// Fraction of each delay that is randomised, so many devices don't reconnect in lockstep.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_ms: 500,
+            max_ms: 30_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Backoff { policy, attempt: 0 }
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let policy = &self.policy;
+        let base = (policy.initial_ms as f64 * policy.multiplier.powi(self.attempt as i32))
+            .min(policy.max_ms as f64);
+        self.attempt = self.attempt.saturating_add(1);
+
+        // Uniform in [-1, 1) from the random bits of a v4 UUID.
+        let random = (Uuid::new_v4().as_u128() >> 75) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0;
+        let jitter = policy.jitter.clamp(0.0, 1.0);
+        Duration::from_millis((base * (1.0 + jitter * random)).max(0.0) as u64)
+    }
+
+    /// Starts over from the initial delay after a successful connection.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_to_the_maximum_and_resets() {
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            initial_ms: 100,
+            max_ms: 1000,
+            multiplier: 2.0,
+            jitter: 0.0,
+        });
+        let delays: Vec<u128> = (0..6).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff.attempts(), 6);
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            initial_ms: 1000,
+            max_ms: 1000,
+            multiplier: 2.0,
+            jitter: 0.2,
+        });
+        for _ in 0..100 {
+            let delay = backoff.next_delay().as_millis();
+            assert!((800..=1200).contains(&delay), "{}", delay);
+        }
+    }
+}
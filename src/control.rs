@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::device::{ActiveAlert, AlertSpec};
+use crate::mock_alert_rpt::{ReporterEvent, ReporterHandle};
+use crate::pcd04_msg::PCD04Message;
+use crate::shutdown::Shutdown;
+use crate::technical::TechnicalAlarm;
+use crate::transport::{self, DefaultTransport, Listener, Socket, Transport};
+
+/// Longest command line accepted.
+const MAX_LINE: usize = 64 * 1024;
+
+/// How long a command waits for the manager to answer the message it sent.
+const ACK_WAIT: Duration = Duration::from_secs(10);
+
+const PRIORITIES: [&str; 4] = ["PN", "PL", "PM", "PH"];
+
+/// One line sent to the control port, e.g.
+///
+/// ```text
+/// {"command": "alert_start", "alert": "spo2", "code": "196670^MDC_EVT_LO^MDC", "priority": "PM"}
+/// {"command": "set_priority", "alert": "spo2", "priority": "PH"}
+/// {"command": "alert_end", "alert": "spo2"}
+/// {"command": "raise", "condition": "lead_off"}
+/// {"command": "clear", "condition": "lead_off"}
+/// {"command": "pause_heartbeat"}
+/// {"command": "disconnect", "offline_ms": 5000}
+/// {"command": "set_patient", "patient_id": "HO2009002^^^Hospital^PI", "location": "ICU^Room2^Bed1"}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    AlertStart {
+        alert: String,
+        #[serde(flatten)]
+        spec: AlertSpec,
+    },
+    AlertEnd {
+        alert: String,
+    },
+    /// Raises a technical alarm from the catalog, e.g. `lead_off`.
+    Raise {
+        condition: TechnicalAlarm,
+        priority: Option<String>,
+    },
+    Clear {
+        condition: TechnicalAlarm,
+    },
+    /// Escalates or de-escalates a started alert or raised technical alarm.
+    SetPriority {
+        alert: String,
+        priority: String,
+    },
+    PauseHeartbeat,
+    ResumeHeartbeat,
+    /// Drops the connection to the manager and reconnects after `offline_ms`.
+    Disconnect {
+        #[serde(default)]
+        offline_ms: u64,
+    },
+    /// Associates the device with another patient or bed; fields left out keep their value.
+    SetPatient {
+        patient_id: Option<String>,
+        patient_name: Option<String>,
+        patient_dob: Option<String>,
+        patient_sex: Option<String>,
+        location: Option<String>,
+    },
+}
+
+/// The answer to one command, sent back as one JSON line.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Reply {
+    /// The command was carried out and, if it sent a message, the manager answered AA or CA.
+    pub ok: bool,
+    /// MSH-10 of the message the command sent.
+    pub control_id: Option<String>,
+    /// MSA-1 of the manager's answer.
+    pub ack: Option<String>,
+    /// MSA-3 of the manager's answer.
+    pub ack_text: Option<String>,
+    /// The manager has not answered yet; the alert stays queued and is retried.
+    pub queued: bool,
+    pub error: Option<String>,
+}
+
+impl Reply {
+    fn done() -> Reply {
+        Reply {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    fn error(error: impl Into<String>) -> Reply {
+        Reply {
+            error: Some(error.into()),
+            ..Default::default()
+        }
+    }
+}
+
+fn rank(priority: &str) -> Option<usize> {
+    PRIORITIES.iter().position(|p| *p == priority)
+}
+
+/// Carries out commands on one reporter. Alerts started through any control connection can be
+/// ended through any other.
+struct Controller {
+    reporter: ReporterHandle,
+    alerts: Mutex<HashMap<String, ActiveAlert>>,
+}
+
+impl Controller {
+    async fn execute(&self, command: Command) -> Reply {
+        match command {
+            Command::AlertStart { alert, spec } => self.start(alert, spec).await,
+            Command::AlertEnd { alert } => self.end(&alert).await,
+            Command::Raise {
+                condition,
+                priority,
+            } => {
+                let mut spec = condition.spec();
+                if let Some(priority) = priority {
+                    spec.priority = priority;
+                }
+                self.start(condition.to_string(), spec).await
+            }
+            Command::Clear { condition } => self.end(condition.name()).await,
+            Command::SetPriority { alert, priority } => {
+                if rank(&priority).is_none() {
+                    return Reply::error(format!("unknown priority {}", priority));
+                }
+                let msg = {
+                    let mut alerts = self.alerts.lock().unwrap();
+                    let Some(active) = alerts.get_mut(&alert) else {
+                        return Reply::error(format!("alert {} was not started", alert));
+                    };
+                    let phase = if rank(&priority) < rank(&active.spec.priority) {
+                        "deescalate"
+                    } else {
+                        "escalate"
+                    };
+                    active.update += 1;
+                    active.spec.priority = priority;
+                    self.reporter
+                        .device()
+                        .alert_message(active, phase, "active")
+                };
+                self.send(msg).await
+            }
+            Command::PauseHeartbeat => {
+                self.reporter.pause_heartbeat(true);
+                Reply::done()
+            }
+            Command::ResumeHeartbeat => {
+                self.reporter.pause_heartbeat(false);
+                Reply::done()
+            }
+            Command::Disconnect { offline_ms } => {
+                self.reporter.disconnect(Duration::from_millis(offline_ms));
+                Reply::done()
+            }
+            Command::SetPatient {
+                patient_id,
+                patient_name,
+                patient_dob,
+                patient_sex,
+                location,
+            } => {
+                self.reporter.update_device(|device| {
+                    let fields = [
+                        (&mut device.patient_id, patient_id),
+                        (&mut device.patient_name, patient_name),
+                        (&mut device.patient_dob, patient_dob),
+                        (&mut device.patient_sex, patient_sex),
+                        (&mut device.location, location),
+                    ];
+                    for (field, value) in fields {
+                        if let Some(value) = value {
+                            *field = value;
+                        }
+                    }
+                });
+                Reply::done()
+            }
+        }
+    }
+
+    async fn start(&self, name: String, spec: AlertSpec) -> Reply {
+        let msg = {
+            let mut alerts = self.alerts.lock().unwrap();
+            if alerts.contains_key(&name) {
+                return Reply::error(format!("alert {} is already active", name));
+            }
+            let active = ActiveAlert {
+                id: Uuid::new_v4().to_string(),
+                spec,
+                update: 0,
+            };
+            let msg = self
+                .reporter
+                .device()
+                .alert_message(&active, "start", "active");
+            alerts.insert(name, active);
+            msg
+        };
+        self.send(msg).await
+    }
+
+    async fn end(&self, name: &str) -> Reply {
+        let Some(mut active) = self.alerts.lock().unwrap().remove(name) else {
+            return Reply::error(format!("alert {} was not started", name));
+        };
+        active.update += 1;
+        let msg = self
+            .reporter
+            .device()
+            .alert_message(&active, "end", "inactive");
+        self.send(msg).await
+    }
+
+    /// Queues `msg` and waits for the manager's answer to it.
+    async fn send(&self, msg: PCD04Message) -> Reply {
+        // Subscribe first so the answer can't go by before anyone listens.
+        let mut events = self.reporter.subscribe();
+        let control_id = self.reporter.send_alert(msg);
+        let answer = tokio::time::timeout(ACK_WAIT, async {
+            loop {
+                match events.recv().await {
+                    Ok(ReporterEvent::Acknowledged {
+                        control_id: id,
+                        ack,
+                    }) if id == control_id => return Ok(ack),
+                    Ok(ReporterEvent::Failed {
+                        control_id: id,
+                        error,
+                    }) if id == control_id => return Err(error),
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return Err("reporter stopped".to_string()),
+                }
+            }
+        })
+        .await;
+
+        let mut reply = Reply {
+            control_id: Some(control_id),
+            ..Default::default()
+        };
+        match answer {
+            Ok(Ok(ack)) => {
+                reply.ok = matches!(ack.msa.msa_1_acknowledgment_code.as_str(), "AA" | "CA");
+                reply.ack = Some(ack.msa.msa_1_acknowledgment_code);
+                reply.ack_text = Some(ack.msa.msa_3_text_message).filter(|t| !t.is_empty());
+            }
+            Ok(Err(error)) => {
+                reply.queued = true;
+                reply.error = Some(error);
+            }
+            Err(_) => {
+                reply.queued = true;
+                reply.error = Some(format!("no answer from the manager within {:?}", ACK_WAIT));
+            }
+        }
+        reply
+    }
+
+    async fn handle_connection(
+        self: Arc<Self>,
+        socket: Box<dyn Socket>,
+        peer: String,
+        shutdown: Shutdown,
+    ) {
+        let mut lines = Framed::new(socket, LinesCodec::new_with_max_length(MAX_LINE));
+        loop {
+            let line = tokio::select! {
+                line = lines.next() => line,
+                _ = shutdown.wait() => break,
+            };
+            let reply = match line {
+                None | Some(Err(LinesCodecError::Io(_))) => break,
+                Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+                    Reply::error(format!("command longer than {} bytes", MAX_LINE))
+                }
+                Some(Ok(line)) if line.trim().is_empty() => continue,
+                Some(Ok(line)) => match serde_json::from_str(&line) {
+                    Ok(command) => {
+                        info!(%peer, command = line.trim(), "Control command");
+                        self.execute(command).await
+                    }
+                    Err(e) => Reply::error(format!("invalid command: {}", e)),
+                },
+            };
+            let reply = serde_json::to_string(&reply).expect("replies serialize");
+            if lines.send(reply).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn serve(self: Arc<Self>, listener: Box<dyn Listener>, shutdown: Shutdown) {
+        let mut connections = JoinSet::new();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                _ = shutdown.wait() => break,
+            };
+            match accepted {
+                Ok((socket, peer)) => {
+                    connections.spawn(Arc::clone(&self).handle_connection(
+                        socket,
+                        peer,
+                        shutdown.clone(),
+                    ));
+                }
+                Err(e) => warn!("Error accepting control connection: {}", e),
+            }
+        }
+        while connections.join_next().await.is_some() {}
+    }
+}
+
+/// A local admin port through which test scripts drive a running reporter: one JSON
+/// [`Command`] per line in, one JSON [`Reply`] per line out. Commands that send a message wait
+/// for the manager's ACK and return its control ID and result.
+pub struct ControlPort {
+    local_address: String,
+    task: JoinHandle<()>,
+}
+
+impl ControlPort {
+    /// Listens on `unix:PATH`, `mem:NAME` or a loopback TCP address. Anyone who can connect can
+    /// control the reporter, so other interfaces are refused.
+    pub async fn open(
+        address: &str,
+        reporter: ReporterHandle,
+        shutdown: &Shutdown,
+    ) -> io::Result<ControlPort> {
+        transport::ensure_local(address)?;
+        let listener = DefaultTransport.listen(address).await.map_err(|e| {
+            io::Error::new(e.kind(), format!("cannot listen on {}: {}", address, e))
+        })?;
+        let local_address = listener.local_address()?;
+        let controller = Arc::new(Controller {
+            reporter,
+            alerts: Mutex::new(HashMap::new()),
+        });
+        Ok(ControlPort {
+            local_address,
+            task: tokio::spawn(controller.serve(listener, shutdown.child())),
+        })
+    }
+
+    pub fn local_address(&self) -> &str {
+        &self.local_address
+    }
+
+    /// Waits until the shutdown coordinator closes the port.
+    pub async fn wait(self) {
+        let _ = self.task.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_alert_mgr::{AlertManager, AlertMgrConfig};
+    use crate::mock_alert_rpt::{AlertReporter, AlertRptConfig};
+
+    fn parse(line: &str) -> Result<Command, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+
+    #[test]
+    fn command_lines() {
+        let Command::AlertStart { alert, spec } = parse(
+            r#"{"command": "alert_start", "alert": "spo2", "code": "196670^MDC_EVT_LO^MDC", "priority": "PM"}"#,
+        )
+        .unwrap() else {
+            panic!("not alert_start");
+        };
+        assert_eq!(alert, "spo2");
+        assert_eq!(spec.code, "196670^MDC_EVT_LO^MDC");
+        assert_eq!(spec.kind, "SP");
+
+        assert_eq!(
+            parse(r#"{"command": "raise", "condition": "lead_off"}"#).unwrap(),
+            Command::Raise {
+                condition: TechnicalAlarm::LeadOff,
+                priority: None
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command": "disconnect"}"#).unwrap(),
+            Command::Disconnect { offline_ms: 0 }
+        );
+        assert_eq!(
+            parse(r#"{"command": "pause_heartbeat"}"#).unwrap(),
+            Command::PauseHeartbeat
+        );
+        let Command::SetPatient {
+            patient_id,
+            location,
+            patient_name,
+            ..
+        } = parse(r#"{"command": "set_patient", "patient_id": "HO2009002^^^Hospital^PI", "location": "ICU^Room2^Bed1"}"#)
+            .unwrap()
+        else {
+            panic!("not set_patient");
+        };
+        assert_eq!(patient_id.as_deref(), Some("HO2009002^^^Hospital^PI"));
+        assert_eq!(location.as_deref(), Some("ICU^Room2^Bed1"));
+        assert_eq!(patient_name, None);
+
+        assert!(parse(r#"{"command": "raise", "condition": "smoke"}"#).is_err());
+        assert!(parse(r#"{"command": "alert_end"}"#).is_err());
+        assert!(parse(r#"{"command": "reboot"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn control_port_drives_the_reporter() {
+        let mut manager = AlertManager::new(AlertMgrConfig {
+            listen_address: "mem:control-manager".to_string(),
+            ..Default::default()
+        });
+        manager.start().await.unwrap();
+        let mut reporter = AlertReporter::new(AlertRptConfig {
+            managers: vec!["mem:control-manager".to_string()],
+            heartbeat_interval: Duration::from_secs(60),
+            ..Default::default()
+        })
+        .unwrap();
+        reporter.start();
+        let shutdown = Shutdown::new();
+        let port = ControlPort::open("mem:control-port", reporter.handle(), &shutdown)
+            .await
+            .unwrap();
+
+        let socket = DefaultTransport.connect("mem:control-port").await.unwrap();
+        let mut lines = Framed::new(socket, LinesCodec::new());
+        let mut command = async |line: &str| -> serde_json::Value {
+            lines.send(line).await.unwrap();
+            let reply = lines.next().await.unwrap().unwrap();
+            serde_json::from_str(&reply).unwrap()
+        };
+
+        let reply = command(r#"{"command": "raise", "condition": "probe_off"}"#).await;
+        assert_eq!(reply["ok"], true);
+        assert_eq!(reply["ack"], "AA");
+        assert!(reply["control_id"].is_string());
+
+        let reply = command(r#"{"command": "raise", "condition": "probe_off"}"#).await;
+        assert_eq!(reply["error"], "alert probe_off is already active");
+        let reply =
+            command(r#"{"command": "set_priority", "alert": "probe_off", "priority": "PX"}"#).await;
+        assert_eq!(reply["error"], "unknown priority PX");
+        let reply =
+            command(r#"{"command": "set_priority", "alert": "probe_off", "priority": "PH"}"#).await;
+        assert_eq!(reply["ok"], true);
+        let reply = command("{\"command\": ").await;
+        assert!(reply["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid command: "));
+
+        let reply = command(r#"{"command": "clear", "condition": "probe_off"}"#).await;
+        assert_eq!(reply["ok"], true);
+        let reply = command(r#"{"command": "alert_end", "alert": "probe_off"}"#).await;
+        assert_eq!(reply["error"], "alert probe_off was not started");
+
+        drop(lines);
+        shutdown.trigger();
+        port.wait().await;
+        reporter.stop().await.unwrap();
+        manager.stop().await.unwrap();
+    }
+}
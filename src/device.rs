@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+
+use crate::pcd04_msg::PCD04Message;
+use crate::profiles::{DeviceClass, Placement, PATIENT_MONITOR};
+
+pub const HEARTBEAT_EVENT: &str = "196614^MDC_EVT_ACTIVE^MDC";
+pub const SPO2: &str = "150456^MDC_PULS_OXIM_SAT_O2^MDC";
+
+/// Identity, patient association and containment tree of one simulated device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceConfig {
+    pub name: String,
+    /// Sender EUI-64 in MSH-3 and OBR-3.
+    pub eui64: String,
+    /// OBX-18 equipment identifier: a `uuid:`/`urn:` URN or an EUI-64.
+    pub equipment_id: String,
+    pub location: String,
+    pub patient_id: String,
+    pub patient_name: String,
+    pub patient_dob: String,
+    pub patient_sex: String,
+    /// MDS, VMD and `<mds>.<vmd>.<channel>` of every alert when the device has no profile. The
+    /// default is the SpO2 channel of a patient monitor.
+    pub mds_type: String,
+    pub vmd_type: String,
+    /// Channel of `containment`, reported in its own OBX row unless empty.
+    pub chan_type: String,
+    pub containment: String,
+    /// Takes the MDS, VMD and containment of each alert from a device profile instead.
+    pub profile: Option<DeviceClass>,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        let spo2 = PATIENT_MONITOR.placement(Some(SPO2));
+        DeviceConfig {
+            name: "device".to_string(),
+            eui64: PCD04Message::DEFAULT_EUI64.to_string(),
+            equipment_id: "uuid:df041f5c-a3c9-11e9-8d8a-0050b612afeb".to_string(),
+            location: "POC^Room^Bed^fac^^^building^floor".to_string(),
+            patient_id: "HO2009001^^^Hospital^PI".to_string(),
+            patient_name: "Abo^Nasser^^^L".to_string(),
+            patient_dob: "18991230".to_string(),
+            patient_sex: "M".to_string(),
+            mds_type: spo2.mds_type,
+            vmd_type: spo2.vmd_type,
+            chan_type: spo2.chan_type,
+            containment: spo2.containment,
+            profile: None,
+        }
+    }
+}
+
+/// The measurement that triggered a physiological alert.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObservationSpec {
+    pub code: String,
+    pub value: String,
+    #[serde(default)]
+    pub unit: String,
+    #[serde(default = "default_value_type")]
+    pub value_type: String,
+}
+
+fn default_value_type() -> String {
+    "NM".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlertSpec {
+    /// MDC event code, e.g. `196670^MDC_EVT_LO^MDC`.
+    pub code: String,
+    #[serde(default)]
+    pub text: String,
+    /// PN, PL, PM or PH.
+    pub priority: String,
+    /// SP (physiological), ST (technical) or SA (advisory).
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    #[serde(default)]
+    pub observation: Option<ObservationSpec>,
+}
+
+fn default_kind() -> String {
+    "SP".to_string()
+}
+
+/// An alert the device has started and not yet ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveAlert {
+    pub id: String,
+    pub spec: AlertSpec,
+    /// Number of updates sent after the start message, reported in OBR-3.
+    pub update: i32,
+}
+
+impl DeviceConfig {
+    fn equipment_instance(&self) -> String {
+        let id_type = if self.equipment_id.contains(':') {
+            "URN"
+        } else {
+            "EUI-64"
+        };
+        format!("{}^^{}^{}", self.equipment_id, self.equipment_id, id_type)
+    }
+
+    /// Where an alert about `metric_code` is reported.
+    pub fn placement(&self, metric_code: Option<&str>) -> Placement {
+        match self.profile {
+            Some(class) => class.profile().placement(metric_code),
+            None => Placement {
+                containment: self.containment.clone(),
+                mds_type: self.mds_type.clone(),
+                vmd_type: self.vmd_type.clone(),
+                chan_type: self.chan_type.clone(),
+            },
+        }
+    }
+
+    pub fn heartbeat(&self) -> PCD04Message {
+        let mut msg = PCD04Message::with_eui64(&self.eui64);
+        let placement = self.placement(None);
+        let mds = placement.containment.split('.').next().unwrap_or("1");
+
+        msg.create_pcd04_message(
+            &self.location,
+            &self.equipment_instance(),
+            &self.patient_id,
+            &self.patient_name,
+            &self.patient_dob,
+            &self.patient_sex,
+            HEARTBEAT_EVENT,
+            "",
+            "start",
+            "PN",
+            &placement.containment,
+            "68480^MDC_ATTR_ALERT_SOURCE^MDC",
+            "",
+            "",
+            "",
+            &self.equipment_id,
+            "SA",
+            "",
+            0,
+            "active",
+            "enabled",
+            "",
+            None,
+            "P",
+            &placement.mds_type,
+            &placement.vmd_type,
+            &placement.chan_type,
+        );
+        msg.append_watchdog_obx_segment("5", "None", &format!("{}.0.0", mds));
+        msg
+    }
+
+    /// Builds the PCD-04 for `alert` in the given event phase and alarm state.
+    pub fn alert_message(&self, alert: &ActiveAlert, phase: &str, state: &str) -> PCD04Message {
+        let mut msg = PCD04Message::with_eui64(&self.eui64);
+        let spec = &alert.spec;
+        let (obs_type, obs_value, obs_unit, obs_value_type) = match &spec.observation {
+            Some(obs) => (
+                obs.code.as_str(),
+                obs.value.as_str(),
+                obs.unit.as_str(),
+                obs.value_type.as_str(),
+            ),
+            None => ("", "", "", ""),
+        };
+        let placement = self.placement(spec.observation.as_ref().map(|o| o.code.as_str()));
+
+        msg.create_pcd04_message(
+            &self.location,
+            &self.equipment_instance(),
+            &self.patient_id,
+            &self.patient_name,
+            &self.patient_dob,
+            &self.patient_sex,
+            &spec.code,
+            &spec.text,
+            phase,
+            &spec.priority,
+            &placement.containment,
+            obs_type,
+            obs_value,
+            obs_value_type,
+            obs_unit,
+            &alert.id,
+            &spec.kind,
+            "",
+            alert.update,
+            state,
+            "enabled",
+            "",
+            None,
+            "P",
+            &placement.mds_type,
+            &placement.vmd_type,
+            &placement.chan_type,
+        );
+        msg
+    }
+}
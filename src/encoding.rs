@@ -0,0 +1,408 @@
+use std::fmt;
+
+/// Separator and escape characters announced in MSH-1 and MSH-2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delimiters {
+    pub field: char,
+    pub component: char,
+    pub repetition: char,
+    pub escape: char,
+    pub subcomponent: char,
+}
+
+/// Character sets from MSH-18 that the crate can decode and encode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Charset {
+    Ascii,
+    #[default]
+    Utf8,
+    Latin1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodingError {
+    MissingMsh,
+    InvalidEncodingCharacters(String),
+    UnsupportedCharset(String),
+    InvalidBytes(Charset),
+    Unrepresentable(char, Charset),
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodingError::MissingMsh => write!(f, "message does not start with an MSH segment"),
+            EncodingError::InvalidEncodingCharacters(chars) => {
+                write!(f, "invalid MSH-2 encoding characters: {:?}", chars)
+            }
+            EncodingError::UnsupportedCharset(name) => {
+                write!(f, "unsupported MSH-18 character set: {}", name)
+            }
+            EncodingError::InvalidBytes(charset) => {
+                write!(f, "message is not valid {}", charset.msh_18_value())
+            }
+            EncodingError::Unrepresentable(c, charset) => {
+                write!(f, "{:?} cannot be encoded as {}", c, charset.msh_18_value())
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodingError {}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Delimiters {
+            field: '|',
+            component: '^',
+            repetition: '~',
+            escape: '\\',
+            subcomponent: '&',
+        }
+    }
+}
+
+impl Delimiters {
+    /// Reads the delimiters from the start of an MSH segment, e.g. `MSH|^~\&|...`.
+    pub fn from_msh(segment: &str) -> Result<Self, EncodingError> {
+        let rest = segment
+            .strip_prefix("MSH")
+            .ok_or(EncodingError::MissingMsh)?;
+        let mut chars = rest.chars();
+        let field = chars.next().ok_or(EncodingError::MissingMsh)?;
+        let encoding: String = chars.take_while(|c| *c != field).collect();
+
+        let enc: Vec<char> = encoding.chars().collect();
+        if enc.len() < 4 || field.is_alphanumeric() || enc.iter().any(|c| c.is_alphanumeric()) {
+            return Err(EncodingError::InvalidEncodingCharacters(encoding));
+        }
+        let delimiters = Delimiters {
+            field,
+            component: enc[0],
+            repetition: enc[1],
+            escape: enc[2],
+            subcomponent: enc[3],
+        };
+
+        let mut all = vec![field];
+        all.extend_from_slice(&enc[..4]);
+        all.sort_unstable();
+        all.dedup();
+        if all.len() != 5 {
+            return Err(EncodingError::InvalidEncodingCharacters(encoding));
+        }
+        Ok(delimiters)
+    }
+
+    /// The MSH-2 value for these delimiters.
+    pub fn encoding_characters(&self) -> String {
+        [
+            self.component,
+            self.repetition,
+            self.escape,
+            self.subcomponent,
+        ]
+        .iter()
+        .collect()
+    }
+
+    /// Escapes delimiters and line breaks so `text` can be placed in a single component.
+    pub fn escape(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            let seq = match c {
+                c if c == self.field => "F",
+                c if c == self.component => "S",
+                c if c == self.subcomponent => "T",
+                c if c == self.repetition => "R",
+                c if c == self.escape => "E",
+                '\n' => ".br",
+                '\r' => "X0D",
+                _ => {
+                    out.push(c);
+                    continue;
+                }
+            };
+            out.push(self.escape);
+            out.push_str(seq);
+            out.push(self.escape);
+        }
+        out
+    }
+
+    /// Reverses [`Delimiters::escape`]. `\Xhh\` data is decoded using `charset`;
+    /// highlighting sequences are dropped and unknown sequences are kept verbatim.
+    pub fn unescape(&self, text: &str, charset: Charset) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find(self.escape) {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + self.escape.len_utf8()..];
+            let Some(end) = after.find(self.escape) else {
+                out.push_str(&rest[start..]);
+                return out;
+            };
+            let seq = &after[..end];
+
+            match seq {
+                "F" => out.push(self.field),
+                "S" => out.push(self.component),
+                "T" => out.push(self.subcomponent),
+                "R" => out.push(self.repetition),
+                "E" => out.push(self.escape),
+                ".br" => out.push('\n'),
+                "H" | "N" => {}
+                _ => match seq.strip_prefix('X').and_then(decode_hex) {
+                    Some(bytes) => out.push_str(&charset.decode_lossy(&bytes)),
+                    None => {
+                        out.push(self.escape);
+                        out.push_str(seq);
+                        out.push(self.escape);
+                    }
+                },
+            }
+            rest = &after[end + self.escape.len_utf8()..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Charset {
+    /// Maps an MSH-18 value to a charset. An empty value means UTF-8: HL7 assumes ASCII then, and
+    /// UTF-8 reads ASCII unchanged while tolerating senders that leave MSH-18 out anyway.
+    pub fn from_msh_18(value: &str) -> Result<Self, EncodingError> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "" => Ok(Charset::default()),
+            "ASCII" => Ok(Charset::Ascii),
+            "UNICODE UTF-8" | "UTF-8" => Ok(Charset::Utf8),
+            "8859/1" | "ISO-8859-1" | "ISO_IR 100" => Ok(Charset::Latin1),
+            _ => Err(EncodingError::UnsupportedCharset(value.to_string())),
+        }
+    }
+
+    pub fn msh_18_value(&self) -> &'static str {
+        match self {
+            Charset::Ascii => "ASCII",
+            Charset::Utf8 => "UNICODE UTF-8",
+            Charset::Latin1 => "8859/1",
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, EncodingError> {
+        match self {
+            Charset::Ascii if bytes.is_ascii() => Ok(bytes.iter().map(|b| *b as char).collect()),
+            Charset::Ascii => Err(EncodingError::InvalidBytes(*self)),
+            Charset::Utf8 => {
+                String::from_utf8(bytes.to_vec()).map_err(|_| EncodingError::InvalidBytes(*self))
+            }
+            Charset::Latin1 => Ok(bytes.iter().map(|b| *b as char).collect()),
+        }
+    }
+
+    fn decode_lossy(&self, bytes: &[u8]) -> String {
+        match self {
+            Charset::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            _ => bytes.iter().map(|b| *b as char).collect(),
+        }
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, EncodingError> {
+        let limit = match self {
+            Charset::Utf8 => return Ok(text.as_bytes().to_vec()),
+            Charset::Ascii => 0x7F,
+            Charset::Latin1 => 0xFF,
+        };
+        text.chars()
+            .map(|c| {
+                if (c as u32) <= limit {
+                    Ok(c as u8)
+                } else {
+                    Err(EncodingError::Unrepresentable(c, *self))
+                }
+            })
+            .collect()
+    }
+}
+
+/// Decodes a raw message using the character set declared in its MSH-18, UTF-8 if there is none.
+pub fn decode_message(bytes: &[u8]) -> Result<String, EncodingError> {
+    let charset = Charset::from_msh_18(msh_18(bytes)?.as_deref().unwrap_or(""))?;
+    charset.decode(bytes)
+}
+
+fn msh_18(bytes: &[u8]) -> Result<Option<String>, EncodingError> {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .ok_or(EncodingError::MissingMsh)?;
+    let msh_end = bytes[start..]
+        .iter()
+        .position(|b| *b == b'\r' || *b == b'\n')
+        .map_or(bytes.len(), |end| start + end);
+    // The MSH segment itself must be plain ASCII up to MSH-18, whatever the charset.
+    let msh = String::from_utf8_lossy(&bytes[start..msh_end]);
+    let delimiters = Delimiters::from_msh(&msh)?;
+
+    // MSH-1 is the field separator itself, so MSH-18 is the 18th element of the split.
+    let field = msh
+        .split(delimiters.field)
+        .nth(17)
+        .and_then(|f| f.split(delimiters.repetition).next())
+        .filter(|f| !f.is_empty());
+    Ok(field.map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceConfig;
+    use crate::messages::Message;
+    use crate::segments::{component, RawSegment};
+
+    fn custom() -> Delimiters {
+        Delimiters::from_msh("MSH#:*@%#APP").unwrap()
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        let delimiters = Delimiters::default();
+        let text = "SpO2 < 90|low^high~x&y\\z\nsecond line\r";
+        let escaped = delimiters.escape(text);
+        assert_eq!(
+            escaped,
+            "SpO2 < 90\\F\\low\\S\\high\\R\\x\\T\\y\\E\\z\\.br\\second line\\X0D\\"
+        );
+        assert_eq!(delimiters.unescape(&escaped, Charset::Utf8), text);
+    }
+
+    #[test]
+    fn custom_delimiters_round_trip() {
+        let delimiters = custom();
+        assert_eq!(delimiters.encoding_characters(), ":*@%");
+        let text = "a#b:c*d@e%f|g^h\\i";
+        let escaped = delimiters.escape(text);
+        assert_eq!(escaped, "a@F@b@S@c@R@d@E@e@T@f|g^h\\i");
+        assert_eq!(delimiters.unescape(&escaped, Charset::Utf8), text);
+    }
+
+    #[test]
+    fn custom_delimiters_translate_to_default() {
+        let line = "OBX#1#ST#196670:MDC_EVT_LO:MDC#1.0.0.1#low @S@ 90*high|x^y";
+        let segment = RawSegment::parse(line, &custom(), Charset::Utf8);
+        assert_eq!(segment.field(3), "196670^MDC_EVT_LO^MDC");
+        assert_eq!(segment.field(5), "low \\S\\ 90~high\\X7C\\x\\X5E\\y");
+        assert_eq!(segment.encode(&custom(), Charset::Utf8), line);
+    }
+
+    #[test]
+    fn hex_data_uses_charset() {
+        let delimiters = Delimiters::default();
+        assert_eq!(delimiters.unescape("caf\\XC3A9\\", Charset::Utf8), "café");
+        assert_eq!(delimiters.unescape("caf\\XE9\\", Charset::Latin1), "café");
+        assert_eq!(delimiters.unescape("\\X0D0A\\", Charset::Ascii), "\r\n");
+    }
+
+    #[test]
+    fn unescape_keeps_what_it_cannot_decode() {
+        let delimiters = Delimiters::default();
+        assert_eq!(delimiters.unescape("\\H\\bold\\N\\", Charset::Utf8), "bold");
+        assert_eq!(delimiters.unescape("\\Z1\\", Charset::Utf8), "\\Z1\\");
+        assert_eq!(delimiters.unescape("\\XE\\", Charset::Utf8), "\\XE\\");
+        assert_eq!(delimiters.unescape("100\\", Charset::Utf8), "100\\");
+    }
+
+    #[test]
+    fn invalid_encoding_characters() {
+        assert_eq!(
+            Delimiters::from_msh("MSH|^~\\^|"),
+            Err(EncodingError::InvalidEncodingCharacters(
+                "^~\\^".to_string()
+            ))
+        );
+        assert_eq!(
+            Delimiters::from_msh("MSH|^~|"),
+            Err(EncodingError::InvalidEncodingCharacters("^~".to_string()))
+        );
+        assert_eq!(
+            Delimiters::from_msh("PID|^~\\&|"),
+            Err(EncodingError::MissingMsh)
+        );
+    }
+
+    #[test]
+    fn msh_18_values() {
+        assert_eq!(Charset::from_msh_18(""), Ok(Charset::Utf8));
+        assert_eq!(Charset::from_msh_18("ASCII"), Ok(Charset::Ascii));
+        assert_eq!(Charset::from_msh_18("unicode utf-8"), Ok(Charset::Utf8));
+        assert_eq!(Charset::from_msh_18("8859/1"), Ok(Charset::Latin1));
+        assert!(Charset::from_msh_18("EBCDIC").is_err());
+        assert_eq!(
+            Charset::Ascii.encode("é"),
+            Err(EncodingError::Unrepresentable('é', Charset::Ascii))
+        );
+    }
+
+    #[test]
+    fn decode_message_reads_msh_18() {
+        let mut latin1 =
+            b"MSH|^~\\&|AR||||||ORU^R40^ORU_R40|1|P|2.6||||||8859/1\rOBX|1|ST|||caf".to_vec();
+        latin1.push(0xE9);
+        assert!(decode_message(&latin1).unwrap().ends_with("café"));
+
+        let mut utf8 = b"MSH|^~\\&|AR||||||ORU^R40^ORU_R40|1|P|2.6\rOBX|1|ST|||caf".to_vec();
+        utf8.push(0xE9);
+        assert_eq!(
+            decode_message(&utf8),
+            Err(EncodingError::InvalidBytes(Charset::Utf8))
+        );
+    }
+    #[test]
+    fn patient_name_and_location_round_trip() {
+        let device = DeviceConfig {
+            patient_name: "O'Brien|Smith^Mary~Ann&Lou\\x^^^L".to_string(),
+            location: "ICU|East^Bed 3&4^~1".to_string(),
+            ..Default::default()
+        };
+        let text = device.heartbeat().encode();
+        let Ok(Message::Oru(oru)) = text.parse::<Message>() else {
+            panic!("not an ORU: {}", text);
+        };
+        let charset = Charset::Utf8;
+        let delimiters = Delimiters::default();
+        let patient = oru.patient.unwrap();
+
+        let name = &patient.pid.pid_5_patient_name[0];
+        assert_eq!(
+            delimiters.unescape(component(name, 1), charset),
+            "O'Brien|Smith"
+        );
+        assert_eq!(
+            delimiters.unescape(component(name, 2), charset),
+            "Mary~Ann&Lou\\x"
+        );
+        assert_eq!(component(name, 5), "L");
+
+        let location = patient.pv1.unwrap().pv1_3_assigned_patient_location;
+        assert_eq!(
+            delimiters.unescape(component(&location, 1), charset),
+            "ICU|East"
+        );
+        assert_eq!(
+            delimiters.unescape(component(&location, 2), charset),
+            "Bed 3&4"
+        );
+        assert_eq!(delimiters.unescape(component(&location, 3), charset), "~1");
+    }
+}
@@ -0,0 +1,575 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::device::HEARTBEAT_EVENT;
+use crate::encoding::Delimiters;
+use crate::messages::{self, Ack, Oru};
+use crate::segments::{component, OBX};
+
+const EVENT_PHASE: &str = "68481";
+const ALARM_STATE: &str = "68482";
+const ALARM_PRIORITY: &str = "68484";
+const ALERT_TYPE: &str = "68485";
+const CONFIRM_TIMEOUT: &str = "67860";
+
+/// What an inbound ORU is about; decides which [`AlertHandler`] method sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Heartbeat,
+    AlertStart,
+    /// Any phase between start and end: continue, update, escalate, de-escalate and so on.
+    AlertUpdate,
+    AlertEnd,
+    /// A technical (`ST`) alert, in any phase.
+    Technical,
+    /// Not an ORU^R40, or one without an alert OBX.
+    Unknown,
+}
+
+impl MessageKind {
+    pub fn of(oru: &Oru) -> MessageKind {
+        if oru.msh.trigger_event() != "R40" {
+            return MessageKind::Unknown;
+        }
+        let Some(alert) = alert_obx(oru, 1) else {
+            return MessageKind::Unknown;
+        };
+        if alert.observation_code() == component(HEARTBEAT_EVENT, 1) {
+            return MessageKind::Heartbeat;
+        }
+        if attribute(oru, ALERT_TYPE) == "ST" {
+            return MessageKind::Technical;
+        }
+        match attribute(oru, EVENT_PHASE) {
+            "start" | "start_only" => MessageKind::AlertStart,
+            "end" => MessageKind::AlertEnd,
+            _ => MessageKind::AlertUpdate,
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MessageKind::Heartbeat => "heartbeat",
+            MessageKind::AlertStart => "alert start",
+            MessageKind::AlertUpdate => "alert update",
+            MessageKind::AlertEnd => "alert end",
+            MessageKind::Technical => "technical alert",
+            MessageKind::Unknown => "unknown message",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The OBX of facet `facet` in the first alert of `oru`.
+fn alert_obx(oru: &Oru, facet: u32) -> Option<&OBX> {
+    oru.observations()
+        .map(|group| &group.obx)
+        .find(|obx| obx.facet() == Some(facet))
+}
+
+/// Second component of OBR-3, the same for every message about one alert.
+pub fn alert_id(oru: &Oru) -> &str {
+    oru.orders.first().map_or("", |order| {
+        component(&order.obr.obr_3_filler_order_number, 2)
+    })
+}
+
+/// MDC_ATTR_ALARM_PRIORITY of `oru`: PN, PL, PM or PH.
+pub fn alarm_priority(oru: &Oru) -> &str {
+    attribute(oru, ALARM_PRIORITY)
+}
+
+/// First value of the MDC_ATTR_* row with code `code`.
+fn attribute<'a>(oru: &'a Oru, code: &str) -> &'a str {
+    oru.observations()
+        .map(|group| &group.obx)
+        .find(|obx| obx.observation_code() == code)
+        .map_or("", |obx| obx.first_value())
+}
+
+/// One inbound message on its way through the handlers, with the answer being built for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageContext {
+    /// Address of the connection it arrived on.
+    pub peer: String,
+    /// MSH-3 of the message, or the peer if it has none.
+    pub source: String,
+    pub kind: MessageKind,
+    pub oru: Oru,
+    /// The acknowledgment sent back, `AA` unless a handler or the conformance check says
+    /// otherwise.
+    pub ack: Ack,
+    /// Destinations chosen by routing handlers, e.g. a care team or pager group.
+    pub routes: Vec<String>,
+    /// Facts added by enriching handlers, e.g. the bed's nurse from a staffing system.
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl MessageContext {
+    pub fn control_id(&self) -> &str {
+        &self.oru.msh.msh_10_message_control_id
+    }
+
+    /// MDC code of the alert, e.g. `196670`.
+    pub fn alert_code(&self) -> Option<&str> {
+        alert_obx(&self.oru, 1).map(OBX::observation_code)
+    }
+
+    pub fn alert_text(&self) -> &str {
+        alert_obx(&self.oru, 1).map_or("", OBX::first_value)
+    }
+
+    /// Second component of OBR-3, the same for every message about one alert.
+    pub fn alert_id(&self) -> &str {
+        alert_id(&self.oru)
+    }
+
+    /// SP, ST or SA.
+    pub fn alert_type(&self) -> &str {
+        attribute(&self.oru, ALERT_TYPE)
+    }
+
+    pub fn event_phase(&self) -> &str {
+        attribute(&self.oru, EVENT_PHASE)
+    }
+
+    /// E.g. `active` or `inactive`.
+    pub fn alarm_state(&self) -> &str {
+        attribute(&self.oru, ALARM_STATE)
+    }
+
+    /// Value and unit of the measurement that triggered the alert.
+    pub fn observation(&self) -> Option<(&str, &str)> {
+        alert_obx(&self.oru, 2).map(|obx| (obx.first_value(), obx.obx_6_units.as_str()))
+    }
+
+    /// Seconds until the next heartbeat is due, as stated by a heartbeat.
+    pub fn confirm_timeout(&self) -> Option<u64> {
+        attribute(&self.oru, CONFIRM_TIMEOUT).parse().ok()
+    }
+
+    /// PN, PL, PM or PH.
+    pub fn priority(&self) -> &str {
+        alarm_priority(&self.oru)
+    }
+
+    /// PV1-3, e.g. `ICU^Room1^Bed1`.
+    pub fn location(&self) -> &str {
+        self.oru
+            .patient
+            .as_ref()
+            .and_then(|p| p.pv1.as_ref())
+            .map_or("", |pv1| pv1.pv1_3_assigned_patient_location.as_str())
+    }
+
+    /// ID number of the first PID-3 identifier.
+    pub fn patient_id(&self) -> &str {
+        self.oru
+            .patient
+            .as_ref()
+            .and_then(|p| p.pid.pid_3_patient_identifier_list.first())
+            .map_or("", |id| component(id, 1))
+    }
+
+    /// Decodes the escape sequences of a value read from the message, e.g. `\S\` back to
+    /// `^`. Fields are held with the default delimiters whatever the message was sent with.
+    pub fn unescape(&self, text: &str) -> String {
+        Delimiters::default().unescape(text, messages::charset_of(&self.oru.msh))
+    }
+}
+
+/// What a handler wants done with a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Pass it on to the next handler.
+    Continue,
+    /// Acknowledge it but process it no further: later handlers don't see it and it is not
+    /// reported as an alert.
+    Suppress,
+    /// Answer with `code` (AE or AR) and `reason` in MSA-3, and process it no further.
+    Reject { code: String, reason: String },
+}
+
+impl Verdict {
+    pub fn reject(code: &str, reason: impl Into<String>) -> Verdict {
+        Verdict::Reject {
+            code: code.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Site-specific processing of inbound messages. Each method sees one [`MessageKind`] and may
+/// change the context (enrich it, add routes, adjust the ACK) before returning its verdict. The
+/// defaults pass everything on.
+pub trait AlertHandler: Send + Sync {
+    /// Shown in the log when the handler suppresses or rejects a message.
+    fn name(&self) -> &str;
+
+    fn heartbeat(&self, _ctx: &mut MessageContext) -> Verdict {
+        Verdict::Continue
+    }
+
+    fn alert_start(&self, _ctx: &mut MessageContext) -> Verdict {
+        Verdict::Continue
+    }
+
+    fn alert_update(&self, _ctx: &mut MessageContext) -> Verdict {
+        Verdict::Continue
+    }
+
+    fn alert_end(&self, _ctx: &mut MessageContext) -> Verdict {
+        Verdict::Continue
+    }
+
+    fn technical(&self, _ctx: &mut MessageContext) -> Verdict {
+        Verdict::Continue
+    }
+
+    fn unknown(&self, _ctx: &mut MessageContext) -> Verdict {
+        Verdict::Continue
+    }
+}
+
+/// How a message left the pipeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every handler let it through.
+    Processed,
+    Suppressed {
+        by: String,
+    },
+    Rejected {
+        by: String,
+    },
+}
+
+/// Handlers in the order they see each message.
+#[derive(Clone, Default)]
+pub struct HandlerPipeline {
+    handlers: Vec<Arc<dyn AlertHandler>>,
+}
+
+impl fmt::Debug for HandlerPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.handlers.iter().map(|h| h.name()))
+            .finish()
+    }
+}
+
+impl HandlerPipeline {
+    pub fn new() -> Self {
+        HandlerPipeline::default()
+    }
+
+    /// The manager's usual behaviour: log every message.
+    pub fn standard() -> Self {
+        HandlerPipeline::new().with(ConsoleHandler)
+    }
+
+    pub fn with(mut self, handler: impl AlertHandler + 'static) -> Self {
+        self.push(Arc::new(handler));
+        self
+    }
+
+    pub fn push(&mut self, handler: Arc<dyn AlertHandler>) {
+        self.handlers.push(handler);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    pub fn run(&self, ctx: &mut MessageContext) -> Outcome {
+        for handler in &self.handlers {
+            let verdict = match ctx.kind {
+                MessageKind::Heartbeat => handler.heartbeat(ctx),
+                MessageKind::AlertStart => handler.alert_start(ctx),
+                MessageKind::AlertUpdate => handler.alert_update(ctx),
+                MessageKind::AlertEnd => handler.alert_end(ctx),
+                MessageKind::Technical => handler.technical(ctx),
+                MessageKind::Unknown => handler.unknown(ctx),
+            };
+            match verdict {
+                Verdict::Continue => {}
+                Verdict::Suppress => {
+                    return Outcome::Suppressed {
+                        by: handler.name().to_string(),
+                    }
+                }
+                Verdict::Reject { code, reason } => {
+                    ctx.ack.msa.msa_1_acknowledgment_code = code;
+                    ctx.ack.msa.msa_3_text_message = reason;
+                    return Outcome::Rejected {
+                        by: handler.name().to_string(),
+                    };
+                }
+            }
+        }
+        Outcome::Processed
+    }
+}
+
+/// Logs what arrived and fills an empty MSA-3 the way the mock manager always has. Messages
+/// other than ORU^R40 are rejected with AR.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConsoleHandler;
+
+impl ConsoleHandler {
+    /// Sets MSA-3 unless the conformance check already explained the answer there.
+    fn note(ctx: &mut MessageContext, text: &str) {
+        if ctx.ack.msa.msa_3_text_message.is_empty() {
+            ctx.ack.msa.msa_3_text_message = text.to_string();
+        }
+    }
+
+    fn alert(&self, ctx: &mut MessageContext) -> Verdict {
+        let alarm_type = alert_obx(&ctx.oru, 1).map_or("", |obx| &obx.obx_3_observation_identifier);
+        info!(
+            alarm = alarm_type,
+            priority = ctx.priority(),
+            phase = ctx.event_phase(),
+            "Got {}",
+            ctx.kind
+        );
+        ConsoleHandler::note(ctx, "Delivered");
+        Verdict::Continue
+    }
+}
+
+impl AlertHandler for ConsoleHandler {
+    fn name(&self) -> &str {
+        "console"
+    }
+
+    fn heartbeat(&self, _ctx: &mut MessageContext) -> Verdict {
+        debug!("Got heartbeat");
+        Verdict::Continue
+    }
+
+    fn alert_start(&self, ctx: &mut MessageContext) -> Verdict {
+        self.alert(ctx)
+    }
+
+    fn alert_update(&self, ctx: &mut MessageContext) -> Verdict {
+        self.alert(ctx)
+    }
+
+    fn alert_end(&self, ctx: &mut MessageContext) -> Verdict {
+        self.alert(ctx)
+    }
+
+    fn technical(&self, ctx: &mut MessageContext) -> Verdict {
+        self.alert(ctx)
+    }
+
+    fn unknown(&self, ctx: &mut MessageContext) -> Verdict {
+        if ctx.oru.msh.trigger_event() != "R40" {
+            warn!(
+                message_type = %ctx.oru.msh.msh_9_message_type,
+                "Got unknown message type"
+            );
+            return Verdict::reject("AR", "Unsupported message type");
+        }
+        ConsoleHandler::note(ctx, "No alert OBX");
+        Verdict::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::device::{self, ActiveAlert, AlertSpec, DeviceConfig, ObservationSpec};
+    use crate::messages::Message;
+    use crate::pcd04_msg::PCD04Message;
+    use crate::technical::TechnicalAlarm;
+
+    fn context(msg: &PCD04Message) -> MessageContext {
+        let Message::Oru(oru) = msg.encode().parse().unwrap() else {
+            panic!("not an ORU");
+        };
+        let ack = Ack::for_message(&oru.msh, "AA", "20261018120000");
+        MessageContext {
+            peer: "mem:1".to_string(),
+            source: oru.msh.msh_3_sending_application.clone(),
+            kind: MessageKind::of(&oru),
+            oru,
+            ack,
+            routes: Vec::new(),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    fn spo2_low() -> ActiveAlert {
+        ActiveAlert {
+            id: "alert-1".to_string(),
+            spec: AlertSpec {
+                code: "196670^MDC_EVT_LO^MDC".to_string(),
+                text: "SpO2 & low".to_string(),
+                priority: "PH".to_string(),
+                kind: "SP".to_string(),
+                observation: Some(ObservationSpec {
+                    code: device::SPO2.to_string(),
+                    value: "85".to_string(),
+                    unit: "262688^MDC_DIM_PERCENT^MDC".to_string(),
+                    value_type: "NM".to_string(),
+                }),
+            },
+            update: 0,
+        }
+    }
+
+    #[test]
+    fn message_kinds() {
+        let device = DeviceConfig::default();
+        let alert = spo2_low();
+        let kind = |msg| context(&msg).kind;
+        assert_eq!(kind(device.heartbeat()), MessageKind::Heartbeat);
+        assert_eq!(
+            kind(device.alert_message(&alert, "start", "active")),
+            MessageKind::AlertStart
+        );
+        assert_eq!(
+            kind(device.alert_message(&alert, "escalate", "active")),
+            MessageKind::AlertUpdate
+        );
+        assert_eq!(
+            kind(device.alert_message(&alert, "end", "inactive")),
+            MessageKind::AlertEnd
+        );
+        let technical = ActiveAlert {
+            spec: TechnicalAlarm::LowBattery.spec(),
+            ..spo2_low()
+        };
+        assert_eq!(
+            kind(device.alert_message(&technical, "end", "inactive")),
+            MessageKind::Technical
+        );
+
+        let mut r41 = context(&device.alert_message(&alert, "start", "active"));
+        r41.oru.msh.msh_9_message_type = "ORU^R41^ORU_R41".to_string();
+        assert_eq!(MessageKind::of(&r41.oru), MessageKind::Unknown);
+    }
+
+    #[test]
+    fn context_reads_the_alert() {
+        let device = DeviceConfig::default();
+        let ctx = context(&device.alert_message(&spo2_low(), "start", "active"));
+        assert_eq!(ctx.alert_code(), Some("196670"));
+        assert_eq!(ctx.alert_id(), "alert-1");
+        assert_eq!(ctx.alert_text(), "SpO2 \\T\\ low");
+        assert_eq!(ctx.unescape(ctx.alert_text()), "SpO2 & low");
+        assert_eq!(ctx.alert_type(), "SP");
+        assert_eq!(ctx.event_phase(), "start");
+        assert_eq!(ctx.alarm_state(), "active");
+        assert_eq!(ctx.priority(), "PH");
+        assert_eq!(
+            ctx.observation(),
+            Some(("85", "262688^MDC_DIM_PERCENT^MDC"))
+        );
+        assert_eq!(ctx.location(), device.location);
+        assert_eq!(ctx.patient_id(), "HO2009001");
+
+        let heartbeat = context(&device.heartbeat());
+        assert!(heartbeat.confirm_timeout().is_some());
+    }
+
+    /// Records which handlers saw a message and answers with a fixed verdict.
+    struct Fixed {
+        name: &'static str,
+        verdict: Verdict,
+        seen: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl AlertHandler for Fixed {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn alert_start(&self, ctx: &mut MessageContext) -> Verdict {
+            self.seen.lock().unwrap().push(self.name);
+            ctx.routes.push(self.name.to_string());
+            self.verdict.clone()
+        }
+    }
+
+    #[test]
+    fn pipeline_stops_at_the_first_verdict() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let fixed = |name, verdict| Fixed {
+            name,
+            verdict,
+            seen: Arc::clone(&seen),
+        };
+        let alert = DeviceConfig::default().alert_message(&spo2_low(), "start", "active");
+
+        let pipeline = HandlerPipeline::new()
+            .with(fixed("route", Verdict::Continue))
+            .with(fixed("mute", Verdict::Suppress))
+            .with(fixed("never", Verdict::Continue));
+        let mut ctx = context(&alert);
+        assert_eq!(
+            pipeline.run(&mut ctx),
+            Outcome::Suppressed {
+                by: "mute".to_string()
+            }
+        );
+        assert_eq!(ctx.routes, ["route", "mute"]);
+        assert_eq!(ctx.ack.msa.msa_1_acknowledgment_code, "AA");
+        assert_eq!(
+            format!("{:?}", pipeline),
+            "[\"route\", \"mute\", \"never\"]"
+        );
+
+        let pipeline = HandlerPipeline::standard()
+            .with(fixed("policy", Verdict::reject("AE", "Bed not monitored")))
+            .with(fixed("never", Verdict::Continue));
+        let mut ctx = context(&alert);
+        assert_eq!(
+            pipeline.run(&mut ctx),
+            Outcome::Rejected {
+                by: "policy".to_string()
+            }
+        );
+        assert_eq!(ctx.ack.msa.msa_1_acknowledgment_code, "AE");
+        assert_eq!(ctx.ack.msa.msa_3_text_message, "Bed not monitored");
+        assert_eq!(*seen.lock().unwrap(), ["route", "mute", "policy"]);
+    }
+
+    #[test]
+    fn console_handler_answers() {
+        let device = DeviceConfig::default();
+        let pipeline = HandlerPipeline::standard();
+
+        let mut ctx = context(&device.alert_message(&spo2_low(), "start", "active"));
+        assert_eq!(pipeline.run(&mut ctx), Outcome::Processed);
+        assert_eq!(ctx.ack.msa.msa_3_text_message, "Delivered");
+
+        // A note from the conformance check is kept.
+        let mut ctx = context(&device.alert_message(&spo2_low(), "start", "active"));
+        ctx.ack.msa.msa_3_text_message = "OBX-8 priority PX".to_string();
+        pipeline.run(&mut ctx);
+        assert_eq!(ctx.ack.msa.msa_3_text_message, "OBX-8 priority PX");
+
+        let mut ctx = context(&device.heartbeat());
+        ctx.oru.msh.msh_9_message_type = "ORU^R01^ORU_R01".to_string();
+        ctx.kind = MessageKind::of(&ctx.oru);
+        assert_eq!(
+            pipeline.run(&mut ctx),
+            Outcome::Rejected {
+                by: "console".to_string()
+            }
+        );
+        assert_eq!(ctx.ack.msa.msa_1_acknowledgment_code, "AR");
+    }
+}
@@ -0,0 +1,285 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, warn};
+
+use crate::shutdown::Shutdown;
+use crate::transport::{self, DefaultTransport, Listener, Socket, Transport};
+
+/// Largest request head accepted; the API only serves GET requests, which have no body.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A GET request as far as the API cares: the path, the decoded query parameters and the
+/// headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    /// Names in lower case.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Parses the request line, e.g. `GET /alerts?priority=PH HTTP/1.1`, and the header lines.
+    fn parse(head: &str) -> Option<Request> {
+        let mut lines = head.lines();
+        let mut parts = lines.next()?.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        parts
+            .next()
+            .filter(|version| version.starts_with("HTTP/"))?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Some(Request {
+            method,
+            path: percent_decode(path)?,
+            query: query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    Some((percent_decode(name)?, percent_decode(value)?))
+                })
+                .collect::<Option<_>>()?,
+            headers: lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+                .collect(),
+        })
+    }
+
+    /// The first value of query parameter `name`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The first value of header `name`, given in lower case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Decodes `%XX` escapes and `+` as a space.
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.bytes();
+    while let Some(b) = rest.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [rest.next()?, rest.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+pub enum Body {
+    Full(Vec<u8>),
+    /// Sent chunk by chunk as they come, until the stream ends or the client goes away.
+    Stream(BoxStream<'static, Vec<u8>>),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Full(body) => write!(f, "Full({} bytes)", body.len()),
+            Body::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn json(value: &impl Serialize) -> Response {
+        let mut body = serde_json::to_vec_pretty(value).expect("responses serialize");
+        body.push(b'\n');
+        Response {
+            status: 200,
+            content_type: "application/json",
+            body: Body::Full(body),
+        }
+    }
+
+    pub fn text(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status,
+            content_type,
+            body: Body::Full(body.into()),
+        }
+    }
+
+    /// A `200` whose body is written as `chunks` yields it, e.g. Server-Sent Events.
+    pub fn stream(content_type: &'static str, chunks: BoxStream<'static, Vec<u8>>) -> Response {
+        Response {
+            status: 200,
+            content_type,
+            body: Body::Stream(chunks),
+        }
+    }
+
+    /// `{"error": message}` with the given status.
+    pub fn error(status: u16, message: impl Into<String>) -> Response {
+        #[derive(Serialize)]
+        struct Error {
+            error: String,
+        }
+        Response {
+            status,
+            ..Response::json(&Error {
+                error: message.into(),
+            })
+        }
+    }
+
+    pub fn not_found() -> Response {
+        Response::error(404, "not found")
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// Reads one request, answers it and closes the connection.
+async fn handle_connection(socket: Box<dyn Socket>, handler: Handler) -> io::Result<()> {
+    let mut socket = BufReader::new(socket);
+    let head = tokio::time::timeout(READ_TIMEOUT, async {
+        let mut head = Vec::new();
+        // The head ends with an empty line.
+        while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+            if socket.read_until(b'\n', &mut head).await? == 0 || head.len() > MAX_HEAD {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(head)
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no request in time"))??;
+
+    let text = String::from_utf8_lossy(&head);
+    let response = match Request::parse(&text) {
+        _ if head.len() > MAX_HEAD => Response::error(431, "request too large"),
+        None => Response::error(400, "malformed request"),
+        Some(request) if request.method != "GET" => Response::error(405, "only GET is supported"),
+        Some(request) => handler(&request),
+    };
+
+    let framing = match &response.body {
+        Body::Full(body) => format!("Content-Length: {}", body.len()),
+        // Without a length the body ends when the connection closes.
+        Body::Stream(_) => "Cache-Control: no-cache".to_string(),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n{}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        framing
+    );
+    let socket = socket.get_mut();
+    socket.write_all(head.as_bytes()).await?;
+    match response.body {
+        Body::Full(body) => socket.write_all(&body).await?,
+        Body::Stream(mut chunks) => {
+            socket.flush().await?;
+            while let Some(chunk) = chunks.next().await {
+                socket.write_all(&chunk).await?;
+                socket.flush().await?;
+            }
+        }
+    }
+    socket.shutdown().await
+}
+
+async fn serve(listener: Box<dyn Listener>, handler: Handler, shutdown: Shutdown) {
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = shutdown.wait() => break,
+        };
+        match accepted {
+            Ok((socket, _peer)) => {
+                let handler = Arc::clone(&handler);
+                connections.spawn(async move {
+                    if let Err(e) = handle_connection(socket, handler).await {
+                        debug!("Error serving HTTP request: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Error accepting HTTP connection: {}", e),
+        }
+    }
+    connections.shutdown().await;
+}
+
+/// A small HTTP/1.1 server for local tools: one GET request per connection, answered by
+/// `handler`. Streamed responses are cut off when the server shuts down.
+pub struct HttpServer {
+    local_address: String,
+    task: JoinHandle<()>,
+}
+
+impl HttpServer {
+    /// Listens on a loopback TCP address, `unix:PATH` or `mem:NAME`. The API has no
+    /// authentication, so other interfaces are refused.
+    pub async fn open(
+        address: &str,
+        handler: Handler,
+        shutdown: &Shutdown,
+    ) -> io::Result<HttpServer> {
+        transport::ensure_local(address)?;
+        let listener = DefaultTransport.listen(address).await.map_err(|e| {
+            io::Error::new(e.kind(), format!("cannot listen on {}: {}", address, e))
+        })?;
+        Ok(HttpServer {
+            local_address: listener.local_address()?,
+            task: tokio::spawn(serve(listener, handler, shutdown.child())),
+        })
+    }
+
+    pub fn local_address(&self) -> &str {
+        &self.local_address
+    }
+
+    /// Waits until the shutdown coordinator closes the server.
+    pub async fn wait(self) {
+        let _ = self.task.await;
+    }
+}
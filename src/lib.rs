@@ -1,3 +1,29 @@
+pub mod ack_policy;
+pub mod alert_store;
+pub mod api;
+pub mod audit;
+pub mod conformance;
+pub mod connection;
+pub mod control;
+pub mod device;
+pub mod encoding;
+pub mod handlers;
+pub mod http;
+pub mod load;
+pub mod logging;
+pub mod messages;
+pub mod metrics;
+pub mod mllp;
 pub mod mock_alert_mgr;
 pub mod mock_alert_rpt;
+pub mod outbound;
 pub mod pcd04_msg;
+pub mod profiles;
+pub mod scenario;
+pub mod segments;
+pub mod shutdown;
+pub mod technical;
+pub mod tls;
+pub mod transport;
+pub mod validate;
+pub mod vitals;
@@ -0,0 +1,399 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::connection::{self, ManagerConnection};
+use crate::device::{ActiveAlert, AlertSpec, DeviceConfig, ObservationSpec};
+use crate::pcd04_msg::PCD04Message;
+use crate::scenario::read_config;
+use crate::tls::{self, TlsConfig};
+use crate::transport::Transport;
+
+/// One class of simulated device; devices are spread over the mix by weight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoadMix {
+    pub weight: u32,
+    pub heartbeat_interval_ms: u64,
+    /// How often an alarm starts or ends; 0 disables alarms.
+    pub alarm_interval_ms: u64,
+}
+
+impl Default for LoadMix {
+    fn default() -> Self {
+        LoadMix {
+            weight: 1,
+            heartbeat_interval_ms: 1000,
+            alarm_interval_ms: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoadConfig {
+    /// `host:port`, or `unix:/path` for a manager on a Unix socket.
+    pub manager: String,
+    pub devices: usize,
+    pub duration_secs: u64,
+    pub ack_timeout_ms: u64,
+    /// Connect to the manager over mutually authenticated TLS.
+    pub tls: Option<TlsConfig>,
+    pub mix: Vec<LoadMix>,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        LoadConfig {
+            manager: "127.0.0.1:8888".to_string(),
+            devices: 10,
+            duration_secs: 60,
+            ack_timeout_ms: 5000,
+            tls: None,
+            mix: vec![LoadMix::default()],
+        }
+    }
+}
+
+impl LoadConfig {
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        read_config(path)
+    }
+
+    /// The transport to the manager, with the TLS certificates loaded if `tls` is set.
+    pub fn transport(&self) -> io::Result<Arc<dyn Transport>> {
+        tls::client_transport(self.tls.as_ref())
+    }
+
+    /// The mix entry device `n` belongs to.
+    fn mix_for(&self, n: usize) -> LoadMix {
+        let total: u64 = self.mix.iter().map(|m| m.weight as u64).sum();
+        if total == 0 {
+            return LoadMix::default();
+        }
+        let mut slot = n as u64 % total;
+        for mix in &self.mix {
+            if slot < mix.weight as u64 {
+                return mix.clone();
+            }
+            slot -= mix.weight as u64;
+        }
+        unreachable!()
+    }
+}
+
+/// Simulated device number `n` with its own EUI-64, bed and patient.
+pub fn load_device(n: usize) -> DeviceConfig {
+    let eui64 = format!("0050B6FFFE{:06X}", n + 1);
+    DeviceConfig {
+        name: format!("dev-{:04}", n + 1),
+        equipment_id: eui64.clone(),
+        eui64,
+        location: format!(
+            "Ward{}^Room{}^Bed{}^fac^^^building^floor",
+            n / 40 + 1,
+            n / 2 + 1,
+            n % 2 + 1
+        ),
+        patient_id: format!("LOAD{:06}^^^Hospital^PI", n + 1),
+        patient_name: format!("Patient^{}^^^L", n + 1),
+        ..Default::default()
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeviceStats {
+    sent: u64,
+    accepted: u64,
+    rejected: u64,
+    timeouts: u64,
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Latency {
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoadReport {
+    pub devices: usize,
+    pub elapsed_secs: f64,
+    pub sent: u64,
+    /// Messages answered with AA or CA.
+    pub accepted: u64,
+    /// Messages answered with AE, AR, CE or CR.
+    pub rejected: u64,
+    /// Messages with no ACK within the timeout.
+    pub timeouts: u64,
+    /// Connect, write and protocol errors.
+    pub errors: u64,
+    pub messages_per_sec: f64,
+    pub ack_latency: Option<Latency>,
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} devices, {} messages in {:.1}s ({:.1} msg/s)",
+            self.devices, self.sent, self.elapsed_secs, self.messages_per_sec
+        )?;
+        writeln!(
+            f,
+            "accepted {}, rejected {}, timeouts {}, errors {}",
+            self.accepted, self.rejected, self.timeouts, self.errors
+        )?;
+        match &self.ack_latency {
+            Some(l) => write!(
+                f,
+                "ACK latency p50 {:.2}ms, p90 {:.2}ms, p95 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+                l.p50_ms, l.p90_ms, l.p95_ms, l.p99_ms, l.max_ms
+            ),
+            None => write!(f, "no ACKs received"),
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1].as_secs_f64() * 1000.0
+}
+
+struct LoadDevice {
+    config: DeviceConfig,
+    mix: LoadMix,
+    transport: Arc<dyn Transport>,
+    connection: Option<ManagerConnection>,
+    alert: Option<ActiveAlert>,
+    alerts_raised: u64,
+    sequence: u64,
+    stats: DeviceStats,
+}
+
+impl LoadDevice {
+    async fn send(&mut self, load: &LoadConfig, mut msg: PCD04Message) {
+        self.sequence += 1;
+        msg.set_control_id(&format!("{}-{}", self.config.name, self.sequence));
+        self.stats.sent += 1;
+
+        if self.connection.is_none() {
+            let timeout = Duration::from_millis(load.ack_timeout_ms);
+            let connected =
+                ManagerConnection::connect_with(self.transport.as_ref(), &load.manager, timeout);
+            match connected.await {
+                Ok(connection) => self.connection = Some(connection),
+                Err(_) => {
+                    self.stats.errors += 1;
+                    return;
+                }
+            }
+        }
+
+        let started = Instant::now();
+        match self.connection.as_mut().unwrap().exchange(&msg).await {
+            Ok(ack) => {
+                self.stats.latencies.push(started.elapsed());
+                match ack.msa.msa_1_acknowledgment_code.as_str() {
+                    "AA" | "CA" => self.stats.accepted += 1,
+                    _ => self.stats.rejected += 1,
+                }
+            }
+            Err(e) => {
+                if connection::is_timeout(&e) {
+                    self.stats.timeouts += 1;
+                } else {
+                    self.stats.errors += 1;
+                }
+                // Start over on a fresh connection, as a device would after a failed exchange.
+                if let Some(connection) = self.connection.take() {
+                    connection.close().await;
+                }
+            }
+        }
+    }
+
+    /// Starts an SpO2 alarm, or ends the running one.
+    async fn toggle_alarm(&mut self, load: &LoadConfig) {
+        let msg = match self.alert.take() {
+            Some(mut alert) => {
+                alert.update += 1;
+                self.config.alert_message(&alert, "end", "inactive")
+            }
+            None => {
+                self.alerts_raised += 1;
+                let alert = ActiveAlert {
+                    id: format!("{}-{}", self.config.name, self.alerts_raised),
+                    spec: AlertSpec {
+                        code: "196670^MDC_EVT_LO^MDC".to_string(),
+                        text: "SpO2 low".to_string(),
+                        priority: "PM".to_string(),
+                        kind: "SP".to_string(),
+                        observation: Some(ObservationSpec {
+                            code: "150456^MDC_PULS_OXIM_SAT_O2^MDC".to_string(),
+                            value: "85".to_string(),
+                            unit: "262688^MDC_DIM_PERCENT^MDC".to_string(),
+                            value_type: "NM".to_string(),
+                        }),
+                    },
+                    update: 0,
+                };
+                let msg = self.config.alert_message(&alert, "start", "active");
+                self.alert = Some(alert);
+                msg
+            }
+        };
+        self.send(load, msg).await;
+    }
+
+    async fn run(mut self, load: &LoadConfig, offset: Duration, deadline: Instant) -> DeviceStats {
+        let heartbeat_every = Duration::from_millis(self.mix.heartbeat_interval_ms.max(1));
+        let alarm_every = Duration::from_millis(self.mix.alarm_interval_ms);
+        let start = Instant::now() + offset;
+        let mut next_heartbeat = start;
+        let mut next_alarm = (self.mix.alarm_interval_ms > 0).then(|| start + alarm_every);
+
+        loop {
+            let due = next_alarm.map_or(next_heartbeat, |a| a.min(next_heartbeat));
+            if due >= deadline {
+                tokio::time::sleep(deadline.saturating_duration_since(Instant::now())).await;
+                break;
+            }
+            tokio::time::sleep(due.saturating_duration_since(Instant::now())).await;
+
+            if next_alarm == Some(due) {
+                self.toggle_alarm(load).await;
+                next_alarm = Some(due + alarm_every);
+            } else {
+                let msg = self.config.heartbeat();
+                self.send(load, msg).await;
+                next_heartbeat = due + heartbeat_every;
+            }
+        }
+        if let Some(connection) = self.connection.take() {
+            connection.close().await;
+        }
+        self.stats
+    }
+}
+
+/// Runs `config.devices` simulated devices against the manager for the configured duration.
+///
+/// Each device has its own connection and task. Start times are staggered over one heartbeat
+/// interval so the manager sees a steady rate rather than bursts. Fails only if the transport
+/// can't be set up; per-message errors are counted in the report.
+pub async fn run_load(config: &LoadConfig) -> io::Result<LoadReport> {
+    let transport = config.transport()?;
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(config.duration_secs);
+    let shared = Arc::new(config.clone());
+
+    let handles: Vec<_> = (0..config.devices)
+        .map(|n| {
+            let mix = config.mix_for(n);
+            let offset = Duration::from_millis(
+                mix.heartbeat_interval_ms * n as u64 / config.devices.max(1) as u64,
+            );
+            let device = LoadDevice {
+                config: load_device(n),
+                mix,
+                transport: Arc::clone(&transport),
+                connection: None,
+                alert: None,
+                alerts_raised: 0,
+                sequence: 0,
+                stats: DeviceStats::default(),
+            };
+            let load = Arc::clone(&shared);
+            tokio::spawn(async move { device.run(&load, offset, deadline).await })
+        })
+        .collect();
+    let mut stats = Vec::with_capacity(handles.len());
+    for handle in handles {
+        stats.push(handle.await.expect("load device task panicked"));
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let mut latencies: Vec<Duration> = Vec::new();
+    let mut report = LoadReport {
+        devices: config.devices,
+        elapsed_secs: elapsed,
+        sent: 0,
+        accepted: 0,
+        rejected: 0,
+        timeouts: 0,
+        errors: 0,
+        messages_per_sec: 0.0,
+        ack_latency: None,
+    };
+    for s in stats {
+        report.sent += s.sent;
+        report.accepted += s.accepted;
+        report.rejected += s.rejected;
+        report.timeouts += s.timeouts;
+        report.errors += s.errors;
+        latencies.extend(s.latencies);
+    }
+    report.messages_per_sec = report.sent as f64 / elapsed.max(f64::EPSILON);
+
+    latencies.sort();
+    if !latencies.is_empty() {
+        report.ack_latency = Some(Latency {
+            p50_ms: percentile(&latencies, 50.0),
+            p90_ms: percentile(&latencies, 90.0),
+            p95_ms: percentile(&latencies, 95.0),
+            p99_ms: percentile(&latencies, 99.0),
+            max_ms: percentile(&latencies, 100.0),
+        });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ack_policy::{AckPolicy, AckRule};
+    use crate::mock_alert_mgr::{AlertManager, AlertMgrConfig};
+
+    #[tokio::test]
+    async fn commit_accepts_are_accepted() {
+        let mut manager = AlertManager::new(AlertMgrConfig {
+            listen_address: "mem:load-commit-accept".to_string(),
+            ack_policy: AckPolicy {
+                rules: vec![AckRule {
+                    ack_code: Some("CA".to_string()),
+                    ..Default::default()
+                }],
+            },
+            ..Default::default()
+        });
+        manager.start().await.unwrap();
+
+        let report = run_load(&LoadConfig {
+            manager: "mem:load-commit-accept".to_string(),
+            devices: 2,
+            duration_secs: 1,
+            mix: vec![LoadMix {
+                weight: 1,
+                heartbeat_interval_ms: 100,
+                alarm_interval_ms: 300,
+            }],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert!(report.sent > 0);
+        assert_eq!(report.accepted, report.sent);
+        assert_eq!(report.rejected, 0);
+        manager.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use std::env;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+
+use tracing_subscriber::EnvFilter;
+
+/// Filter used when neither `--log` nor `RUST_LOG` gives one.
+const DEFAULT_FILTER: &str = "info";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One line per event with the fields of its spans, for people.
+    #[default]
+    Human,
+    /// One JSON object per line with the event, its fields and its spans, for log shippers.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {} (human or json)", s)),
+        }
+    }
+}
+
+/// What the actors log and how. Logs go to stderr, so stdout stays free for reports.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogConfig {
+    /// Level and per-module directives in `RUST_LOG` syntax, e.g.
+    /// `warn,pcd_acm::mock_alert_mgr=debug`. None falls back to `RUST_LOG`, then `info`.
+    pub filter: Option<String>,
+    pub format: LogFormat,
+}
+
+impl LogConfig {
+    /// Takes `--log FILTER` and `--log-format human|json` out of `args`, wherever they are, and
+    /// returns the rest for the program's own parsing.
+    pub fn from_args(args: Vec<String>) -> Result<(LogConfig, Vec<String>), String> {
+        let mut config = LogConfig::default();
+        let mut rest = Vec::with_capacity(args.len());
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--log" => {
+                    config.filter = Some(args.next().ok_or("--log needs a filter")?);
+                }
+                "--log-format" => {
+                    config.format = args.next().ok_or("--log-format needs a format")?.parse()?;
+                }
+                _ => rest.push(arg),
+            }
+        }
+        Ok((config, rest))
+    }
+}
+
+/// Installs the global subscriber. Call once, at the start of `main`.
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let filter = match &config.filter {
+        Some(filter) => filter.clone(),
+        None => env::var(EnvFilter::DEFAULT_ENV).unwrap_or_else(|_| DEFAULT_FILTER.to_string()),
+    };
+    let filter =
+        EnvFilter::try_new(&filter).map_err(|e| format!("invalid log filter {}: {}", filter, e))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    let installed = match config.format {
+        LogFormat::Human => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .try_init(),
+    };
+    installed.map_err(|e| format!("cannot install logger: {}", e))
+}
+
+/// [`LogConfig::from_args`] followed by [`init`]; returns the arguments that are left.
+pub fn init_from_args(args: Vec<String>) -> Result<Vec<String>, String> {
+    let (config, rest) = LogConfig::from_args(args)?;
+    init(&config)?;
+    Ok(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn logging_options_are_taken_out() {
+        let (config, rest) = LogConfig::from_args(args(
+            "send-alert --log warn,pcd_acm=debug --metric spo2 --log-format json --value 85",
+        ))
+        .unwrap();
+        assert_eq!(
+            config,
+            LogConfig {
+                filter: Some("warn,pcd_acm=debug".to_string()),
+                format: LogFormat::Json,
+            }
+        );
+        assert_eq!(rest, args("send-alert --metric spo2 --value 85"));
+
+        let (config, rest) = LogConfig::from_args(args("heartbeat")).unwrap();
+        assert_eq!(config, LogConfig::default());
+        assert_eq!(rest, args("heartbeat"));
+    }
+
+    #[test]
+    fn bad_logging_options() {
+        assert_eq!(
+            LogConfig::from_args(args("heartbeat --log")),
+            Err("--log needs a filter".to_string())
+        );
+        assert_eq!(
+            LogConfig::from_args(args("--log-format")),
+            Err("--log-format needs a format".to_string())
+        );
+        assert_eq!(
+            LogConfig::from_args(args("--log-format xml")),
+            Err("unknown log format xml (human or json)".to_string())
+        );
+    }
+}
@@ -1,19 +1,32 @@
-mod mock_alert_mgr;
-mod mock_alert_rpt;
-mod pcd04_msg;
+use tracing::{error, info};
 
-fn main() {
-    let alert_mgr_handle = std::thread::spawn(|| mock_alert_mgr::run_mock_alert_mgr());
-    let alert_rpt_handle = std::thread::spawn(|| mock_alert_rpt::run_mock_alert_rpt());
+use pcd_acm::logging::{self, LogConfig};
+use pcd_acm::shutdown::Shutdown;
+use pcd_acm::{mock_alert_mgr, mock_alert_rpt};
 
-    // Join the threads and handle the result
-    thread_result(alert_mgr_handle.join(), "mock_alert_mgr");
-    thread_result(alert_rpt_handle.join(), "mock_alert_rpt");
+#[tokio::main]
+async fn main() {
+    if let Err(e) = logging::init(&LogConfig::default()) {
+        eprintln!("{}", e);
+    }
+    // One coordinator for both actors: Ctrl+C, or `q` at the reporter prompt, stops them together.
+    let shutdown = Shutdown::new();
+    shutdown.on_ctrl_c();
+    let alert_mgr_handle = tokio::spawn(mock_alert_mgr::run_mock_alert_mgr(shutdown.clone()));
+
+    let rpt_result = mock_alert_rpt::run_mock_alert_rpt(shutdown.clone()).await;
+    // The reporter may have stopped on its own, e.g. when its queue file can't be read.
+    shutdown.trigger();
+    task_result(rpt_result.map(drop), "mock_alert_rpt");
+    match alert_mgr_handle.await {
+        Ok(result) => task_result(result.map(drop), "mock_alert_mgr"),
+        Err(err) => error!("Error joining mock_alert_mgr task: {:?}", err),
+    }
 }
 
-fn thread_result(result: std::thread::Result<()>, thread_name: &str) {
+fn task_result(result: std::io::Result<()>, task_name: &str) {
     match result {
-        Ok(()) => println!("{} thread joined successfully.", thread_name),
-        Err(err) => eprintln!("Error joining {} thread: {:?}", thread_name, err),
+        Ok(()) => info!(task = task_name, "Finished"),
+        Err(err) => error!(task = task_name, "Error running: {}", err),
     }
 }
@@ -0,0 +1,463 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::{self, Charset, Delimiters, EncodingError};
+use crate::segments::{RawSegment, Segment, ERR, MSA, MSH, NTE, OBR, OBX, PID, PRT, PV1};
+
+pub const SEGMENT_TERMINATOR: char = '\r';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Encoding(EncodingError),
+    Empty,
+    MissingSegment(&'static str),
+    UnexpectedSegment { id: String, index: usize },
+    UnsupportedMessageType(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Encoding(e) => write!(f, "{}", e),
+            ParseError::Empty => write!(f, "message is empty"),
+            ParseError::MissingSegment(id) => write!(f, "missing {} segment", id),
+            ParseError::UnexpectedSegment { id, index } => {
+                write!(f, "unexpected {} segment at position {}", id, index + 1)
+            }
+            ParseError::UnsupportedMessageType(t) => write!(f, "unsupported message type {}", t),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<EncodingError> for ParseError {
+    fn from(e: EncodingError) -> Self {
+        ParseError::Encoding(e)
+    }
+}
+
+/// Splits a message into raw segments, honouring the delimiters declared in its MSH and the
+/// MSH-18 character set for `\Xhh\` escapes.
+pub fn parse_segments(text: &str) -> Result<(Delimiters, Vec<RawSegment>), ParseError> {
+    let mut lines = text
+        .split(['\r', '\n'])
+        .map(str::trim_start)
+        .filter(|line| !line.is_empty());
+    let first = lines.next().ok_or(ParseError::Empty)?;
+    let delimiters = Delimiters::from_msh(first)?;
+
+    // MSH-18 is plain ASCII, so a first pass with the default charset is enough to find it.
+    let msh = RawSegment::parse(first, &delimiters, Charset::default());
+    let charset = Charset::from_msh_18(msh.component(18, 1))?;
+
+    let mut segments = vec![RawSegment::parse(first, &delimiters, charset)];
+    segments.extend(lines.map(|line| RawSegment::parse(line, &delimiters, charset)));
+    Ok((delimiters, segments))
+}
+
+fn encode_segments(segments: &[RawSegment], delimiters: &Delimiters, charset: Charset) -> String {
+    segments
+        .iter()
+        .map(|segment| {
+            let mut line = segment.encode(delimiters, charset);
+            line.push(SEGMENT_TERMINATOR);
+            line
+        })
+        .collect()
+}
+
+pub(crate) fn charset_of(msh: &MSH) -> Charset {
+    msh.msh_18_character_set
+        .first()
+        .and_then(|value| Charset::from_msh_18(value).ok())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatientGroup {
+    pub pid: PID,
+    pub pv1: Option<PV1>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObservationGroup {
+    pub obx: OBX,
+    pub prt: Vec<PRT>,
+    pub nte: Vec<NTE>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderGroup {
+    pub obr: OBR,
+    pub prt: Vec<PRT>,
+    pub observations: Vec<ObservationGroup>,
+}
+
+/// The ORU structure shared by ORU^R40 (PCD-04), ORU^R41 (PCD-05) and ORU^R42 (PCD-06).
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Oru {
+    pub msh: MSH,
+    pub patient: Option<PatientGroup>,
+    pub orders: Vec<OrderGroup>,
+}
+
+/// A general acknowledgment with optional error details.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ack {
+    pub msh: MSH,
+    pub msa: MSA,
+    pub err: Vec<ERR>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum Message {
+    Oru(Oru),
+    Ack(Ack),
+}
+
+impl Oru {
+    pub fn observations(&self) -> impl Iterator<Item = &ObservationGroup> {
+        self.orders.iter().flat_map(|order| &order.observations)
+    }
+
+    pub fn observations_mut(&mut self) -> impl Iterator<Item = &mut ObservationGroup> {
+        self.orders
+            .iter_mut()
+            .flat_map(|order| &mut order.observations)
+    }
+
+    pub fn to_segments(&self) -> Vec<RawSegment> {
+        let mut segments = vec![self.msh.to_raw()];
+        if let Some(patient) = &self.patient {
+            segments.push(patient.pid.to_raw());
+            segments.extend(patient.pv1.iter().map(Segment::to_raw));
+        }
+        for order in &self.orders {
+            segments.push(order.obr.to_raw());
+            segments.extend(order.prt.iter().map(Segment::to_raw));
+            for observation in &order.observations {
+                segments.push(observation.obx.to_raw());
+                segments.extend(observation.prt.iter().map(Segment::to_raw));
+                segments.extend(observation.nte.iter().map(Segment::to_raw));
+            }
+        }
+        segments
+    }
+
+    pub fn from_segments(segments: &[RawSegment]) -> Result<Self, ParseError> {
+        let mut iter = segments.iter().enumerate().peekable();
+        let msh = match iter.next() {
+            Some((_, raw)) if raw.id() == MSH::ID => MSH::from_raw(raw),
+            _ => return Err(ParseError::MissingSegment(MSH::ID)),
+        };
+        let mut oru = Oru {
+            msh,
+            ..Default::default()
+        };
+
+        if let Some((_, raw)) = iter.next_if(|(_, raw)| raw.id() == PID::ID) {
+            let pv1 = iter
+                .next_if(|(_, raw)| raw.id() == PV1::ID)
+                .map(|(_, raw)| PV1::from_raw(raw));
+            oru.patient = Some(PatientGroup {
+                pid: PID::from_raw(raw),
+                pv1,
+            });
+        }
+
+        for (index, raw) in iter {
+            match raw.id() {
+                "OBR" => oru.orders.push(OrderGroup {
+                    obr: OBR::from_raw(raw),
+                    ..Default::default()
+                }),
+                "OBX" => match oru.orders.last_mut() {
+                    Some(order) => order.observations.push(ObservationGroup {
+                        obx: OBX::from_raw(raw),
+                        ..Default::default()
+                    }),
+                    None => return Err(unexpected(raw, index)),
+                },
+                "PRT" => {
+                    let prt = PRT::from_raw(raw);
+                    match oru.orders.last_mut() {
+                        Some(order) => match order.observations.last_mut() {
+                            Some(observation) => observation.prt.push(prt),
+                            None => order.prt.push(prt),
+                        },
+                        None => return Err(unexpected(raw, index)),
+                    }
+                }
+                "NTE" => match oru
+                    .orders
+                    .last_mut()
+                    .and_then(|o| o.observations.last_mut())
+                {
+                    Some(observation) => observation.nte.push(NTE::from_raw(raw)),
+                    None => return Err(unexpected(raw, index)),
+                },
+                // Z-segments are site specific and may appear anywhere.
+                id if id.starts_with('Z') => {}
+                _ => return Err(unexpected(raw, index)),
+            }
+        }
+        Ok(oru)
+    }
+
+    pub fn encode(&self, delimiters: &Delimiters) -> String {
+        encode_segments(&self.to_segments(), delimiters, charset_of(&self.msh))
+    }
+}
+
+impl Ack {
+    /// Builds the acknowledgment for `original` with the given MSA-1 code (AA, AE or AR).
+    pub fn for_message(original: &MSH, code: &str, timestamp: &str) -> Self {
+        let msh = MSH {
+            msh_3_sending_application: original.msh_5_receiving_application.clone(),
+            msh_4_sending_facility: original.msh_6_receiving_facility.clone(),
+            msh_5_receiving_application: original.msh_3_sending_application.clone(),
+            msh_6_receiving_facility: original.msh_4_sending_facility.clone(),
+            msh_7_date_time_of_message: timestamp.to_string(),
+            msh_9_message_type: format!("ACK^{}^ACK", original.trigger_event()),
+            msh_10_message_control_id: format!("{}-ACK", original.msh_10_message_control_id),
+            msh_11_processing_id: original.msh_11_processing_id.clone(),
+            msh_12_version_id: original.msh_12_version_id.clone(),
+            msh_15_accept_acknowledgment_type: "NE".to_string(),
+            msh_16_application_acknowledgment_type: "NE".to_string(),
+            msh_18_character_set: original.msh_18_character_set.clone(),
+            ..Default::default()
+        };
+        Ack {
+            msh,
+            msa: MSA {
+                msa_1_acknowledgment_code: code.to_string(),
+                msa_2_message_control_id: original.msh_10_message_control_id.clone(),
+                ..Default::default()
+            },
+            err: Vec::new(),
+        }
+    }
+
+    pub fn to_segments(&self) -> Vec<RawSegment> {
+        let mut segments = vec![self.msh.to_raw(), self.msa.to_raw()];
+        segments.extend(self.err.iter().map(Segment::to_raw));
+        segments
+    }
+
+    pub fn from_segments(segments: &[RawSegment]) -> Result<Self, ParseError> {
+        let mut iter = segments.iter().enumerate();
+        let msh = match iter.next() {
+            Some((_, raw)) if raw.id() == MSH::ID => MSH::from_raw(raw),
+            _ => return Err(ParseError::MissingSegment(MSH::ID)),
+        };
+        let msa = match iter.next() {
+            Some((_, raw)) if raw.id() == MSA::ID => MSA::from_raw(raw),
+            _ => return Err(ParseError::MissingSegment(MSA::ID)),
+        };
+        let mut err = Vec::new();
+        for (index, raw) in iter {
+            match raw.id() {
+                "ERR" => err.push(ERR::from_raw(raw)),
+                id if id.starts_with('Z') => {}
+                _ => return Err(unexpected(raw, index)),
+            }
+        }
+        Ok(Ack { msh, msa, err })
+    }
+
+    pub fn encode(&self, delimiters: &Delimiters) -> String {
+        encode_segments(&self.to_segments(), delimiters, charset_of(&self.msh))
+    }
+}
+
+fn unexpected(raw: &RawSegment, index: usize) -> ParseError {
+    ParseError::UnexpectedSegment {
+        id: raw.id().to_string(),
+        index,
+    }
+}
+
+impl Message {
+    pub fn msh(&self) -> &MSH {
+        match self {
+            Message::Oru(oru) => &oru.msh,
+            Message::Ack(ack) => &ack.msh,
+        }
+    }
+
+    pub fn encode(&self, delimiters: &Delimiters) -> String {
+        match self {
+            Message::Oru(oru) => oru.encode(delimiters),
+            Message::Ack(ack) => ack.encode(delimiters),
+        }
+    }
+
+    /// Encodes the message into bytes in the character set named by its MSH-18.
+    pub fn to_bytes(&self, delimiters: &Delimiters) -> Result<Vec<u8>, EncodingError> {
+        charset_of(self.msh()).encode(&self.encode(delimiters))
+    }
+
+    /// Decodes raw bytes using their MSH-18 character set and parses them.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        encoding::decode_message(bytes)?.parse()
+    }
+}
+
+impl FromStr for Message {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (_, segments) = parse_segments(s)?;
+        let msh = MSH::from_raw(&segments[0]);
+        match msh.message_code() {
+            "ORU" => Oru::from_segments(&segments).map(Message::Oru),
+            "ACK" => Ack::from_segments(&segments).map(Message::Ack),
+            _ => Err(ParseError::UnsupportedMessageType(
+                msh.msh_9_message_type.clone(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORU_R40: &str = "MSH|^~\\&|AcmeInc^ACDE48234567ABCD^EUI-64|||\
+        |20261018120000+0000||ORU^R40^ORU_R40|MSG0001|P|2.6|||AL|NE||UNICODE UTF-8\r\
+        PID|||HO2009001^^^Hospital^PI||Doe^John^^^^^L\r\
+        PV1||I|ICU^Bed 3^^Ward 7\r\
+        OBR|1|AB12^AcmeAH^ACDE48234567ABCD^EUI-64|CD12^AcmeAH^ACDE48234567ABCD^EUI-64\
+        |196616^MDC_EVT_ALARM^MDC|||20261018120000+0000\r\
+        PRT|1|UC||SB^Send by^participation||||||AcmeInc^ACDE48234567ABCD^EUI-64\r\
+        OBX|1|ST|196674^MDC_EVT_DESAT^MDC|1.0.0.0.1|SpO2 \\T\\ pulse low|||PM~SP|||F\
+        |||20261018120000+0000\r\
+        PRT|2|UC||OP^Operator^participation|nurse^Jane\r\
+        NTE|1||First note~Second note\r\
+        OBX|2|NM|150456^MDC_PULS_OXIM_SAT_O2^MDC|1.0.0.0.2|85|262688^MDC_DIM_PERCENT^MDC\
+        |90-100|L|||F|||20261018120000+0000\r";
+
+    const ACK: &str = "MSH|^~\\&|ACM|Hospital|AcmeInc^ACDE48234567ABCD^EUI-64||20261018120001+0000\
+        ||ACK^R40^ACK|MSG0001-ACK|P|2.6|||NE|NE||UNICODE UTF-8\r\
+        MSA|AE|MSG0001|Unknown alert code\r\
+        ERR||OBX^1^3|103^Table value not found^HL70357|E||||Unsupported observation\r";
+
+    #[test]
+    fn oru_round_trip() {
+        let Message::Oru(oru) = ORU_R40.parse().unwrap() else {
+            panic!("not an ORU");
+        };
+        assert_eq!(oru.msh.trigger_event(), "R40");
+        assert_eq!(oru.msh.msh_18_character_set, ["UNICODE UTF-8"]);
+
+        let patient = oru.patient.as_ref().unwrap();
+        assert_eq!(patient.pid.pid_5_patient_name, ["Doe^John^^^^^L"]);
+        assert_eq!(
+            patient
+                .pv1
+                .as_ref()
+                .unwrap()
+                .pv1_3_assigned_patient_location,
+            "ICU^Bed 3^^Ward 7"
+        );
+
+        assert_eq!(oru.orders.len(), 1);
+        let order = &oru.orders[0];
+        assert_eq!(order.prt.len(), 1);
+        let observations: Vec<_> = oru.observations().collect();
+        assert_eq!(observations.len(), 2);
+        assert_eq!(observations[0].obx.obx_8_interpretation_codes, ["PM", "SP"]);
+        assert_eq!(
+            observations[0].obx.obx_5_observation_value,
+            ["SpO2 \\T\\ pulse low"]
+        );
+        assert_eq!(
+            observations[0].prt[0].prt_5_participation_person,
+            ["nurse^Jane"]
+        );
+        assert_eq!(
+            observations[0].nte[0].nte_3_comment,
+            ["First note", "Second note"]
+        );
+        assert_eq!(observations[1].obx.first_value(), "85");
+        assert!(observations[1].prt.is_empty());
+
+        assert_eq!(oru.encode(&Delimiters::default()), ORU_R40);
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        let message: Message = ACK.parse().unwrap();
+        let Message::Ack(ack) = &message else {
+            panic!("not an ACK");
+        };
+        assert_eq!(ack.msa.msa_1_acknowledgment_code, "AE");
+        assert_eq!(ack.msa.msa_2_message_control_id, "MSG0001");
+        assert_eq!(ack.err.len(), 1);
+        assert_eq!(ack.err[0].err_2_error_location, ["OBX^1^3"]);
+        assert_eq!(ack.err[0].err_4_severity, "E");
+
+        assert_eq!(message.encode(&Delimiters::default()), ACK);
+        assert_eq!(
+            Message::from_bytes(&message.to_bytes(&Delimiters::default()).unwrap()).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn round_trip_with_custom_delimiters() {
+        let text = ORU_R40
+            .replace("|^~\\&|", "#$*@%#")
+            .replace('|', "#")
+            .replace('^', "$")
+            .replace('~', "*")
+            .replace("\\T\\", "@T@");
+        let (delimiters, segments) = parse_segments(&text).unwrap();
+        let oru = Oru::from_segments(&segments).unwrap();
+
+        // Field values are held with the default delimiters whatever the message used.
+        let Message::Oru(expected) = ORU_R40.parse().unwrap() else {
+            panic!("not an ORU");
+        };
+        assert_eq!(oru, expected);
+        assert_eq!(oru.encode(&delimiters), text);
+    }
+
+    #[test]
+    fn ack_for_message_reverses_the_route() {
+        let Message::Oru(oru) = ORU_R40.parse().unwrap() else {
+            panic!("not an ORU");
+        };
+        let ack = Ack::for_message(&oru.msh, "AA", "20261018120001+0000");
+        assert_eq!(ack.msh.msh_9_message_type, "ACK^R40^ACK");
+        assert_eq!(
+            ack.msh.msh_5_receiving_application,
+            oru.msh.msh_3_sending_application
+        );
+        assert_eq!(ack.msa.msa_2_message_control_id, "MSG0001");
+
+        let parsed: Message = ack.encode(&Delimiters::default()).parse().unwrap();
+        assert_eq!(parsed, Message::Ack(ack));
+    }
+
+    #[test]
+    fn misplaced_segments_are_rejected() {
+        let text = "MSH|^~\\&|||||||ORU^R40^ORU_R40|1|P|2.6\rOBX|1|NM|x\r";
+        assert_eq!(
+            text.parse::<Message>(),
+            Err(ParseError::UnexpectedSegment {
+                id: "OBX".to_string(),
+                index: 1
+            })
+        );
+        let text = "MSH|^~\\&|||||||ACK^R40^ACK|1|P|2.6\rERR||x\r";
+        assert_eq!(
+            text.parse::<Message>(),
+            Err(ParseError::MissingSegment("MSA"))
+        );
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::handlers::MessageKind;
+use crate::http::{Handler, Response};
+
+pub const MESSAGES_RECEIVED: &str = "acm_messages_received_total";
+pub const MESSAGES_SENT: &str = "acm_messages_sent_total";
+pub const ACKS_SENT: &str = "acm_acks_sent_total";
+pub const ACKS_RECEIVED: &str = "acm_acks_received_total";
+pub const ACK_LATENCY: &str = "acm_ack_latency_seconds";
+pub const PARSE_FAILURES: &str = "acm_parse_failures_total";
+pub const VALIDATION_FAILURES: &str = "acm_validation_failures_total";
+pub const RECONNECTS: &str = "acm_reconnects_total";
+pub const HEARTBEAT_JITTER: &str = "acm_heartbeat_jitter_seconds";
+pub const ACTIVE_ALERTS: &str = "acm_active_alerts";
+pub const CONNECTED_SOURCES: &str = "acm_connected_sources";
+pub const MANAGER_CONNECTED: &str = "acm_manager_connected";
+pub const QUEUED_ALERTS: &str = "acm_queued_alerts";
+
+/// Upper bounds in seconds for ACK round trips, from a local manager to a slow one near the
+/// usual 5 s timeout.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Upper bounds in seconds for how far heartbeats stray from their schedule.
+const JITTER_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram(&'static [f64]),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Series {
+    Value(f64),
+    /// Observations per bucket (not cumulative), then their sum and count.
+    Histogram {
+        buckets: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug)]
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    series: BTreeMap<Labels, Series>,
+}
+
+/// Counters, gauges and histograms of one actor, rendered in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    fn register(self, name: &'static str, help: &'static str, kind: Kind) -> Self {
+        self.families.lock().unwrap().insert(
+            name,
+            Family {
+                help,
+                kind,
+                series: BTreeMap::new(),
+            },
+        );
+        self
+    }
+
+    pub fn counter(self, name: &'static str, help: &'static str) -> Self {
+        self.register(name, help, Kind::Counter)
+    }
+
+    pub fn gauge(self, name: &'static str, help: &'static str) -> Self {
+        self.register(name, help, Kind::Gauge)
+    }
+
+    /// A histogram with the given bucket upper bounds, in ascending order.
+    pub fn histogram(
+        self,
+        name: &'static str,
+        help: &'static str,
+        buckets: &'static [f64],
+    ) -> Self {
+        self.register(name, help, Kind::Histogram(buckets))
+    }
+
+    fn update(&self, name: &str, labels: &[(&str, &str)], update: impl FnOnce(Kind, &mut Series)) {
+        let mut families = self.families.lock().unwrap();
+        let family = families
+            .get_mut(name)
+            .unwrap_or_else(|| panic!("metric {} is not registered", name));
+        let labels = labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let kind = family.kind;
+        let series = family.series.entry(labels).or_insert_with(|| match kind {
+            Kind::Histogram(bounds) => Series::Histogram {
+                buckets: vec![0; bounds.len()],
+                sum: 0.0,
+                count: 0,
+            },
+            _ => Series::Value(0.0),
+        });
+        update(kind, series);
+    }
+
+    pub fn inc(&self, name: &str, labels: &[(&str, &str)]) {
+        self.update(name, labels, |_, series| {
+            if let Series::Value(value) = series {
+                *value += 1.0;
+            }
+        });
+    }
+
+    pub fn set(&self, name: &str, labels: &[(&str, &str)], to: f64) {
+        self.update(name, labels, |_, series| {
+            if let Series::Value(value) = series {
+                *value = to;
+            }
+        });
+    }
+
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        self.update(name, labels, |kind, series| {
+            if let (
+                Kind::Histogram(bounds),
+                Series::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                },
+            ) = (kind, series)
+            {
+                // Observations above the last bound only show in the +Inf bucket.
+                if let Some(bucket) = bounds.iter().position(|bound| seconds <= *bound) {
+                    buckets[bucket] += 1;
+                }
+                *sum += seconds;
+                *count += 1;
+            }
+        });
+    }
+
+    /// Drops every series of a gauge, e.g. before setting the current per-priority counts so
+    /// priorities with no alerts left disappear.
+    pub fn clear(&self, name: &str) {
+        if let Some(family) = self.families.lock().unwrap().get_mut(name) {
+            family.series.clear();
+        }
+    }
+
+    /// The text exposition format, version 0.0.4.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram(_) => "histogram",
+            };
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, series) in &family.series {
+                match (family.kind, series) {
+                    (
+                        Kind::Histogram(bounds),
+                        Series::Histogram {
+                            buckets,
+                            sum,
+                            count,
+                        },
+                    ) => {
+                        let mut cumulative = 0;
+                        for (bound, n) in bounds.iter().zip(buckets) {
+                            cumulative += n;
+                            let le = bound.to_string();
+                            let labels = label_set(labels, Some(&le));
+                            let _ = writeln!(out, "{}_bucket{} {}", name, labels, cumulative);
+                        }
+                        let _ = writeln!(
+                            out,
+                            "{}_bucket{} {}",
+                            name,
+                            label_set(labels, Some("+Inf")),
+                            count
+                        );
+                        let _ = writeln!(out, "{}_sum{} {}", name, label_set(labels, None), sum);
+                        let _ =
+                            writeln!(out, "{}_count{} {}", name, label_set(labels, None), count);
+                    }
+                    (_, Series::Value(value)) => {
+                        let _ = writeln!(out, "{}{} {}", name, label_set(labels, None), value);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        out
+    }
+}
+
+/// `{name="value",…}`, or nothing when there are no labels.
+fn label_set(labels: &Labels, le: Option<&str>) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .chain(le.map(|le| ("le", le)))
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// The `type` label of a message.
+pub fn kind_label(kind: MessageKind) -> &'static str {
+    match kind {
+        MessageKind::Heartbeat => "heartbeat",
+        MessageKind::AlertStart => "alert_start",
+        MessageKind::AlertUpdate => "alert_update",
+        MessageKind::AlertEnd => "alert_end",
+        MessageKind::Technical => "technical",
+        MessageKind::Unknown => "unknown",
+    }
+}
+
+/// What the manager counts.
+pub fn manager() -> Metrics {
+    Metrics::new()
+        .counter(
+            MESSAGES_RECEIVED,
+            "ORU messages received, by type and alarm priority.",
+        )
+        .counter(ACKS_SENT, "Acknowledgments sent, by MSA-1 code.")
+        .histogram(
+            ACK_LATENCY,
+            "Time from receiving a message to sending its acknowledgment.",
+            LATENCY_BUCKETS,
+        )
+        .counter(
+            PARSE_FAILURES,
+            "Frames that could not be decoded or parsed as HL7.",
+        )
+        .counter(
+            VALIDATION_FAILURES,
+            "Messages with PCD-04 conformance errors (conformance mode only).",
+        )
+        .histogram(
+            HEARTBEAT_JITTER,
+            "Difference between consecutive heartbeat intervals of a source.",
+            JITTER_BUCKETS,
+        )
+        .gauge(
+            ACTIVE_ALERTS,
+            "Alerts started and not yet ended, by priority.",
+        )
+        .gauge(CONNECTED_SOURCES, "Sources whose connection is open.")
+}
+
+/// What the reporter counts.
+pub fn reporter() -> Metrics {
+    Metrics::new()
+        .counter(
+            MESSAGES_SENT,
+            "ORU messages sent, by type and alarm priority.",
+        )
+        .counter(ACKS_RECEIVED, "Acknowledgments received, by MSA-1 code.")
+        .histogram(
+            ACK_LATENCY,
+            "Time from sending a message to receiving its acknowledgment.",
+            LATENCY_BUCKETS,
+        )
+        .counter(
+            PARSE_FAILURES,
+            "Answers from the manager that could not be parsed as an ACK.",
+        )
+        .counter(
+            RECONNECTS,
+            "Connections opened to a manager after the first one.",
+        )
+        .histogram(
+            HEARTBEAT_JITTER,
+            "How late heartbeats were sent compared to their schedule.",
+            JITTER_BUCKETS,
+        )
+        .gauge(
+            MANAGER_CONNECTED,
+            "1 while connected to the manager, by address.",
+        )
+        .gauge(QUEUED_ALERTS, "Alerts waiting for the manager.")
+}
+
+/// Serves `GET /metrics`. `refresh` updates gauges that are read from state rather than counted
+/// as things happen, right before each scrape.
+pub fn routes(
+    metrics: Arc<Metrics>,
+    refresh: impl Fn(&Metrics) + Send + Sync + 'static,
+) -> Handler {
+    Arc::new(move |request| match request.path.as_str() {
+        "/metrics" => {
+            refresh(&metrics);
+            Response::text(200, "text/plain; version=0.0.4", metrics.render())
+        }
+        _ => Response::not_found(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_and_gauges() {
+        let metrics = Metrics::new()
+            .counter(ACKS_SENT, "Acknowledgments sent.")
+            .gauge(QUEUED_ALERTS, "Alerts waiting.");
+        metrics.inc(ACKS_SENT, &[("code", "AA")]);
+        metrics.inc(ACKS_SENT, &[("code", "AA")]);
+        metrics.inc(ACKS_SENT, &[("code", "AE")]);
+        metrics.set(QUEUED_ALERTS, &[], 3.0);
+
+        assert_eq!(
+            metrics.render(),
+            "# HELP acm_acks_sent_total Acknowledgments sent.\n\
+             # TYPE acm_acks_sent_total counter\n\
+             acm_acks_sent_total{code=\"AA\"} 2\n\
+             acm_acks_sent_total{code=\"AE\"} 1\n\
+             # HELP acm_queued_alerts Alerts waiting.\n\
+             # TYPE acm_queued_alerts gauge\n\
+             acm_queued_alerts 3\n"
+        );
+
+        metrics.clear(QUEUED_ALERTS);
+        assert!(metrics
+            .render()
+            .ends_with("# TYPE acm_queued_alerts gauge\n"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = Metrics::new().gauge(MANAGER_CONNECTED, "Connected.");
+        metrics.set(
+            MANAGER_CONNECTED,
+            &[("address", "C:\\pipe \"acm\"\nnext"), ("tls", "1")],
+            1.0,
+        );
+        assert!(metrics.render().contains(
+            "acm_manager_connected{address=\"C:\\\\pipe \\\"acm\\\"\\nnext\",tls=\"1\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::new().histogram(ACK_LATENCY, "Latency.", &[0.01, 0.1]);
+        for ms in [5, 50, 60, 500] {
+            metrics.observe(ACK_LATENCY, &[], Duration::from_millis(ms));
+        }
+        let text = metrics.render();
+        assert!(text.contains("# TYPE acm_ack_latency_seconds histogram\n"));
+        assert!(text.contains(
+            "acm_ack_latency_seconds_bucket{le=\"0.01\"} 1\n\
+             acm_ack_latency_seconds_bucket{le=\"0.1\"} 3\n\
+             acm_ack_latency_seconds_bucket{le=\"+Inf\"} 4\n\
+             acm_ack_latency_seconds_sum 0.615\n\
+             acm_ack_latency_seconds_count 4\n"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "metric acm_reconnects_total is not registered")]
+    fn unregistered_metrics_panic() {
+        manager().inc(RECONNECTS, &[]);
+    }
+}
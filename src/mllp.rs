@@ -0,0 +1,234 @@
+use std::io::{self, BufRead, Write};
+
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub const START_BLOCK: u8 = 0x0B;
+pub const END_BLOCK: u8 = 0x1C;
+pub const CARRIAGE_RETURN: u8 = 0x0D;
+
+/// Largest payload accepted by default. A peer that sends more without an end block is cut off, so
+/// a connection never buffers more than this.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Wraps one message in an MLLP frame and writes it.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 3);
+    frame.push(START_BLOCK);
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(&[END_BLOCK, CARRIAGE_RETURN]);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Reads the next MLLP frame and returns its payload, or `None` once the peer has closed the
+/// connection between frames.
+pub fn read_frame<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut skipped = Vec::new();
+    if reader.read_until(START_BLOCK, &mut skipped)? == 0 {
+        return Ok(None);
+    }
+    if skipped.last() != Some(&START_BLOCK) {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed before start of frame",
+        ));
+    }
+
+    let mut payload = Vec::new();
+    reader.read_until(END_BLOCK, &mut payload)?;
+    if payload.pop() != Some(END_BLOCK) {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed inside a frame",
+        ));
+    }
+
+    // The trailing carriage return is optional in practice; only consume it if present.
+    if reader.fill_buf()?.first() == Some(&CARRIAGE_RETURN) {
+        reader.consume(1);
+    }
+    Ok(Some(payload))
+}
+
+/// MLLP framing for async streams: each item is the payload of one frame.
+#[derive(Debug, Clone)]
+pub struct MllpCodec {
+    max_frame_len: usize,
+    /// How much of the buffered frame has already been searched for the end block.
+    scanned: usize,
+}
+
+impl MllpCodec {
+    pub fn new() -> Self {
+        MllpCodec::with_max_frame_len(DEFAULT_MAX_FRAME_LEN)
+    }
+
+    pub fn with_max_frame_len(max_frame_len: usize) -> Self {
+        MllpCodec {
+            max_frame_len,
+            scanned: 0,
+        }
+    }
+}
+
+impl Default for MllpCodec {
+    fn default() -> Self {
+        MllpCodec::new()
+    }
+}
+
+impl Decoder for MllpCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        // Anything before the start block, such as the carriage return ending the previous
+        // frame, is skipped.
+        match src.iter().position(|&b| b == START_BLOCK) {
+            Some(0) => {}
+            Some(n) => {
+                src.advance(n);
+                self.scanned = 0;
+            }
+            None => {
+                src.clear();
+                self.scanned = 0;
+                return Ok(None);
+            }
+        }
+
+        let from = self.scanned.max(1);
+        let Some(end) = src[from..].iter().position(|&b| b == END_BLOCK) else {
+            if src.len() - 1 > self.max_frame_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("MLLP frame exceeds {} bytes", self.max_frame_len),
+                ));
+            }
+            self.scanned = src.len();
+            return Ok(None);
+        };
+        let end = from + end;
+        let frame = src.split_to(end + 1);
+        self.scanned = 0;
+        if src.first() == Some(&CARRIAGE_RETURN) {
+            src.advance(1);
+        }
+        Ok(Some(frame[1..end].to_vec()))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed inside a frame",
+            )),
+        }
+    }
+}
+
+impl Encoder<&[u8]> for MllpCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, payload: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+        dst.reserve(payload.len() + 3);
+        dst.put_u8(START_BLOCK);
+        dst.extend_from_slice(payload);
+        dst.put_slice(&[END_BLOCK, CARRIAGE_RETURN]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, payload).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let mut codec = MllpCodec::new();
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for (i, b) in frame(b"MSH|^~\\&|A").into_iter().enumerate() {
+            src.put_u8(b);
+            if let Some(payload) = codec.decode(&mut src).unwrap() {
+                decoded.push((i, payload));
+            }
+        }
+        // The frame is complete at the end block, one byte before the final carriage return.
+        assert_eq!(decoded, vec![(11, b"MSH|^~\\&|A".to_vec())]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn concatenated_frames() {
+        let mut codec = MllpCodec::new();
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&frame(b"one"));
+        src.extend_from_slice(b"\r\n");
+        src.extend_from_slice(&frame(b"two"));
+        // The second frame leaves its carriage return out.
+        src.extend_from_slice(&[START_BLOCK, b'3', END_BLOCK]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(b"one".to_vec()));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(b"3".to_vec()));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn partial_frame_at_eof() {
+        let mut codec = MllpCodec::new();
+        let mut src = BytesMut::from(&[START_BLOCK, b'M', b'S'][..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        let err = codec.decode_eof(&mut src).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut garbage = BytesMut::from(&b"noise"[..]);
+        assert_eq!(codec.decode_eof(&mut garbage).unwrap(), None);
+    }
+
+    #[test]
+    fn oversize_frame() {
+        let mut codec = MllpCodec::with_max_frame_len(4);
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&frame(b"1234"));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(b"1234".to_vec()));
+
+        src.extend_from_slice(&[START_BLOCK, b'1', b'2', b'3', b'4']);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.put_u8(b'5');
+        let err = codec.decode(&mut src).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encoder_matches_write_frame() {
+        let mut dst = BytesMut::new();
+        MllpCodec::new().encode(&b"payload"[..], &mut dst).unwrap();
+        assert_eq!(&dst[..], &frame(b"payload")[..]);
+    }
+
+    #[test]
+    fn blocking_reader() {
+        let mut bytes = b"\r".to_vec();
+        bytes.extend_from_slice(&frame(b"one"));
+        bytes.extend_from_slice(&[START_BLOCK, b'2', END_BLOCK]);
+        bytes.extend_from_slice(&[START_BLOCK, b'3']);
+        let mut reader = io::Cursor::new(bytes);
+
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(b"one".to_vec()));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(b"2".to_vec()));
+        let err = read_frame(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+}
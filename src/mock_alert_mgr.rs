@@ -1,190 +1,951 @@
-use hl7::messages::ORU_R01;
-use hl7::segments::OBX;
-use std::io::{self, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::time::SystemTime;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument, Span};
+
+use crate::ack_policy::{AckPolicy, AckPolicyEngine, AckRule, Delivery};
+use crate::alert_store::{AlertEvent, AlertStore};
+use crate::api;
+use crate::audit::{AuditEvent, AuditEventKind, AuditLog, AuditSink};
+use crate::conformance::{self, ConformanceConfig, ConformanceTracker};
+use crate::encoding::{self, Delimiters};
+use crate::handlers::{HandlerPipeline, MessageContext, MessageKind, Outcome};
+use crate::http::HttpServer;
+use crate::messages::{parse_segments, Ack, Message, ObservationGroup, Oru};
+use crate::metrics::{self, Metrics};
+use crate::mllp::{self, MllpCodec};
+use crate::segments::{component, Segment, MSH, OBX};
+use crate::shutdown::{self, Shutdown};
+use crate::tls::HandshakeError;
+use crate::transport::{DefaultTransport, FrameStream, Listener, Socket, Transport};
+use crate::validate;
+
+#[derive(Debug, Clone)]
+pub struct AlertMgrConfig {
+    /// `host:port`, `unix:/path` or `mem:name`, as understood by `transport`.
+    pub listen_address: String,
+    /// Validate every inbound PCD-04 and keep per-source conformance reports.
+    pub conformance: Option<ConformanceConfig>,
+    /// Negative-testing behaviour for acknowledgments; empty means always answer AA.
+    pub ack_policy: AckPolicy,
+    /// Site-specific processing of each inbound ORU, in order.
+    pub handlers: HandlerPipeline,
+    pub transport: Arc<dyn Transport>,
+    /// Where the audit trail of received and acknowledged messages is written.
+    pub audit: Vec<AuditSink>,
+    /// Connections beyond this are refused.
+    pub max_connections: usize,
+    /// Largest inbound message; bounds the memory each connection can hold.
+    pub max_frame_len: usize,
+    /// Where the HTTP query API listens, see [`api`]. Only local addresses are accepted.
+    pub api_address: Option<String>,
+    /// Where Prometheus metrics are served as `/metrics`. Only local addresses are accepted.
+    pub metrics_address: Option<String>,
+}
+
+impl Default for AlertMgrConfig {
+    fn default() -> Self {
+        AlertMgrConfig {
+            listen_address: "127.0.0.1:8888".to_string(),
+            conformance: None,
+            ack_policy: AckPolicy::default(),
+            handlers: HandlerPipeline::standard(),
+            transport: Arc::new(DefaultTransport),
+            audit: Vec::new(),
+            max_connections: 10_000,
+            max_frame_len: mllp::DEFAULT_MAX_FRAME_LEN,
+            api_address: None,
+            metrics_address: None,
+        }
+    }
+}
+
+/// What the manager did while it ran, reported when it stops.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ManagerStats {
+    pub connections: u64,
+    /// Connections turned away because `max_connections` were open.
+    pub refused: u64,
+    pub auth_failures: u64,
+    pub messages: u64,
+    /// Messages answered with AA or CA.
+    pub acknowledged: u64,
+    /// Messages answered with AE or AR.
+    pub rejected: u64,
+    /// Messages a handler acknowledged but kept from further processing.
+    pub suppressed: u64,
+    /// Frames that could not be decoded or parsed, and failed writes.
+    pub errors: u64,
+}
+
+impl fmt::Display for ManagerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} connections ({} refused, {} failed authentication), {} messages, \
+             {} acknowledged, {} rejected, {} suppressed, {} errors",
+            self.connections,
+            self.refused,
+            self.auth_failures,
+            self.messages,
+            self.acknowledged,
+            self.rejected,
+            self.suppressed,
+            self.errors
+        )
+    }
+}
+
+/// How many events a slow subscriber may fall behind before it misses some.
+const EVENT_CAPACITY: usize = 1024;
+
+/// How often sources are checked for overdue heartbeats.
+const HEARTBEAT_CHECK: Duration = Duration::from_secs(1);
+
+/// How often conformance reports of sources with new messages are rewritten.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What an [`AlertManager`] tells its subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManagerEvent {
+    Connected {
+        peer: String,
+    },
+    /// A message as it arrived, before it is answered.
+    Received {
+        peer: String,
+        /// MSH-3 of the message, or the peer if it has none.
+        source: String,
+        message: String,
+    },
+    /// A message that went through every handler, with what they added to it.
+    Processed(Box<MessageContext>),
+    /// The acknowledgment decided for a message; the ACK policy may still delay or drop it.
+    Answered {
+        peer: String,
+        ack: Box<Ack>,
+    },
+    Disconnected {
+        peer: String,
+    },
+}
 
 #[derive(Debug)]
-struct MockAlertMgr;
+struct MockAlertMgr {
+    config: AlertMgrConfig,
+    tracker: Mutex<ConformanceTracker>,
+    ack_policy: AckPolicyEngine,
+    audit: AuditLog,
+    stats: Mutex<ManagerStats>,
+    alerts: Mutex<AlertStore>,
+    metrics: Arc<Metrics>,
+    events: broadcast::Sender<ManagerEvent>,
+}
 
 impl MockAlertMgr {
-    const START_MARKER: u8 = 0x0B;
-    const END_MARKER: u8 = 0x1C;
-
-    fn get_obx_segment<'a>(msgs: &'a [&OBX], id: &str) -> Option<&'a OBX> {
+    fn get_obx_segment(msgs: &[ObservationGroup], facet: u32) -> Option<&OBX> {
         msgs.iter()
-            .find(|msg| msg.obx_4_observation_sub_id == id)
-            .cloned()
+            .map(|msg| &msg.obx)
+            .find(|obx| obx.facet() == Some(facet))
     }
 
-    fn receive_one_msg(stream: &mut TcpStream) -> Result<String, io::Error> {
-        let mut start = [0];
+    async fn receive_one_msg(stream: &mut FrameStream) -> Result<Option<String>, io::Error> {
+        let Some(buffer) = stream.receive_frame().await? else {
+            return Ok(None);
+        };
 
-        stream.read_exact(&mut start)?;
+        encoding::decode_message(&buffer)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
 
-        if start[0] != MockAlertMgr::START_MARKER {
-            return Err(io::Error::new(io::ErrorKind::Other, "Invalid start marker"));
+    async fn send_acknowledgment(in_sock: &mut FrameStream, ack: &Ack) -> Result<(), io::Error> {
+        let bytes = Message::Ack(ack.clone())
+            .to_bytes(&Delimiters::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        in_sock.send_frame(&bytes).await
+    }
+
+    /// Sends `ack` the way `rule` asks for. Returns false once the connection must be closed.
+    async fn deliver(
+        in_sock: &mut FrameStream,
+        mut ack: Ack,
+        rule: Option<AckRule>,
+    ) -> io::Result<bool> {
+        let Some(rule) = rule else {
+            MockAlertMgr::send_acknowledgment(in_sock, &ack).await?;
+            return Ok(true);
+        };
+        info!(?rule, "Applying ACK policy rule");
+
+        if let Some(code) = &rule.ack_code {
+            ack.msa.msa_1_acknowledgment_code = code.clone();
+        }
+        if rule.wrong_control_id {
+            ack.msa.msa_2_message_control_id =
+                format!("WRONG-{}", ack.msa.msa_2_message_control_id);
         }
+        tokio::time::sleep(rule.delay()).await;
 
-        let mut buffer = Vec::new();
-        loop {
-            let mut chunk = [0];
-            match stream.read(&mut chunk) {
-                Ok(0) => break,
-                Ok(_) => {
-                    buffer.push(chunk[0]);
-                    if chunk[0] == MockAlertMgr::END_MARKER {
-                        break;
-                    }
+        match rule.delivery {
+            Delivery::Normal => MockAlertMgr::send_acknowledgment(in_sock, &ack).await?,
+            Delivery::Drop => info!("Dropping ACK"),
+            Delivery::Duplicate { copies } => {
+                for _ in 0..copies.max(1) {
+                    MockAlertMgr::send_acknowledgment(in_sock, &ack).await?;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                Err(e) => return Err(e),
+            }
+            Delivery::CloseMidFrame => {
+                let bytes = ack.encode(&Delimiters::default()).into_bytes();
+                in_sock.write_raw(&[mllp::START_BLOCK]).await?;
+                in_sock.write_raw(&bytes[..bytes.len() / 2]).await?;
+                in_sock.shutdown().await;
+                return Ok(false);
             }
         }
+        Ok(true)
+    }
 
-        Ok(String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
+    fn alert_code(oru: &Oru) -> Option<String> {
+        let observations: Vec<ObservationGroup> = oru.observations().cloned().collect();
+        MockAlertMgr::get_obx_segment(&observations, 1)
+            .map(|obx| obx.observation_code().to_string())
     }
 
-    fn pretty(msg: &ORU_R01) -> String {
-        let json_str = serde_json::to_string_pretty(msg).unwrap();
-        json_str
+    fn timestamp() -> String {
+        Utc::now().format("%Y%m%d%H%M%S%z").to_string()
     }
 
-    fn send_acknowledgment(
-        in_sock: &mut TcpStream,
-        original_message_control_id: &str,
-    ) -> Result<(), io::Error> {
-        let acknowledgment_message = format!(
-            "MSH|^~\\&|||||{}||ACK||P|2.1\rMSA|AA|{}\r",
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            original_message_control_id
-        );
+    /// Answers an ORU. The conformance check runs first so handlers see its findings in the ACK.
+    fn process(&self, oru: Oru, text: &str, peer: &str, source: &str) -> Ack {
+        let mut ack = Ack::for_message(&oru.msh, "AA", &MockAlertMgr::timestamp());
+        ack.msh.msh_4_sending_facility = "MockAM".to_string();
+        if oru.msh.trigger_event() == "R40" {
+            self.check_conformance(text, &oru.msh, source, &mut ack);
+        }
 
-        in_sock.write_all(&acknowledgment_message.into_bytes())?;
-        Ok(())
+        let mut ctx = MessageContext {
+            peer: peer.to_string(),
+            source: source.to_string(),
+            kind: MessageKind::of(&oru),
+            oru,
+            ack,
+            routes: Vec::new(),
+            attributes: BTreeMap::new(),
+        };
+        if !ctx.alert_id().is_empty() {
+            Span::current().record("alert", ctx.alert_id());
+        }
+        self.metrics.inc(
+            metrics::MESSAGES_RECEIVED,
+            &[
+                ("type", metrics::kind_label(ctx.kind)),
+                ("priority", ctx.priority()),
+            ],
+        );
+        match self.config.handlers.run(&mut ctx) {
+            // The conformance check rejected it: the handlers saw it, but it is not an alert.
+            Outcome::Processed if ctx.ack.msa.msa_1_acknowledgment_code == "AE" => {
+                debug!("Non-conforming {} not recorded", ctx.kind)
+            }
+            Outcome::Processed => {
+                if !ctx.routes.is_empty() {
+                    info!(routes = %ctx.routes.join(", "), "Routing {}", ctx.kind);
+                }
+                let mut alerts = self.alerts.lock().unwrap();
+                alerts.record(&ctx, Utc::now());
+                let jitter = alerts
+                    .source(&ctx.source)
+                    .filter(|_| ctx.kind == MessageKind::Heartbeat)
+                    .and_then(|source| source.heartbeat_jitter_ms);
+                drop(alerts);
+                if let Some(jitter) = jitter {
+                    self.metrics.observe(
+                        metrics::HEARTBEAT_JITTER,
+                        &[],
+                        Duration::from_millis(jitter),
+                    );
+                }
+                self.publish(ManagerEvent::Processed(Box::new(ctx.clone())));
+            }
+            Outcome::Suppressed { by } => {
+                info!(%by, "{} suppressed", ctx.kind);
+                self.count(|s| s.suppressed += 1);
+            }
+            Outcome::Rejected { by } => warn!(
+                %by,
+                reason = %ctx.ack.msa.msa_3_text_message,
+                "{} rejected",
+                ctx.kind
+            ),
+        }
+        ctx.ack
     }
-}
 
-pub fn run_mock_alert_mgr() {
-    println!("Binding socket...");
-    let listener = TcpListener::bind("127.0.0.1:8888").unwrap();
+    /// Validates an inbound PCD-04, records the result and adds ERR segments to `answer`.
+    fn check_conformance(&self, text: &str, msh: &MSH, source: &str, answer: &mut Ack) {
+        if self.config.conformance.is_none() {
+            return;
+        }
+        let findings = validate::validate_pcd04(text);
 
-    loop {
-        println!("Waiting for connection...");
-        let (mut in_sock, _) = match listener.accept() {
-            Ok(result) => result,
-            Err(e) => {
-                eprintln!("Error accepting connection: {}", e);
-                continue;
-            }
+        answer.err.extend(findings.iter().map(conformance::to_err));
+        if validate::has_errors(&findings) {
+            self.metrics.inc(metrics::VALIDATION_FAILURES, &[]);
+            answer.msa.msa_1_acknowledgment_code = "AE".to_string();
+            answer.msa.msa_3_text_message = "Message does not conform to PCD-04".to_string();
+        }
+        for finding in &findings {
+            warn!(%source, "Conformance: {}", finding);
+        }
+
+        self.tracker
+            .lock()
+            .unwrap()
+            .record(source, &msh.msh_10_message_control_id, &findings);
+    }
+
+    /// Brings the report files of sources with new messages up to date.
+    fn write_conformance_reports(&self) {
+        let Some(config) = &self.config.conformance else {
+            return;
         };
+        let written = self
+            .tracker
+            .lock()
+            .unwrap()
+            .write_changed_reports(&config.report_dir);
+        if let Err(e) = written {
+            error!("Error writing conformance report: {}", e);
+        }
+    }
+
+    fn count(&self, update: impl FnOnce(&mut ManagerStats)) {
+        update(&mut self.stats.lock().unwrap());
+    }
+
+    fn publish(&self, event: ManagerEvent) {
+        // Nobody listening is fine.
+        let _ = self.events.send(event);
+    }
+
+    fn audit_auth_failure(&self, error: &io::Error) {
+        if let Some(failure) = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<HandshakeError>())
+        {
+            self.count(|s| s.auth_failures += 1);
+            self.audit.record(
+                AuditEvent::new(
+                    AuditEventKind::NodeAuthenticationFailure,
+                    &failure.peer,
+                    &self.config.listen_address,
+                )
+                .detail(failure.reason.clone()),
+            );
+        }
+    }
+
+    /// Serves one connection until the peer closes it or the manager shuts down. A message that
+    /// has started arriving is still answered after shutdown is requested.
+    async fn handle_connection(&self, socket: Box<dyn Socket>, peer: String, shutdown: Shutdown) {
+        let mut in_sock = FrameStream::with_codec(
+            socket,
+            MllpCodec::with_max_frame_len(self.config.max_frame_len),
+        );
+        self.publish(ManagerEvent::Connected { peer: peer.clone() });
+        loop {
+            let received = tokio::select! {
+                received = MockAlertMgr::receive_one_msg(&mut in_sock) => received,
+                _ = shutdown.wait() => {
+                    if !in_sock.is_mid_frame() {
+                        break;
+                    }
+                    // The drain timeout bounds how long a stalled frame can hold shutdown up.
+                    MockAlertMgr::receive_one_msg(&mut in_sock).await
+                }
+            };
+            let text = match received {
+                Ok(Some(text)) => text,
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    warn!("Error decoding message: {}", e);
+                    self.count(|s| s.errors += 1);
+                    self.metrics.inc(metrics::PARSE_FAILURES, &[]);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Error receiving message: {}", e);
+                    break;
+                }
+            };
+            let received_at = Instant::now();
+            self.count(|s| s.messages += 1);
 
-        let msg = match MockAlertMgr::receive_one_msg(&mut in_sock) {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("Error receiving message: {}", e);
+            let Some(msh) = parse_segments(&text)
+                .ok()
+                .map(|(_, segments)| MSH::from_raw(&segments[0]))
+            else {
+                warn!("Error parsing message: no MSH segment");
+                self.count(|s| s.errors += 1);
+                self.metrics.inc(metrics::PARSE_FAILURES, &[]);
                 continue;
-            }
-        };
+            };
+            let source = match component(&msh.msh_3_sending_application, 1) {
+                "" => peer.clone(),
+                app => app.to_string(),
+            };
+            let control_id = &msh.msh_10_message_control_id;
+            let message_type = &msh.msh_9_message_type;
+            let span = info_span!("message", %source, %control_id, alert = field::Empty);
+            self.publish(ManagerEvent::Received {
+                peer: peer.clone(),
+                source: source.clone(),
+                message: text.clone(),
+            });
+            self.audit.record(
+                AuditEvent::new(
+                    AuditEventKind::MessageReceived,
+                    &peer,
+                    &self.config.listen_address,
+                )
+                .message(control_id, message_type),
+            );
+
+            let mut alert_code = None;
+            let answer = span.in_scope(|| match text.parse::<Message>() {
+                Ok(parsed_msg) => {
+                    debug!("Received {}", message_type);
+                    trace!(json = %serde_json::to_string(&parsed_msg).unwrap(), "Full message");
 
-        match msg.parse::<ORU_R01>() {
-            Ok(parsed_msg) => {
-                let msg_type = parsed_msg.msh.msh_9_message_type.clone();
-                let obs = &parsed_msg.oru_r01_patient_result[0]
-                    .oru_r01_patient_observation
-                    .iter()
-                    .find(|obs| obs.obx.obx_4_observation_sub_id == "1")
-                    .unwrap();
-                let obx_slice = &[&obs.obx];
-
-                if msg_type == "ACK^R41" {
-                    println!("Got ACK {}", 9);
-                } else if msg_type == "ORU^R40^ORU_R40" {
-                    if let Some(seg) = MockAlertMgr::get_obx_segment(obx_slice, "1") {
-                        let alarm_type_txt = seg.obx_3_observation_identifier.clone();
-                        let mut answer = ORU_R01::default();
-
-                        if alarm_type_txt == "196614^MDC_EVT_ACTIVE^MDC" {
-                            println!("************ Got Heartbeat ************");
-                            println!(
-                                "Full message {}: \n{}",
-                                SystemTime::now()
-                                    .duration_since(SystemTime::UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs(),
-                                MockAlertMgr::pretty(&parsed_msg)
-                            );
-
-                            answer.msh.msh_9_message_type = "ACK^R40".to_string();
-                            answer.msh.msh_15_accept_acknowledgment_type = Some("CA".to_string());
-
-                            let orig_msg_ctrl_id = parsed_msg.msh.msh_10_message_control_id.clone();
-                            if let Err(e) =
-                                MockAlertMgr::send_acknowledgment(&mut in_sock, &orig_msg_ctrl_id)
-                            {
-                                eprintln!("Error sending acknowledgment: {}", e);
-                                continue;
-                            }
-                        } else {
-                            println!("************ Got Alarm {} ************", alarm_type_txt);
-                            println!(
-                                "Full message {}: \n{}",
-                                SystemTime::now()
-                                    .duration_since(SystemTime::UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs(),
-                                MockAlertMgr::pretty(&parsed_msg)
-                            );
-
-                            answer.msh.msh_9_message_type = "ACK^R40".to_string();
-                            answer.msh.msh_4_sending_facility = Some("MockAM".to_string());
-
-                            answer
-                                .oru_r01_patient_result
-                                .get_mut(0)
-                                .and_then(|result| result.oru_r01_patient_observation.get_mut(0))
-                                .and_then(|observation| {
-                                    observation.prt.as_mut().map(|prt_vec| {
-                                        if let Some(prt) = prt_vec.get_mut(0) {
-                                            prt.prt_3_action_reason = Some("Delivered".to_string());
-                                            "Delivered".to_string()
-                                        } else {
-                                            "Not Delivered".to_string()
-                                        }
-                                    })
-                                })
-                                .unwrap_or_else(|| "Not Delivered".to_string());
-
-                            answer.msh.msh_10_message_control_id =
-                                parsed_msg.msh.msh_10_message_control_id.clone();
+                    match parsed_msg {
+                        Message::Ack(ack) => {
+                            debug!("Got ACK {}", ack.msa.msa_2_message_control_id);
+                            None
                         }
-                        println!(
-                            "Answering {}: \n{:?}",
-                            SystemTime::now()
-                                .duration_since(SystemTime::UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs(),
-                            MockAlertMgr::pretty(&answer)
-                        );
-                        if let Err(e) = in_sock.write_all(MockAlertMgr::pretty(&answer).as_bytes())
-                        {
-                            eprintln!("Error sending response: {}", e);
-                            continue;
+                        Message::Oru(oru) => {
+                            alert_code = MockAlertMgr::alert_code(&oru);
+                            Some(self.process(oru, &text, &peer, &source))
                         }
-                        println!("*******************************");
                     }
-                } else {
-                    println!("Got unknown message type: {}", msg_type);
+                }
+                Err(e) => {
+                    warn!("Error parsing message: {}", e);
+                    self.count(|s| s.errors += 1);
+                    self.metrics.inc(metrics::PARSE_FAILURES, &[]);
+                    // A structurally broken PCD-04 still deserves an answer in conformance mode.
+                    self.config
+                        .conformance
+                        .as_ref()
+                        .filter(|_| msh.trigger_event() == "R40")
+                        .map(|_| {
+                            let mut ack = Ack::for_message(&msh, "AE", &MockAlertMgr::timestamp());
+                            self.check_conformance(&text, &msh, &source, &mut ack);
+                            ack
+                        })
+                }
+            });
+
+            if let Some(answer) = answer {
+                let code = answer.msa.msa_1_acknowledgment_code.clone();
+                span.in_scope(|| {
+                    debug!(text = %answer.msa.msa_3_text_message, "Answering {}", code);
+                    trace!(
+                        json = %serde_json::to_string(&Message::Ack(answer.clone())).unwrap(),
+                        "Full answer"
+                    );
+                });
+                self.metrics.inc(metrics::ACKS_SENT, &[("code", &code)]);
+                let kind = match code.as_str() {
+                    "AA" | "CA" => {
+                        self.count(|s| s.acknowledged += 1);
+                        AuditEventKind::MessageAcknowledged
+                    }
+                    _ => {
+                        self.count(|s| s.rejected += 1);
+                        AuditEventKind::MessageRejected
+                    }
+                };
+                let mut event = AuditEvent::new(kind, &peer, &self.config.listen_address)
+                    .message(control_id, message_type)
+                    .ack_code(&code);
+                if !answer.msa.msa_3_text_message.is_empty() {
+                    event = event.detail(answer.msa.msa_3_text_message.clone());
+                }
+                self.audit.record(event);
+                self.publish(ManagerEvent::Answered {
+                    peer: peer.clone(),
+                    ack: Box::new(answer.clone()),
+                });
+
+                let rule = self.ack_policy.decide(alert_code.as_deref());
+                let delivered = MockAlertMgr::deliver(&mut in_sock, answer, rule)
+                    .instrument(span.clone())
+                    .await;
+                if delivered.is_ok() {
+                    self.metrics
+                        .observe(metrics::ACK_LATENCY, &[], received_at.elapsed());
+                }
+                match delivered {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        span.in_scope(|| warn!("Error sending acknowledgment: {}", e));
+                        self.count(|s| s.errors += 1);
+                        break;
+                    }
                 }
             }
+        }
+        self.alerts.lock().unwrap().disconnected(&peer, Utc::now());
+        self.publish(ManagerEvent::Disconnected { peer });
+    }
 
-            Err(e) => {
-                eprintln!("Error parsing message: {}", e);
+    /// Accepts connections until `shutdown` is triggered, serving each in its own task. Then it
+    /// stops listening, gives open connections [`shutdown::DRAIN_TIMEOUT`] to answer the message in
+    /// hand and writes out the conformance reports and the audit trail.
+    async fn serve(
+        self: Arc<Self>,
+        listener: Box<dyn Listener>,
+        shutdown: Shutdown,
+    ) -> ManagerStats {
+        let limit = Arc::new(Semaphore::new(self.config.max_connections));
+        let mut connections = JoinSet::new();
+        let mut heartbeat_check = tokio::time::interval(HEARTBEAT_CHECK);
+        let mut report_update = tokio::time::interval(REPORT_INTERVAL);
+
+        let address = listener.local_address();
+        info!(
+            "Waiting for connections on {}",
+            address.as_deref().unwrap_or(&self.config.listen_address)
+        );
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                _ = heartbeat_check.tick() => {
+                    self.alerts.lock().unwrap().check_heartbeats(Utc::now());
+                    continue;
+                }
+                _ = report_update.tick() => {
+                    self.write_conformance_reports();
+                    continue;
+                }
+                _ = shutdown.wait() => break,
+            };
+            let (socket, peer) = match accepted {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("Error accepting connection: {}", e);
+                    continue;
+                }
+            };
+            let Ok(permit) = Arc::clone(&limit).try_acquire_owned() else {
+                warn!(
+                    %peer,
+                    "Refusing connection: {} connections open",
+                    self.config.max_connections
+                );
+                self.count(|s| s.refused += 1);
+                continue;
+            };
+            self.count(|s| s.connections += 1);
+
+            let handshake = listener.handshake(socket, &peer);
+            let mgr = Arc::clone(&self);
+            let shutdown = shutdown.clone();
+            let span = info_span!("connection", %peer);
+            connections.spawn(
+                async move {
+                    debug!("Connected");
+                    match handshake.await {
+                        Ok(socket) => mgr.handle_connection(socket, peer, shutdown).await,
+                        Err(e) => {
+                            warn!("Error accepting connection: {}", e);
+                            mgr.audit_auth_failure(&e);
+                        }
+                    }
+                    debug!("Disconnected");
+                    drop(permit);
+                }
+                .instrument(span),
+            );
+        }
+
+        drop(listener);
+        info!(
+            "Stopped listening, waiting for {} open connections",
+            connections.len()
+        );
+        let drained = tokio::time::timeout(shutdown::DRAIN_TIMEOUT, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "{} connections did not finish within {:?}, closing them",
+                connections.len(),
+                shutdown::DRAIN_TIMEOUT
+            );
+            connections.shutdown().await;
+        }
+        self.write_conformance_reports();
+        self.audit.flush().await;
+
+        let stats = self.stats.lock().unwrap().clone();
+        info!("Alert manager stopped: {}", stats);
+        stats
+    }
+}
+
+/// Read access to a running manager, e.g. for the query API. Clones are cheap.
+#[derive(Clone)]
+pub struct ManagerHandle {
+    mgr: Arc<MockAlertMgr>,
+}
+
+impl ManagerHandle {
+    pub fn stats(&self) -> ManagerStats {
+        self.mgr.stats.lock().unwrap().clone()
+    }
+
+    /// Runs `read` on the sources and alerts seen so far. Messages wait while it runs, so it
+    /// should be quick.
+    pub fn alerts<T>(&self, read: impl FnOnce(&AlertStore) -> T) -> T {
+        read(&self.mgr.alerts.lock().unwrap())
+    }
+
+    /// See [`AlertManager::subscribe`].
+    pub fn subscribe(&self) -> broadcast::Receiver<ManagerEvent> {
+        self.mgr.events.subscribe()
+    }
+
+    /// What the manager counted so far, including the gauges read from its alert store.
+    pub fn metrics(&self) -> String {
+        self.refresh_metrics(&self.mgr.metrics);
+        self.mgr.metrics.render()
+    }
+
+    /// Sets the gauges that are read from the alert store rather than counted.
+    fn refresh_metrics(&self, metrics: &Metrics) {
+        self.alerts(|store| {
+            metrics.clear(metrics::ACTIVE_ALERTS);
+            for (priority, count) in store.active_by_priority() {
+                metrics.set(
+                    metrics::ACTIVE_ALERTS,
+                    &[("priority", &priority)],
+                    count as f64,
+                );
             }
+            let connected = store.sources().filter(|s| s.connected).count();
+            metrics.set(metrics::CONNECTED_SOURCES, &[], connected as f64);
+        });
+    }
+
+    /// The kept alert lifecycle events after sequence number `after` (none if it is `None`),
+    /// and a receiver for the events that follow them.
+    pub fn alert_events(
+        &self,
+        after: Option<u64>,
+    ) -> (Vec<AlertEvent>, broadcast::Receiver<AlertEvent>) {
+        self.alerts(|store| store.events_after(after.unwrap_or(store.last_seq())))
+    }
+}
+
+/// An alert manager that can be embedded in another program, e.g. several per test suite.
+///
+/// ```no_run
+/// # async fn demo() -> std::io::Result<()> {
+/// use pcd_acm::mock_alert_mgr::{AlertManager, AlertMgrConfig};
+///
+/// let mut manager = AlertManager::new(AlertMgrConfig {
+///     listen_address: "mem:manager".to_string(),
+///     ..Default::default()
+/// });
+/// let mut events = manager.subscribe();
+/// manager.start().await?;
+/// while let Ok(event) = events.recv().await {
+///     println!("{:?}", event);
+/// }
+/// let stats = manager.stop().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AlertManager {
+    config: AlertMgrConfig,
+    shutdown: Shutdown,
+    events: broadcast::Sender<ManagerEvent>,
+    running: Option<(Arc<MockAlertMgr>, JoinHandle<ManagerStats>)>,
+    local_address: Option<String>,
+    api: Option<HttpServer>,
+    metrics: Option<HttpServer>,
+}
+
+impl AlertManager {
+    pub fn new(config: AlertMgrConfig) -> Self {
+        AlertManager::with_shutdown(config, &Shutdown::new())
+    }
+
+    /// A manager that also stops when `shutdown` is triggered.
+    pub fn with_shutdown(config: AlertMgrConfig, shutdown: &Shutdown) -> Self {
+        AlertManager {
+            config,
+            shutdown: shutdown.child(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            running: None,
+            local_address: None,
+            api: None,
+            metrics: None,
+        }
+    }
+
+    /// Binds the listen address (and the API address, if any) and starts accepting connections
+    /// in the background. Listening and audit trail errors are returned here rather than logged.
+    pub async fn start(&mut self) -> io::Result<()> {
+        if self.running.is_some() {
+            return Ok(());
+        }
+        let config = self.config.clone();
+        debug!("Binding {}", config.listen_address);
+        let listener = config
+            .transport
+            .listen(&config.listen_address)
+            .await
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("cannot listen on {}: {}", config.listen_address, e),
+                )
+            })?;
+        self.local_address = listener.local_address().ok();
+        if let Some(conformance) = &config.conformance {
+            info!(
+                "Conformance mode on, writing reports to {}",
+                conformance.report_dir.display()
+            );
+        }
+        let audit = AuditLog::open("alert_manager", &config.audit)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot open audit trail: {}", e)))?;
+        let mgr = Arc::new(MockAlertMgr {
+            ack_policy: AckPolicyEngine::new(config.ack_policy.clone()),
+            audit,
+            config,
+            tracker: Mutex::new(ConformanceTracker::default()),
+            stats: Mutex::new(ManagerStats::default()),
+            alerts: Mutex::new(AlertStore::new()),
+            metrics: Arc::new(metrics::manager()),
+            events: self.events.clone(),
+        });
+        let handle = ManagerHandle {
+            mgr: Arc::clone(&mgr),
+        };
+        if let Some(address) = &mgr.config.api_address {
+            let routes = api::routes(handle.clone());
+            let server = HttpServer::open(address, routes, &self.shutdown).await?;
+            info!("Query API listening on {}", server.local_address());
+            self.api = Some(server);
+        }
+        if let Some(address) = &mgr.config.metrics_address {
+            let routes = metrics::routes(Arc::clone(&mgr.metrics), move |metrics| {
+                handle.refresh_metrics(metrics)
+            });
+            let server = HttpServer::open(address, routes, &self.shutdown).await?;
+            info!("Metrics listening on {}", server.local_address());
+            self.metrics = Some(server);
+        }
+        let task = tokio::spawn(Arc::clone(&mgr).serve(listener, self.shutdown.clone()));
+        self.running = Some((mgr, task));
+        Ok(())
+    }
+
+    /// The address reporters reach the manager at once it is started, with the actual port if
+    /// it was bound to port 0.
+    pub fn local_address(&self) -> Option<&str> {
+        self.local_address.as_deref()
+    }
+
+    /// Where the query API listens once the manager is started, if it has one.
+    pub fn api_address(&self) -> Option<&str> {
+        self.api.as_ref().map(HttpServer::local_address)
+    }
+
+    /// Where `/metrics` is served once the manager is started, if anywhere.
+    pub fn metrics_address(&self) -> Option<&str> {
+        self.metrics.as_ref().map(HttpServer::local_address)
+    }
+
+    /// For reading the manager's state while it runs; None before it is started.
+    pub fn handle(&self) -> Option<ManagerHandle> {
+        self.running.as_ref().map(|(mgr, _)| ManagerHandle {
+            mgr: Arc::clone(mgr),
+        })
+    }
+
+    /// Events from now on. A subscriber that falls far behind misses events and is told so with
+    /// [`broadcast::error::RecvError::Lagged`].
+    pub fn subscribe(&self) -> broadcast::Receiver<ManagerEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn stats(&self) -> ManagerStats {
+        self.running
+            .as_ref()
+            .map(|(mgr, _)| mgr.stats.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+
+    /// Stops listening, drains open connections and returns the final statistics.
+    pub async fn stop(self) -> io::Result<ManagerStats> {
+        self.shutdown.trigger();
+        self.wait().await
+    }
+
+    /// Waits until the manager is stopped through its shutdown coordinator. Fails if the
+    /// manager task panicked.
+    pub async fn wait(self) -> io::Result<ManagerStats> {
+        match self.running {
+            Some((_, task)) => Ok(task.await?),
+            None => Ok(ManagerStats::default()),
         }
     }
 }
 
+pub async fn run_mock_alert_mgr(shutdown: Shutdown) -> io::Result<ManagerStats> {
+    run_mock_alert_mgr_with(AlertMgrConfig::default(), shutdown).await
+}
+
+/// Runs a manager until `shutdown` is triggered and returns what it did.
+pub async fn run_mock_alert_mgr_with(
+    config: AlertMgrConfig,
+    shutdown: Shutdown,
+) -> io::Result<ManagerStats> {
+    let mut manager = AlertManager::with_shutdown(config, &shutdown);
+    manager.start().await?;
+    manager.wait().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ManagerConnection;
+    use crate::device::{ActiveAlert, AlertSpec, DeviceConfig};
+    use crate::mock_alert_rpt::{AlertReporter, AlertRptConfig, ReporterEvent};
+
+    #[tokio::test]
+    async fn non_conforming_alerts_are_not_recorded() {
+        let report_dir = std::env::temp_dir().join(format!("nonconforming-{}", std::process::id()));
+        let mut manager = AlertManager::new(AlertMgrConfig {
+            listen_address: "mem:nonconforming-test".to_string(),
+            conformance: Some(ConformanceConfig {
+                report_dir: report_dir.clone(),
+            }),
+            ..Default::default()
+        });
+        manager.start().await.unwrap();
+
+        let device = DeviceConfig::default();
+        let mut connection =
+            ManagerConnection::connect("mem:nonconforming-test", Duration::from_secs(5))
+                .await
+                .unwrap();
+        for (id, priority) in [("alert-px", "PX"), ("alert-ph", "PH")] {
+            let alert = ActiveAlert {
+                id: id.to_string(),
+                spec: AlertSpec {
+                    code: "196670^MDC_EVT_LO^MDC".to_string(),
+                    text: "SpO2 low".to_string(),
+                    priority: priority.to_string(),
+                    kind: "SP".to_string(),
+                    observation: None,
+                },
+                update: 0,
+            };
+            let ack = connection
+                .exchange(&device.alert_message(&alert, "start", "active"))
+                .await
+                .unwrap();
+            let expected = if priority == "PX" { "AE" } else { "AA" };
+            assert_eq!(ack.msa.msa_1_acknowledgment_code, expected);
+        }
+
+        let handle = manager.handle().unwrap();
+        assert!(handle.alerts(|store| store.alert("alert-px").is_none()));
+        assert_eq!(
+            handle.alerts(|store| store.active_by_priority()),
+            BTreeMap::from([("PH".to_string(), 1)])
+        );
+        connection.close().await;
+        manager.stop().await.unwrap();
+        let _ = std::fs::remove_dir_all(&report_dir);
+    }
+
+    #[tokio::test]
+    async fn reporter_exchange_over_memory() {
+        let mut manager = AlertManager::new(AlertMgrConfig {
+            listen_address: "mem:exchange-test".to_string(),
+            ..Default::default()
+        });
+        manager.start().await.unwrap();
+        let mut reporter = AlertReporter::new(AlertRptConfig {
+            managers: vec!["mem:exchange-test".to_string()],
+            heartbeat_interval: Duration::from_secs(60),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut events = reporter.subscribe();
+        reporter.start();
+
+        let alert = ActiveAlert {
+            id: "alert-1".to_string(),
+            spec: AlertSpec {
+                code: "196670^MDC_EVT_LO^MDC".to_string(),
+                text: "SpO2 < 90^low".to_string(),
+                priority: "PH".to_string(),
+                kind: "SP".to_string(),
+                observation: None,
+            },
+            update: 0,
+        };
+        let control_id =
+            reporter.send_alert(reporter.device().alert_message(&alert, "start", "active"));
+
+        let ack = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match events.recv().await.unwrap() {
+                    ReporterEvent::Acknowledged {
+                        control_id: id,
+                        ack,
+                    } if id == control_id => {
+                        break ack;
+                    }
+                    ReporterEvent::Failed { error, .. } => panic!("{}", error),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(ack.msa.msa_1_acknowledgment_code, "AA");
+        assert_eq!(reporter.queued(), 0);
+
+        let handle = manager.handle().unwrap();
+        let stored = handle
+            .alerts(|store| store.alert("alert-1").cloned())
+            .unwrap();
+        assert_eq!(stored.text, "SpO2 < 90^low");
+        assert_eq!(stored.priority, "PH");
+        assert_eq!(stored.source, reporter.device().eui64);
+
+        let sent = reporter.stop().await.unwrap();
+        let received = manager.stop().await.unwrap();
+        assert!(sent.acknowledged >= 1);
+        assert_eq!(received.messages, received.acknowledged);
+    }
+}
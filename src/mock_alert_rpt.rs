@@ -1,221 +1,967 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
-use std::io::prelude::*;
-use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 
+use crate::audit::{AuditEvent, AuditEventKind, AuditLog, AuditSink};
+use crate::connection::{self, Backoff, ManagerConnection, ReconnectPolicy};
+use crate::control::ControlPort;
+use crate::device::{self, ActiveAlert, AlertSpec, DeviceConfig, ObservationSpec};
+use crate::handlers::{self, MessageKind};
+use crate::http::HttpServer;
+use crate::messages::{Ack, Message};
+use crate::metrics::{self, Metrics};
+use crate::outbound::OutboundQueue;
 use crate::pcd04_msg::PCD04Message;
+use crate::shutdown::Shutdown;
+use crate::technical::TechnicalAlarm;
+use crate::tls;
+use crate::transport::{DefaultTransport, Transport};
 
-#[allow(dead_code)]
-struct MockAlertRpt {
-    device_id: &'static str,
-    device_location: &'static str,
-    out_address: &'static str,
-    out_part: u16,
-}
-#[allow(dead_code)]
-impl MockAlertRpt {
-    const DEVICE_ID: &'static str = "uuid:df041f5c-a3c9-11e9-8d8a-0050b612afeb";
-    const DEVICE_LOCATION: &'static str = "POC^Room^Bed^fac^^^building^floor";
-    const OUT_ADDRESS: &'static str = "127.0.0.1";
-    const OUT_PORT: u16 = 8888;
-    const SEND_HEARTBEAT: bool = true;
-    fn send_alert() {
-        print!("*** Sending Example Alert ***");
-
-        let mut msg = PCD04Message::new();
-
-        msg.create_pcd04_message(
-            Self::DEVICE_ID,
-            &format!("{}^^{}^URN", Self::DEVICE_ID, Self::DEVICE_ID),
-            "HO2009001^^^Hospital^PI",
-            "Abo^Nasser^^^L",
-            "18991230",
-            "M",
-            "196670^MDC_EVT_LO^MDC",
-            "Low Alert",
-            "start",
-            "PM",
-            "1.1.1",
-            "150456^MDC_PULS_OXIM_SAT_O2^MDC",
-            "42",
-            "NM",
-            "",
-            Self::DEVICE_ID,
-            "SP",
-            "",
-            0,
-            "",
-            "",
-            "",
-            None,
-            "",
-            "69837^MDC_DEV_METER_PHYSIO_MULTI_PARAM_MDS^MDC",
-            "69686^MDC_DEV_ANALY_BLD_CHEM_MULTI_PARAM_VMD^MDC",
-        );
-        if let Some(message) = msg.get_message() {
-            let json_message = serde_json::to_string(&message);
+#[derive(Debug, Clone)]
+pub struct AlertRptConfig {
+    /// Alert managers in order of preference; the first is the primary.
+    pub managers: Vec<String>,
+    pub ack_timeout: Duration,
+    /// Unanswered messages in a row, counted across reconnects to the same manager, after which
+    /// the reporter fails over to the next manager.
+    pub max_missed_acks: u32,
+    /// While on a secondary, how often to check whether the primary is back. None disables
+    /// failback.
+    pub failback_interval: Option<Duration>,
+    pub heartbeat_interval: Duration,
+    pub reconnect: ReconnectPolicy,
+    /// How many unacknowledged alerts are kept while the manager is unreachable.
+    pub queue_capacity: usize,
+    /// Keeps the outbound queue in this file so it survives a restart.
+    pub queue_file: Option<PathBuf>,
+    /// How manager addresses are reached; the default picks TCP, `unix:` or `mem:` by prefix.
+    pub transport: Arc<dyn Transport>,
+    /// Where the audit trail of sent and acknowledged messages is written.
+    pub audit: Vec<AuditSink>,
+    /// Identity of the reporting device, used for heartbeats and the audit trail. The patient
+    /// association can be changed while running with [`ReporterHandle::update_device`].
+    pub device: DeviceConfig,
+    /// Where the interactive reporter accepts remote-control commands, see
+    /// [`ControlPort`](crate::control::ControlPort).
+    pub control_address: Option<String>,
+    /// Where the interactive reporter serves Prometheus metrics as `/metrics`. Only local
+    /// addresses are accepted.
+    pub metrics_address: Option<String>,
+}
 
-            match json_message {
-                Ok(json_str) => {
-                    if let Ok(mut stream) = TcpStream::connect((Self::OUT_ADDRESS, Self::OUT_PORT))
-                    {
-                        if let Err(err) = stream.write_all(json_str.as_bytes()) {
-                            eprintln!("Error writing to stream: {}", err);
-                        }
-                    } else {
-                        eprintln!("Error connecting to server");
-                    }
-                }
-                Err(err) => eprintln!("Error serializing message to JSON: {}", err),
-            }
+impl Default for AlertRptConfig {
+    fn default() -> Self {
+        AlertRptConfig {
+            managers: vec!["127.0.0.1:8888".to_string()],
+            ack_timeout: Duration::from_secs(5),
+            max_missed_acks: 2,
+            failback_interval: None,
+            heartbeat_interval: Duration::from_secs(1),
+            reconnect: ReconnectPolicy::default(),
+            queue_capacity: 1000,
+            queue_file: None,
+            transport: Arc::new(DefaultTransport),
+            audit: Vec::new(),
+            device: DeviceConfig::default(),
+            control_address: None,
+            metrics_address: None,
         }
     }
+}
 
-    fn create_heartbeat_msg() -> PCD04Message {
-        let mut msg = PCD04Message::new();
-
-        msg.create_pcd04_message(
-            Self::DEVICE_LOCATION,
-            &format!("{}^^{}^URN", Self::DEVICE_ID, Self::DEVICE_ID),
-            "HO2009001^^^Hospital^PI",
-            "Abo^Nasser^^^L",
-            "18991230",
-            "M",
-            "196614^MDC_EVT_ACTIVE^MDC",
-            "",
-            "start",
-            "PN",
-            "1.1.1",
-            "68480^MDC_ATTR_ALERT_SOURCE^MDC",
-            "",
-            "ST",
-            "",
-            Self::DEVICE_ID,
-            "SA",
-            "",
-            0,
-            "",
-            "",
-            "",
-            None,
-            "",
-            "",
-            "",
-        );
-        msg.append_watchdog_obx_segment("5", "None", "0.0.1");
+/// What the reporter did while it ran, reported when it stops.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReporterStats {
+    pub sent: u64,
+    /// Messages answered with AA or CA.
+    pub acknowledged: u64,
+    /// Messages answered with AE or AR.
+    pub rejected: u64,
+    /// Messages that got no ACK, because of a timeout or a broken connection.
+    pub failed: u64,
+    pub failovers: u64,
+    /// Alerts still waiting for the manager when the reporter stopped.
+    pub queued: usize,
+}
 
-        return msg;
+impl fmt::Display for ReporterStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} messages sent, {} acknowledged, {} rejected, {} failed, {} failovers, \
+             {} alerts still queued",
+            self.sent, self.acknowledged, self.rejected, self.failed, self.failovers, self.queued
+        )
     }
+}
 
-    fn main_loop(stop_event: Arc<Mutex<bool>>) {
-        println!(
-            "Opening socket to {}:{}",
-            MockAlertRpt::OUT_ADDRESS,
-            MockAlertRpt::OUT_PORT
-        );
+/// How many events a slow subscriber may fall behind before it misses some.
+const EVENT_CAPACITY: usize = 1024;
 
-        if let Ok(mut out_socket) =
-            TcpStream::connect((MockAlertRpt::OUT_ADDRESS, MockAlertRpt::OUT_PORT))
-        {
-            println!("Socket open, sending alive every 5 sec");
-            let mut msg = Self::create_heartbeat_msg();
+/// What an [`AlertReporter`] tells its subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReporterEvent {
+    Connected {
+        manager: String,
+    },
+    Disconnected {
+        manager: String,
+        reason: String,
+    },
+    FailedOver {
+        from: String,
+        to: String,
+        reason: String,
+    },
+    /// The manager answered a message, with AA or otherwise.
+    Acknowledged {
+        control_id: String,
+        ack: Box<Ack>,
+    },
+    /// A message got no answer; queued alerts are retried, heartbeats are not.
+    Failed {
+        control_id: String,
+        error: String,
+    },
+}
 
-            loop {
-                let msg_id = Uuid::new_v4().to_string();
-                msg.set_control_id(&msg_id);
-                println!("Sending msg with ID {}", msg_id);
+/// Where the outcome of every exchange goes: the audit trail, the statistics and subscribers.
+struct Journal {
+    /// Audit identity of the reporter.
+    node: String,
+    audit: AuditLog,
+    events: broadcast::Sender<ReporterEvent>,
+    stats: ReporterStats,
+    metrics: Arc<Metrics>,
+}
 
-                let json_message = serde_json::to_string(&msg.get_message().unwrap());
+impl Journal {
+    fn publish(&self, event: ReporterEvent) {
+        // Nobody listening is fine.
+        let _ = self.events.send(event);
+    }
 
-                match json_message {
-                    Ok(json_str) => {
-                        if let Err(err) = out_socket.write_all(json_str.as_bytes()) {
-                            eprintln!("Error writing to stream: {}", err);
-                        }
-                    }
-                    Err(err) => eprintln!("Error serializing message to JSON: {}", err),
-                }
+    fn connected(&self, manager: &str, connected: bool) {
+        let value = if connected { 1.0 } else { 0.0 };
+        self.metrics
+            .set(metrics::MANAGER_CONNECTED, &[("manager", manager)], value);
+    }
 
-                thread::sleep(Duration::from_secs(1));
+    fn auth_failure(&self, address: &str, error: &io::Error) {
+        if tls::is_handshake_failure(error) {
+            self.audit.record(
+                AuditEvent::new(
+                    AuditEventKind::NodeAuthenticationFailure,
+                    &self.node,
+                    address,
+                )
+                .detail(error.to_string()),
+            );
+        }
+    }
+}
+
+/// Alerts waiting for the manager, and a wake-up for the connection loop when one is added.
+struct Outbox {
+    queue: Mutex<OutboundQueue>,
+    ready: Notify,
+}
+
+type SharedQueue = Arc<Outbox>;
+
+/// What can be changed while the reporter runs.
+struct Controls {
+    device: Mutex<DeviceConfig>,
+    heartbeat_paused: AtomicBool,
+    /// Set to drop the connection and stay offline for the given time.
+    disconnect: Mutex<Option<Duration>>,
+}
+
+/// A handle on a reporter for other tasks, e.g. a [`ControlPort`](crate::control::ControlPort).
+/// Clones are cheap and control the same reporter.
+#[derive(Clone)]
+pub struct ReporterHandle {
+    outbox: SharedQueue,
+    controls: Arc<Controls>,
+    events: broadcast::Sender<ReporterEvent>,
+    metrics: Arc<Metrics>,
+}
+
+impl ReporterHandle {
+    /// The device as it is now, including changes made with [`update_device`].
+    ///
+    /// [`update_device`]: ReporterHandle::update_device
+    pub fn device(&self) -> DeviceConfig {
+        self.controls.device.lock().unwrap().clone()
+    }
+
+    /// Changes the device, e.g. its patient association. Heartbeats and alerts built from
+    /// then on carry the change.
+    pub fn update_device(&self, update: impl FnOnce(&mut DeviceConfig)) {
+        update(&mut self.controls.device.lock().unwrap());
+    }
+
+    /// Queues an alert under a fresh control ID and returns that ID. It is sent, in order with
+    /// other alerts, as soon as a manager is reachable.
+    pub fn send_alert(&self, mut msg: PCD04Message) -> String {
+        let control_id = Uuid::new_v4().to_string();
+        msg.set_control_id(&control_id);
+        enqueue(&self.outbox, &msg);
+        control_id
+    }
+
+    /// Alerts not yet acknowledged by a manager.
+    pub fn queued(&self) -> usize {
+        self.outbox.queue.lock().unwrap().len()
+    }
 
-                if *stop_event.lock().unwrap() {
-                    break;
+    /// Events from now on. A subscriber that falls far behind misses events and is told so with
+    /// [`broadcast::error::RecvError::Lagged`].
+    pub fn subscribe(&self) -> broadcast::Receiver<ReporterEvent> {
+        self.events.subscribe()
+    }
+
+    /// What the reporter counted so far, including the gauges read from its state.
+    pub fn metrics(&self) -> String {
+        self.refresh_metrics(&self.metrics);
+        self.metrics.render()
+    }
+
+    fn refresh_metrics(&self, metrics: &Metrics) {
+        metrics.set(metrics::QUEUED_ALERTS, &[], self.queued() as f64);
+    }
+
+    /// Stops or resumes heartbeats; queued alerts are still sent.
+    pub fn pause_heartbeat(&self, paused: bool) {
+        self.controls
+            .heartbeat_paused
+            .store(paused, Ordering::Relaxed);
+    }
+
+    pub fn heartbeat_paused(&self) -> bool {
+        self.controls.heartbeat_paused.load(Ordering::Relaxed)
+    }
+
+    /// Closes the connection to the manager and reconnects after `offline`.
+    pub fn disconnect(&self, offline: Duration) {
+        *self.controls.disconnect.lock().unwrap() = Some(offline);
+        self.outbox.ready.notify_one();
+    }
+}
+
+/// A low SpO2 alert from `device`, placed in its containment tree by its profile.
+fn example_alert(device: &DeviceConfig) -> PCD04Message {
+    let alert = ActiveAlert {
+        id: Uuid::new_v4().to_string(),
+        spec: AlertSpec {
+            code: "196670^MDC_EVT_LO^MDC".to_string(),
+            text: "Low Alert".to_string(),
+            priority: "PM".to_string(),
+            kind: "SP".to_string(),
+            observation: Some(ObservationSpec {
+                code: device::SPO2.to_string(),
+                value: "42".to_string(),
+                unit: "262688^MDC_DIM_PERCENT^MDC".to_string(),
+                value_type: "NM".to_string(),
+            }),
+        },
+        update: 0,
+    };
+    info!(code = %alert.spec.code, "Sending example alert");
+    device.alert_message(&alert, "start", "active")
+}
+
+/// Hands an alert to the connection loop, which sends it once the manager is reachable.
+fn enqueue(queue: &Outbox, msg: &PCD04Message) {
+    let mut pending = queue.queue.lock().unwrap();
+    if let Some(dropped) = pending.push(msg) {
+        warn!(
+            control_id = %dropped.control_id,
+            "Outbound queue full, dropped unacknowledged alert"
+        );
+    }
+    if pending.len() > 1 {
+        info!("{} alerts waiting for the manager", pending.len());
+    }
+    queue.ready.notify_one();
+}
+
+/// Raises or clears a technical alarm from the catalog.
+fn send_technical(
+    reporter: &AlertReporter,
+    active: &mut HashMap<TechnicalAlarm, ActiveAlert>,
+    alarm: TechnicalAlarm,
+    raise: bool,
+) {
+    let device = reporter.device();
+    let msg = if raise {
+        info!("Raising {}", alarm.text());
+        let alert = ActiveAlert {
+            id: Uuid::new_v4().to_string(),
+            spec: alarm.spec(),
+            update: 0,
+        };
+        let msg = device.alert_message(&alert, "start", "active");
+        active.insert(alarm, alert);
+        msg
+    } else {
+        let Some(mut alert) = active.remove(&alarm) else {
+            warn!(%alarm, "Technical alarm is not raised");
+            return;
+        };
+        info!("Clearing {}", alarm.text());
+        alert.update += 1;
+        device.alert_message(&alert, "end", "inactive")
+    };
+    reporter.send_alert(msg);
+}
+
+fn log_ack(ack: &Ack) {
+    let code = &ack.msa.msa_1_acknowledgment_code;
+    match code.as_str() {
+        "AA" | "CA" => debug!("Received {}", code),
+        _ => warn!(text = %ack.msa.msa_3_text_message, "Received {}", code),
+    }
+}
+
+/// The span of one exchange, under the span of the connection it goes over. The alert UUID
+/// is filled in once the message is parsed.
+fn message_span(connection: &Span, control_id: &str) -> Span {
+    info_span!(parent: connection, "message", %control_id, alert = field::Empty)
+}
+
+/// Sends one encoded message and records the exchange in the journal.
+async fn audited_exchange(
+    conn: &mut ManagerConnection,
+    journal: &mut Journal,
+    address: &str,
+    control_id: &str,
+    bytes: &[u8],
+) -> io::Result<Ack> {
+    journal.audit.record(
+        AuditEvent::new(AuditEventKind::MessageSent, &journal.node, address)
+            .message(control_id, "ORU^R40"),
+    );
+    journal.stats.sent += 1;
+    let (kind, priority) = match Message::from_bytes(bytes) {
+        Ok(Message::Oru(oru)) => {
+            let alert = handlers::alert_id(&oru);
+            if !alert.is_empty() {
+                Span::current().record("alert", alert);
+            }
+            (
+                MessageKind::of(&oru),
+                handlers::alarm_priority(&oru).to_string(),
+            )
+        }
+        _ => (MessageKind::Unknown, String::new()),
+    };
+    match kind {
+        MessageKind::Heartbeat => debug!("Sending heartbeat"),
+        _ => info!(%priority, "Sending {}", kind),
+    }
+    journal.metrics.inc(
+        metrics::MESSAGES_SENT,
+        &[("type", metrics::kind_label(kind)), ("priority", &priority)],
+    );
+    let sent_at = Instant::now();
+    let result = conn.exchange_frame(control_id, bytes).await;
+    match &result {
+        Ok(ack) => {
+            log_ack(ack);
+            journal
+                .metrics
+                .observe(metrics::ACK_LATENCY, &[], sent_at.elapsed());
+            journal.metrics.inc(
+                metrics::ACKS_RECEIVED,
+                &[("code", &ack.msa.msa_1_acknowledgment_code)],
+            );
+            let kind = match ack.msa.msa_1_acknowledgment_code.as_str() {
+                "AA" | "CA" => {
+                    journal.stats.acknowledged += 1;
+                    AuditEventKind::MessageAcknowledged
                 }
+                _ => {
+                    journal.stats.rejected += 1;
+                    AuditEventKind::MessageRejected
+                }
+            };
+            let mut event = AuditEvent::new(kind, &journal.node, address)
+                .message(control_id, "ORU^R40")
+                .ack_code(&ack.msa.msa_1_acknowledgment_code);
+            if !ack.msa.msa_3_text_message.is_empty() {
+                event = event.detail(ack.msa.msa_3_text_message.clone());
             }
+            journal.audit.record(event);
+            journal.publish(ReporterEvent::Acknowledged {
+                control_id: control_id.to_string(),
+                ack: Box::new(ack.clone()),
+            });
         }
+        Err(e) => {
+            journal.stats.failed += 1;
+            if e.kind() == io::ErrorKind::InvalidData {
+                journal.metrics.inc(metrics::PARSE_FAILURES, &[]);
+            }
+            journal.auth_failure(address, e);
+            journal.publish(ReporterEvent::Failed {
+                control_id: control_id.to_string(),
+                error: e.to_string(),
+            });
+        }
+    }
+    result
+}
+
+/// Sleeps for `delay`, waking early when the reporter is stopped.
+async fn sleep_unless_stopped(delay: Duration, shutdown: &Shutdown) {
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => {}
+        _ = shutdown.wait() => {}
     }
+}
+
+/// Moves on to the next manager in the list, returning true when it wrapped around to the
+/// primary.
+fn fail_over(
+    config: &AlertRptConfig,
+    journal: &mut Journal,
+    current: &mut usize,
+    reason: &str,
+) -> bool {
+    let from = *current;
+    *current = (*current + 1) % config.managers.len();
+    if config.managers.len() > 1 {
+        journal.stats.failovers += 1;
+        journal.publish(ReporterEvent::FailedOver {
+            from: config.managers[from].clone(),
+            to: config.managers[*current].clone(),
+            reason: reason.to_string(),
+        });
+        warn!(
+            from = %config.managers[from],
+            to = %config.managers[*current],
+            %reason,
+            "FAILOVER"
+        );
+    }
+    *current == 0
+}
 
-    fn receive_loop(stop_event: Arc<Mutex<bool>>) {
-        while !*stop_event.lock().unwrap() {
-            if let Ok(mut out_socket) =
-                TcpStream::connect((MockAlertRpt::OUT_ADDRESS, MockAlertRpt::OUT_PORT))
+/// Keeps a connection to one of the managers, replaying queued alerts in order and sending
+/// heartbeats. A manager that can't be reached or stops acknowledging is replaced by the next
+/// one; a full round of failures is retried with exponential backoff. An alert stays queued
+/// until an AA or CA for its control ID arrives, and is sent again with backoff if the manager
+/// answers otherwise. A message in flight when shutdown starts still gets its ACK.
+async fn main_loop(
+    config: AlertRptConfig,
+    handle: ReporterHandle,
+    mut journal: Journal,
+    shutdown: Shutdown,
+) -> ReporterStats {
+    let queue = &handle.outbox;
+    let controls = &handle.controls;
+    let mut backoff = Backoff::new(config.reconnect.clone());
+    // Paces resending the oldest queued alert after the manager refused it.
+    let mut resend = Backoff::new(config.reconnect.clone());
+    let mut resend_at = Instant::now();
+    let mut connection: Option<ManagerConnection> = None;
+    let mut current = 0;
+    let mut missed_acks = 0;
+    let mut last_failback_check = Instant::now();
+    let mut next_heartbeat = Instant::now();
+    let mut connected_before = false;
+    let mut connection_span = Span::none();
+
+    while !shutdown.is_triggered() {
+        let address = &config.managers[current];
+        let offline = controls.disconnect.lock().unwrap().take();
+        if let Some(offline) = offline {
+            info!(manager = %address, "Disconnecting for {:?} on request", offline);
+            if let Some(conn) = connection.take() {
+                conn.close().await;
+                journal.connected(address, false);
+                journal.publish(ReporterEvent::Disconnected {
+                    manager: address.clone(),
+                    reason: "disconnected on request".to_string(),
+                });
+            }
+            sleep_unless_stopped(offline, &shutdown).await;
+            continue;
+        }
+        let Some(conn) = connection.as_mut() else {
+            debug!(manager = %address, "Connecting");
+            match ManagerConnection::connect_with(
+                config.transport.as_ref(),
+                address,
+                config.ack_timeout,
+            )
+            .await
             {
-                let mut received = [0; 1024 * 1024];
-                match out_socket.read(&mut received) {
-                    Ok(size) => {
-                        if size > 0 {
-                            let decoded = String::from_utf8_lossy(&received[..size]);
-                            println!("Received message:\n{}\n", decoded);
-                        }
+                Ok(conn) => {
+                    connection_span = info_span!("connection", manager = %address);
+                    connection_span.in_scope(|| {
+                        info!(
+                            "Connected, sending heartbeats every {:?}",
+                            config.heartbeat_interval
+                        )
+                    });
+                    // Missed ACKs carry over: a manager that accepts connections but never
+                    // answers must still be failed over from.
+                    backoff.reset();
+                    last_failback_check = Instant::now();
+                    connection = Some(conn);
+                    if std::mem::replace(&mut connected_before, true) {
+                        journal.metrics.inc(metrics::RECONNECTS, &[]);
+                    }
+                    journal.connected(address, true);
+                    journal.publish(ReporterEvent::Connected {
+                        manager: address.clone(),
+                    });
+                }
+                Err(err) => {
+                    warn!(manager = %address, "Error connecting: {}", err);
+                    journal.auth_failure(address, &err);
+                    let reason = format!("connect failed: {}", err);
+                    if fail_over(&config, &mut journal, &mut current, &reason) {
+                        let delay = backoff.next_delay();
+                        warn!(
+                            "No manager reachable (attempt {}, retrying in {:?}, {} alerts queued)",
+                            backoff.attempts(),
+                            delay,
+                            queue.queue.lock().unwrap().len()
+                        );
+                        sleep_unless_stopped(delay, &shutdown).await;
                     }
-                    Err(e) => eprintln!("Error reading from socket: {}", e),
                 }
+            }
+            continue;
+        };
+
+        if let Some(interval) = config.failback_interval {
+            if current != 0 && last_failback_check.elapsed() >= interval {
+                last_failback_check = Instant::now();
+                if let Ok(primary) = ManagerConnection::connect_with(
+                    config.transport.as_ref(),
+                    &config.managers[0],
+                    config.ack_timeout,
+                )
+                .await
+                {
+                    info!(from = %address, to = %config.managers[0], "FAILBACK");
+                    connection_span = info_span!("connection", manager = %config.managers[0]);
+                    if let Some(conn) = connection.replace(primary) {
+                        conn.close().await;
+                    }
+                    journal.metrics.inc(metrics::RECONNECTS, &[]);
+                    journal.connected(address, false);
+                    journal.connected(&config.managers[0], true);
+                    current = 0;
+                    missed_acks = 0;
+                    journal.publish(ReporterEvent::Connected {
+                        manager: config.managers[0].clone(),
+                    });
+                    continue;
+                }
+            }
+        }
+
+        // Queued alerts go out before anything else, oldest first.
+        let queued = if Instant::now() >= resend_at {
+            queue.queue.lock().unwrap().front().cloned()
+        } else {
+            None
+        };
+        let result = if let Some(item) = queued {
+            audited_exchange(
+                conn,
+                &mut journal,
+                address,
+                &item.control_id,
+                item.message.as_bytes(),
+            )
+            .instrument(message_span(&connection_span, &item.control_id))
+            .await
+            .map(|ack| match ack.msa.msa_1_acknowledgment_code.as_str() {
+                "AA" | "CA" => {
+                    resend.reset();
+                    let mut queue = queue.queue.lock().unwrap();
+                    // The item may have been dropped by an overflow while it was in flight.
+                    if queue
+                        .front()
+                        .is_some_and(|f| f.control_id == item.control_id)
+                    {
+                        queue.pop_front();
+                    }
+                }
+                code => {
+                    // Only a positive ACK takes the alert off the queue.
+                    let delay = resend.next_delay();
+                    resend_at = Instant::now() + delay;
+                    warn!(
+                        control_id = %item.control_id,
+                        "Alert answered with {}, sending it again in {:?}",
+                        code,
+                        delay
+                    );
+                }
+            })
+        } else if Instant::now() >= next_heartbeat {
+            let late = next_heartbeat.elapsed();
+            next_heartbeat = Instant::now() + config.heartbeat_interval;
+            if controls.heartbeat_paused.load(Ordering::Relaxed) {
+                continue;
+            }
+            journal
+                .metrics
+                .observe(metrics::HEARTBEAT_JITTER, &[], late);
+            let mut heartbeat = controls.device.lock().unwrap().heartbeat();
+            let msg_id = Uuid::new_v4().to_string();
+            heartbeat.set_control_id(&msg_id);
+            audited_exchange(conn, &mut journal, address, &msg_id, &heartbeat.to_bytes())
+                .instrument(message_span(&connection_span, &msg_id))
+                .await
+                .map(drop)
+        } else {
+            // Nothing to send: wait for a new alert, the next heartbeat, the next attempt at a
+            // refused alert or shutdown.
+            let wake = if resend_at > Instant::now() {
+                next_heartbeat.min(resend_at)
             } else {
-                println!("Socket not open, next time...")
+                next_heartbeat
+            };
+            tokio::select! {
+                _ = queue.ready.notified() => {}
+                _ = tokio::time::sleep_until(wake.into()) => {}
+                _ = shutdown.wait() => {}
+            }
+            continue;
+        };
+
+        match result {
+            Ok(()) => missed_acks = 0,
+            Err(err) => {
+                connection_span.in_scope(|| warn!("Lost connection: {}", err));
+                if let Some(conn) = connection.take() {
+                    conn.close().await;
+                }
+                journal.connected(address, false);
+                journal.publish(ReporterEvent::Disconnected {
+                    manager: address.clone(),
+                    reason: err.to_string(),
+                });
+                missed_acks += 1;
+                if missed_acks >= config.max_missed_acks {
+                    let reason = if connection::is_timeout(&err) {
+                        format!("{} acknowledgments missed", missed_acks)
+                    } else {
+                        format!("connection lost: {}", err)
+                    };
+                    fail_over(&config, &mut journal, &mut current, &reason);
+                    missed_acks = 0;
+                }
             }
         }
     }
+    if let Some(conn) = connection {
+        conn.close().await;
+    }
+    journal.audit.flush().await;
+    journal.stats.queued = queue.queue.lock().unwrap().len();
+    info!("Alert reporter stopped: {}", journal.stats);
+    journal.stats
 }
 
-pub fn run_mock_alert_rpt() {
-    let stop_event = Arc::new(Mutex::new(false));
-    let stop_event_clone = Arc::clone(&stop_event);
+/// Reads console lines on a plain thread: a blocked read can't be cancelled, and a runtime task
+/// stuck in one would keep the program from exiting.
+fn console_lines() -> mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
 
-    ctrlc::set_handler(move || {
-        *stop_event_clone.lock().unwrap() = true;
-    })
-    .expect("Error setting Ctrl+C");
+/// An alert reporter that can be embedded in another program, e.g. several per test suite next
+/// to an [`AlertManager`](crate::mock_alert_mgr::AlertManager).
+///
+/// ```no_run
+/// # async fn demo() -> std::io::Result<()> {
+/// use pcd_acm::mock_alert_rpt::{AlertReporter, AlertRptConfig};
+///
+/// let mut reporter = AlertReporter::new(AlertRptConfig {
+///     managers: vec!["mem:manager".to_string()],
+///     ..Default::default()
+/// })?;
+/// let mut events = reporter.subscribe();
+/// reporter.start();
+/// let msg = reporter.device().heartbeat();
+/// let control_id = reporter.send_alert(msg);
+/// let event = events.recv().await;
+/// let stats = reporter.stop().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AlertReporter {
+    config: AlertRptConfig,
+    handle: ReporterHandle,
+    /// Moves into the connection task on start.
+    journal: Option<Journal>,
+    shutdown: Shutdown,
+    task: Option<JoinHandle<ReporterStats>>,
+}
 
-    let stop_event1 = stop_event.clone();
-    let main_handle = thread::spawn(move || {
-        MockAlertRpt::main_loop(stop_event1.clone());
-    });
-    let stop_event_receive = Arc::clone(&stop_event);
-    let receive_handle = thread::spawn(move || {
-        MockAlertRpt::receive_loop(stop_event_receive);
-    });
+impl AlertReporter {
+    /// Loads the outbound queue and opens the audit trail; nothing is sent before [`start`].
+    ///
+    /// [`start`]: AlertReporter::start
+    pub fn new(config: AlertRptConfig) -> io::Result<Self> {
+        AlertReporter::with_shutdown(config, &Shutdown::new())
+    }
+
+    /// A reporter that also stops when `shutdown` is triggered.
+    pub fn with_shutdown(config: AlertRptConfig, shutdown: &Shutdown) -> io::Result<Self> {
+        let queue = OutboundQueue::new(config.queue_capacity, config.queue_file.clone())
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot load outbound queue: {}", e)))?;
+        if !queue.is_empty() {
+            info!("{} unacknowledged alerts restored", queue.len());
+        }
+        let audit = AuditLog::open("alert_reporter", &config.audit)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot open audit trail: {}", e)))?;
+        let events = broadcast::channel(EVENT_CAPACITY).0;
+        let metrics = Arc::new(metrics::reporter());
+        Ok(AlertReporter {
+            journal: Some(Journal {
+                node: config.device.equipment_id.clone(),
+                audit,
+                events: events.clone(),
+                stats: ReporterStats::default(),
+                metrics: Arc::clone(&metrics),
+            }),
+            handle: ReporterHandle {
+                outbox: Arc::new(Outbox {
+                    queue: Mutex::new(queue),
+                    ready: Notify::new(),
+                }),
+                controls: Arc::new(Controls {
+                    device: Mutex::new(config.device.clone()),
+                    heartbeat_paused: AtomicBool::new(false),
+                    disconnect: Mutex::new(None),
+                }),
+                events,
+                metrics,
+            },
+            config,
+            shutdown: shutdown.child(),
+            task: None,
+        })
+    }
+
+    /// Connects to the managers and starts sending heartbeats and queued alerts in the
+    /// background. Must be called from within a tokio runtime.
+    pub fn start(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            let span = info_span!("reporter", source = %self.config.device.eui64);
+            self.task = Some(tokio::spawn(
+                main_loop(
+                    self.config.clone(),
+                    self.handle.clone(),
+                    journal,
+                    self.shutdown.clone(),
+                )
+                .instrument(span),
+            ));
+        }
+    }
+
+    /// For controlling the reporter from other tasks while it runs.
+    pub fn handle(&self) -> ReporterHandle {
+        self.handle.clone()
+    }
+
+    pub fn device(&self) -> DeviceConfig {
+        self.handle.device()
+    }
+
+    /// See [`ReporterHandle::send_alert`].
+    pub fn send_alert(&self, msg: PCD04Message) -> String {
+        self.handle.send_alert(msg)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.handle.queued()
+    }
+
+    /// See [`ReporterHandle::subscribe`].
+    pub fn subscribe(&self) -> broadcast::Receiver<ReporterEvent> {
+        self.handle.subscribe()
+    }
+
+    /// Waits for the ACK of a message in flight, closes the connection and returns the final
+    /// statistics. Queued alerts stay in the queue file, if there is one.
+    pub async fn stop(self) -> io::Result<ReporterStats> {
+        self.shutdown.trigger();
+        self.wait().await
+    }
+
+    /// Waits until the reporter is stopped through its shutdown coordinator. Fails if the
+    /// reporter task panicked.
+    pub async fn wait(self) -> io::Result<ReporterStats> {
+        match self.task {
+            Some(task) => Ok(task.await?),
+            None => Ok(ReporterStats {
+                queued: self.handle.queued(),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+pub async fn run_mock_alert_rpt(shutdown: Shutdown) -> io::Result<ReporterStats> {
+    run_mock_alert_rpt_with(AlertRptConfig::default(), shutdown).await
+}
+
+/// Runs the interactive reporter until `q` is typed or `shutdown` is triggered; `q` triggers it
+/// too, so other actors sharing the coordinator stop as well.
+pub async fn run_mock_alert_rpt_with(
+    config: AlertRptConfig,
+    shutdown: Shutdown,
+) -> io::Result<ReporterStats> {
+    let control_address = config.control_address.clone();
+    let metrics_address = config.metrics_address.clone();
+    let mut reporter = AlertReporter::with_shutdown(config, &shutdown)?;
+    if let Some(address) = control_address {
+        let port = ControlPort::open(&address, reporter.handle(), &shutdown).await?;
+        info!("Accepting control commands on {}", port.local_address());
+    }
+    if let Some(address) = metrics_address {
+        let handle = reporter.handle();
+        let routes = metrics::routes(Arc::clone(&handle.metrics), move |metrics| {
+            handle.refresh_metrics(metrics)
+        });
+        let server = HttpServer::open(&address, routes, &shutdown).await?;
+        info!("Metrics listening on {}", server.local_address());
+    }
+    reporter.start();
 
     println!("PCD-ACM AR Simulator");
     println!("Press a to Simulate sending an alert");
     println!("Press t to toggle heartbeat simulation");
+    println!("Type r NAME / c NAME to raise / clear a technical alarm, one of:");
+    println!(
+        "    {}",
+        TechnicalAlarm::ALL.map(TechnicalAlarm::name).join(", ")
+    );
     println!("Press q to quit");
 
-    let mut next = true;
-    while next {
-        let mut input = String::new();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read line");
+    let mut technical = HashMap::new();
+    let mut console = console_lines();
+    loop {
+        let input = tokio::select! {
+            Some(line) = console.recv() => line,
+            _ = shutdown.wait() => break,
+        };
 
         let key = input.trim();
         match key {
-            "q" => {
-                next = false;
-                let stop_event_clone = Arc::clone(&stop_event);
-                *stop_event_clone.lock().unwrap() = true;
+            "q" => break,
+            "a" => {
+                reporter.send_alert(example_alert(&reporter.device()));
             }
-            "a" => MockAlertRpt::send_alert(),
             "t" => {
-                println!("Toggling heartbeat from {}", MockAlertRpt::SEND_HEARTBEAT);
+                let handle = reporter.handle();
+                let paused = !handle.heartbeat_paused();
+                handle.pause_heartbeat(paused);
+                info!(paused, "Toggled heartbeat");
             }
-            _ => println!("Unknown key: {}", key),
+            _ => match key.split_once(' ') {
+                Some((command @ ("r" | "c"), name)) => match name.trim().parse() {
+                    Ok(alarm) => send_technical(&reporter, &mut technical, alarm, command == "r"),
+                    Err(e) => warn!("{}", e),
+                },
+                _ => warn!(key, "Unknown key"),
+            },
         }
     }
-    println!("Simulation completed...");
+    shutdown.trigger();
+    info!("Simulation completed");
+
+    reporter.wait().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ack_policy::{AckPolicy, AckRule, Delivery};
+    use crate::mock_alert_mgr::{AlertManager, AlertMgrConfig};
+
+    #[tokio::test]
+    async fn fails_over_from_a_manager_that_never_acknowledges() {
+        let mut silent = AlertManager::new(AlertMgrConfig {
+            listen_address: "mem:failover-silent".to_string(),
+            ack_policy: AckPolicy {
+                rules: vec![AckRule {
+                    delivery: Delivery::Drop,
+                    ..Default::default()
+                }],
+            },
+            ..Default::default()
+        });
+        silent.start().await.unwrap();
+        let mut backup = AlertManager::new(AlertMgrConfig {
+            listen_address: "mem:failover-backup".to_string(),
+            ..Default::default()
+        });
+        backup.start().await.unwrap();
 
-    main_handle.join().unwrap();
-    receive_handle.join().unwrap();
+        let mut reporter = AlertReporter::new(AlertRptConfig {
+            managers: vec![
+                "mem:failover-silent".to_string(),
+                "mem:failover-backup".to_string(),
+            ],
+            ack_timeout: Duration::from_millis(100),
+            heartbeat_interval: Duration::from_millis(20),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut events = reporter.subscribe();
+        reporter.start();
+
+        let (from, to) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let ReporterEvent::FailedOver { from, to, .. } = events.recv().await.unwrap() {
+                    break (from, to);
+                }
+            }
+        })
+        .await
+        .expect("no failover");
+        assert_eq!(from, "mem:failover-silent");
+        assert_eq!(to, "mem:failover-backup");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !matches!(events.recv().await, Ok(ReporterEvent::Acknowledged { .. })) {}
+        })
+        .await
+        .expect("backup never acknowledged");
+
+        let stats = reporter.stop().await.unwrap();
+        assert_eq!(stats.failovers, 1);
+        assert!(silent.stop().await.unwrap().messages >= 2);
+        assert!(backup.stop().await.unwrap().acknowledged >= 1);
+    }
 }
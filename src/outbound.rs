@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::pcd04_msg::PCD04Message;
+
+/// An alert waiting for the manager's acknowledgment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub control_id: String,
+    /// The message in ER7 form.
+    pub message: String,
+}
+
+/// One line of the queue file. The file is a journal of these, so each change appends a line
+/// instead of rewriting the whole queue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JournalEntry {
+    Push(QueuedMessage),
+    /// The front message was acknowledged, or dropped to make room.
+    Pop {
+        control_id: String,
+    },
+}
+
+/// A bounded store-and-forward queue of unacknowledged alerts, optionally persisted so they
+/// survive a restart of the reporter.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    capacity: usize,
+    path: Option<PathBuf>,
+    journal: Option<File>,
+    /// Entries appended since the journal was last compacted.
+    appended: usize,
+    items: VecDeque<QueuedMessage>,
+}
+
+impl OutboundQueue {
+    /// Creates the queue, reloading any messages still stored at `path`. If more were stored than
+    /// fit in `capacity`, the oldest are dropped.
+    pub fn new(capacity: usize, path: Option<PathBuf>) -> io::Result<Self> {
+        let mut items = match &path {
+            Some(path) if path.exists() => replay(path)?,
+            _ => VecDeque::new(),
+        };
+        let capacity = capacity.max(1);
+        let excess = items.len().saturating_sub(capacity);
+        if excess > 0 {
+            warn!(
+                dropped = excess,
+                capacity, "Outbound queue file holds more alerts than fit; dropping the oldest"
+            );
+            items.drain(..excess);
+        }
+        let mut queue = OutboundQueue {
+            capacity,
+            path,
+            journal: None,
+            appended: 0,
+            items,
+        };
+        if queue.path.is_some() {
+            queue.compact()?;
+        }
+        Ok(queue)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn front(&self) -> Option<&QueuedMessage> {
+        self.items.front()
+    }
+
+    /// Queues `msg`. When the queue is full the oldest message is dropped and returned.
+    pub fn push(&mut self, msg: &PCD04Message) -> Option<QueuedMessage> {
+        let oru = msg.get_message()?;
+        let item = QueuedMessage {
+            control_id: oru.msh.msh_10_message_control_id.clone(),
+            message: msg.encode(),
+        };
+        let mut entries = Vec::new();
+        let dropped = if self.items.len() >= self.capacity {
+            self.items.pop_front()
+        } else {
+            None
+        };
+        if let Some(dropped) = &dropped {
+            entries.push(JournalEntry::Pop {
+                control_id: dropped.control_id.clone(),
+            });
+        }
+        entries.push(JournalEntry::Push(item.clone()));
+        self.items.push_back(item);
+        self.persist(&entries);
+        dropped
+    }
+
+    /// Removes the front message once it has been acknowledged.
+    pub fn pop_front(&mut self) -> Option<QueuedMessage> {
+        let item = self.items.pop_front()?;
+        self.persist(&[JournalEntry::Pop {
+            control_id: item.control_id.clone(),
+        }]);
+        Some(item)
+    }
+
+    fn persist(&mut self, entries: &[JournalEntry]) {
+        if self.path.is_none() {
+            return;
+        }
+        // Once the journal is mostly history, rewrite it with just the queued messages.
+        let result = if self.appended + entries.len() > 2 * self.capacity {
+            self.compact()
+        } else {
+            self.append(entries)
+        };
+        if let (Err(e), Some(path)) = (result, &self.path) {
+            error!(path = %path.display(), "Error persisting outbound queue: {}", e);
+            // Start from a clean file next time rather than appending after a partial line.
+            self.journal = None;
+        }
+    }
+
+    fn append(&mut self, entries: &[JournalEntry]) -> io::Result<()> {
+        let Some(journal) = &mut self.journal else {
+            return self.compact();
+        };
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry).map_err(io::Error::other)?);
+            lines.push('\n');
+        }
+        journal.write_all(lines.as_bytes())?;
+        self.appended += entries.len();
+        Ok(())
+    }
+
+    /// Replaces the journal with one `push` per queued message.
+    fn compact(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        self.journal = None;
+        let mut lines = String::new();
+        for item in &self.items {
+            let entry = JournalEntry::Push(item.clone());
+            lines.push_str(&serde_json::to_string(&entry).map_err(io::Error::other)?);
+            lines.push('\n');
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, lines)?;
+        fs::rename(&tmp, path)?;
+        self.journal = Some(OpenOptions::new().append(true).open(path)?);
+        self.appended = 0;
+        Ok(())
+    }
+}
+
+/// Rebuilds the queue from its journal. A torn last line, left by a crash in the middle of a
+/// write, is ignored.
+fn replay(path: &Path) -> io::Result<VecDeque<QueuedMessage>> {
+    let text = fs::read_to_string(path)?;
+    let mut items = VecDeque::new();
+    let mut lines = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .peekable();
+    while let Some(line) = lines.next() {
+        match serde_json::from_str(line) {
+            Ok(JournalEntry::Push(item)) => items.push_back(item),
+            Ok(JournalEntry::Pop { control_id }) => {
+                if items
+                    .front()
+                    .is_some_and(|item| item.control_id == control_id)
+                {
+                    items.pop_front();
+                }
+            }
+            Err(e) if lines.peek().is_none() => {
+                warn!(path = %path.display(), "Ignoring incomplete last entry: {}", e)
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceConfig;
+
+    fn message(control_id: &str) -> PCD04Message {
+        let mut msg = DeviceConfig::default().heartbeat();
+        msg.set_control_id(control_id);
+        msg
+    }
+
+    fn control_ids(queue: &OutboundQueue) -> Vec<String> {
+        queue
+            .items
+            .iter()
+            .map(|item| item.control_id.clone())
+            .collect()
+    }
+
+    fn queue_file(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("outbound-{}-{}", test, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn reload_restores_unacknowledged_messages() {
+        let path = queue_file("reload");
+        let mut queue = OutboundQueue::new(3, Some(path.clone())).unwrap();
+        for id in ["1", "2", "3", "4"] {
+            queue.push(&message(id));
+        }
+        assert_eq!(queue.pop_front().unwrap().control_id, "2");
+        drop(queue);
+
+        let mut queue = OutboundQueue::new(3, Some(path.clone())).unwrap();
+        assert_eq!(control_ids(&queue), ["3", "4"]);
+        assert_eq!(queue.front().unwrap().message, message("3").encode());
+
+        // A smaller queue keeps the newest messages.
+        queue.push(&message("5"));
+        drop(queue);
+        let queue = OutboundQueue::new(2, Some(path.clone())).unwrap();
+        assert_eq!(control_ids(&queue), ["4", "5"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journal_is_appended_and_compacted() {
+        let path = queue_file("compact");
+        let mut queue = OutboundQueue::new(2, Some(path.clone())).unwrap();
+        queue.push(&message("1"));
+        queue.push(&message("2"));
+        queue.pop_front();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        // Past twice the capacity the journal is rewritten with only what is queued.
+        queue.push(&message("3"));
+        queue.pop_front();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        drop(queue);
+        let queue = OutboundQueue::new(2, Some(path.clone())).unwrap();
+        assert_eq!(control_ids(&queue), ["3"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_last_entry_is_ignored() {
+        let path = queue_file("torn");
+        let mut queue = OutboundQueue::new(5, Some(path.clone())).unwrap();
+        queue.push(&message("1"));
+        queue.push(&message("2"));
+        drop(queue);
+        let mut text = fs::read_to_string(&path).unwrap();
+        text.push_str("{\"push\":{\"control_id\":\"3\",\"mess");
+        fs::write(&path, text).unwrap();
+
+        let queue = OutboundQueue::new(5, Some(path.clone())).unwrap();
+        assert_eq!(control_ids(&queue), ["1", "2"]);
+
+        fs::write(&path, "garbage\n{}\n").unwrap();
+        assert!(OutboundQueue::new(5, Some(path.clone())).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
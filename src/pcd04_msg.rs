@@ -1,37 +1,59 @@
 use chrono::Utc;
-use hl7::messages::ORU_R01;
-use hl7::segments::{NTE, OBX, ORC, PID, PV1};
+
+use crate::encoding::Delimiters;
+use crate::messages::{Message, ObservationGroup, OrderGroup, Oru, PatientGroup};
+use crate::segments::{NTE, OBR, OBX, PID, PV1};
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct PCD04Message {
     heartbeat_ar_type: &'static str,
-    oru_r40: ORU_R01,
+    oru_r40: Oru,
     msg_control_id_iter: usize,
     obx_count: usize,
     equip_ii: String,
+    delimiters: Delimiters,
+    /// EUI-64 of the reporting actor, sent in MSH-3 and OBR-3.
+    actor_eui64: String,
 }
 #[allow(dead_code)]
 impl PCD04Message {
-    const ACTOR_EUI64: &'static str = "0000000000000001^EUI-64";
-    const ACTOR_EUI64_SUB: &'static str = "0000000000000001&EUI-64";
+    pub const DEFAULT_EUI64: &'static str = "0000000000000001";
     const ACCEPT_ACK_TYPE_ACM: &'static str = "AL";
     const APP_ACK_TYPE: &'static str = "NE";
 
     pub(crate) fn new() -> Self {
+        PCD04Message::with_eui64(Self::DEFAULT_EUI64)
+    }
+
+    pub(crate) fn with_eui64(actor_eui64: &str) -> Self {
         PCD04Message {
             heartbeat_ar_type: "",
-            oru_r40: ORU_R01::default(),
+            oru_r40: Oru::default(),
             msg_control_id_iter: 0,
             obx_count: 0,
             equip_ii: String::new(), // ntf
+            delimiters: Delimiters::default(),
+            actor_eui64: actor_eui64.to_string(),
         }
     }
 
-    pub fn get_message(&self) -> Option<ORU_R01> {
+    pub fn get_message(&self) -> Option<Oru> {
         Some(self.oru_r40.clone())
     }
 
+    /// The message in ER7 form, ready to be framed and sent.
+    pub fn encode(&self) -> String {
+        self.oru_r40.encode(&self.delimiters)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Message::Oru(self.oru_r40.clone())
+            .to_bytes(&self.delimiters)
+            .unwrap_or_else(|_| self.encode().into_bytes())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn create_pcd04_message(
         &mut self,
         assigned_location: &str,
@@ -60,10 +82,12 @@ impl PCD04Message {
         processing_id: &str,
         mds_type: &str,
         vmd_type: &str,
+        chan_type: &str,
     ) {
         let msg_time = Utc::now();
-        let msg_time_str = msg_time.format("%Y%m%d%H%M%S%Z").to_string();
+        let msg_time_str = msg_time.format("%Y%m%d%H%M%S%z").to_string();
 
+        self.oru_r40 = Oru::default();
         self.create_msh_segment_acm(
             &msg_time_str,
             sending_facility,
@@ -76,35 +100,45 @@ impl PCD04Message {
         self.create_obr_segment_acm(&msg_time_str, unique_alert_uuid, alert_counter);
         self.obx_count = 0;
 
-        let mds_tree = format!("{}.0.0", src_containment_tree_id.split(".").next().unwrap());
+        let mds_tree = format!("{}.0.0", src_containment_tree_id.split('.').next().unwrap());
         let vmd_tree = format!(
             "{}.{}.0",
-            src_containment_tree_id.split(".").next().unwrap(),
-            src_containment_tree_id.split(".").nth(1).unwrap()
+            src_containment_tree_id.split('.').next().unwrap(),
+            src_containment_tree_id.split('.').nth(1).unwrap()
         );
+        // Free text must not leak delimiters into the message structure.
+        let alert_text = self.delimiters.escape(alert_text);
+        let obs_value = self.delimiters.escape(obs_value);
+        let alert_text_type = if alert_text.is_empty() { "" } else { "ST" };
 
         self.create_obx_segment_acm(0, mds_type, "", "", "", "", "", &mds_tree);
         self.create_obx_segment_acm(0, vmd_type, "", "", "", "", "", &vmd_tree);
+        if !chan_type.is_empty() && src_containment_tree_id.split('.').nth(2) != Some("0") {
+            self.create_obx_segment_acm(0, chan_type, "", "", "", "", "", src_containment_tree_id);
+        }
         self.create_obx_segment_acm(
             1,
             alert_type,
-            alert_text,
-            "",
+            &alert_text,
             "",
             "",
             "",
+            alert_text_type,
             src_containment_tree_id,
         );
-        self.create_obx_segment_acm(
-            2,
-            obs_type,
-            obs_value,
-            obs_unit,
-            obs_det_time,
-            "",
-            obs_value_type,
-            src_containment_tree_id,
-        );
+        // Technical alarms have no triggering measurement; leave facet 2 out entirely.
+        if !obs_type.is_empty() {
+            self.create_obx_segment_acm(
+                2,
+                obs_type,
+                &obs_value,
+                obs_unit,
+                obs_det_time,
+                "",
+                obs_value_type,
+                src_containment_tree_id,
+            );
+        }
         self.create_obx_segment_acm(
             3,
             "68481^MDC_ATTR_EVENT_PHASE^MDC",
@@ -112,7 +146,7 @@ impl PCD04Message {
             "",
             "",
             "",
-            "",
+            "ST",
             src_containment_tree_id,
         );
         self.create_obx_segment_acm(
@@ -122,7 +156,7 @@ impl PCD04Message {
             "",
             "",
             "",
-            "",
+            "ST",
             src_containment_tree_id,
         );
         self.create_obx_segment_acm(
@@ -132,7 +166,7 @@ impl PCD04Message {
             "",
             "",
             "",
-            "",
+            "ST",
             src_containment_tree_id,
         );
         self.create_obx_segment_acm(
@@ -142,7 +176,7 @@ impl PCD04Message {
             "",
             "",
             "",
-            "",
+            "ST",
             src_containment_tree_id,
         );
         self.create_obx_segment_acm(
@@ -152,7 +186,7 @@ impl PCD04Message {
             "",
             "",
             "",
-            "",
+            "ST",
             src_containment_tree_id,
         );
     }
@@ -190,21 +224,22 @@ impl PCD04Message {
         self.msg_control_id_iter += 1;
 
         let msh = &mut self.oru_r40.msh;
-        msh.msh_3_sending_application = Some(Self::ACTOR_EUI64.to_string());
-        msh.msh_4_sending_facility = Some(sending_facility.to_string());
+        msh.msh_3_sending_application = format!("{}^EUI-64", self.actor_eui64);
+        msh.msh_4_sending_facility = sending_facility.to_string();
 
         if let Some(receiving_app) = receiving_app {
-            msh.msh_5_receiving_application = Some(receiving_app.to_string());
+            msh.msh_5_receiving_application = receiving_app.to_string();
         }
         msh.msh_7_date_time_of_message = msg_time_str.to_string();
         msh.msh_9_message_type = "ORU^R40^ORU_R40".to_string();
         msh.msh_10_message_control_id = msg_control_id_val.to_string();
         msh.msh_11_processing_id = processing_id.to_string();
-        msh.msh_15_accept_acknowledgment_type = Some(Self::ACCEPT_ACK_TYPE_ACM.to_string());
-        msh.msh_16_application_acknowledgment_type = Some(Self::APP_ACK_TYPE.to_string());
-        msh.msh_21_message_profile_identifier = Some(vec![
-            "IHE_PCD_ACM_001^IHE PCD^1.3.6.1.4.1.19376.1.6.1.4.1^ISO".to_string(),
-        ]);
+        msh.msh_12_version_id = "2.6".to_string();
+        msh.msh_15_accept_acknowledgment_type = Self::ACCEPT_ACK_TYPE_ACM.to_string();
+        msh.msh_16_application_acknowledgment_type = Self::APP_ACK_TYPE.to_string();
+        msh.msh_18_character_set = vec!["UNICODE UTF-8".to_string()];
+        msh.msh_21_message_profile_identifier =
+            vec!["IHE_PCD_ACM_001^IHE PCD^1.3.6.1.4.1.19376.1.6.1.4.1^ISO".to_string()];
     }
 
     fn create_pid_segment_acm(
@@ -214,126 +249,110 @@ impl PCD04Message {
         patient_dob: &str,
         patient_sex: &str,
     ) {
-        let mut pid = PID::default();
-
-        pid.pid3_patient_identifier_list = vec![patient_id_list.to_string()];
-        pid.pid5_patient_name = vec![patient_name.to_string()];
-        pid.pid7_date_time_of_birth = Some(patient_dob.to_string());
-        pid.pid8_administrative_sex = Some(patient_sex.to_string());
+        let pid = PID {
+            pid_3_patient_identifier_list: vec![self.escape_components(patient_id_list)],
+            pid_5_patient_name: vec![self.escape_components(patient_name)],
+            pid_7_date_time_of_birth: patient_dob.to_string(),
+            pid_8_administrative_sex: patient_sex.to_string(),
+            ..Default::default()
+        };
 
         self.oru_r40
-            .oru_r01_patient_result
-            .iter_mut()
-            .flat_map(|patient_result| &mut patient_result.oru_r01_patient)
-            .for_each(|patient| patient.pid = pid.clone());
+            .patient
+            .get_or_insert_with(PatientGroup::default)
+            .pid = pid;
     }
 
     fn create_pv1_segment_acm(&mut self, location: &str) {
-        let mut pv1 = PV1::default();
-        pv1.pv1_2_patient_class = "I".to_string();
-        pv1.pv1_3_assigned_patient_location = Some(location.to_string());
-
-        for patient_result in &mut self.oru_r40.oru_r01_patient_result {
-            if let Some(patient) = &mut patient_result.oru_r01_patient {
-                if let Some(visit) = &mut patient.oru_r01_visit {
-                    visit.pv1 = pv1.clone();
-                }
-            }
-        }
+        let pv1 = PV1 {
+            pv1_2_patient_class: "I".to_string(),
+            pv1_3_assigned_patient_location: self.escape_components(location),
+            ..Default::default()
+        };
+
+        self.oru_r40
+            .patient
+            .get_or_insert_with(PatientGroup::default)
+            .pv1 = Some(pv1);
+    }
+
+    /// Escapes each component of a `^`-separated value such as a name or a bed label, so a
+    /// delimiter inside one component can't shift the others.
+    fn escape_components(&self, value: &str) -> String {
+        let separator = self.delimiters.component.to_string();
+        value
+            .split(self.delimiters.component)
+            .map(|c| self.delimiters.escape(c))
+            .collect::<Vec<_>>()
+            .join(&separator)
     }
 
     fn inc_alert_counter(&mut self) {
-        let mut orc = ORC::default();
-        if let Some(old_count_str) = orc
-            .orc_3_filler_order_number
-            .as_deref()
-            .map(|s| s.split('^').collect::<Vec<_>>())
-        {
-            if let Some(old_count) = old_count_str[0].parse::<i32>().ok() {
-                let filler_order_number = format!(
-                    "{}^{}^{}",
-                    old_count + 1,
-                    old_count_str[1],
-                    old_count_str[2]
-                );
-
-                orc.orc_3_filler_order_number = Some(filler_order_number);
-
-                if let Some(obx_segment) = self.oru_r40.oru_r01_patient_result[0]
-                    .oru_r01_patient_observation
-                    .get_mut(2)
-                {
-                    if let Some(obx_5_observation_value) =
-                        &mut obx_segment.obx.obx_5_observation_value
-                    {
-                        obx_5_observation_value[0] = "continue".to_string();
-                    }
-                }
-            }
+        let Some(order) = self.oru_r40.orders.first_mut() else {
+            return;
+        };
+        let old_count_str: Vec<String> = order
+            .obr
+            .obr_3_filler_order_number
+            .splitn(2, '^')
+            .map(str::to_string)
+            .collect();
+
+        if let Ok(old_count) = old_count_str[0].parse::<i32>() {
+            order.obr.obr_3_filler_order_number = format!(
+                "{}^{}",
+                old_count + 1,
+                old_count_str.get(1).map_or("", |s| s.as_str())
+            );
+            self.set_alarm_phase("continue");
         }
     }
 
+    pub fn get_control_id(&self) -> &str {
+        &self.oru_r40.msh.msh_10_message_control_id
+    }
+
     pub fn set_control_id(&mut self, id: &str) {
         let msg_ctrl_id = &mut self.oru_r40.msh;
         msg_ctrl_id.msh_10_message_control_id = id.to_string();
     }
 
-    fn set_observation_value_by_index(&mut self, index: usize, observation_value: &str) {
+    fn alert_obx_mut(&mut self, index: u32) -> Option<&mut OBX> {
         self.oru_r40
-            .oru_r01_patient_result
-            .iter_mut()
-            .flat_map(|res| &mut res.oru_r01_patient_observation)
-            .enumerate()
-            .for_each(|(obs_index, result)| {
-                if obs_index == index {
-                    let mut obx_seg = OBX::default();
-                    obx_seg.obx_5_observation_value = Some(vec![observation_value.to_string()]);
-                    result.obx = obx_seg;
-                }
-            });
+            .observations_mut()
+            .map(|observation| &mut observation.obx)
+            .find(|obx| index != 0 && obx.facet() == Some(index))
+    }
+
+    fn set_observation_value_by_index(&mut self, index: u32, observation_value: &str) {
+        if let Some(obx) = self.alert_obx_mut(index) {
+            obx.obx_5_observation_value = vec![observation_value.to_string()];
+        }
     }
 
     fn set_alarm_type_and_text(&mut self, alarm_type: &str, alarm_text: &str) {
-        self.oru_r40
-            .oru_r01_patient_result
-            .iter_mut()
-            .flat_map(|result| &mut result.oru_r01_patient_observation)
-            .for_each(|patient| {
-                patient.obx = {
-                    let mut obx_seg1 = OBX::default();
-                    obx_seg1.obx_3_observation_identifier = alarm_type.to_string();
-                    obx_seg1.obx_5_observation_value = Some(vec![alarm_text.to_string()]);
-                    obx_seg1
-                }
-            })
+        let alarm_text = self.delimiters.escape(alarm_text);
+        if let Some(obx) = self.alert_obx_mut(1) {
+            obx.obx_3_observation_identifier = alarm_type.to_string();
+            obx.obx_5_observation_value = vec![alarm_text];
+        }
     }
 
     fn set_alarm_value(&mut self, value: f64, obs_type: &str, unit: &str, time: &str) {
-        self.oru_r40
-            .oru_r01_patient_result
-            .iter_mut()
-            .flat_map(|res| &mut res.oru_r01_patient_observation)
-            .for_each(|patient| {
-                let mut obx_seg2 = OBX::default();
-                obx_seg2.obx_2_value_type = value.to_string();
-                obx_seg2.obx_5_observation_value = Some(vec![obs_type.to_string()]);
-                obx_seg2.obx_6_units = Some(unit.to_string());
-                obx_seg2.obx_14_date_time_of_the_observation = Some(time.to_string());
-
-                patient.obx = obx_seg2
-            })
+        if let Some(obx) = self.alert_obx_mut(2) {
+            obx.obx_2_value_type = "NM".to_string();
+            obx.obx_3_observation_identifier = obs_type.to_string();
+            obx.obx_5_observation_value = vec![value.to_string()];
+            obx.obx_6_units = unit.to_string();
+            obx.obx_11_observation_result_status = "F".to_string();
+            obx.obx_14_date_time_of_the_observation = time.to_string();
+        }
     }
 
     fn set_alarm_ctp(&mut self, nte: NTE) {
         self.oru_r40
-            .oru_r01_patient_result
-            .iter_mut()
-            .for_each(|result| {
-                result
-                    .oru_r01_patient
-                    .iter_mut()
-                    .for_each(|p| p.nte = Some(vec![nte.clone()]));
-            })
+            .observations_mut()
+            .for_each(|observation| observation.nte = vec![nte.clone()]);
     }
 
     #[allow(dead_code)]
@@ -361,87 +380,59 @@ impl PCD04Message {
 
     fn get_device_id(&self) -> Option<&str> {
         self.oru_r40
-            .oru_r01_patient_result
-            .iter()
-            .flat_map(|result| &result.oru_r01_patient_observation)
-            .flat_map(|r| r.obx.obx_18_equipment_instance_identifier.as_deref())
-            .flat_map(|s| s.get(0).map(|s| s.as_str()))
+            .observations()
+            .flat_map(|r| r.obx.obx_18_equipment_instance_identifier.first())
+            .map(|s| s.as_str())
             .next()
     }
 
     fn get_location(&self) -> Option<&str> {
-        for location in &self.oru_r40.oru_r01_patient_result {
-            if let Some(p) = &location.oru_r01_patient {
-                if let Some(visit) = &p.oru_r01_visit {
-                    return visit.pv1.pv1_3_assigned_patient_location.as_deref();
-                }
-            }
-        }
-        None
+        self.oru_r40
+            .patient
+            .as_ref()
+            .and_then(|patient| patient.pv1.as_ref())
+            .map(|pv1| pv1.pv1_3_assigned_patient_location.as_str())
     }
+
     fn get_equip(&self) -> Option<&str> {
-        for result in &self.oru_r40.oru_r01_patient_result {
-            for obs in &result.oru_r01_patient_observation {
-                if let Some(obx) = Option::from(&obs.obx) {
-                    return obx
-                        .obx_18_equipment_instance_identifier
-                        .as_deref()
-                        .and_then(|v| v.first().map(|s| s.as_str()));
-                }
-            }
-        }
-        None
+        self.oru_r40
+            .observations()
+            .next()
+            .and_then(|obs| obs.obx.obx_18_equipment_instance_identifier.first())
+            .map(|s| s.as_str())
+    }
+
+    fn first_patient(&self) -> Option<&PID> {
+        self.oru_r40.patient.as_ref().map(|patient| &patient.pid)
     }
 
     fn get_patient_id(&self) -> Option<&str> {
-        for patient_result in &self.oru_r40.oru_r01_patient_result {
-            for patient in &patient_result.oru_r01_patient {
-                return patient
-                    .pid
-                    .pid3_patient_identifier_list
-                    .get(0)
-                    .map(|s| s.as_str());
-            }
-        }
-        None
+        self.first_patient()
+            .and_then(|pid| pid.pid_3_patient_identifier_list.first())
+            .map(|s| s.as_str())
     }
 
     fn get_patient_name(&self) -> Option<&str> {
-        for patient_result in &self.oru_r40.oru_r01_patient_result {
-            for patient in &patient_result.oru_r01_patient {
-                return patient.pid.pid5_patient_name.first().map(|s| s.as_str());
-            }
-        }
-        None
+        self.first_patient()
+            .and_then(|pid| pid.pid_5_patient_name.first())
+            .map(|s| s.as_str())
     }
 
     fn get_patient_dob(&self) -> Option<&str> {
-        for patient_result in &self.oru_r40.oru_r01_patient_result {
-            for patient in &patient_result.oru_r01_patient {
-                return patient.pid.pid7_date_time_of_birth.as_deref();
-            }
-        }
-        None
+        self.first_patient()
+            .map(|pid| pid.pid_7_date_time_of_birth.as_str())
     }
 
     fn get_patient_sex(&self) -> Option<&str> {
-        for patient_result in &self.oru_r40.oru_r01_patient_result {
-            for patient in &patient_result.oru_r01_patient {
-                return patient.pid.pid8_administrative_sex.as_deref();
-            }
-        }
-        None
+        self.first_patient()
+            .map(|pid| pid.pid_8_administrative_sex.as_str())
     }
 
-    fn get_obx_segment(&self, nr: &str) -> Option<&str> {
-        for patient_result in &self.oru_r40.oru_r01_patient_result {
-            for patient_obs in &patient_result.oru_r01_patient_observation {
-                if patient_obs.obx.obx_1_set_id == Some(nr.to_string()) {
-                    return patient_obs.obx.obx_1_set_id.as_deref();
-                }
-            }
-        }
-        None
+    fn get_obx_segment(&self, nr: &str) -> Option<&OBX> {
+        self.oru_r40
+            .observations()
+            .map(|observation| &observation.obx)
+            .find(|obx| obx.obx_1_set_id == nr)
     }
 
     fn create_obr_segment_acm(
@@ -450,25 +441,31 @@ impl PCD04Message {
         unique_alert_uuid: &str,
         alert_update: i32,
     ) {
-        let mut obr = ORC::default();
         let filler_order_number = format!(
-            "{}^{}^{}",
-            alert_update,
-            unique_alert_uuid,
-            Self::ACTOR_EUI64
+            "{}^{}^{}^EUI-64",
+            alert_update, unique_alert_uuid, self.actor_eui64
         );
 
-        obr.orc_1_order_control = "1".to_string();
-        obr.orc_3_filler_order_number = Some(filler_order_number);
-        obr.orc_4_placer_group_number = Some("196616^MDC_EVT_ALARM^MDC".to_string());
-        obr.orc_7_quantity_timing = Some(vec![message_time_str.to_string()]);
+        let mut obr = OBR {
+            obr_1_set_id: "1".to_string(),
+            obr_3_filler_order_number: filler_order_number,
+            obr_4_universal_service_identifier: "196616^MDC_EVT_ALARM^MDC".to_string(),
+            obr_7_observation_date_time: message_time_str.to_string(),
+            ..Default::default()
+        };
 
         if alert_update > 0 {
-            let parent_alert = format!("^0&{}&{}", unique_alert_uuid, Self::ACTOR_EUI64_SUB);
-            obr.orc_29_order_type = Some(parent_alert.to_string())
+            let parent_alert = format!("^0&{}&{}&EUI-64", unique_alert_uuid, self.actor_eui64);
+            obr.obr_29_parent = parent_alert.to_string()
         }
+
+        self.oru_r40.orders.push(OrderGroup {
+            obr,
+            ..Default::default()
+        });
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn create_obx_segment_acm(
         &mut self,
         set_id: usize,
@@ -484,7 +481,7 @@ impl PCD04Message {
 
         self.obx_count += 1;
 
-        obx.obx_1_set_id = Some(self.obx_count.to_string());
+        obx.obx_1_set_id = self.obx_count.to_string();
 
         if !obs_value_type.is_empty() {
             obx.obx_2_value_type = obs_value_type.to_string();
@@ -495,10 +492,17 @@ impl PCD04Message {
 
         obx.obx_3_observation_identifier = obs_id.to_string();
         obx.obx_4_observation_sub_id = format!("{}.{}", ctp, set_id);
-        obx.obx_5_observation_value = Some(vec![obs_value.to_string()]);
-        obx.obx_6_units = Some(obs_unit.to_string());
-        obx.obx_14_date_time_of_the_observation = Some(obs_time_str.to_string());
-        obx.obx_18_equipment_instance_identifier = Some(vec![self.equip_ii.to_string()]);
-        obx.obx_20_observation_site = Some(vec![obs_site.to_string()]);
+        obx.obx_5_observation_value = vec![obs_value.to_string()];
+        obx.obx_6_units = obs_unit.to_string();
+        obx.obx_14_date_time_of_the_observation = obs_time_str.to_string();
+        obx.obx_18_equipment_instance_identifier = vec![self.equip_ii.to_string()];
+        obx.obx_20_observation_site = vec![obs_site.to_string()];
+
+        if let Some(order) = self.oru_r40.orders.last_mut() {
+            order.observations.push(ObservationGroup {
+                obx,
+                ..Default::default()
+            });
+        }
     }
 }
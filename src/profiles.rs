@@ -0,0 +1,407 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::device::{AlertSpec, ObservationSpec};
+use crate::technical::TechnicalAlarm;
+use crate::vitals::{EVENT_HIGH, EVENT_LOW};
+
+/// A numeric the device measures, with its default alarm limits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metric {
+    pub code: &'static str,
+    pub unit: &'static str,
+    pub low: Option<f64>,
+    pub high: Option<f64>,
+    pub priority: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Channel {
+    pub code: &'static str,
+    pub metrics: &'static [Metric],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vmd {
+    pub code: &'static str,
+    pub channels: &'static [Channel],
+}
+
+/// The containment tree, metrics and alarms of a typical device class.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceProfile {
+    pub name: &'static str,
+    pub mds: &'static str,
+    pub vmds: &'static [Vmd],
+    pub technical: &'static [TechnicalAlarm],
+}
+
+/// Where an alert sits in the device's containment tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    /// `<mds>.<vmd>.<channel>`, 1-based.
+    pub containment: String,
+    pub mds_type: String,
+    pub vmd_type: String,
+    /// Empty for alerts about the VMD as a whole (channel 0).
+    pub chan_type: String,
+}
+
+const fn metric(
+    code: &'static str,
+    unit: &'static str,
+    low: Option<f64>,
+    high: Option<f64>,
+    priority: &'static str,
+) -> Metric {
+    Metric {
+        code,
+        unit,
+        low,
+        high,
+        priority,
+    }
+}
+
+const PERCENT: &str = "262688^MDC_DIM_PERCENT^MDC";
+const MMHG: &str = "266016^MDC_DIM_MMHG^MDC";
+const ML: &str = "263762^MDC_DIM_MILLI_L^MDC";
+
+pub const PATIENT_MONITOR: DeviceProfile = DeviceProfile {
+    name: "patient_monitor",
+    mds: "69965^MDC_DEV_MON_PHYSIO_MULTI_PARAM_MDS^MDC",
+    vmds: &[
+        Vmd {
+            code: "69798^MDC_DEV_ECG_VMD^MDC",
+            channels: &[Channel {
+                code: "69799^MDC_DEV_ECG_CHAN^MDC",
+                metrics: &[metric(
+                    "147842^MDC_ECG_HEART_RATE^MDC",
+                    "264864^MDC_DIM_BEAT_PER_MIN^MDC",
+                    Some(50.0),
+                    Some(120.0),
+                    "PM",
+                )],
+            }],
+        },
+        Vmd {
+            code: "69710^MDC_DEV_ANALY_SAT_O2_VMD^MDC",
+            channels: &[Channel {
+                code: "69711^MDC_DEV_ANALY_SAT_O2_CHAN^MDC",
+                metrics: &[metric(
+                    "150456^MDC_PULS_OXIM_SAT_O2^MDC",
+                    PERCENT,
+                    Some(90.0),
+                    None,
+                    "PH",
+                )],
+            }],
+        },
+        Vmd {
+            code: "69742^MDC_DEV_PRESS_BLD_NONINV_VMD^MDC",
+            channels: &[Channel {
+                code: "69743^MDC_DEV_PRESS_BLD_NONINV_CHAN^MDC",
+                metrics: &[
+                    metric(
+                        "150021^MDC_PRESS_BLD_NONINV_SYS^MDC",
+                        MMHG,
+                        Some(90.0),
+                        Some(160.0),
+                        "PM",
+                    ),
+                    metric(
+                        "150022^MDC_PRESS_BLD_NONINV_DIA^MDC",
+                        MMHG,
+                        Some(50.0),
+                        Some(90.0),
+                        "PM",
+                    ),
+                ],
+            }],
+        },
+        Vmd {
+            code: "69642^MDC_DEV_ANALY_RESP_RATE_VMD^MDC",
+            channels: &[Channel {
+                code: "69643^MDC_DEV_ANALY_RESP_RATE_CHAN^MDC",
+                metrics: &[metric(
+                    "151562^MDC_RESP_RATE^MDC",
+                    "264928^MDC_DIM_RESP_PER_MIN^MDC",
+                    Some(8.0),
+                    Some(30.0),
+                    "PM",
+                )],
+            }],
+        },
+        Vmd {
+            code: "69902^MDC_DEV_METER_TEMP_VMD^MDC",
+            channels: &[Channel {
+                code: "69903^MDC_DEV_METER_TEMP_CHAN^MDC",
+                metrics: &[metric(
+                    "150344^MDC_TEMP^MDC",
+                    "268192^MDC_DIM_DEGC^MDC",
+                    Some(35.5),
+                    Some(38.5),
+                    "PL",
+                )],
+            }],
+        },
+    ],
+    technical: &[
+        TechnicalAlarm::LeadOff,
+        TechnicalAlarm::ProbeOff,
+        TechnicalAlarm::LowBattery,
+        TechnicalAlarm::SensorFault,
+        TechnicalAlarm::CommLost,
+    ],
+};
+
+pub const INFUSION_PUMP: DeviceProfile = DeviceProfile {
+    name: "infusion_pump",
+    mds: "69985^MDC_DEV_PUMP_INFUS_MDS^MDC",
+    vmds: &[Vmd {
+        code: "69986^MDC_DEV_PUMP_INFUS_VMD^MDC",
+        channels: &[Channel {
+            code: "126978^MDC_DEV_PUMP_INFUS_CHAN_DELIVERY^MDC",
+            metrics: &[
+                metric(
+                    "157784^MDC_FLOW_FLUID_PUMP^MDC",
+                    "265266^MDC_DIM_MILLI_L_PER_HR^MDC",
+                    None,
+                    Some(999.0),
+                    "PH",
+                ),
+                metric(
+                    "157872^MDC_VOL_FLUID_TBI_REMAIN^MDC",
+                    ML,
+                    Some(5.0),
+                    None,
+                    "PL",
+                ),
+            ],
+        }],
+    }],
+    technical: &[
+        TechnicalAlarm::Occlusion,
+        TechnicalAlarm::AirInLine,
+        TechnicalAlarm::LowBattery,
+        TechnicalAlarm::SensorFault,
+        TechnicalAlarm::CommLost,
+    ],
+};
+
+pub const VENTILATOR: DeviceProfile = DeviceProfile {
+    name: "ventilator",
+    mds: "70081^MDC_DEV_SYS_PT_VENT_MDS^MDC",
+    vmds: &[Vmd {
+        code: "70082^MDC_DEV_SYS_PT_VENT_VMD^MDC",
+        channels: &[Channel {
+            code: "70083^MDC_DEV_SYS_PT_VENT_CHAN^MDC",
+            metrics: &[
+                metric(
+                    "151562^MDC_RESP_RATE^MDC",
+                    "264928^MDC_DIM_RESP_PER_MIN^MDC",
+                    Some(8.0),
+                    Some(35.0),
+                    "PM",
+                ),
+                metric(
+                    "151980^MDC_VENT_VOL_TIDAL^MDC",
+                    ML,
+                    Some(250.0),
+                    Some(800.0),
+                    "PM",
+                ),
+                metric(
+                    "151957^MDC_PRESS_AWAY_INSP_PEAK^MDC",
+                    "266048^MDC_DIM_CM_H2O^MDC",
+                    None,
+                    Some(40.0),
+                    "PH",
+                ),
+                metric(
+                    "150672^MDC_CONC_AWAY_O2_INSP^MDC",
+                    PERCENT,
+                    Some(21.0),
+                    None,
+                    "PH",
+                ),
+            ],
+        }],
+    }],
+    technical: &[
+        TechnicalAlarm::SensorFault,
+        TechnicalAlarm::LowBattery,
+        TechnicalAlarm::CommLost,
+    ],
+};
+
+pub const PROFILES: [DeviceProfile; 3] = [PATIENT_MONITOR, INFUSION_PUMP, VENTILATOR];
+
+impl DeviceProfile {
+    fn find_metric(&self, code: &str) -> Option<(usize, usize, &'static Metric)> {
+        let code = code.split('^').next().unwrap_or(code);
+        self.vmds.iter().enumerate().find_map(|(v, vmd)| {
+            vmd.channels.iter().enumerate().find_map(|(c, channel)| {
+                channel
+                    .metrics
+                    .iter()
+                    .find(|m| m.code.split('^').next() == Some(code))
+                    .map(|m| (v, c, m))
+            })
+        })
+    }
+
+    pub fn metric(&self, code: &str) -> Option<&'static Metric> {
+        self.find_metric(code).map(|(_, _, m)| m)
+    }
+
+    /// The channel that reports `metric_code`, or the first VMD for alerts about the device.
+    pub fn placement(&self, metric_code: Option<&str>) -> Placement {
+        let (v, c) = metric_code
+            .and_then(|code| self.find_metric(code))
+            .map_or((0, None), |(v, c, _)| (v, Some(c)));
+        Placement {
+            containment: format!("1.{}.{}", v + 1, c.map_or(0, |c| c + 1)),
+            mds_type: self.mds.to_string(),
+            vmd_type: self.vmds[v].code.to_string(),
+            chan_type: c.map_or(String::new(), |c| self.vmds[v].channels[c].code.to_string()),
+        }
+    }
+
+    /// The alert raised by `value` of `metric_code` under the default limits, if any.
+    pub fn check_limits(&self, metric_code: &str, value: f64) -> Option<AlertSpec> {
+        let metric = self.metric(metric_code)?;
+        let (event, word) = match (metric.low, metric.high) {
+            (_, Some(high)) if value > high => (EVENT_HIGH, "high"),
+            (Some(low), _) if value < low => (EVENT_LOW, "low"),
+            _ => return None,
+        };
+        let name = metric.code.split('^').nth(1).unwrap_or(metric.code);
+        Some(AlertSpec {
+            code: event.to_string(),
+            text: format!("{} {}", name.trim_start_matches("MDC_"), word),
+            priority: metric.priority.to_string(),
+            kind: "SP".to_string(),
+            observation: Some(ObservationSpec {
+                code: metric.code.to_string(),
+                value: value.to_string(),
+                unit: metric.unit.to_string(),
+                value_type: "NM".to_string(),
+            }),
+        })
+    }
+}
+
+impl fmt::Display for DeviceProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+/// A profile named in a configuration file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceClass {
+    PatientMonitor,
+    InfusionPump,
+    Ventilator,
+}
+
+impl DeviceClass {
+    pub fn profile(self) -> &'static DeviceProfile {
+        match self {
+            DeviceClass::PatientMonitor => &PATIENT_MONITOR,
+            DeviceClass::InfusionPump => &INFUSION_PUMP,
+            DeviceClass::Ventilator => &VENTILATOR,
+        }
+    }
+}
+
+impl FromStr for DeviceClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "patient_monitor" => Ok(DeviceClass::PatientMonitor),
+            "infusion_pump" => Ok(DeviceClass::InfusionPump),
+            "ventilator" => Ok(DeviceClass::Ventilator),
+            _ => Err(format!(
+                "unknown device profile {:?}, expected patient_monitor, infusion_pump or ventilator",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPO2: &str = "150456^MDC_PULS_OXIM_SAT_O2^MDC";
+    const NIBP_DIA: &str = "150022";
+
+    #[test]
+    fn placement_numbers_the_containment_tree() {
+        let spo2 = PATIENT_MONITOR.placement(Some(SPO2));
+        assert_eq!(spo2.containment, "1.2.1");
+        assert_eq!(spo2.vmd_type, "69710^MDC_DEV_ANALY_SAT_O2_VMD^MDC");
+        assert_eq!(spo2.chan_type, "69711^MDC_DEV_ANALY_SAT_O2_CHAN^MDC");
+
+        // Both blood pressure numerics sit on the same channel of the third VMD.
+        assert_eq!(
+            PATIENT_MONITOR.placement(Some(NIBP_DIA)).containment,
+            "1.3.1"
+        );
+        let tidal = VENTILATOR.placement(Some("151980^MDC_VENT_VOL_TIDAL^MDC"));
+        assert_eq!(tidal.containment, "1.1.1");
+        assert_eq!(tidal.mds_type, VENTILATOR.mds);
+    }
+
+    #[test]
+    fn device_alerts_sit_on_channel_zero() {
+        for metric in [None, Some("999999^MDC_UNKNOWN^MDC")] {
+            let placement = INFUSION_PUMP.placement(metric);
+            assert_eq!(placement.containment, "1.1.0");
+            assert_eq!(placement.vmd_type, "69986^MDC_DEV_PUMP_INFUS_VMD^MDC");
+            assert_eq!(placement.chan_type, "");
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let low = PATIENT_MONITOR.check_limits(SPO2, 85.0).unwrap();
+        assert_eq!(low.code, EVENT_LOW);
+        assert_eq!(low.text, "PULS_OXIM_SAT_O2 low");
+        assert_eq!(low.priority, "PH");
+        let observation = low.observation.unwrap();
+        assert_eq!(observation.value, "85");
+        assert_eq!(observation.unit, PERCENT);
+
+        let high = PATIENT_MONITOR.check_limits(NIBP_DIA, 95.5).unwrap();
+        assert_eq!(high.code, EVENT_HIGH);
+        assert_eq!(high.text, "PRESS_BLD_NONINV_DIA high");
+
+        // On the limit is still in range, and a missing limit never fires.
+        assert_eq!(PATIENT_MONITOR.check_limits(SPO2, 90.0), None);
+        assert_eq!(PATIENT_MONITOR.check_limits(SPO2, 100.0), None);
+        assert_eq!(INFUSION_PUMP.check_limits("157784", 0.0), None);
+        assert_eq!(INFUSION_PUMP.check_limits(SPO2, 10.0), None);
+    }
+
+    #[test]
+    fn device_class_names() {
+        for (name, profile) in [
+            ("patient_monitor", &PATIENT_MONITOR),
+            ("infusion_pump", &INFUSION_PUMP),
+            ("ventilator", &VENTILATOR),
+        ] {
+            let class: DeviceClass = name.parse().unwrap();
+            assert_eq!(class.profile(), profile);
+            assert_eq!(profile.to_string(), name);
+        }
+        let err = "monitor".parse::<DeviceClass>().unwrap_err();
+        assert!(err.starts_with("unknown device profile \"monitor\""));
+    }
+}
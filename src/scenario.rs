@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::connection::ManagerConnection;
+use crate::device::{ActiveAlert, AlertSpec, DeviceConfig};
+use crate::pcd04_msg::PCD04Message;
+use crate::technical::TechnicalAlarm;
+use crate::tls::{self, TlsConfig};
+use crate::transport::Transport;
+
+/// A timeline of device activity that is played back in order, e.g.
+///
+/// ```yaml
+/// manager: 127.0.0.1:8888
+/// devices:
+///   - name: monitor-1
+/// steps:
+///   - action: heartbeat
+///     count: 3
+///     interval_ms: 1000
+///   - action: alert_start
+///     alert: spo2
+///     code: 196670^MDC_EVT_LO^MDC
+///     text: SpO2 low
+///     priority: PM
+///     observation: { code: 150456^MDC_PULS_OXIM_SAT_O2^MDC, value: "85", unit: "%" }
+///   - action: escalate
+///     alert: spo2
+///     priority: PH
+///   - action: alert_end
+///     alert: spo2
+///   - action: raise
+///     condition: probe_off
+///   - action: clear
+///     condition: probe_off
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scenario {
+    #[serde(default = "default_manager")]
+    pub manager: String,
+    #[serde(default = "default_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+    #[serde(default = "default_devices")]
+    pub devices: Vec<DeviceConfig>,
+    /// Connect to the manager over mutually authenticated TLS.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    pub steps: Vec<Step>,
+}
+
+fn default_manager() -> String {
+    "127.0.0.1:8888".to_string()
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_devices() -> Vec<DeviceConfig> {
+    vec![DeviceConfig::default()]
+}
+
+fn default_count() -> u32 {
+    1
+}
+
+/// One entry of the timeline. Steps without a `device` apply to the first device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Step {
+    Heartbeat {
+        device: Option<String>,
+        #[serde(default = "default_count")]
+        count: u32,
+        #[serde(default)]
+        interval_ms: u64,
+    },
+    AlertStart {
+        device: Option<String>,
+        alert: String,
+        #[serde(flatten)]
+        spec: AlertSpec,
+    },
+    AlertContinue {
+        device: Option<String>,
+        alert: String,
+        /// New value of the triggering observation.
+        value: Option<String>,
+    },
+    Escalate {
+        device: Option<String>,
+        alert: String,
+        priority: String,
+    },
+    AlertEnd {
+        device: Option<String>,
+        alert: String,
+    },
+    /// Raises a technical alarm from the catalog, e.g. `lead_off`.
+    Raise {
+        device: Option<String>,
+        condition: TechnicalAlarm,
+        priority: Option<String>,
+    },
+    Clear {
+        device: Option<String>,
+        condition: TechnicalAlarm,
+    },
+    Disconnect {
+        device: Option<String>,
+    },
+    Pause {
+        ms: u64,
+    },
+}
+
+/// What happened to one message sent while playing a scenario.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StepOutcome {
+    pub step: usize,
+    pub device: String,
+    /// Empty for a step that could not be played, e.g. one that ends an alert that was never
+    /// started; `error` says why.
+    pub control_id: String,
+    /// MSA-1 of the acknowledgment, if one arrived.
+    pub ack: Option<String>,
+    pub error: Option<String>,
+}
+
+impl StepOutcome {
+    /// The manager accepted the message with an `AA` or `CA`.
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none() && matches!(self.ack.as_deref(), Some("AA" | "CA"))
+    }
+
+    /// A message was sent but no acknowledgment came back: the manager could not be reached or
+    /// did not answer in time.
+    pub fn is_unreachable(&self) -> bool {
+        self.ack.is_none() && !self.control_id.is_empty()
+    }
+}
+
+/// Reads a JSON (`.json`) or YAML (anything else) configuration file.
+pub(crate) fn read_config<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let text = fs::read_to_string(path)?;
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))
+    } else {
+        serde_yaml::from_str(&text).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+impl Scenario {
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        read_config(path)
+    }
+
+    /// The transport to the manager, with the TLS certificates loaded if `tls` is set.
+    pub fn transport(&self) -> io::Result<Arc<dyn Transport>> {
+        tls::client_transport(self.tls.as_ref())
+    }
+}
+
+/// Sends one device's messages to a manager over a connection that is opened on first use and
+/// reopened after a failure.
+pub struct DeviceSender {
+    pub config: DeviceConfig,
+    transport: Arc<dyn Transport>,
+    manager: String,
+    ack_timeout: Duration,
+    connection: Option<ManagerConnection>,
+}
+
+impl DeviceSender {
+    pub fn new(
+        config: DeviceConfig,
+        transport: Arc<dyn Transport>,
+        manager: &str,
+        ack_timeout: Duration,
+    ) -> Self {
+        DeviceSender {
+            config,
+            transport,
+            manager: manager.to_string(),
+            ack_timeout,
+            connection: None,
+        }
+    }
+
+    /// Sends `msg` under `control_id` and reports what became of it as part of `step`.
+    pub async fn send(
+        &mut self,
+        step: usize,
+        control_id: String,
+        mut msg: PCD04Message,
+    ) -> StepOutcome {
+        msg.set_control_id(&control_id);
+        let (ack, error) = match self.exchange(&msg).await {
+            Ok(code) => (Some(code), None),
+            Err(e) => {
+                // Drop the connection so the next message starts from a fresh one.
+                self.connection = None;
+                (None, Some(e.to_string()))
+            }
+        };
+        info!(
+            step,
+            device = %self.config.name,
+            %control_id,
+            result = ack.as_deref().or(error.as_deref()).unwrap_or(""),
+            "Sent"
+        );
+        StepOutcome {
+            step,
+            device: self.config.name.clone(),
+            control_id,
+            ack,
+            error,
+        }
+    }
+
+    /// Sends one message and returns MSA-1 of the acknowledgment.
+    async fn exchange(&mut self, msg: &PCD04Message) -> io::Result<String> {
+        if self.connection.is_none() {
+            let connection = ManagerConnection::connect_with(
+                self.transport.as_ref(),
+                &self.manager,
+                self.ack_timeout,
+            )
+            .await?;
+            self.connection = Some(connection);
+        }
+        let ack = self.connection.as_mut().unwrap().exchange(msg).await?;
+        Ok(ack.msa.msa_1_acknowledgment_code)
+    }
+
+    /// Closes the connection, if one is open; the next message opens a new one.
+    pub async fn disconnect(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            connection.close().await;
+        }
+    }
+}
+
+struct DeviceState {
+    sender: DeviceSender,
+    alerts: HashMap<String, ActiveAlert>,
+    /// How often each alert or technical alarm has been started, so a restart gets a new ID.
+    started: HashMap<String, u64>,
+    sequence: u64,
+}
+
+impl DeviceState {
+    fn config(&self) -> &DeviceConfig {
+        &self.sender.config
+    }
+
+    /// `<device>-<alert>-<n>` for the `n`th start of `alert`.
+    fn next_alert_id(&mut self, alert: &str) -> String {
+        let count = self.started.entry(alert.to_string()).or_insert(0);
+        *count += 1;
+        format!("{}-{}-{}", self.sender.config.name, alert, count)
+    }
+}
+
+struct ScenarioRunner {
+    devices: Vec<DeviceState>,
+    outcomes: Vec<StepOutcome>,
+}
+
+impl ScenarioRunner {
+    fn new(scenario: &Scenario, transport: Arc<dyn Transport>) -> Self {
+        let ack_timeout = Duration::from_millis(scenario.ack_timeout_ms);
+        ScenarioRunner {
+            devices: scenario
+                .devices
+                .iter()
+                .map(|config| DeviceState {
+                    sender: DeviceSender::new(
+                        config.clone(),
+                        transport.clone(),
+                        &scenario.manager,
+                        ack_timeout,
+                    ),
+                    alerts: HashMap::new(),
+                    started: HashMap::new(),
+                    sequence: 0,
+                })
+                .collect(),
+            outcomes: Vec::new(),
+        }
+    }
+
+    async fn run(mut self, scenario: &Scenario) -> Vec<StepOutcome> {
+        for (index, step) in scenario.steps.iter().enumerate() {
+            if let Err(error) = self.play(index + 1, step).await {
+                warn!(step = index + 1, "{}", error);
+                self.outcomes.push(StepOutcome {
+                    step: index + 1,
+                    device: String::new(),
+                    control_id: String::new(),
+                    ack: None,
+                    error: Some(error),
+                });
+            }
+        }
+        self.outcomes
+    }
+
+    fn device_index(&self, device: &Option<String>) -> Result<usize, String> {
+        match device {
+            None if !self.devices.is_empty() => Ok(0),
+            None => Err("scenario has no devices".to_string()),
+            Some(name) => self
+                .devices
+                .iter()
+                .position(|d| &d.config().name == name)
+                .ok_or_else(|| format!("unknown device {}", name)),
+        }
+    }
+
+    fn alert_mut<'a>(
+        state: &'a mut DeviceState,
+        alert: &str,
+    ) -> Result<&'a mut ActiveAlert, String> {
+        state
+            .alerts
+            .get_mut(alert)
+            .ok_or_else(|| format!("alert {} was not started", alert))
+    }
+
+    async fn play(&mut self, step_no: usize, step: &Step) -> Result<(), String> {
+        match step {
+            Step::Heartbeat {
+                device,
+                count,
+                interval_ms,
+            } => {
+                let index = self.device_index(device)?;
+                for n in 0..*count {
+                    if n > 0 {
+                        tokio::time::sleep(Duration::from_millis(*interval_ms)).await;
+                    }
+                    let msg = self.devices[index].config().heartbeat();
+                    self.send(step_no, index, msg).await;
+                }
+            }
+            Step::AlertStart {
+                device,
+                alert,
+                spec,
+            } => {
+                let index = self.device_index(device)?;
+                let state = &mut self.devices[index];
+                let active = ActiveAlert {
+                    id: state.next_alert_id(alert),
+                    spec: spec.clone(),
+                    update: 0,
+                };
+                let msg = state.config().alert_message(&active, "start", "active");
+                state.alerts.insert(alert.clone(), active);
+                self.send(step_no, index, msg).await;
+            }
+            Step::AlertContinue {
+                device,
+                alert,
+                value,
+            } => {
+                let index = self.device_index(device)?;
+                let state = &mut self.devices[index];
+                let active = Self::alert_mut(state, alert)?;
+                active.update += 1;
+                if let (Some(value), Some(obs)) = (value, active.spec.observation.as_mut()) {
+                    obs.value = value.clone();
+                }
+                let msg = state
+                    .config()
+                    .alert_message(&state.alerts[alert], "continue", "active");
+                self.send(step_no, index, msg).await;
+            }
+            Step::Escalate {
+                device,
+                alert,
+                priority,
+            } => {
+                let index = self.device_index(device)?;
+                let state = &mut self.devices[index];
+                let active = Self::alert_mut(state, alert)?;
+                active.update += 1;
+                active.spec.priority = priority.clone();
+                let msg = state
+                    .config()
+                    .alert_message(&state.alerts[alert], "escalate", "active");
+                self.send(step_no, index, msg).await;
+            }
+            Step::AlertEnd { device, alert } => {
+                let index = self.device_index(device)?;
+                let state = &mut self.devices[index];
+                let mut active = state
+                    .alerts
+                    .remove(alert)
+                    .ok_or_else(|| format!("alert {} was not started", alert))?;
+                active.update += 1;
+                let msg = state.config().alert_message(&active, "end", "inactive");
+                self.send(step_no, index, msg).await;
+            }
+            Step::Raise {
+                device,
+                condition,
+                priority,
+            } => {
+                let index = self.device_index(device)?;
+                let state = &mut self.devices[index];
+                if let Some(class) = state.config().profile {
+                    if !class.profile().technical.contains(condition) {
+                        return Err(format!(
+                            "a {} does not raise {}",
+                            class.profile(),
+                            condition
+                        ));
+                    }
+                }
+                let mut spec = condition.spec();
+                if let Some(priority) = priority {
+                    spec.priority = priority.clone();
+                }
+                let active = ActiveAlert {
+                    id: state.next_alert_id(condition.name()),
+                    spec,
+                    update: 0,
+                };
+                let msg = state.config().alert_message(&active, "start", "active");
+                state.alerts.insert(condition.to_string(), active);
+                self.send(step_no, index, msg).await;
+            }
+            Step::Clear { device, condition } => {
+                let index = self.device_index(device)?;
+                let state = &mut self.devices[index];
+                let mut active = state
+                    .alerts
+                    .remove(condition.name())
+                    .ok_or_else(|| format!("technical alarm {} was not raised", condition))?;
+                active.update += 1;
+                let msg = state.config().alert_message(&active, "end", "inactive");
+                self.send(step_no, index, msg).await;
+            }
+            Step::Disconnect { device } => {
+                let index = self.device_index(device)?;
+                self.devices[index].sender.disconnect().await;
+                info!(
+                    step = step_no,
+                    device = %self.devices[index].config().name,
+                    "Disconnected"
+                );
+            }
+            Step::Pause { ms } => tokio::time::sleep(Duration::from_millis(*ms)).await,
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, step_no: usize, index: usize, msg: PCD04Message) {
+        let state = &mut self.devices[index];
+        state.sequence += 1;
+        let control_id = format!("{}-{}", state.config().name, state.sequence);
+        let outcome = state.sender.send(step_no, control_id, msg).await;
+        self.outcomes.push(outcome);
+    }
+}
+
+/// Plays `scenario` against its alert manager, one step at a time, and reports every message sent.
+///
+/// Control IDs are `<device>-<n>` and alert IDs `<device>-<alert>-<n>`, so repeated runs of the
+/// same file produce the same messages apart from timestamps. Fails only if the transport can't be
+/// set up; per-message errors are reported in the outcomes.
+pub async fn run_scenario(scenario: &Scenario) -> io::Result<Vec<StepOutcome>> {
+    Ok(ScenarioRunner::new(scenario, scenario.transport()?)
+        .run(scenario)
+        .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_alert_mgr::{AlertManager, AlertMgrConfig};
+
+    fn outcome(ack: Option<&str>, error: Option<&str>) -> StepOutcome {
+        StepOutcome {
+            step: 1,
+            device: "device".to_string(),
+            control_id: "device-1".to_string(),
+            ack: ack.map(str::to_string),
+            error: error.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn application_and_commit_accepts_are_ok() {
+        assert!(outcome(Some("AA"), None).is_ok());
+        assert!(outcome(Some("CA"), None).is_ok());
+        assert!(!outcome(Some("AE"), None).is_ok());
+        assert!(!outcome(Some("CR"), None).is_ok());
+        assert!(!outcome(None, Some("timed out")).is_ok());
+        assert!(outcome(None, Some("timed out")).is_unreachable());
+    }
+
+    #[tokio::test]
+    async fn plays_the_example_scenario() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios/spo2_desaturation.yaml");
+        let mut scenario = Scenario::from_file(&path).unwrap();
+        scenario.manager = "mem:scenario-example".to_string();
+        let mut manager = AlertManager::new(AlertMgrConfig {
+            listen_address: scenario.manager.clone(),
+            ..Default::default()
+        });
+        manager.start().await.unwrap();
+
+        let outcomes = run_scenario(&scenario).await.unwrap();
+        assert_eq!(outcomes.len(), 11);
+        assert!(outcomes.iter().all(StepOutcome::is_ok), "{:?}", outcomes);
+        assert_eq!(outcomes[0].control_id, "monitor-1-1");
+
+        // Starting spo2 a second time raises a new alert rather than reviving the first.
+        let handle = manager.handle().unwrap();
+        let mut ended: Vec<String> =
+            handle.alerts(|store| store.ended().map(|alert| alert.id.clone()).collect());
+        ended.sort();
+        assert_eq!(
+            ended,
+            [
+                "monitor-1-probe_off-1",
+                "monitor-1-spo2-1",
+                "monitor-1-spo2-2"
+            ]
+        );
+        manager.stop().await.unwrap();
+    }
+}
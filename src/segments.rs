@@ -0,0 +1,385 @@
+#![allow(clippy::upper_case_acronyms)]
+
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::{Charset, Delimiters};
+
+/// A segment as it appears on the wire: `fields[0]` is the segment ID and `fields[n]` is field n.
+///
+/// Field values are kept in ER7 notation with the default delimiters (`^~\&`), whatever the
+/// delimiters of the message they were read from, so typed segments never have to care.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RawSegment {
+    pub fields: Vec<String>,
+}
+
+impl RawSegment {
+    pub fn new(id: &str) -> Self {
+        RawSegment {
+            fields: vec![id.to_string()],
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        self.fields.first().map_or("", |s| s.as_str())
+    }
+
+    pub fn field(&self, n: usize) -> &str {
+        self.fields.get(n).map_or("", |s| s.as_str())
+    }
+
+    pub fn set_field(&mut self, n: usize, value: &str) {
+        if self.fields.len() <= n {
+            self.fields.resize(n + 1, String::new());
+        }
+        self.fields[n] = value.to_string();
+    }
+
+    /// Component `c` (1-based) of the first repetition of field `n`.
+    pub fn component(&self, n: usize, c: usize) -> &str {
+        component(self.field(n), c)
+    }
+
+    /// Number of the last non-empty field.
+    pub fn len(&self) -> usize {
+        self.fields.iter().rposition(|f| !f.is_empty()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Splits one segment line. MSH-1 and MSH-2 are filled in from `delimiters`.
+    pub fn parse(line: &str, delimiters: &Delimiters, charset: Charset) -> Self {
+        let mut parts = line.split(delimiters.field);
+        let id = parts.next().unwrap_or("").to_string();
+        let mut fields = vec![id.clone()];
+
+        if id == "MSH" {
+            fields.push(delimiters.field.to_string());
+            parts.next();
+            fields.push(delimiters.encoding_characters());
+        }
+        let default = Delimiters::default();
+        fields.extend(parts.map(|field| {
+            if *delimiters == default {
+                field.to_string()
+            } else {
+                translate(field, delimiters, &default, charset)
+            }
+        }));
+        RawSegment { fields }
+    }
+
+    /// Joins the segment back into one line using `delimiters`, without a terminator.
+    pub fn encode(&self, delimiters: &Delimiters, charset: Charset) -> String {
+        let default = Delimiters::default();
+        let last = self.len();
+        let mut out = self.id().to_string();
+
+        for (n, field) in self.fields.iter().enumerate().take(last + 1).skip(1) {
+            if self.id() == "MSH" && n == 1 {
+                out.push(delimiters.field);
+                continue;
+            }
+            if self.id() != "MSH" || n > 2 {
+                out.push(delimiters.field);
+            }
+            if self.id() == "MSH" && n == 2 {
+                out.push_str(&delimiters.encoding_characters());
+            } else if *delimiters == default {
+                out.push_str(field);
+            } else {
+                out.push_str(&translate(field, &default, delimiters, charset));
+            }
+        }
+        out
+    }
+}
+
+/// Component `c` (1-based) of the first repetition of an ER7 field value.
+pub fn component(field: &str, c: usize) -> &str {
+    field
+        .split('~')
+        .next()
+        .and_then(|rep| rep.split('^').nth(c.saturating_sub(1)))
+        .unwrap_or("")
+}
+
+/// Subcomponent `s` (1-based) of component `c` of an ER7 field value.
+pub fn subcomponent(field: &str, c: usize, s: usize) -> &str {
+    component(field, c)
+        .split('&')
+        .nth(s.saturating_sub(1))
+        .unwrap_or("")
+}
+
+/// Re-encodes an ER7 field value from one set of delimiters to another.
+fn translate(field: &str, from: &Delimiters, to: &Delimiters, charset: Charset) -> String {
+    let mut out = String::with_capacity(field.len());
+    for (r, rep) in field.split(from.repetition).enumerate() {
+        if r > 0 {
+            out.push(to.repetition);
+        }
+        for (c, comp) in rep.split(from.component).enumerate() {
+            if c > 0 {
+                out.push(to.component);
+            }
+            for (s, sub) in comp.split(from.subcomponent).enumerate() {
+                if s > 0 {
+                    out.push(to.subcomponent);
+                }
+                translate_text(sub, from, to, charset, &mut out);
+            }
+        }
+    }
+    out
+}
+
+/// Re-encodes one subcomponent. Delimiter escapes such as `\T\` stand for a delimiter of the
+/// message, so they are carried over as escapes; text that merely looks like one of the target
+/// delimiters is written as `\Xhh\` data so the two never get confused.
+fn translate_text(
+    text: &str,
+    from: &Delimiters,
+    to: &Delimiters,
+    charset: Charset,
+    out: &mut String,
+) {
+    let literal = |text: &str, out: &mut String| {
+        for c in text.chars() {
+            let delimiter = [
+                to.field,
+                to.component,
+                to.repetition,
+                to.escape,
+                to.subcomponent,
+            ];
+            match charset.encode(c.encode_utf8(&mut [0; 4])) {
+                Ok(bytes) if delimiter.contains(&c) => {
+                    out.push(to.escape);
+                    out.push('X');
+                    out.extend(bytes.iter().map(|b| format!("{:02X}", b)));
+                    out.push(to.escape);
+                }
+                _ => out.push_str(&to.escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+    };
+
+    let mut rest = text;
+    while let Some(start) = rest.find(from.escape) {
+        literal(&rest[..start], out);
+        let after = &rest[start + from.escape.len_utf8()..];
+        let Some(end) = after.find(from.escape) else {
+            literal(&rest[start..], out);
+            return;
+        };
+        let seq = &after[..end];
+        if matches!(seq, "F" | "S" | "T" | "R" | "E") {
+            out.push(to.escape);
+            out.push_str(seq);
+            out.push(to.escape);
+        } else {
+            let escaped = &rest[start..start + from.escape.len_utf8() * 2 + end];
+            literal(&from.unescape(escaped, charset), out);
+        }
+        rest = &after[end + from.escape.len_utf8()..];
+    }
+    literal(rest, out);
+}
+
+/// Conversion between a typed field and its ER7 value.
+pub trait FieldValue: Sized {
+    fn from_er7(value: &str) -> Self;
+    fn to_er7(&self) -> String;
+}
+
+impl FieldValue for String {
+    fn from_er7(value: &str) -> Self {
+        value.to_string()
+    }
+
+    fn to_er7(&self) -> String {
+        self.clone()
+    }
+}
+
+impl FieldValue for Vec<String> {
+    fn from_er7(value: &str) -> Self {
+        if value.is_empty() {
+            Vec::new()
+        } else {
+            value.split('~').map(str::to_string).collect()
+        }
+    }
+
+    fn to_er7(&self) -> String {
+        self.join("~")
+    }
+}
+
+/// A typed segment that can be converted to and from a [`RawSegment`].
+pub trait Segment: Sized {
+    const ID: &'static str;
+
+    fn from_raw(raw: &RawSegment) -> Self;
+    fn to_raw(&self) -> RawSegment;
+}
+
+macro_rules! segment {
+    ($name:ident { $($n:literal => $field:ident: $t:ty,)* }) => {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+        pub struct $name {
+            $(pub $field: $t),*
+        }
+
+        impl Segment for $name {
+            const ID: &'static str = stringify!($name);
+
+            fn from_raw(raw: &RawSegment) -> Self {
+                $name {
+                    $($field: FieldValue::from_er7(raw.field($n))),*
+                }
+            }
+
+            fn to_raw(&self) -> RawSegment {
+                let mut raw = RawSegment::new(Self::ID);
+                $(raw.set_field($n, &self.$field.to_er7());)*
+                raw
+            }
+        }
+    };
+}
+
+// MSH-1 and MSH-2 are taken from the delimiters the message is encoded with.
+segment!(MSH {
+    3 => msh_3_sending_application: String,
+    4 => msh_4_sending_facility: String,
+    5 => msh_5_receiving_application: String,
+    6 => msh_6_receiving_facility: String,
+    7 => msh_7_date_time_of_message: String,
+    8 => msh_8_security: String,
+    9 => msh_9_message_type: String,
+    10 => msh_10_message_control_id: String,
+    11 => msh_11_processing_id: String,
+    12 => msh_12_version_id: String,
+    13 => msh_13_sequence_number: String,
+    14 => msh_14_continuation_pointer: String,
+    15 => msh_15_accept_acknowledgment_type: String,
+    16 => msh_16_application_acknowledgment_type: String,
+    17 => msh_17_country_code: String,
+    18 => msh_18_character_set: Vec<String>,
+    19 => msh_19_principal_language_of_message: String,
+    20 => msh_20_alternate_character_set_handling_scheme: String,
+    21 => msh_21_message_profile_identifier: Vec<String>,
+});
+
+segment!(PID {
+    1 => pid_1_set_id: String,
+    2 => pid_2_patient_id: String,
+    3 => pid_3_patient_identifier_list: Vec<String>,
+    4 => pid_4_alternate_patient_id: String,
+    5 => pid_5_patient_name: Vec<String>,
+    6 => pid_6_mothers_maiden_name: Vec<String>,
+    7 => pid_7_date_time_of_birth: String,
+    8 => pid_8_administrative_sex: String,
+});
+
+segment!(PV1 {
+    1 => pv1_1_set_id: String,
+    2 => pv1_2_patient_class: String,
+    3 => pv1_3_assigned_patient_location: String,
+    19 => pv1_19_visit_number: String,
+});
+
+segment!(OBR {
+    1 => obr_1_set_id: String,
+    2 => obr_2_placer_order_number: String,
+    3 => obr_3_filler_order_number: String,
+    4 => obr_4_universal_service_identifier: String,
+    7 => obr_7_observation_date_time: String,
+    8 => obr_8_observation_end_date_time: String,
+    29 => obr_29_parent: String,
+});
+
+segment!(OBX {
+    1 => obx_1_set_id: String,
+    2 => obx_2_value_type: String,
+    3 => obx_3_observation_identifier: String,
+    4 => obx_4_observation_sub_id: String,
+    5 => obx_5_observation_value: Vec<String>,
+    6 => obx_6_units: String,
+    7 => obx_7_references_range: String,
+    8 => obx_8_interpretation_codes: Vec<String>,
+    11 => obx_11_observation_result_status: String,
+    14 => obx_14_date_time_of_the_observation: String,
+    17 => obx_17_observation_method: Vec<String>,
+    18 => obx_18_equipment_instance_identifier: Vec<String>,
+    20 => obx_20_observation_site: Vec<String>,
+});
+
+segment!(PRT {
+    1 => prt_1_participation_instance_id: String,
+    2 => prt_2_action_code: String,
+    3 => prt_3_action_reason: String,
+    4 => prt_4_participation: String,
+    5 => prt_5_participation_person: Vec<String>,
+    9 => prt_9_participation_location: Vec<String>,
+    10 => prt_10_participation_device: Vec<String>,
+    11 => prt_11_participation_begin_date_time: String,
+    12 => prt_12_participation_end_date_time: String,
+    15 => prt_15_participation_telecommunication_address: Vec<String>,
+});
+
+segment!(NTE {
+    1 => nte_1_set_id: String,
+    2 => nte_2_source_of_comment: String,
+    3 => nte_3_comment: Vec<String>,
+});
+
+segment!(MSA {
+    1 => msa_1_acknowledgment_code: String,
+    2 => msa_2_message_control_id: String,
+    3 => msa_3_text_message: String,
+});
+
+segment!(ERR {
+    2 => err_2_error_location: Vec<String>,
+    3 => err_3_hl7_error_code: String,
+    4 => err_4_severity: String,
+    5 => err_5_application_error_code: String,
+    7 => err_7_diagnostic_information: String,
+    8 => err_8_user_message: String,
+});
+
+impl MSH {
+    /// Trigger event of MSH-9, e.g. `R40` for `ORU^R40^ORU_R40`.
+    pub fn trigger_event(&self) -> &str {
+        component(&self.msh_9_message_type, 2)
+    }
+
+    pub fn message_code(&self) -> &str {
+        component(&self.msh_9_message_type, 1)
+    }
+}
+
+impl OBX {
+    /// Last dotted element of OBX-4, i.e. the facet number of an alert OBX.
+    pub fn facet(&self) -> Option<u32> {
+        self.obx_4_observation_sub_id
+            .rsplit('.')
+            .next()
+            .and_then(|s| s.parse().ok())
+    }
+
+    pub fn observation_code(&self) -> &str {
+        component(&self.obx_3_observation_identifier, 1)
+    }
+
+    pub fn first_value(&self) -> &str {
+        self.obx_5_observation_value
+            .first()
+            .map_or("", |s| s.as_str())
+    }
+}
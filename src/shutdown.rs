@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// How long open connections get to finish the message in hand once shutdown starts.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stops every actor subscribed to it, from Ctrl+C or from code. Clones share the same state, so
+/// one coordinator can be handed to a manager and a reporter running in the same process.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// A coordinator that is triggered with this one but can also be triggered on its own, for
+    /// stopping one actor without the others.
+    pub fn child(&self) -> Shutdown {
+        Shutdown {
+            token: self.token.child_token(),
+        }
+    }
+
+    /// Asks every subscriber to stop. Calling it again has no effect.
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Completes once shutdown has been triggered.
+    pub async fn wait(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Triggers shutdown on the first Ctrl+C; a second one exits right away for when draining
+    /// hangs. Must be called from within a tokio runtime.
+    pub fn on_ctrl_c(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            warn!("Shutting down, press Ctrl+C again to exit immediately");
+            shutdown.trigger();
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn children_stop_with_their_parent() {
+        let shutdown = Shutdown::new();
+        let manager = shutdown.child();
+        let reporter = shutdown.child();
+
+        // A child can be stopped on its own.
+        reporter.trigger();
+        assert!(reporter.is_triggered());
+        assert!(!shutdown.is_triggered());
+        assert!(!manager.is_triggered());
+
+        let waiting = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.wait().await }
+        });
+        shutdown.trigger();
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(manager.is_triggered());
+        assert!(shutdown.clone().is_triggered());
+    }
+}
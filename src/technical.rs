@@ -0,0 +1,167 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::device::AlertSpec;
+
+/// Common technical (equipment) alarm conditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TechnicalAlarm {
+    LeadOff,
+    ProbeOff,
+    LowBattery,
+    Occlusion,
+    AirInLine,
+    SensorFault,
+    CommLost,
+}
+
+impl TechnicalAlarm {
+    pub const ALL: [TechnicalAlarm; 7] = [
+        TechnicalAlarm::LeadOff,
+        TechnicalAlarm::ProbeOff,
+        TechnicalAlarm::LowBattery,
+        TechnicalAlarm::Occlusion,
+        TechnicalAlarm::AirInLine,
+        TechnicalAlarm::SensorFault,
+        TechnicalAlarm::CommLost,
+    ];
+
+    /// The name used in scenarios and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            TechnicalAlarm::LeadOff => "lead_off",
+            TechnicalAlarm::ProbeOff => "probe_off",
+            TechnicalAlarm::LowBattery => "low_battery",
+            TechnicalAlarm::Occlusion => "occlusion",
+            TechnicalAlarm::AirInLine => "air_in_line",
+            TechnicalAlarm::SensorFault => "sensor_fault",
+            TechnicalAlarm::CommLost => "comm_lost",
+        }
+    }
+
+    /// MDC event code (IEEE 11073-10101) reported in the facet-1 OBX.
+    pub fn code(self) -> &'static str {
+        match self {
+            TechnicalAlarm::LeadOff => "196808^MDC_EVT_LEAD_DISCONN^MDC",
+            TechnicalAlarm::ProbeOff => "196816^MDC_EVT_PROBE_DISCONN^MDC",
+            TechnicalAlarm::LowBattery => "196654^MDC_EVT_BATT_LO^MDC",
+            TechnicalAlarm::Occlusion => "196740^MDC_EVT_OCCL^MDC",
+            TechnicalAlarm::AirInLine => "196630^MDC_EVT_AIR_IN_LINE^MDC",
+            TechnicalAlarm::SensorFault => "196828^MDC_EVT_SENSOR_MALF^MDC",
+            TechnicalAlarm::CommLost => "196704^MDC_EVT_COMM_STATUS_ERR^MDC",
+        }
+    }
+
+    pub fn default_priority(self) -> &'static str {
+        match self {
+            TechnicalAlarm::Occlusion | TechnicalAlarm::AirInLine => "PH",
+            TechnicalAlarm::LeadOff | TechnicalAlarm::SensorFault | TechnicalAlarm::CommLost => {
+                "PM"
+            }
+            TechnicalAlarm::ProbeOff | TechnicalAlarm::LowBattery => "PL",
+        }
+    }
+
+    pub fn text(self) -> &'static str {
+        match self {
+            TechnicalAlarm::LeadOff => "ECG lead off",
+            TechnicalAlarm::ProbeOff => "Probe off",
+            TechnicalAlarm::LowBattery => "Battery low",
+            TechnicalAlarm::Occlusion => "Occlusion",
+            TechnicalAlarm::AirInLine => "Air in line",
+            TechnicalAlarm::SensorFault => "Sensor fault",
+            TechnicalAlarm::CommLost => "Communication lost",
+        }
+    }
+
+    /// A technical alert with no triggering measurement, so no facet-2 OBX is sent.
+    pub fn spec(self) -> AlertSpec {
+        AlertSpec {
+            code: self.code().to_string(),
+            text: self.text().to_string(),
+            priority: self.default_priority().to_string(),
+            kind: "ST".to_string(),
+            observation: None,
+        }
+    }
+}
+
+impl fmt::Display for TechnicalAlarm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TechnicalAlarm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TechnicalAlarm::ALL
+            .into_iter()
+            .find(|alarm| alarm.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = TechnicalAlarm::ALL.iter().map(|a| a.name()).collect();
+                format!(
+                    "unknown technical alarm {:?}, expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{ActiveAlert, DeviceConfig};
+    use crate::messages::Message;
+    use crate::validate::{has_errors, validate_pcd04};
+
+    #[test]
+    fn names_round_trip() {
+        for alarm in TechnicalAlarm::ALL {
+            assert_eq!(alarm.to_string().parse(), Ok(alarm));
+            let json = serde_json::to_string(&alarm).unwrap();
+            assert_eq!(json, format!("\"{}\"", alarm.name()));
+        }
+        let err = "lead_of".parse::<TechnicalAlarm>().unwrap_err();
+        assert!(err.starts_with("unknown technical alarm \"lead_of\", expected one of lead_off,"));
+    }
+
+    #[test]
+    fn technical_alert_has_no_measurement_row() {
+        let alert = ActiveAlert {
+            id: "alert-1".to_string(),
+            spec: TechnicalAlarm::Occlusion.spec(),
+            update: 0,
+        };
+        let text = DeviceConfig::default()
+            .alert_message(&alert, "start", "active")
+            .encode();
+        assert!(!has_errors(&validate_pcd04(&text)));
+
+        let Message::Oru(oru) = text.parse().unwrap() else {
+            panic!("not an ORU");
+        };
+        let event = oru
+            .observations()
+            .find(|o| o.obx.facet() == Some(1))
+            .unwrap();
+        assert_eq!(
+            event.obx.obx_3_observation_identifier,
+            "196740^MDC_EVT_OCCL^MDC"
+        );
+        let value = |code| {
+            let row = oru
+                .observations()
+                .find(|o| o.obx.observation_code() == code);
+            row.unwrap().obx.first_value().to_string()
+        };
+        assert_eq!(value("68484"), "PH");
+        assert_eq!(value("68485"), "ST");
+        assert!(oru.observations().all(|o| o.obx.facet() != Some(2)));
+    }
+}
@@ -0,0 +1,319 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{AlertDescription, ClientConfig, RootCertStore, ServerConfig};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::transport::{BoxFuture, DefaultTransport, Listener, Socket, Transport};
+
+/// How long an accepted connection may take to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificates for mutually authenticated TLS. The same identity is presented whether the actor
+/// connects or listens, and peers must present a certificate issued by one of the trust anchors.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key: PathBuf,
+    /// PEM files with the CA certificates peers are verified against.
+    pub ca: Vec<PathBuf>,
+    /// Name checked against the manager's certificate; defaults to the host of the address.
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+/// A TLS handshake that failed, typically because the peer's certificate was not trusted.
+#[derive(Debug)]
+pub struct HandshakeError {
+    pub peer: String,
+    pub reason: String,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "TLS handshake with {} failed: {}",
+            self.peer, self.reason
+        )
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Whether `error` is a failed TLS handshake, i.e. the peer could not be authenticated.
+pub fn is_handshake_failure(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<HandshakeError>())
+}
+
+fn config_error(path: &Path, what: &str, e: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} {}: {}", what, path.display(), e),
+    )
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| config_error(path, "cannot read certificates from", e))?;
+    if certs.is_empty() {
+        return Err(config_error(path, "no certificate in", "empty PEM file"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| config_error(path, "cannot read key from", e))
+}
+
+impl TlsConfig {
+    fn roots(&self) -> io::Result<Arc<RootCertStore>> {
+        if self.ca.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS needs at least one trust anchor (CA file)",
+            ));
+        }
+        let mut roots = RootCertStore::empty();
+        for path in &self.ca {
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| config_error(path, "invalid trust anchor in", e))?;
+            }
+        }
+        Ok(Arc::new(roots))
+    }
+
+    fn client_config(&self, roots: Arc<RootCertStore>) -> io::Result<ClientConfig> {
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(load_certs(&self.cert)?, load_key(&self.key)?)
+            .map_err(|e| config_error(&self.key, "key does not match certificate", e))
+    }
+
+    fn server_config(&self, roots: Arc<RootCertStore>) -> io::Result<ServerConfig> {
+        let verifier = WebPkiClientVerifier::builder(roots)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)
+            .map_err(|e| config_error(&self.key, "key does not match certificate", e))
+    }
+}
+
+/// The transport a device uses to reach its manager: the [`DefaultTransport`] for TCP, `unix:`
+/// and `mem:` addresses, run through TLS if `tls` is set.
+pub fn client_transport(tls: Option<&TlsConfig>) -> io::Result<Arc<dyn Transport>> {
+    Ok(match tls {
+        Some(tls) => Arc::new(TlsTransport::new(tls, Arc::new(DefaultTransport))?),
+        None => Arc::new(DefaultTransport),
+    })
+}
+
+/// Runs another transport (normally TCP) through mutually authenticated TLS.
+pub struct TlsTransport {
+    inner: Arc<dyn Transport>,
+    server_name: Option<String>,
+    connector: TlsConnector,
+    acceptor: TlsAcceptor,
+}
+
+impl fmt::Debug for TlsTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsTransport")
+            .field("inner", &self.inner)
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsTransport {
+    /// Loads the certificates up front so configuration mistakes show before any connection.
+    pub fn new(config: &TlsConfig, inner: Arc<dyn Transport>) -> io::Result<Self> {
+        let roots = config.roots()?;
+        Ok(TlsTransport {
+            inner,
+            server_name: config.server_name.clone(),
+            connector: TlsConnector::from(Arc::new(config.client_config(Arc::clone(&roots))?)),
+            acceptor: TlsAcceptor::from(Arc::new(config.server_config(roots)?)),
+        })
+    }
+
+    fn server_name(&self, address: &str) -> io::Result<ServerName<'static>> {
+        let name = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => address
+                .rsplit_once(':')
+                .map_or(address, |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']'),
+        };
+        ServerName::try_from(name.to_string()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid TLS server name {:?}: {}", name, e),
+            )
+        })
+    }
+}
+
+impl Transport for TlsTransport {
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Socket>>> {
+        Box::pin(async move {
+            let server_name = self.server_name(address)?;
+            let socket = self.inner.connect(address).await?;
+            let stream = self
+                .connector
+                .connect(server_name, socket)
+                .await
+                .map_err(|e| handshake_error(address, e))?;
+            Ok(Box::new(TlsSocket {
+                inner: stream,
+                peer: address.to_string(),
+            }) as Box<dyn Socket>)
+        })
+    }
+
+    fn listen<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            Ok(Box::new(TlsListener {
+                inner: self.inner.listen(address).await?,
+                acceptor: self.acceptor.clone(),
+            }) as Box<dyn Listener>)
+        })
+    }
+}
+
+fn handshake_error(peer: &str, reason: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        HandshakeError {
+            peer: peer.to_string(),
+            reason: reason.to_string(),
+        },
+    )
+}
+
+struct TlsListener {
+    inner: Box<dyn Listener>,
+    acceptor: TlsAcceptor,
+}
+
+impl Listener for TlsListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Socket>, String)>> {
+        self.inner.accept()
+    }
+
+    fn local_address(&self) -> io::Result<String> {
+        self.inner.local_address()
+    }
+
+    fn handshake(
+        &self,
+        socket: Box<dyn Socket>,
+        peer: &str,
+    ) -> BoxFuture<'static, io::Result<Box<dyn Socket>>> {
+        let acceptor = self.acceptor.clone();
+        let peer = peer.to_string();
+        Box::pin(async move {
+            let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+                .await
+                .map_err(|_| handshake_error(&peer, "timed out"))?
+                .map_err(|e| handshake_error(&peer, e))?;
+            Ok(Box::new(TlsSocket {
+                inner: stream,
+                peer,
+            }) as Box<dyn Socket>)
+        })
+    }
+}
+
+/// A TLS stream that reports session failures with the peer they happened with.
+struct TlsSocket<S> {
+    inner: S,
+    peer: String,
+}
+
+impl<S> TlsSocket<S> {
+    /// With TLS 1.3 the server checks the client certificate after the client considers the
+    /// handshake done, so a rejected certificate only shows up as an alert on the first read.
+    fn session_error(&self, error: io::Error) -> io::Error {
+        let Some(tls_error) = error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        else {
+            return error;
+        };
+        let rejected = matches!(
+            tls_error,
+            rustls::Error::AlertReceived(
+                AlertDescription::BadCertificate
+                    | AlertDescription::UnsupportedCertificate
+                    | AlertDescription::CertificateRevoked
+                    | AlertDescription::CertificateExpired
+                    | AlertDescription::CertificateUnknown
+                    | AlertDescription::UnknownCA
+                    | AlertDescription::CertificateRequired
+                    | AlertDescription::AccessDenied
+            )
+        );
+        if rejected {
+            return handshake_error(
+                &self.peer,
+                format!("peer rejected our certificate ({})", tls_error),
+            );
+        }
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("TLS session with {} failed: {}", self.peer, tls_error),
+        )
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TlsSocket<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Err(e)) => Poll::Ready(Err(self.session_error(e))),
+            other => other,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TlsSocket<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
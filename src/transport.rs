@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::Framed;
+
+use crate::mllp::MllpCodec;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Buffer size of each direction of an in-process connection.
+const MEMORY_BUFFER: usize = 64 * 1024;
+
+/// A byte stream between a reporter and a manager.
+pub trait Socket: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Socket for T {}
+
+pub trait Listener: Send + Sync {
+    /// Waits for the next connection and returns it with a description of the peer that is
+    /// unique among the listener's open connections.
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Socket>, String)>>;
+
+    /// The address peers connect to, e.g. the actual port after binding port 0.
+    fn local_address(&self) -> io::Result<String>;
+
+    /// Completes any protocol handshake on an accepted connection. It runs in the connection's
+    /// own task so a slow peer does not hold up the accept loop.
+    fn handshake(
+        &self,
+        socket: Box<dyn Socket>,
+        _peer: &str,
+    ) -> BoxFuture<'static, io::Result<Box<dyn Socket>>> {
+        Box::pin(async move { Ok(socket) })
+    }
+}
+
+/// How the actors reach each other: `connect` on the reporter side, `listen` on the manager side.
+pub trait Transport: Debug + Send + Sync {
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Socket>>>;
+    fn listen<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Listener>>>;
+}
+
+/// An MLLP-framed connection over any [`Socket`].
+pub struct FrameStream {
+    framed: Framed<Box<dyn Socket>, MllpCodec>,
+}
+
+impl FrameStream {
+    pub fn new(socket: Box<dyn Socket>) -> Self {
+        FrameStream::with_codec(socket, MllpCodec::new())
+    }
+
+    pub fn with_codec(socket: Box<dyn Socket>, codec: MllpCodec) -> Self {
+        FrameStream {
+            framed: Framed::new(socket, codec),
+        }
+    }
+
+    pub async fn send_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.framed.send(payload).await
+    }
+
+    /// The payload of the next frame, or `None` once the peer has closed the connection.
+    /// Cancelling the wait loses no data.
+    pub async fn receive_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.framed.next().await.transpose()
+    }
+
+    /// Whether part of a frame has been read but not yet decoded.
+    pub fn is_mid_frame(&self) -> bool {
+        !self.framed.read_buffer().is_empty()
+    }
+
+    /// Writes bytes as they are, without framing.
+    pub async fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let socket = self.framed.get_mut();
+        socket.write_all(bytes).await?;
+        socket.flush().await
+    }
+
+    pub async fn shutdown(&mut self) {
+        let _ = self.framed.get_mut().shutdown().await;
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Socket>, String)>> {
+        Box::pin(async move {
+            let (stream, peer) = TcpListener::accept(self).await?;
+            stream.set_nodelay(true)?;
+            Ok((Box::new(stream) as Box<dyn Socket>, peer.to_string()))
+        })
+    }
+
+    fn local_address(&self) -> io::Result<String> {
+        Ok(self.local_addr()?.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Socket>>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(address).await?;
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream) as Box<dyn Socket>)
+        })
+    }
+
+    fn listen<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(
+            async move { Ok(Box::new(TcpListener::bind(address).await?) as Box<dyn Listener>) },
+        )
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::FileTypeExt;
+
+    use tokio::net::{UnixListener, UnixStream};
+
+    use super::{BoxFuture, ConnectionCounter, Listener, Socket};
+
+    struct UnixSocketListener {
+        listener: UnixListener,
+        accepted: ConnectionCounter,
+    }
+
+    impl Listener for UnixSocketListener {
+        fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Socket>, String)>> {
+            Box::pin(async move {
+                let (stream, peer) = self.listener.accept().await?;
+                // Clients rarely bind a path of their own, so number them instead.
+                let peer = match peer.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => self.accepted.next(&self.local_address()?),
+                };
+                Ok((Box::new(stream) as Box<dyn Socket>, peer))
+            })
+        }
+
+        fn local_address(&self) -> io::Result<String> {
+            let addr = self.listener.local_addr()?;
+            let path = addr.as_pathname().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "unnamed Unix socket")
+            })?;
+            Ok(format!("unix:{}", path.display()))
+        }
+    }
+
+    pub async fn connect(path: &str) -> io::Result<Box<dyn Socket>> {
+        Ok(Box::new(UnixStream::connect(path).await?))
+    }
+
+    pub fn listen(path: &str) -> io::Result<Box<dyn Listener>> {
+        // A socket file left behind by an earlier run would make bind fail. Anything else at the
+        // path is more likely a mistyped address and is left alone.
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Box::new(UnixSocketListener {
+            listener: UnixListener::bind(path)?,
+            accepted: ConnectionCounter::default(),
+        }))
+    }
+}
+
+/// Unix domain sockets, addressed by path.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnixTransport;
+
+impl Transport for UnixTransport {
+    #[cfg(unix)]
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Socket>>> {
+        Box::pin(unix::connect(address))
+    }
+
+    #[cfg(unix)]
+    fn listen<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move { unix::listen(address) })
+    }
+
+    #[cfg(not(unix))]
+    fn connect<'a>(&'a self, _address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Socket>>> {
+        Box::pin(async { Err(unsupported()) })
+    }
+
+    #[cfg(not(unix))]
+    fn listen<'a>(&'a self, _address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(async { Err(unsupported()) })
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not available",
+    )
+}
+
+type Registry = Mutex<HashMap<String, UnboundedSender<DuplexStream>>>;
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+/// Numbers the connections of a listener whose peers have no address of their own.
+#[derive(Debug, Default)]
+struct ConnectionCounter(AtomicU64);
+
+impl ConnectionCounter {
+    /// `<local address>#<n>`, counting from 1.
+    fn next(&self, local_address: &str) -> String {
+        format!(
+            "{}#{}",
+            local_address,
+            self.0.fetch_add(1, Ordering::Relaxed) + 1
+        )
+    }
+}
+
+pub struct MemoryListener {
+    name: String,
+    incoming: tokio::sync::Mutex<UnboundedReceiver<DuplexStream>>,
+    accepted: ConnectionCounter,
+}
+
+impl Listener for MemoryListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Socket>, String)>> {
+        Box::pin(async move {
+            let socket = self.incoming.lock().await.recv().await.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "listener was closed")
+            })?;
+            Ok((
+                Box::new(socket) as Box<dyn Socket>,
+                self.accepted.next(&self.local_address()?),
+            ))
+        })
+    }
+
+    fn local_address(&self) -> io::Result<String> {
+        Ok(format!("mem:{}", self.name))
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        registry().lock().unwrap().remove(&self.name);
+    }
+}
+
+/// In-process channels, addressed by name, for running both actors in one process without ports.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryTransport;
+
+impl MemoryTransport {
+    fn connect_now(address: &str) -> io::Result<Box<dyn Socket>> {
+        let registry = registry().lock().unwrap();
+        let listener = registry.get(address).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("no listener on mem:{}", address),
+            )
+        })?;
+        let (client, server) = tokio::io::duplex(MEMORY_BUFFER);
+        listener
+            .send(server)
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, "listener was closed"))?;
+        Ok(Box::new(client))
+    }
+
+    fn listen_now(address: &str) -> io::Result<Box<dyn Listener>> {
+        let mut registry = registry().lock().unwrap();
+        if registry.contains_key(address) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("mem:{} is already listening", address),
+            ));
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        registry.insert(address.to_string(), tx);
+        Ok(Box::new(MemoryListener {
+            name: address.to_string(),
+            incoming: tokio::sync::Mutex::new(rx),
+            accepted: ConnectionCounter::default(),
+        }))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Socket>>> {
+        Box::pin(async move { MemoryTransport::connect_now(address) })
+    }
+
+    fn listen<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move { MemoryTransport::listen_now(address) })
+    }
+}
+
+/// Picks the transport from the address: `unix:/path`, `mem:name`, or `host:port` for TCP.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultTransport;
+
+impl DefaultTransport {
+    fn resolve(address: &str) -> (&'static dyn Transport, &str) {
+        if let Some(path) = address.strip_prefix("unix:") {
+            (&UnixTransport, path)
+        } else if let Some(name) = address.strip_prefix("mem:") {
+            (&MemoryTransport, name)
+        } else {
+            (
+                &TcpTransport,
+                address.strip_prefix("tcp:").unwrap_or(address),
+            )
+        }
+    }
+}
+
+/// Fails unless `address` can only be reached from this host: a Unix socket, an in-process
+/// address, or TCP on a loopback interface. For listeners that have no authentication.
+pub fn ensure_local(address: &str) -> io::Result<()> {
+    if address.starts_with("unix:") || address.starts_with("mem:") {
+        return Ok(());
+    }
+    let target = address.strip_prefix("tcp:").unwrap_or(address);
+    let local = match target.parse::<SocketAddr>() {
+        Ok(socket_address) => socket_address.ip().is_loopback(),
+        Err(_) => target
+            .rsplit_once(':')
+            .is_some_and(|(host, _)| host == "localhost"),
+    };
+    if local {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a local address", address),
+        ))
+    }
+}
+
+impl Transport for DefaultTransport {
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Socket>>> {
+        let (transport, address) = DefaultTransport::resolve(address);
+        transport.connect(address)
+    }
+
+    fn listen<'a>(&'a self, address: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Listener>>> {
+        let (transport, address) = DefaultTransport::resolve(address);
+        transport.listen(address)
+    }
+}
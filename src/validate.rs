@@ -0,0 +1,965 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::messages::parse_segments;
+use crate::segments::{component, RawSegment};
+
+pub const ACM_PROFILE_OID: &str = "1.3.6.1.4.1.19376.1.6.1.4.1";
+
+const EVENT_PHASES: &[&str] = &[
+    "start",
+    "start_only",
+    "continue",
+    "end",
+    "present",
+    "update",
+    "escalate",
+    "inactivate",
+    "deescalate",
+    "reset",
+];
+const ALARM_STATES: &[&str] = &["inactive", "active", "latched"];
+const INACTIVATION_STATES: &[&str] = &[
+    "enabled",
+    "audio-paused",
+    "audio-off",
+    "alarm-paused",
+    "alarm-off",
+    "alert-acknowledged",
+];
+const PRIORITIES: &[&str] = &["PN", "PL", "PM", "PH"];
+const ALERT_KINDS: &[&str] = &["SP", "ST", "SA"];
+const VALUE_TYPES: &[&str] = &[
+    "NM", "ST", "TX", "CWE", "CE", "CF", "DTM", "TS", "SN", "NA", "ED",
+];
+const RESULT_STATUSES: &[&str] = &["F", "X", "R", "P", "C", "S", "D", "I", "W", "N", "U"];
+const PROCESSING_IDS: &[&str] = &["P", "D", "T"];
+
+const EVENT_PHASE: &str = "68481";
+const ALARM_STATE: &str = "68482";
+const INACTIVATION_STATE: &str = "68483";
+const ALARM_PRIORITY: &str = "68484";
+const ALERT_TYPE: &str = "68485";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+/// Where a finding applies. `position` is the 1-based index of the segment in the message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub segment: String,
+    pub position: usize,
+    /// 1-based occurrence of `segment` among the segments of its type, as in ERR-2.2.
+    pub sequence: usize,
+    pub field: Option<usize>,
+    pub component: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub location: Location,
+    pub message: String,
+}
+
+impl Location {
+    fn new(segment: &RawSegment, position: usize, field: Option<usize>) -> Self {
+        Location {
+            segment: segment.id().to_string(),
+            position,
+            sequence: 1,
+            field,
+            component: None,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "ERROR"),
+            Severity::Warning => write!(f, "WARNING"),
+            Severity::Info => write!(f, "INFO"),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]", self.segment, self.position)?;
+        if let Some(field) = self.field {
+            write!(f, "-{}", field)?;
+        }
+        if let Some(component) = self.component {
+            write!(f, ".{}", component)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}: {}",
+            self.severity, self.rule, self.location, self.message
+        )
+    }
+}
+
+pub fn has_errors(findings: &[Finding]) -> bool {
+    findings.iter().any(|f| f.severity == Severity::Error)
+}
+
+/// Checks an ER7-encoded ORU^R40 against the IHE PCD TF-2 PCD-04 constraints.
+pub fn validate_pcd04(text: &str) -> Vec<Finding> {
+    match parse_segments(text) {
+        Ok((_, segments)) => validate_segments(&segments),
+        Err(e) => vec![Finding {
+            rule: "ACM-ENC-001",
+            severity: Severity::Error,
+            location: Location {
+                segment: "MSH".to_string(),
+                position: 1,
+                sequence: 1,
+                field: None,
+                component: None,
+            },
+            message: format!("message cannot be decoded: {}", e),
+        }],
+    }
+}
+
+pub fn validate_segments(segments: &[RawSegment]) -> Vec<Finding> {
+    let mut v = Validator::default();
+    v.check_structure(segments);
+
+    for (index, segment) in segments.iter().enumerate() {
+        let position = index + 1;
+        match segment.id() {
+            "MSH" => v.check_msh(segment, position),
+            "PID" => v.check_pid(segment, position),
+            "PV1" => v.check_pv1(segment, position),
+            "OBR" => v.check_obr(segment, position),
+            "OBX" => v.check_obx(segment, position),
+            _ => {}
+        }
+    }
+    v.check_alert_rows(segments);
+    for finding in &mut v.findings {
+        let location = &mut finding.location;
+        location.sequence = segments
+            .iter()
+            .take(location.position)
+            .filter(|s| s.id() == location.segment)
+            .count()
+            .max(1);
+    }
+    v.findings
+        .sort_by_key(|f| (f.location.position, f.severity));
+    v.findings
+}
+
+#[derive(Default)]
+struct Validator {
+    findings: Vec<Finding>,
+}
+
+impl Validator {
+    fn report(
+        &mut self,
+        rule: &'static str,
+        severity: Severity,
+        location: Location,
+        message: String,
+    ) {
+        self.findings.push(Finding {
+            rule,
+            severity,
+            location,
+            message,
+        });
+    }
+
+    fn error(
+        &mut self,
+        rule: &'static str,
+        seg: &RawSegment,
+        pos: usize,
+        field: usize,
+        msg: String,
+    ) {
+        self.report(
+            rule,
+            Severity::Error,
+            Location::new(seg, pos, Some(field)),
+            msg,
+        );
+    }
+
+    fn warning(
+        &mut self,
+        rule: &'static str,
+        seg: &RawSegment,
+        pos: usize,
+        field: usize,
+        msg: String,
+    ) {
+        self.report(
+            rule,
+            Severity::Warning,
+            Location::new(seg, pos, Some(field)),
+            msg,
+        );
+    }
+
+    fn check_structure(&mut self, segments: &[RawSegment]) {
+        #[derive(PartialEq, PartialOrd)]
+        enum State {
+            Start,
+            Header,
+            Patient,
+            Visit,
+            Order,
+            Observation,
+        }
+
+        let mut state = State::Start;
+        let mut orders = 0;
+        let mut observations_in_order = 0;
+        let mut last_obr: Option<(usize, &RawSegment)> = None;
+
+        for (index, segment) in segments.iter().enumerate() {
+            let position = index + 1;
+            let next = match (segment.id(), &state) {
+                ("MSH", State::Start) => State::Header,
+                (_, State::Start) => {
+                    self.report(
+                        "ACM-STRUCT-001",
+                        Severity::Error,
+                        Location::new(segment, position, None),
+                        "message must start with MSH".to_string(),
+                    );
+                    return;
+                }
+                ("PID", State::Header) => State::Patient,
+                ("PV1", State::Patient) => State::Visit,
+                ("PID", _) | ("PV1", _) => {
+                    self.report(
+                        "ACM-STRUCT-003",
+                        Severity::Error,
+                        Location::new(segment, position, None),
+                        format!(
+                            "{} may appear once, after MSH and before any OBR",
+                            segment.id()
+                        ),
+                    );
+                    continue;
+                }
+                ("OBR", _) => {
+                    self.check_order_has_observations(last_obr, observations_in_order);
+                    orders += 1;
+                    observations_in_order = 0;
+                    last_obr = Some((position, segment));
+                    State::Order
+                }
+                ("OBX", State::Order) | ("OBX", State::Observation) => {
+                    observations_in_order += 1;
+                    State::Observation
+                }
+                ("PRT", State::Order) => State::Order,
+                ("PRT", State::Observation) | ("NTE", State::Observation) => State::Observation,
+                (id, _) if id.starts_with('Z') => continue,
+                (id, _) => {
+                    self.report(
+                        "ACM-STRUCT-002",
+                        Severity::Error,
+                        Location::new(segment, position, None),
+                        format!("{} is not allowed at this position", id),
+                    );
+                    continue;
+                }
+            };
+            state = next;
+        }
+        self.check_order_has_observations(last_obr, observations_in_order);
+
+        if orders == 0 {
+            if let Some(msh) = segments.first() {
+                self.report(
+                    "ACM-STRUCT-004",
+                    Severity::Error,
+                    Location::new(msh, 1, None),
+                    "message contains no OBR alert group".to_string(),
+                );
+            }
+        }
+    }
+
+    fn check_order_has_observations(&mut self, obr: Option<(usize, &RawSegment)>, count: usize) {
+        if let Some((position, segment)) = obr {
+            if count == 0 {
+                self.report(
+                    "ACM-STRUCT-005",
+                    Severity::Error,
+                    Location::new(segment, position, None),
+                    "OBR is not followed by any OBX".to_string(),
+                );
+            }
+        }
+    }
+
+    fn check_msh(&mut self, msh: &RawSegment, pos: usize) {
+        if msh.field(9) != "ORU^R40^ORU_R40" {
+            self.error(
+                "ACM-MSH-001",
+                msh,
+                pos,
+                9,
+                format!("MSH-9 must be ORU^R40^ORU_R40, got {:?}", msh.field(9)),
+            );
+        }
+        if msh.field(10).is_empty() {
+            self.error(
+                "ACM-MSH-002",
+                msh,
+                pos,
+                10,
+                "MSH-10 message control ID is required".into(),
+            );
+        }
+        if !PROCESSING_IDS.contains(&msh.component(11, 1)) {
+            self.error(
+                "ACM-MSH-003",
+                msh,
+                pos,
+                11,
+                format!(
+                    "MSH-11 must be one of {:?}, got {:?}",
+                    PROCESSING_IDS,
+                    msh.field(11)
+                ),
+            );
+        }
+        if !msh.field(12).starts_with("2.6") {
+            self.warning(
+                "ACM-MSH-004",
+                msh,
+                pos,
+                12,
+                format!("MSH-12 should be 2.6, got {:?}", msh.field(12)),
+            );
+        }
+        if msh.field(15) != "AL" {
+            self.error(
+                "ACM-MSH-005",
+                msh,
+                pos,
+                15,
+                format!("MSH-15 must be AL, got {:?}", msh.field(15)),
+            );
+        }
+        if msh.field(16) != "NE" {
+            self.error(
+                "ACM-MSH-006",
+                msh,
+                pos,
+                16,
+                format!("MSH-16 must be NE, got {:?}", msh.field(16)),
+            );
+        }
+        let has_profile = msh.field(21).split('~').any(|profile| {
+            component(profile, 3) == ACM_PROFILE_OID && component(profile, 4) == "ISO"
+        });
+        if !has_profile {
+            self.error(
+                "ACM-MSH-007",
+                msh,
+                pos,
+                21,
+                format!(
+                    "MSH-21 must declare the ACM profile {}^ISO",
+                    ACM_PROFILE_OID
+                ),
+            );
+        }
+        if !msh.field(3).split('^').any(is_eui64) {
+            self.warning(
+                "ACM-MSH-008",
+                msh,
+                pos,
+                3,
+                "MSH-3 should identify the sender by EUI-64".into(),
+            );
+        }
+        if !is_dtm(msh.field(7)) {
+            self.error(
+                "ACM-MSH-009",
+                msh,
+                pos,
+                7,
+                format!("MSH-7 is not a valid DTM: {:?}", msh.field(7)),
+            );
+        }
+        let charset = msh.component(18, 1);
+        if crate::encoding::Charset::from_msh_18(charset).is_err() {
+            self.warning(
+                "ACM-MSH-010",
+                msh,
+                pos,
+                18,
+                format!("MSH-18 character set {:?} is not supported", charset),
+            );
+        }
+    }
+
+    fn check_pid(&mut self, pid: &RawSegment, pos: usize) {
+        if pid.field(3).is_empty() {
+            self.error(
+                "ACM-PID-001",
+                pid,
+                pos,
+                3,
+                "PID-3 patient identifier is required".into(),
+            );
+        }
+        if pid.field(5).is_empty() {
+            self.warning(
+                "ACM-PID-002",
+                pid,
+                pos,
+                5,
+                "PID-5 patient name should be sent".into(),
+            );
+        }
+    }
+
+    fn check_pv1(&mut self, pv1: &RawSegment, pos: usize) {
+        if pv1.field(2).is_empty() {
+            self.error(
+                "ACM-PV1-001",
+                pv1,
+                pos,
+                2,
+                "PV1-2 patient class is required".into(),
+            );
+        }
+        if pv1.field(3).is_empty() {
+            self.warning(
+                "ACM-PV1-002",
+                pv1,
+                pos,
+                3,
+                "PV1-3 assigned location should be sent".into(),
+            );
+        }
+    }
+
+    fn check_obr(&mut self, obr: &RawSegment, pos: usize) {
+        let filler = obr.field(3);
+        let well_formed = !component(filler, 1).is_empty()
+            && !component(filler, 2).is_empty()
+            && is_eui64(component(filler, 3))
+            && component(filler, 4) == "EUI-64";
+        if !well_formed {
+            self.error(
+                "ACM-OBR-001",
+                obr,
+                pos,
+                3,
+                format!(
+                    "OBR-3 must be <update>^<alert id>^<EUI-64>^EUI-64, got {:?}",
+                    filler
+                ),
+            );
+        }
+        if component(obr.field(4), 3) != "MDC" {
+            self.warning(
+                "ACM-OBR-002",
+                obr,
+                pos,
+                4,
+                "OBR-4 should be an MDC event code".into(),
+            );
+        }
+        if !is_dtm(obr.field(7)) {
+            self.error(
+                "ACM-OBR-003",
+                obr,
+                pos,
+                7,
+                format!("OBR-7 is not a valid DTM: {:?}", obr.field(7)),
+            );
+        }
+        let update = component(filler, 1).parse::<u32>().unwrap_or(0);
+        if update > 0 && obr.field(29).is_empty() {
+            self.warning(
+                "ACM-OBR-004",
+                obr,
+                pos,
+                29,
+                "OBR-29 should reference the parent alert of an update".into(),
+            );
+        }
+    }
+
+    fn check_obx(&mut self, obx: &RawSegment, pos: usize) {
+        let value = obx.field(5);
+        let value_type = obx.field(2);
+
+        if !value_type.is_empty() && !VALUE_TYPES.contains(&value_type) {
+            self.error(
+                "ACM-OBX-002",
+                obx,
+                pos,
+                2,
+                format!("OBX-2 value type {:?} is not allowed", value_type),
+            );
+        } else if value_type.is_empty() && !value.is_empty() {
+            self.error(
+                "ACM-OBX-002",
+                obx,
+                pos,
+                2,
+                "OBX-2 is required when OBX-5 is valued".into(),
+            );
+        }
+
+        if component(obx.field(3), 1).is_empty() || component(obx.field(3), 3) != "MDC" {
+            self.error(
+                "ACM-CS-001",
+                obx,
+                pos,
+                3,
+                format!("OBX-3 must be an MDC code, got {:?}", obx.field(3)),
+            );
+        }
+
+        match parse_tree(obx.field(4)) {
+            Some(_) => {}
+            None => self.error(
+                "ACM-OBX-004",
+                obx,
+                pos,
+                4,
+                format!(
+                    "OBX-4 must be <mds>.<vmd>.<channel>.<facet>, got {:?}",
+                    obx.field(4)
+                ),
+            ),
+        }
+
+        let status = obx.field(11);
+        if !RESULT_STATUSES.contains(&status) {
+            self.error(
+                "ACM-OBX-006",
+                obx,
+                pos,
+                11,
+                format!("OBX-11 result status {:?} is not valid", status),
+            );
+        } else if value.is_empty() && status == "F" {
+            self.warning(
+                "ACM-OBX-006",
+                obx,
+                pos,
+                11,
+                "OBX-11 should be X when OBX-5 is empty".into(),
+            );
+        }
+
+        if obx.field(18).is_empty() {
+            self.error(
+                "ACM-OBX-007",
+                obx,
+                pos,
+                18,
+                "OBX-18 equipment instance identifier is required".into(),
+            );
+        }
+
+        let (values, rule): (&[&str], &'static str) = match obx.component(3, 1) {
+            EVENT_PHASE => (EVENT_PHASES, "ACM-VS-001"),
+            ALARM_STATE => (ALARM_STATES, "ACM-VS-002"),
+            INACTIVATION_STATE => (INACTIVATION_STATES, "ACM-VS-003"),
+            ALARM_PRIORITY => (PRIORITIES, "ACM-VS-004"),
+            ALERT_TYPE => (ALERT_KINDS, "ACM-VS-005"),
+            _ => return,
+        };
+        if !values.contains(&value) {
+            self.error(
+                rule,
+                obx,
+                pos,
+                5,
+                format!("{:?} is not one of {:?}", value, values),
+            );
+        }
+    }
+
+    /// Checks the containment tree and the mandatory MDC_ATTR_* rows of each alert group.
+    fn check_alert_rows(&mut self, segments: &[RawSegment]) {
+        let mut groups: Vec<(usize, Vec<(usize, &RawSegment)>)> = Vec::new();
+        for (index, segment) in segments.iter().enumerate() {
+            match segment.id() {
+                "OBR" => groups.push((index + 1, Vec::new())),
+                "OBX" => {
+                    if let Some((_, rows)) = groups.last_mut() {
+                        rows.push((index + 1, segment));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (obr_position, rows) in groups {
+            let obr = &segments[obr_position - 1];
+            let trees: Vec<[u32; 4]> = rows
+                .iter()
+                .filter_map(|(_, s)| parse_tree(s.field(4)))
+                .collect();
+
+            for (expected_set_id, (position, obx)) in (1..).zip(rows.iter()) {
+                if obx.field(1) != expected_set_id.to_string() {
+                    self.warning(
+                        "ACM-OBX-001",
+                        obx,
+                        *position,
+                        1,
+                        format!(
+                            "OBX-1 should be {}, got {:?}",
+                            expected_set_id,
+                            obx.field(1)
+                        ),
+                    );
+                }
+                let Some([mds, vmd, chan, _]) = parse_tree(obx.field(4)) else {
+                    continue;
+                };
+                let has = |t: [u32; 3]| trees.iter().any(|x| x[..3] == t && x[3] == 0);
+                if (vmd > 0 && !has([mds, 0, 0])) || (chan > 0 && !has([mds, vmd, 0])) {
+                    self.warning(
+                        "ACM-OBX-005",
+                        obx,
+                        *position,
+                        4,
+                        "OBX-4 refers to a VMD or MDS that is not reported in this alert".into(),
+                    );
+                }
+            }
+
+            let has_event = rows.iter().any(|(_, s)| {
+                s.component(3, 2).starts_with("MDC_EVT_")
+                    && parse_tree(s.field(4)).map(|t| t[3]) == Some(1)
+            });
+            if !has_event {
+                self.report(
+                    "ACM-OBX-003",
+                    Severity::Error,
+                    Location::new(obr, obr_position, None),
+                    "alert group has no MDC_EVT_* identification OBX (facet 1)".to_string(),
+                );
+            }
+            for (code, name, severity) in [
+                (EVENT_PHASE, "MDC_ATTR_EVENT_PHASE", Severity::Error),
+                (ALARM_STATE, "MDC_ATTR_ALARM_STATE", Severity::Error),
+                (
+                    INACTIVATION_STATE,
+                    "MDC_ATTR_ALARM_INACTIVATION_STATE",
+                    Severity::Warning,
+                ),
+                (ALARM_PRIORITY, "MDC_ATTR_ALARM_PRIORITY", Severity::Error),
+                (ALERT_TYPE, "MDC_ATTR_ALERT_TYPE", Severity::Error),
+            ] {
+                if !rows.iter().any(|(_, s)| s.component(3, 1) == code) {
+                    self.report(
+                        "ACM-OBX-003",
+                        severity,
+                        Location::new(obr, obr_position, None),
+                        format!("alert group is missing the {} OBX", name),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn parse_tree(sub_id: &str) -> Option<[u32; 4]> {
+    let parts: Vec<u32> = sub_id
+        .split('.')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    parts.try_into().ok()
+}
+
+fn is_eui64(value: &str) -> bool {
+    value.len() == 16 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// HL7 DTM: YYYY[MM[DD[HH[MM[SS[.S[S[S[S]]]]]]]]][+/-ZZZZ]
+fn is_dtm(value: &str) -> bool {
+    let (datetime, offset) = match value.find(['+', '-']) {
+        Some(i) => (&value[..i], Some(&value[i + 1..])),
+        None => (value, None),
+    };
+    let (digits, fraction) = match datetime.split_once('.') {
+        Some((d, f)) => (d, Some(f)),
+        None => (datetime, None),
+    };
+    let all_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+
+    [4, 6, 8, 10, 12, 14].contains(&digits.len())
+        && all_digits(digits)
+        && fraction
+            .is_none_or(|f| digits.len() == 14 && (1..=4).contains(&f.len()) && all_digits(f))
+        && offset.is_none_or(|o| o.len() == 4 && all_digits(o))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{self, ActiveAlert, AlertSpec, DeviceConfig, ObservationSpec};
+
+    // Positions in the generated alert: MSH, PID, PV1, OBR, three containment rows, the
+    // MDC_EVT_* row, the measurement and the five MDC_ATTR_* rows.
+    const MSH: usize = 0;
+    const PID: usize = 1;
+    const PV1: usize = 2;
+    const OBR: usize = 3;
+    const VMD: usize = 5;
+    const EVENT: usize = 7;
+    const MEASUREMENT: usize = 8;
+    const PHASE: usize = 9;
+
+    fn alert_segments() -> Vec<RawSegment> {
+        let alert = ActiveAlert {
+            id: "alert-1".to_string(),
+            spec: AlertSpec {
+                code: "196670^MDC_EVT_LO^MDC".to_string(),
+                text: "SpO2 low".to_string(),
+                priority: "PM".to_string(),
+                kind: "SP".to_string(),
+                observation: Some(ObservationSpec {
+                    code: device::SPO2.to_string(),
+                    value: "85".to_string(),
+                    unit: "262688^MDC_DIM_PERCENT^MDC".to_string(),
+                    value_type: "NM".to_string(),
+                }),
+            },
+            update: 0,
+        };
+        let msg = DeviceConfig::default().alert_message(&alert, "start", "active");
+        parse_segments(&msg.encode()).unwrap().1
+    }
+
+    /// Findings of the generated alert after `edit`, as `(rule, severity)`.
+    fn findings(edit: impl FnOnce(&mut Vec<RawSegment>)) -> Vec<(&'static str, Severity)> {
+        let mut segments = alert_segments();
+        edit(&mut segments);
+        validate_segments(&segments)
+            .into_iter()
+            .map(|f| (f.rule, f.severity))
+            .collect()
+    }
+
+    fn set(index: usize, field: usize, value: &str) -> impl FnOnce(&mut Vec<RawSegment>) + '_ {
+        move |segments| segments[index].set_field(field, value)
+    }
+
+    fn remove(index: usize) -> impl FnOnce(&mut Vec<RawSegment>) {
+        move |segments| {
+            segments.remove(index);
+        }
+    }
+
+    #[test]
+    fn generated_alert_is_clean() {
+        assert_eq!(findings(|_| {}), vec![]);
+    }
+
+    #[test]
+    fn undecodable_message() {
+        let findings = validate_pcd04("PID|||1\r");
+        assert_eq!(findings[0].rule, "ACM-ENC-001");
+        assert!(has_errors(&findings));
+    }
+
+    #[test]
+    fn structure_rules() {
+        assert_eq!(
+            findings(|s| s.swap(MSH, PID))[0],
+            ("ACM-STRUCT-001", Severity::Error)
+        );
+        assert_eq!(
+            findings(|s| s.insert(EVENT, RawSegment::new("NK1"))),
+            vec![("ACM-STRUCT-002", Severity::Error)]
+        );
+        assert_eq!(
+            findings(|s| {
+                let pid = s[PID].clone();
+                s.insert(OBR + 1, pid);
+            })[0],
+            ("ACM-STRUCT-003", Severity::Error)
+        );
+        assert!(findings(|s| s.truncate(OBR)).contains(&("ACM-STRUCT-004", Severity::Error)));
+        assert!(findings(|s| {
+            let obr = s[OBR].clone();
+            s.push(obr);
+        })
+        .contains(&("ACM-STRUCT-005", Severity::Error)));
+        assert_eq!(
+            findings(|s| s.insert(EVENT, RawSegment::new("ZXT"))),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn msh_rules() {
+        let cases = [
+            (9, "ADT^A01", "ACM-MSH-001", Severity::Error),
+            (10, "", "ACM-MSH-002", Severity::Error),
+            (11, "X", "ACM-MSH-003", Severity::Error),
+            (12, "2.5", "ACM-MSH-004", Severity::Warning),
+            (15, "NE", "ACM-MSH-005", Severity::Error),
+            (16, "AL", "ACM-MSH-006", Severity::Error),
+            (21, "OTHER^X^1.2.3^ISO", "ACM-MSH-007", Severity::Error),
+            (3, "monitor", "ACM-MSH-008", Severity::Warning),
+            (7, "2026-01-01", "ACM-MSH-009", Severity::Error),
+            (18, "EBCDIC", "ACM-MSH-010", Severity::Warning),
+        ];
+        for (field, value, rule, severity) in cases {
+            assert_eq!(
+                findings(set(MSH, field, value)),
+                vec![(rule, severity)],
+                "{rule}"
+            );
+        }
+    }
+
+    #[test]
+    fn pid_and_pv1_rules() {
+        assert_eq!(
+            findings(set(PID, 3, "")),
+            vec![("ACM-PID-001", Severity::Error)]
+        );
+        assert_eq!(
+            findings(set(PID, 5, "")),
+            vec![("ACM-PID-002", Severity::Warning)]
+        );
+        assert_eq!(
+            findings(set(PV1, 2, "")),
+            vec![("ACM-PV1-001", Severity::Error)]
+        );
+        assert_eq!(
+            findings(set(PV1, 3, "")),
+            vec![("ACM-PV1-002", Severity::Warning)]
+        );
+    }
+
+    #[test]
+    fn obr_rules() {
+        assert_eq!(
+            findings(set(OBR, 3, "0^alert-1^not-an-eui^EUI-64")),
+            vec![("ACM-OBR-001", Severity::Error)]
+        );
+        assert_eq!(
+            findings(set(OBR, 4, "196616^MDC_EVT_ALARM^LOCAL")),
+            vec![("ACM-OBR-002", Severity::Warning)]
+        );
+        assert_eq!(
+            findings(set(OBR, 7, "yesterday")),
+            vec![("ACM-OBR-003", Severity::Error)]
+        );
+        assert_eq!(
+            findings(set(OBR, 3, "1^alert-1^0000000000000001^EUI-64")),
+            vec![("ACM-OBR-004", Severity::Warning)]
+        );
+    }
+
+    #[test]
+    fn locations_count_segments_of_their_type() {
+        let mut segments = alert_segments();
+        segments[MEASUREMENT].set_field(2, "XX");
+        let findings = validate_segments(&segments);
+        assert_eq!(findings[0].location.position, MEASUREMENT + 1);
+        assert_eq!(findings[0].location.sequence, MEASUREMENT - OBR);
+
+        let err = crate::conformance::to_err(&findings[0]);
+        assert_eq!(err.err_2_error_location, ["OBX^5^2"]);
+    }
+
+    #[test]
+    fn obx_rules() {
+        assert_eq!(
+            findings(set(EVENT, 1, "9")),
+            vec![("ACM-OBX-001", Severity::Warning)]
+        );
+        assert_eq!(
+            findings(set(MEASUREMENT, 2, "XX")),
+            vec![("ACM-OBX-002", Severity::Error)]
+        );
+        assert_eq!(
+            findings(set(MEASUREMENT, 2, "")),
+            vec![("ACM-OBX-002", Severity::Error)]
+        );
+        assert_eq!(
+            findings(set(MEASUREMENT, 3, "150456^SAT_O2^LOCAL")),
+            vec![("ACM-CS-001", Severity::Error)]
+        );
+        assert!(findings(set(MEASUREMENT, 4, "1.2.1")).contains(&("ACM-OBX-004", Severity::Error)));
+        assert!(findings(remove(VMD)).contains(&("ACM-OBX-005", Severity::Warning)));
+        assert_eq!(
+            findings(set(MEASUREMENT, 11, "Q")),
+            vec![("ACM-OBX-006", Severity::Error)]
+        );
+        assert_eq!(
+            findings(|s| {
+                s[MEASUREMENT].set_field(5, "");
+                s[MEASUREMENT].set_field(2, "");
+            }),
+            vec![("ACM-OBX-006", Severity::Warning)]
+        );
+        assert_eq!(
+            findings(set(EVENT, 18, "")),
+            vec![("ACM-OBX-007", Severity::Error)]
+        );
+    }
+    #[test]
+    fn mandatory_alert_rows() {
+        assert!(findings(set(EVENT, 4, "1.2.1.9")).contains(&("ACM-OBX-003", Severity::Error)));
+        for (row, severity) in [
+            (PHASE, Severity::Error),
+            (PHASE + 1, Severity::Error),
+            (PHASE + 2, Severity::Warning),
+            (PHASE + 3, Severity::Error),
+            (PHASE + 4, Severity::Error),
+        ] {
+            assert!(
+                findings(remove(row)).contains(&("ACM-OBX-003", severity)),
+                "row {row}"
+            );
+        }
+    }
+
+    #[test]
+    fn value_set_rules() {
+        for (offset, rule) in (0..).zip([
+            "ACM-VS-001",
+            "ACM-VS-002",
+            "ACM-VS-003",
+            "ACM-VS-004",
+            "ACM-VS-005",
+        ]) {
+            assert_eq!(
+                findings(set(PHASE + offset, 5, "bogus")),
+                vec![(rule, Severity::Error)]
+            );
+        }
+    }
+}
@@ -0,0 +1,583 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::device::{ActiveAlert, AlertSpec, DeviceConfig, ObservationSpec};
+use crate::scenario::{read_config, DeviceSender, StepOutcome};
+use crate::tls::{self, TlsConfig};
+use crate::transport::Transport;
+
+pub const EVENT_HIGH: &str = "196648^MDC_EVT_HI^MDC";
+pub const EVENT_LOW: &str = "196670^MDC_EVT_LO^MDC";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Vital {
+    HeartRate,
+    Spo2,
+    NibpSystolic,
+    NibpDiastolic,
+    RespRate,
+    Temperature,
+}
+
+impl Vital {
+    pub const ALL: [Vital; 6] = [
+        Vital::HeartRate,
+        Vital::Spo2,
+        Vital::NibpSystolic,
+        Vital::NibpDiastolic,
+        Vital::RespRate,
+        Vital::Temperature,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Vital::HeartRate => "147842^MDC_ECG_HEART_RATE^MDC",
+            Vital::Spo2 => "150456^MDC_PULS_OXIM_SAT_O2^MDC",
+            Vital::NibpSystolic => "150021^MDC_PRESS_BLD_NONINV_SYS^MDC",
+            Vital::NibpDiastolic => "150022^MDC_PRESS_BLD_NONINV_DIA^MDC",
+            Vital::RespRate => "151562^MDC_RESP_RATE^MDC",
+            Vital::Temperature => "150344^MDC_TEMP^MDC",
+        }
+    }
+
+    pub fn unit(self) -> &'static str {
+        match self {
+            Vital::HeartRate => "264864^MDC_DIM_BEAT_PER_MIN^MDC",
+            Vital::Spo2 => "262688^MDC_DIM_PERCENT^MDC",
+            Vital::NibpSystolic | Vital::NibpDiastolic => "266016^MDC_DIM_MMHG^MDC",
+            Vital::RespRate => "264928^MDC_DIM_RESP_PER_MIN^MDC",
+            Vital::Temperature => "268192^MDC_DIM_DEGC^MDC",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Vital::HeartRate => "HR",
+            Vital::Spo2 => "SpO2",
+            Vital::NibpSystolic => "NBPs",
+            Vital::NibpDiastolic => "NBPd",
+            Vital::RespRate => "RR",
+            Vital::Temperature => "Temp",
+        }
+    }
+
+    /// Resting adult value and the standard deviation of its beat-to-beat noise.
+    fn normal(self) -> (f64, f64) {
+        match self {
+            Vital::HeartRate => (75.0, 1.5),
+            Vital::Spo2 => (97.0, 0.5),
+            Vital::NibpSystolic => (120.0, 2.0),
+            Vital::NibpDiastolic => (78.0, 1.5),
+            Vital::RespRate => (16.0, 0.7),
+            Vital::Temperature => (37.0, 0.03),
+        }
+    }
+
+    fn format(self, value: f64) -> String {
+        match self {
+            Vital::Temperature => format!("{:.1}", value),
+            _ => format!("{:.0}", value),
+        }
+    }
+}
+
+/// Overrides the resting value or noise of one vital sign.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VitalSpec {
+    pub vital: Vital,
+    pub baseline: Option<f64>,
+    pub noise: Option<f64>,
+}
+
+/// A clinical event that shifts a vital sign by `delta`: it ramps in, holds, then ramps out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VitalEvent {
+    pub vital: Vital,
+    pub start_secs: f64,
+    #[serde(default)]
+    pub ramp_secs: f64,
+    pub hold_secs: f64,
+    pub delta: f64,
+}
+
+impl VitalEvent {
+    fn offset(&self, t: f64) -> f64 {
+        let ramp = self.ramp_secs.max(f64::EPSILON);
+        let since = t - self.start_secs;
+        let until_end = self.start_secs + 2.0 * self.ramp_secs + self.hold_secs - t;
+        if since <= 0.0 || until_end <= 0.0 {
+            0.0
+        } else {
+            self.delta * (since / ramp).min(until_end / ramp).min(1.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlarmLimit {
+    pub vital: Vital,
+    pub low: Option<f64>,
+    pub high: Option<f64>,
+    /// PN, PL, PM or PH.
+    #[serde(default = "default_priority")]
+    pub priority: String,
+    /// How far back inside the limit the value must return before the alarm ends.
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// How long the value must stay outside the limit before the alarm starts.
+    #[serde(default)]
+    pub delay_secs: f64,
+}
+
+fn default_priority() -> String {
+    "PM".to_string()
+}
+
+fn default_limits() -> Vec<AlarmLimit> {
+    let limit = |vital, low, high, priority: &str, hysteresis, delay_secs| AlarmLimit {
+        vital,
+        low: Some(low),
+        high: Some(high),
+        priority: priority.to_string(),
+        hysteresis,
+        delay_secs,
+    };
+    vec![
+        limit(Vital::HeartRate, 50.0, 120.0, "PM", 5.0, 10.0),
+        limit(Vital::Spo2, 90.0, 101.0, "PH", 2.0, 15.0),
+        limit(Vital::NibpSystolic, 90.0, 160.0, "PM", 5.0, 0.0),
+        limit(Vital::RespRate, 8.0, 30.0, "PM", 2.0, 15.0),
+        limit(Vital::Temperature, 35.5, 38.5, "PL", 0.2, 30.0),
+    ]
+}
+
+/// A simulated patient monitor whose alarms are driven by its vital signs, e.g.
+///
+/// ```yaml
+/// seed: 7
+/// duration_secs: 600
+/// time_scale: 10
+/// events:
+///   - { vital: spo2, start_secs: 60, ramp_secs: 30, hold_secs: 120, delta: -12 }
+///   - { vital: heart_rate, start_secs: 300, ramp_secs: 20, hold_secs: 60, delta: 60 }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VitalsConfig {
+    /// `host:port`, or `unix:/path` for a manager on a Unix socket.
+    pub manager: String,
+    pub ack_timeout_ms: u64,
+    /// Connect to the manager over mutually authenticated TLS.
+    pub tls: Option<TlsConfig>,
+    pub device: DeviceConfig,
+    /// Seed of the noise generator; the same seed gives the same trends.
+    pub seed: u64,
+    /// Simulated seconds between samples.
+    pub tick_secs: f64,
+    pub duration_secs: f64,
+    /// How much faster than real time the simulation runs.
+    pub time_scale: f64,
+    /// Simulated seconds between continue messages of an active alarm.
+    pub update_secs: f64,
+    pub vitals: Vec<VitalSpec>,
+    pub limits: Vec<AlarmLimit>,
+    pub events: Vec<VitalEvent>,
+}
+
+impl Default for VitalsConfig {
+    fn default() -> Self {
+        VitalsConfig {
+            manager: "127.0.0.1:8888".to_string(),
+            ack_timeout_ms: 5000,
+            tls: None,
+            device: DeviceConfig::default(),
+            seed: 1,
+            tick_secs: 1.0,
+            duration_secs: 300.0,
+            time_scale: 1.0,
+            update_secs: 30.0,
+            vitals: Vec::new(),
+            limits: default_limits(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl VitalsConfig {
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        read_config(path)
+    }
+
+    /// The transport to the manager, with the TLS certificates loaded if `tls` is set.
+    pub fn transport(&self) -> io::Result<Arc<dyn Transport>> {
+        tls::client_transport(self.tls.as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    High,
+    Low,
+}
+
+#[derive(Debug)]
+struct AlarmState {
+    limit: AlarmLimit,
+    direction: Direction,
+    pending_since: Option<f64>,
+    active: Option<ActiveAlert>,
+    last_sent: f64,
+    raised: u32,
+}
+
+impl AlarmState {
+    fn threshold(&self) -> Option<f64> {
+        match self.direction {
+            Direction::High => self.limit.high,
+            Direction::Low => self.limit.low,
+        }
+    }
+
+    fn violated(&self, value: f64) -> bool {
+        match (self.direction, self.threshold()) {
+            (Direction::High, Some(high)) => value > high,
+            (Direction::Low, Some(low)) => value < low,
+            _ => false,
+        }
+    }
+
+    fn cleared(&self, value: f64) -> bool {
+        match (self.direction, self.threshold()) {
+            (Direction::High, Some(high)) => value <= high - self.limit.hysteresis,
+            (Direction::Low, Some(low)) => value >= low + self.limit.hysteresis,
+            _ => true,
+        }
+    }
+}
+
+/// A PCD-04 the simulated device has to send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlarmEvent {
+    /// `start`, `continue` or `end`.
+    pub phase: &'static str,
+    /// `active` or `inactive`.
+    pub state: &'static str,
+    pub alert: ActiveAlert,
+}
+
+/// Produces vital-sign trends and the alarm events they trigger, one sample at a time.
+pub struct VitalSimulator {
+    config: VitalsConfig,
+    rng: u64,
+    time: f64,
+    /// Slowly varying noise per vital, so consecutive samples are correlated.
+    noise: HashMap<Vital, f64>,
+    alarms: Vec<AlarmState>,
+}
+
+impl VitalSimulator {
+    pub fn new(config: VitalsConfig) -> Self {
+        let alarms = config
+            .limits
+            .iter()
+            .flat_map(|limit| {
+                [Direction::High, Direction::Low].map(|direction| AlarmState {
+                    limit: limit.clone(),
+                    direction,
+                    pending_since: None,
+                    active: None,
+                    last_sent: 0.0,
+                    raised: 0,
+                })
+            })
+            .filter(|alarm| alarm.threshold().is_some())
+            .collect();
+        VitalSimulator {
+            rng: config.seed.max(1),
+            config,
+            time: 0.0,
+            noise: HashMap::new(),
+            alarms,
+        }
+    }
+
+    /// Simulated seconds since the start.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Uniform in [0, 1) from a xorshift generator.
+    fn uniform(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Approximately standard normal.
+    fn gaussian(&mut self) -> f64 {
+        (0..12).map(|_| self.uniform()).sum::<f64>() - 6.0
+    }
+
+    fn value_of(&mut self, vital: Vital) -> f64 {
+        let (mut baseline, mut sd) = vital.normal();
+        if let Some(spec) = self.config.vitals.iter().find(|s| s.vital == vital) {
+            baseline = spec.baseline.unwrap_or(baseline);
+            sd = spec.noise.unwrap_or(sd);
+        }
+        let step = self.gaussian() * sd * 0.45;
+        let noise = self.noise.entry(vital).or_insert(0.0);
+        *noise = 0.9 * *noise + step;
+        let noise = *noise;
+
+        let offset: f64 = self
+            .config
+            .events
+            .iter()
+            .filter(|e| e.vital == vital)
+            .map(|e| e.offset(self.time))
+            .sum();
+        let value = baseline + offset + noise;
+        match vital {
+            Vital::Spo2 => value.clamp(50.0, 100.0),
+            _ => value.max(0.0),
+        }
+    }
+
+    /// Advances one tick and returns the current value of every vital sign.
+    pub fn sample(&mut self) -> Vec<(Vital, f64)> {
+        self.time += self.config.tick_secs;
+        Vital::ALL.iter().map(|&v| (v, self.value_of(v))).collect()
+    }
+
+    fn alert_spec(&self, alarm: &AlarmState, value: f64) -> AlertSpec {
+        let vital = alarm.limit.vital;
+        let (code, word) = match alarm.direction {
+            Direction::High => (EVENT_HIGH, "high"),
+            Direction::Low => (EVENT_LOW, "low"),
+        };
+        AlertSpec {
+            code: code.to_string(),
+            text: format!("{} {}", vital.label(), word),
+            priority: alarm.limit.priority.clone(),
+            kind: "SP".to_string(),
+            observation: Some(ObservationSpec {
+                code: vital.code().to_string(),
+                value: vital.format(value),
+                unit: vital.unit().to_string(),
+                value_type: "NM".to_string(),
+            }),
+        }
+    }
+
+    /// Applies the alarm limits to one sample.
+    pub fn evaluate(&mut self, sample: &[(Vital, f64)]) -> Vec<AlarmEvent> {
+        let now = self.time;
+        let mut events = Vec::new();
+
+        for i in 0..self.alarms.len() {
+            let vital = self.alarms[i].limit.vital;
+            let Some(&(_, value)) = sample.iter().find(|(v, _)| *v == vital) else {
+                continue;
+            };
+            let spec = self.alert_spec(&self.alarms[i], value);
+            let alarm = &mut self.alarms[i];
+            let (violated, cleared) = (alarm.violated(value), alarm.cleared(value));
+            let update_due = now - alarm.last_sent >= self.config.update_secs;
+
+            match alarm.active.as_mut() {
+                None if violated => {
+                    let since = *alarm.pending_since.get_or_insert(now);
+                    if now - since >= alarm.limit.delay_secs {
+                        alarm.raised += 1;
+                        let alert = ActiveAlert {
+                            id: format!(
+                                "{}-{}-{}",
+                                self.config.device.name,
+                                spec.text.replace(' ', "-"),
+                                alarm.raised
+                            ),
+                            spec,
+                            update: 0,
+                        };
+                        events.push(AlarmEvent {
+                            phase: "start",
+                            state: "active",
+                            alert: alert.clone(),
+                        });
+                        alarm.active = Some(alert);
+                        alarm.last_sent = now;
+                    }
+                }
+                None => alarm.pending_since = None,
+                Some(_) if cleared => {
+                    let mut alert = alarm.active.take().unwrap();
+                    alert.update += 1;
+                    alert.spec.observation = spec.observation;
+                    events.push(AlarmEvent {
+                        phase: "end",
+                        state: "inactive",
+                        alert,
+                    });
+                    alarm.pending_since = None;
+                }
+                Some(alert) if update_due => {
+                    alert.update += 1;
+                    alert.spec.observation = spec.observation;
+                    events.push(AlarmEvent {
+                        phase: "continue",
+                        state: "active",
+                        alert: alert.clone(),
+                    });
+                    alarm.last_sent = now;
+                }
+                Some(_) => {}
+            }
+        }
+        events
+    }
+}
+
+/// Runs the simulation against the manager, sending every alarm event as a PCD-04. Fails only if
+/// the transport can't be set up; per-message errors are reported in the outcomes.
+pub async fn run_vitals(config: &VitalsConfig) -> io::Result<Vec<StepOutcome>> {
+    let mut simulator = VitalSimulator::new(config.clone());
+    let mut sender = DeviceSender::new(
+        config.device.clone(),
+        config.transport()?,
+        &config.manager,
+        Duration::from_millis(config.ack_timeout_ms),
+    );
+    let tick = Duration::from_secs_f64(config.tick_secs / config.time_scale.max(f64::EPSILON));
+    let mut outcomes = Vec::new();
+    let mut tick_no = 0;
+    let mut sequence = 0;
+
+    while simulator.time() < config.duration_secs {
+        tick_no += 1;
+        let sample = simulator.sample();
+        let events = simulator.evaluate(&sample);
+
+        for event in events {
+            sequence += 1;
+            let observed = event
+                .alert
+                .spec
+                .observation
+                .as_ref()
+                .map_or("", |o| o.value.as_str());
+            info!(
+                t = format_args!("{:.0}s", simulator.time()),
+                alert = %event.alert.spec.text,
+                phase = %event.phase,
+                value = observed,
+                "Alarm"
+            );
+            let msg = config
+                .device
+                .alert_message(&event.alert, event.phase, event.state);
+            let control_id = format!("{}-{}", config.device.name, sequence);
+            outcomes.push(sender.send(tick_no, control_id, msg).await);
+        }
+        tokio::time::sleep(tick).await;
+    }
+    sender.disconnect().await;
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A simulator with one SpO2 low limit at 90 that clears at 92.
+    fn simulator(delay_secs: f64) -> VitalSimulator {
+        VitalSimulator::new(VitalsConfig {
+            update_secs: 30.0,
+            limits: vec![AlarmLimit {
+                vital: Vital::Spo2,
+                low: Some(90.0),
+                high: None,
+                priority: "PH".to_string(),
+                hysteresis: 2.0,
+                delay_secs,
+            }],
+            ..Default::default()
+        })
+    }
+
+    /// The phases of the events raised by an SpO2 reading of `value` at `time`.
+    fn phases(simulator: &mut VitalSimulator, time: f64, value: f64) -> Vec<&'static str> {
+        simulator.time = time;
+        simulator
+            .evaluate(&[(Vital::Spo2, value)])
+            .iter()
+            .map(|event| event.phase)
+            .collect()
+    }
+
+    #[test]
+    fn alarm_ends_only_outside_the_hysteresis_band() {
+        let mut simulator = simulator(0.0);
+        assert_eq!(phases(&mut simulator, 1.0, 85.0), ["start"]);
+        // Back above the limit but still inside the band.
+        assert!(phases(&mut simulator, 2.0, 91.0).is_empty());
+        assert!(phases(&mut simulator, 3.0, 91.9).is_empty());
+        assert_eq!(phases(&mut simulator, 4.0, 92.0), ["end"]);
+    }
+
+    #[test]
+    fn violation_shorter_than_the_delay_does_not_raise() {
+        let mut simulator = simulator(10.0);
+        assert!(phases(&mut simulator, 1.0, 85.0).is_empty());
+        assert!(phases(&mut simulator, 9.0, 85.0).is_empty());
+        assert!(phases(&mut simulator, 10.0, 95.0).is_empty());
+        // The delay starts over with the next violation.
+        assert!(phases(&mut simulator, 12.0, 85.0).is_empty());
+        assert!(phases(&mut simulator, 20.0, 85.0).is_empty());
+        assert_eq!(phases(&mut simulator, 22.0, 85.0), ["start"]);
+    }
+
+    #[test]
+    fn start_continue_end() {
+        let mut simulator = simulator(0.0);
+        simulator.time = 1.0;
+        let start = simulator.evaluate(&[(Vital::Spo2, 85.0)]);
+        assert!(phases(&mut simulator, 20.0, 84.0).is_empty());
+        simulator.time = 31.0;
+        let update = simulator.evaluate(&[(Vital::Spo2, 83.0)]);
+        simulator.time = 40.0;
+        let end = simulator.evaluate(&[(Vital::Spo2, 97.0)]);
+
+        let events: Vec<&AlarmEvent> = start.iter().chain(&update).chain(&end).collect();
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.phase, e.state, e.alert.update))
+                .collect::<Vec<_>>(),
+            [
+                ("start", "active", 0),
+                ("continue", "active", 1),
+                ("end", "inactive", 2)
+            ]
+        );
+        assert!(events.iter().all(|e| e.alert.id == start[0].alert.id));
+        assert_eq!(events[0].alert.spec.code, EVENT_LOW);
+        assert_eq!(events[0].alert.spec.priority, "PH");
+        let value = |e: &AlarmEvent| e.alert.spec.observation.as_ref().unwrap().value.clone();
+        assert_eq!(
+            events.iter().map(|e| value(e)).collect::<Vec<_>>(),
+            ["85", "83", "97"]
+        );
+
+        // The next alarm gets a new ID.
+        let again = simulator.evaluate(&[(Vital::Spo2, 80.0)]);
+        assert_ne!(again[0].alert.id, start[0].alert.id);
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use pcd_acm::tls::{self, TlsConfig, TlsTransport};
+use pcd_acm::transport::{DefaultTransport, Transport};
+
+/// A certificate authority generated for one test, with its files in a scratch directory.
+struct TestCa {
+    dir: PathBuf,
+    name: String,
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl TestCa {
+    fn new(dir: &Path, name: &str) -> Self {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = params.self_signed(&key).unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+        TestCa {
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+            cert,
+            key,
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(format!("{}.pem", self.name))
+    }
+
+    /// An identity for `localhost` issued by this CA that trusts the CAs in `trusted`.
+    fn issue(&self, name: &str, trusted: &[&TestCa]) -> TlsConfig {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        let config = TlsConfig {
+            cert: self.dir.join(format!("{}.crt", name)),
+            key: self.dir.join(format!("{}.key", name)),
+            ca: trusted.iter().map(|ca| ca.path()).collect(),
+            server_name: Some("localhost".to_string()),
+        };
+        fs::write(&config.cert, cert.pem()).unwrap();
+        fs::write(&config.key, key.serialize_pem()).unwrap();
+        config
+    }
+}
+
+fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pcd-acm-tls-{}-{}", test, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn transport(config: &TlsConfig) -> TlsTransport {
+    TlsTransport::new(config, Arc::new(DefaultTransport)).unwrap()
+}
+
+/// Connects `client` to `server` over TCP and echoes one message, returning the errors of the
+/// manager and reporter sides.
+async fn exchange(
+    server: &TlsTransport,
+    client: &TlsTransport,
+) -> (io::Result<()>, io::Result<()>) {
+    let listener = server.listen("127.0.0.1:0").await.unwrap();
+    let address = listener.local_address().unwrap();
+
+    let manager = async {
+        let (socket, peer) = listener.accept().await?;
+        let mut socket = listener.handshake(socket, &peer).await?;
+        let mut buf = [0; 5];
+        socket.read_exact(&mut buf).await?;
+        socket.write_all(&buf).await?;
+        socket.flush().await
+    };
+    let reporter = async {
+        let mut socket = client.connect(&address).await?;
+        socket.write_all(b"hello").await?;
+        socket.flush().await?;
+        let mut buf = [0; 5];
+        socket.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    };
+    tokio::join!(manager, reporter)
+}
+
+#[tokio::test]
+async fn mutual_handshake() {
+    let dir = scratch_dir("mutual");
+    let ca = TestCa::new(&dir, "hospital-ca");
+    let server = transport(&ca.issue("manager", &[&ca]));
+    let client = transport(&ca.issue("reporter", &[&ca]));
+
+    let (manager, reporter) = exchange(&server, &client).await;
+    manager.unwrap();
+    reporter.unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn untrusted_client_certificate_fails_the_handshake() {
+    let dir = scratch_dir("untrusted");
+    let ca = TestCa::new(&dir, "hospital-ca");
+    let rogue = TestCa::new(&dir, "rogue-ca");
+    let server = transport(&ca.issue("manager", &[&ca]));
+    // The reporter trusts the manager, but its own certificate comes from another CA.
+    let client = transport(&rogue.issue("reporter", &[&ca]));
+
+    let (manager, reporter) = exchange(&server, &client).await;
+    let manager = manager.unwrap_err();
+    assert!(tls::is_handshake_failure(&manager), "{}", manager);
+    let reporter = reporter.unwrap_err();
+    assert!(tls::is_handshake_failure(&reporter), "{}", reporter);
+    fs::remove_dir_all(&dir).unwrap();
+}